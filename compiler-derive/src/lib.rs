@@ -0,0 +1,285 @@
+//! `#[derive(IntoValue, FromValue)]` for the conversion traits in `compiler::interpreter::value`.
+//!
+//! - Structs with named fields become objects keyed by field name.
+//! - Newtype structs convert as their only field, other tuple structs as arrays and unit structs as nil.
+//! - Unit enum variants become strings of the variant name. Other variants become objects
+//!   with the variant name under the `type` key, plus their fields keyed by name or index.
+//!
+//! `#[rlox(rename = "...")]` renames a field or variant, `#[rlox(tag = "...")]` on an enum changes the `type` key.
+//! `Option` fields are nil when `None`, which leaves them out of the object.
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::{format_ident, quote};
+use syn::{
+    parse_macro_input, parse_quote, Attribute, Data, DeriveInput, Fields, Generics, Ident, LitStr,
+    Path, Result,
+};
+
+#[proc_macro_derive(IntoValue, attributes(rlox))]
+pub fn derive_into_value(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    into_value(&input)
+        .unwrap_or_else(|err| err.to_compile_error())
+        .into()
+}
+
+#[proc_macro_derive(FromValue, attributes(rlox))]
+pub fn derive_from_value(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    from_value(&input)
+        .unwrap_or_else(|err| err.to_compile_error())
+        .into()
+}
+
+#[derive(Default)]
+struct Attrs {
+    rename: Option<String>,
+    tag: Option<String>,
+}
+impl Attrs {
+    fn parse(attrs: &[Attribute]) -> Result<Self> {
+        let mut result = Self::default();
+        for attr in attrs.iter().filter(|attr| attr.path().is_ident("rlox")) {
+            attr.parse_nested_meta(|meta| {
+                let value = || meta.value()?.parse::<LitStr>().map(|lit| lit.value());
+                if meta.path.is_ident("rename") {
+                    result.rename = Some(value()?);
+                } else if meta.path.is_ident("tag") {
+                    result.tag = Some(value()?);
+                } else {
+                    return Err(meta.error("expected `rename` or `tag`"));
+                }
+                Ok(())
+            })?;
+        }
+        Ok(result)
+    }
+}
+
+fn value_path() -> Path {
+    parse_quote!(::compiler::interpreter::value)
+}
+
+/// Script-side name of a field or variant.
+fn name_of(ident: &Ident, attrs: &[Attribute]) -> Result<String> {
+    Ok(Attrs::parse(attrs)?
+        .rename
+        .unwrap_or_else(|| ident.to_string()))
+}
+
+/// Adds `bound` to every type parameter.
+fn with_bound(generics: &Generics, bound: TokenStream2) -> Generics {
+    let mut generics = generics.clone();
+    for param in generics.type_params_mut() {
+        param.bounds.push(parse_quote!(#bound));
+    }
+    generics
+}
+
+/// Object keys of `fields`, as expressions building the key `Value`.
+fn field_keys(fields: &Fields) -> Result<Vec<TokenStream2>> {
+    let value = value_path();
+    fields
+        .iter()
+        .enumerate()
+        .map(|(i, field)| match &field.ident {
+            Some(ident) => {
+                let name = name_of(ident, &field.attrs)?;
+                Ok(quote!(#value::Value::String(
+                    ::compiler::interpreter::string::ValueStr::from(#name)
+                )))
+            }
+            None => {
+                let index = i as f64;
+                Ok(quote!(#value::Value::Number(#index)))
+            }
+        })
+        .collect()
+}
+
+/// Bindings for destructuring `fields`, `f0, f1, ...` for tuple fields.
+fn field_bindings(fields: &Fields) -> Vec<Ident> {
+    fields
+        .iter()
+        .enumerate()
+        .map(|(i, field)| {
+            field
+                .ident
+                .clone()
+                .unwrap_or_else(|| format_ident!("f{}", i))
+        })
+        .collect()
+}
+
+fn destructure(path: TokenStream2, fields: &Fields, bindings: &[Ident]) -> TokenStream2 {
+    match fields {
+        Fields::Named(_) => quote!(#path { #(#bindings),* }),
+        Fields::Unnamed(_) => quote!(#path ( #(#bindings),* )),
+        Fields::Unit => path,
+    }
+}
+
+fn into_value(input: &DeriveInput) -> Result<TokenStream2> {
+    let value = value_path();
+    let ident = &input.ident;
+    let generics = with_bound(&input.generics, quote!(#value::IntoValue));
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+
+    let body = match &input.data {
+        Data::Struct(data) => {
+            let bindings = field_bindings(&data.fields);
+            let pattern = destructure(quote!(Self), &data.fields, &bindings);
+            let conversion = match &data.fields {
+                Fields::Named(_) => {
+                    let keys = field_keys(&data.fields)?;
+                    quote!(#value::IntoValue::into_value(#value::Object::from_entries([
+                        #((#keys, #value::IntoValue::into_value(#bindings))),*
+                    ])))
+                }
+                Fields::Unnamed(fields) if fields.unnamed.len() == 1 => {
+                    quote!(#value::IntoValue::into_value(#(#bindings)*))
+                }
+                Fields::Unnamed(_) => quote!(#value::IntoValue::into_value(
+                    ::std::vec![#(#value::IntoValue::into_value(#bindings)),*]
+                )),
+                Fields::Unit => quote!(#value::Value::Nil),
+            };
+            quote!(let #pattern = self; #conversion)
+        }
+        Data::Enum(data) => {
+            let tag = Attrs::parse(&input.attrs)?
+                .tag
+                .unwrap_or_else(|| "type".to_owned());
+            let arms = data
+                .variants
+                .iter()
+                .map(|variant| {
+                    let variant_ident = &variant.ident;
+                    let name = name_of(variant_ident, &variant.attrs)?;
+                    let bindings = field_bindings(&variant.fields);
+                    let pattern =
+                        destructure(quote!(Self::#variant_ident), &variant.fields, &bindings);
+                    if let Fields::Unit = variant.fields {
+                        return Ok(quote!(#pattern => #value::IntoValue::into_value(#name)));
+                    }
+                    let keys = field_keys(&variant.fields)?;
+                    Ok(quote!(#pattern => #value::IntoValue::into_value(
+                        #value::Object::from_entries([
+                            (#value::IntoValue::into_value(#tag), #value::IntoValue::into_value(#name)),
+                            #((#keys, #value::IntoValue::into_value(#bindings))),*
+                        ])
+                    )))
+                })
+                .collect::<Result<Vec<_>>>()?;
+            quote!(match self { #(#arms,)* })
+        }
+        Data::Union(data) => {
+            return Err(syn::Error::new(
+                data.union_token.span,
+                "IntoValue can't be derived for unions",
+            ))
+        }
+    };
+
+    Ok(quote! {
+        impl #impl_generics #value::IntoValue for #ident #ty_generics #where_clause {
+            fn into_value(self) -> #value::Value {
+                #body
+            }
+        }
+    })
+}
+
+/// Expression converting the fields read from `object` into `path`.
+fn construct(path: TokenStream2, fields: &Fields) -> Result<TokenStream2> {
+    let value = value_path();
+    let keys = field_keys(fields)?;
+    let bindings = field_bindings(fields);
+    let constructor = destructure(path, fields, &bindings);
+    Ok(quote!({
+        #(let #bindings = #value::FromValue::from_value(object.get_property(&#keys)?)?;)*
+        #constructor
+    }))
+}
+
+fn from_value(input: &DeriveInput) -> Result<TokenStream2> {
+    let value = value_path();
+    let error = quote!(::compiler::error::ErrorKind);
+    let ident = &input.ident;
+    let type_name = ident.to_string();
+    let generics = with_bound(&input.generics, quote!(#value::FromValue));
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+
+    let body = match &input.data {
+        Data::Struct(data) => match &data.fields {
+            Fields::Named(_) => {
+                let constructor = construct(quote!(Self), &data.fields)?;
+                quote! {
+                    let object = value.try_object()?;
+                    let object = object.borrow();
+                    Ok(#constructor)
+                }
+            }
+            Fields::Unnamed(fields) if fields.unnamed.len() == 1 => {
+                quote!(Ok(Self(#value::FromValue::from_value(value)?)))
+            }
+            Fields::Unnamed(_) => {
+                let constructor = construct(quote!(Self), &data.fields)?;
+                quote! {
+                    let object = value;
+                    Ok(#constructor)
+                }
+            }
+            Fields::Unit => quote! {
+                <() as #value::FromValue>::from_value(value)?;
+                Ok(Self)
+            },
+        },
+        Data::Enum(data) => {
+            let tag = Attrs::parse(&input.attrs)?
+                .tag
+                .unwrap_or_else(|| "type".to_owned());
+            let arms = data
+                .variants
+                .iter()
+                .map(|variant| {
+                    let variant_ident = &variant.ident;
+                    let name = name_of(variant_ident, &variant.attrs)?;
+                    let constructor = construct(quote!(Self::#variant_ident), &variant.fields)?;
+                    Ok(quote!(#name => Ok(#constructor)))
+                })
+                .collect::<Result<Vec<_>>>()?;
+            quote! {
+                let name = match &value {
+                    #value::Value::String(name) => name.clone(),
+                    #value::Value::Object(object) => {
+                        let tag = object.borrow().get_property(&#value::IntoValue::into_value(#tag))?;
+                        <::compiler::interpreter::string::ValueStr as #value::FromValue>::from_value(tag)?
+                    }
+                    value => return Err(#error::InvalidType(value.type_str(), #type_name)),
+                };
+                let object = value;
+                match name.as_str() {
+                    #(#arms,)*
+                    _ => Err(#error::InvalidVariant(name, #type_name)),
+                }
+            }
+        }
+        Data::Union(data) => {
+            return Err(syn::Error::new(
+                data.union_token.span,
+                "FromValue can't be derived for unions",
+            ))
+        }
+    };
+
+    Ok(quote! {
+        impl #impl_generics #value::FromValue for #ident #ty_generics #where_clause {
+            #[allow(unused_variables)]
+            fn from_value(value: #value::Value) -> ::std::result::Result<Self, #error> {
+                #body
+            }
+        }
+    })
+}
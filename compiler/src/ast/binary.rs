@@ -5,6 +5,29 @@ use crate::{
 
 use super::*;
 
+/// Binary operators from the loosest to the tightest binding, as parsed by the `next_*` chain below.
+/// All of them are left associative. `and` and `or` are parsed as `&&` and `||`.
+pub const PRECEDENCE: &[&[&str]] = &[
+    &["||"],
+    &["&&"],
+    &["|"],
+    &["^"],
+    &["&"],
+    &["==", "!="],
+    &["<", ">", "<=", ">="],
+    &[">>", "<<", ">>>"],
+    &["+", "-"],
+    &["*", "/", "%"],
+    &["**"],
+];
+
+/// Index of the operator in `PRECEDENCE`, higher binding tighter.
+pub fn precedence(operator: &str) -> Option<usize> {
+    PRECEDENCE
+        .iter()
+        .position(|operators| operators.contains(&operator))
+}
+
 impl<R: BufRead> Parser<R> {
     pub fn next_binary(&mut self, skip_newline: bool) -> Result<Option<Expression>> {
         self.next_assign(skip_newline)
@@ -8,11 +8,12 @@ pub mod primitive;
 pub mod statement;
 pub mod unary;
 
-use std::{cell::RefCell, fmt, io::BufRead, rc::Rc};
+use std::{cell::RefCell, collections::BTreeMap, fmt, io::BufRead, rc::Rc};
 
 use crate::{
     error::{Error, ErrorKind, Result},
-    span::{Span, SpanOf},
+    source_map::SourceMap,
+    span::{FileId, Span, SpanOf},
 };
 
 /// Struct that handles iteration over chars and storing accumulated chars.
@@ -23,39 +24,68 @@ use crate::{
 pub struct Parser<R> {
     reader: Rc<RefCell<R>>,
     buffer: Rc<RefCell<String>>,
+    sources: SourceMap,
+    file: FileId,
     offset: usize,
+    comments: Rc<RefCell<BTreeMap<usize, Span>>>, // by start, as backtracking skips comments more than once
+    errors: Option<Rc<RefCell<Vec<Error>>>>, // syntax errors recovered from, when parsing with `parse_program`
 }
 impl<R> Parser<R> {
     pub fn source(&self) -> Rc<RefCell<String>> {
         self.buffer.clone()
     }
+    /// Source map the parsed file is in.
+    pub fn sources(&self) -> SourceMap {
+        self.sources.clone()
+    }
+    pub fn file(&self) -> FileId {
+        self.file
+    }
+    /// Spans of the `--` and `--[[ ]]` comments skipped so far, in source order.
+    pub fn comments(&self) -> Vec<Span> {
+        self.comments.borrow().values().copied().collect()
+    }
 }
 impl<R> Clone for Parser<R> {
     fn clone(&self) -> Self {
         Self {
             reader: self.reader.clone(),
             buffer: self.buffer.clone(),
+            sources: self.sources.clone(),
+            file: self.file,
             offset: self.offset,
+            comments: self.comments.clone(),
+            errors: self.errors.clone(),
         }
     }
 }
 impl<R: BufRead> Parser<R> {
+    /// Parser of an unnamed file in a source map of its own.
     pub fn new(reader: R) -> Self {
+        Self::with_file(reader, &SourceMap::new(), "")
+    }
+    /// Parser of a file added to `sources` as `name`, so spans of several files can be told apart.
+    pub fn with_file(reader: R, sources: &SourceMap, name: &str) -> Self {
+        let buffer = Rc::new(RefCell::new(String::new()));
         Self {
             reader: Rc::new(RefCell::new(reader)),
-            buffer: Rc::new(RefCell::new(String::new())),
+            file: sources.add(name, buffer.clone()),
+            buffer,
+            sources: sources.clone(),
             offset: 0,
+            comments: Default::default(),
+            errors: None,
         }
     }
     pub fn error(&self, span: Span, kind: ErrorKind) -> Error {
-        Error {
-            kind,
-            span,
-            source: self.buffer.clone(),
-        }
+        Error::new(kind, span, self.sources.clone())
     }
     pub fn error_to_here(&self, from: usize, kind: ErrorKind) -> Error {
-        self.error(Span::new(from, self.offset), kind)
+        self.error(self.span(from, self.offset), kind)
+    }
+    /// Span in the parsed file.
+    pub fn span(&self, start: usize, end: usize) -> Span {
+        Span::new(start, end).in_file(self.file)
     }
     pub fn next_and<T>(
         &mut self,
@@ -92,13 +122,14 @@ impl<R: BufRead> Parser<R> {
                 Some(ch) => {
                     let index = self.offset;
                     self.offset += ch.len_utf8();
-                    return Ok(Some(SpanOf(Span::from_len(index, ch.len_utf8()), ch)));
+                    let span = Span::from_len(index, ch.len_utf8()).in_file(self.file);
+                    return Ok(Some(SpanOf(span, ch)));
                 }
                 None => {
                     let mut reader = self.reader.borrow_mut();
                     if reader
                         .read_line(&mut buffer)
-                        .map_err(|e| self.error(Span::from_len(self.offset, 0), e.into()))?
+                        .map_err(|e| self.error(self.span(self.offset, self.offset), e.into()))?
                         == 0
                     {
                         return Ok(None);
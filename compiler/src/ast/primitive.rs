@@ -63,7 +63,7 @@ impl<R: BufRead> Parser<R> {
     }
     fn skip_comments(&mut self) -> Result<bool> {
         let mut skipped = false;
-        if self.next_sequence("--")?.is_some() {
+        if let Some(start) = self.next_sequence("--")? {
             skipped = true;
             if self.next_sequence("[[")?.is_some() {
                 loop {
@@ -74,6 +74,8 @@ impl<R: BufRead> Parser<R> {
             } else {
                 while self.next_if(|ch| ch.1 != '\n')?.is_some() {}
             }
+            let span = start.with_end(self.offset);
+            self.comments.borrow_mut().insert(span.start, span);
         }
         Ok(skipped)
     }
@@ -89,11 +91,13 @@ impl<R: BufRead> Parser<R> {
     }
     pub fn skip_seperator(&mut self) -> Result<bool> {
         let mut skipped = false;
-        self.skip(false)?;
-        while self.next_if(|ch| ch.1 == ';' || ch.1 == '\n')?.is_some() {
+        loop {
+            self.skip(false)?;
+            if self.next_if(|ch| ch.1 == ';' || ch.1 == '\n')?.is_none() {
+                return Ok(skipped);
+            }
             skipped = true;
         }
-        Ok(skipped)
     }
     /// Parses partial integer. More specifically, it parses integer without the prefix part, sending the radix as a parameter
     fn next_partial_integer(&mut self, radix: u32) -> Result<Option<SpanOf<BigUint>>> {
@@ -168,7 +172,10 @@ impl<R: BufRead> Parser<R> {
         let Some(first_digit) = self.next_digit(radix)? else {
             return Err(match sign {
                 Some(s) => self.error(s.0, ErrorKind::MissingExponent),
-                None => self.error(Span::from_len(self.offset, 0), ErrorKind::MissingExponent),
+                None => self.error(
+                    self.span(self.offset, self.offset),
+                    ErrorKind::MissingExponent,
+                ),
             });
         };
         let mut integer = first_digit.map(|i| i as i64);
@@ -104,8 +104,13 @@ impl<R: BufRead> Parser<R> {
             if let Some(terminator) = self.next_terminators()? {
                 return Ok((statements, Some(terminator)));
             }
-            if let Some(statement) = self.next_statement()? {
-                statements.push(statement);
+            match self.next_statement() {
+                Ok(Some(statement)) => statements.push(statement),
+                Ok(None) => {}
+                Err(err) => {
+                    self.recover(err)?;
+                    continue;
+                }
             }
             if !self.skip_seperator()? {
                 return Ok((statements, self.next_terminators()?));
@@ -117,10 +122,14 @@ impl<R: BufRead> Parser<R> {
             return Ok(None);
         };
         let (statements, Some(terminator)) = self.next_block()? else {
-            return Err(self.error(do_keyword.0, ErrorKind::ExpectedEnd));
+            return Err(self
+                .error_to_here(self.offset, ErrorKind::ExpectedEnd)
+                .with_label(do_keyword.0, "block opened here"));
         };
         if &*terminator.get_str() != "end" {
-            return Err(self.error(terminator.0, ErrorKind::ExpectedEnd));
+            return Err(self
+                .error(terminator.0, ErrorKind::ExpectedEnd)
+                .with_label(do_keyword.0, "block opened here"));
         }
         Ok(Some(SpanOf(do_keyword.0.concat(terminator.0), statements)))
     }
@@ -179,7 +188,9 @@ impl<R: BufRead> Parser<R> {
             return Err(self.error(condition.span(), ErrorKind::ExpectedThen));
         };
         let (met_block, Some(terminator)) = self.next_block()? else {
-            return Err(self.error(if_keyword.0.concat(then_keyword.0), ErrorKind::ExpectedElse));
+            return Err(self
+                .error_to_here(self.offset, ErrorKind::ExpectedElse)
+                .with_label(if_keyword.0.concat(then_keyword.0), "block opened here"));
         };
 
         let keyword = terminator.get_str();
@@ -210,10 +221,14 @@ impl<R: BufRead> Parser<R> {
                     }))
                 } else {
                     let (else_block, Some(else_terminator)) = self.next_block()? else {
-                        return Err(self.error(terminator.0, ErrorKind::ExpectedEnd));
+                        return Err(self
+                            .error_to_here(self.offset, ErrorKind::ExpectedEnd)
+                            .with_label(terminator.0, "block opened here"));
                     };
                     if &*else_terminator.get_str() != "end" {
-                        return Err(self.error(else_terminator.0, ErrorKind::ExpectedEnd));
+                        return Err(self
+                            .error(else_terminator.0, ErrorKind::ExpectedEnd)
+                            .with_label(terminator.0, "block opened here"));
                     }
                     Ok(Some(Statement::If {
                         span: if_keyword.0.concat(else_terminator.0),
@@ -273,7 +288,76 @@ impl<R: BufRead> Parser<R> {
                 return Ok(Some(stmt));
             }
         }
-        Ok(None)
+        // a stray terminator is reported as a whole
+        if let Some(terminator) = self.next_terminators()? {
+            return Err(self.error(terminator.0, ErrorKind::UnexpectedToken));
+        }
+        match self.next_ch()? {
+            Some(ch) => Err(self.error(ch.0, ErrorKind::UnexpectedToken)),
+            None => Ok(None),
+        }
+    }
+    /// Parses the whole input, recovering from syntax errors to report all of them along with the statements that
+    /// could be parsed.
+    pub fn parse_program(&mut self) -> (Vec<Statement>, Vec<Error>) {
+        let errors = Rc::new(RefCell::new(vec![]));
+        self.errors = Some(errors.clone());
+        let mut statements = vec![];
+        loop {
+            match self.next_statement() {
+                Ok(Some(statement)) => statements.push(statement),
+                Ok(None) => break,
+                Err(err) => {
+                    if let Err(err) = self.recover(err) {
+                        errors.borrow_mut().push(err);
+                        break;
+                    }
+                }
+            }
+        }
+        self.errors = None;
+        let errors = errors.take();
+        (statements, errors)
+    }
+    /// Records the error if recovering from errors and skips to where parsing can continue, which is after the next
+    /// separator or before the `end` or `else` terminating the current block. Blocks opened while skipping are
+    /// skipped entirely.
+    fn recover(&mut self, err: Error) -> Result<()> {
+        let Some(errors) = &self.errors else {
+            return Err(err);
+        };
+        errors.borrow_mut().push(err);
+        let mut depth = 0usize;
+        loop {
+            self.skip(false)?;
+            if self.next_if(|ch| ch.1 == ';' || ch.1 == '\n')?.is_some() {
+                if depth == 0 {
+                    return Ok(());
+                }
+                continue;
+            }
+            let prev = self.clone();
+            if let Some(keyword) = self.next_keywords(["do", "then", "end", "else"], false)? {
+                match (&*keyword.get_str(), depth) {
+                    ("end" | "else", 0) => {
+                        *self = prev;
+                        return Ok(());
+                    }
+                    ("end", _) => depth -= 1,
+                    ("else", _) => {}
+                    _ => depth += 1,
+                }
+                continue;
+            }
+            // literals are skipped as a whole so that keywords in strings don't count
+            if let Ok(Some(_)) = self.next_primitive(false) {
+                continue;
+            }
+            *self = prev;
+            if self.next_ch()?.is_none() {
+                return Ok(());
+            }
+        }
     }
 }
 
@@ -344,4 +428,48 @@ end",
             assert_eq!(result, answer);
         }
     }
+
+    #[test]
+    fn test_recovery() {
+        let source = r#"
+let a = )
+fn f(x) do
+    print(x @ 1)
+    return "end" + x
+end
+if a then print(a) else print(f(a) end
+) let b = 2
+"#;
+        let mut parser = Parser::new(source.as_bytes());
+        let (statements, errors) = parser.parse_program();
+        let errors = errors
+            .iter()
+            .map(|err| (err.kind.to_string(), &source[err.span.start..err.span.end]))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            errors,
+            [
+                ("Expected expression".to_string(), "="),
+                ("Expected `)`".to_string(), "("),
+                ("Expected `)`".to_string(), "("),
+                ("Unexpected token".to_string(), ")"),
+            ]
+        );
+        let statements = statements
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            statements,
+            [
+                "fn f(x) do\n. return (\"end\") + (x)\nend",
+                // the else block recovers up to its `end`
+                "if a then\n. (print)(a)\nelse\nend",
+            ]
+        );
+
+        // without `parse_program`, parsing stops at the first error
+        let mut parser = Parser::new(source.as_bytes());
+        assert!(parser.next_statement().is_err());
+    }
 }
@@ -1,11 +1,8 @@
 use crate::{
     ast::expression::{Assignee, Expression},
-    codegen::Codegen,
+    codegen::{Codegen, Resolved},
     error::Result,
-    interpreter::{
-        bytecode::{BinaryOp, Bytecode},
-        string::ValueStr,
-    },
+    interpreter::bytecode::{BinaryOp, Bytecode},
     span::{GetSpan, SpanOf},
 };
 
@@ -70,13 +67,11 @@ impl Codegen {
 
         match assignee {
             Assignee::Ident(ident) => {
-                let name = ValueStr::interned(&ident.get_str());
-                let bytecode = if let Some(id) = self.get_local_var(name.clone()) {
-                    Bytecode::StoreLocal(id)
-                } else if let Some(id) = self.get_upvalue(name.clone()) {
-                    Bytecode::StoreUpvalue(id)
-                } else {
-                    Bytecode::StoreGlobal(name)
+                let name = self.intern(&ident.get_str());
+                let bytecode = match self.resolve(name, ident.0) {
+                    Resolved::Local(id) => Bytecode::StoreLocal(id),
+                    Resolved::Upvalue(id) => Bytecode::StoreUpvalue(id),
+                    Resolved::Global(slot) => Bytecode::StoreGlobal(slot),
                 };
                 self.push_bytecode(SpanOf(ident.0, bytecode));
             }
@@ -89,7 +84,7 @@ impl Codegen {
                 self.gen_expr(operand)?;
                 self.push_bytecode(SpanOf(
                     assignee.span(),
-                    Bytecode::StoreProperty(ValueStr::interned(&ident.get_str())),
+                    Bytecode::StoreProperty(self.intern(&ident.get_str())),
                 ));
             }
         }
@@ -108,7 +103,7 @@ mod tests {
     #[test]
     fn assign_gen_test() {
         let mut parser = Parser::new("a=b=c.d=e.f[0]=1+2".as_bytes());
-        let mut codegen = Codegen::with_source(parser.source());
+        let mut codegen = Codegen::with_context(parser.sources(), Default::default());
 
         codegen
             .gen_expr(&parser.next_expression(false).unwrap().unwrap())
@@ -119,19 +114,19 @@ mod tests {
             Bytecode::LoadNum(2.0),
             Bytecode::Binary(BinaryOp::Add),
             Bytecode::Dup(2),
-            Bytecode::LoadGlobal("e".into()),
+            Bytecode::LoadGlobal(0),
             Bytecode::LoadProperty("f".into()),
             Bytecode::LoadNum(0.0),
             Bytecode::StorePropertyIndirect,
             Bytecode::Dup(2),
-            Bytecode::LoadGlobal("c".into()),
+            Bytecode::LoadGlobal(1),
             Bytecode::StoreProperty("d".into()),
             Bytecode::Dup(2),
-            Bytecode::StoreGlobal("b".into()),
+            Bytecode::StoreGlobal(2),
             Bytecode::Dup(2),
-            Bytecode::StoreGlobal("a".into()),
+            Bytecode::StoreGlobal(3),
         ];
-        for (bc, expected) in codegen.bytecodes().into_iter().zip(expected) {
+        for (bc, expected) in codegen.bytecodes().iter().zip(expected) {
             println!("{:?}", bc.1);
             assert_eq!(format!("{:?}", expected), format!("{:?}", bc.1));
         }
@@ -140,7 +135,7 @@ mod tests {
     #[test]
     fn binary_gen_test() {
         let mut parser = Parser::new("1!=0 + 2 * 0.2 or 3 <= 3 and 3>2".as_bytes());
-        let mut codegen = Codegen::with_source(parser.source());
+        let mut codegen = Codegen::with_context(parser.sources(), Default::default());
 
         let expected = [
             Bytecode::LoadNum(1.0),
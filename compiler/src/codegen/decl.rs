@@ -3,11 +3,11 @@ use std::rc::Rc;
 use crate::{
     ast::{
         declaration::{Declaration, FuncDecl, FunctionBody, VarDecl},
-        expression::Closure,
+        expression::{Closure, Expression},
     },
     codegen::Codegen,
     error::Result,
-    interpreter::{bytecode::Bytecode, string::ValueStr, FnBody, FnSignature},
+    interpreter::{bytecode::Bytecode, debug::DebugInfo, string::ValueStr, FnBody, FnSignature},
     span::{GetSpan, SpanOf},
 };
 
@@ -25,17 +25,24 @@ impl Codegen {
         debug_assert_eq!(self.stack_size(), 0);
         Ok(())
     }
-    pub(crate) fn create_func_sig(&mut self, decl: &Closure) -> Result<FnSignature> {
+    /// Compiles the closure, `name` being the name it's declared with, if any.
+    pub(crate) fn create_func_sig(
+        &mut self,
+        decl: &Closure,
+        name: Option<ValueStr>,
+    ) -> Result<FnSignature> {
         self.push_frame();
 
         // declare params as local variables
         for p in &decl.params.1 {
-            let p_name = ValueStr::interned(&p.get_str());
-            self.decl_local(p_name).unwrap();
+            let p_name = self.intern(&p.get_str());
+            let id = self.decl_local(p_name.clone()).unwrap();
+            self.define_symbol(p_name, p.0, Some(id));
         }
         if let Some(var) = decl.variadic.as_ref() {
-            let var_name = ValueStr::interned(&var.1.get_str());
-            self.decl_local(var_name).unwrap();
+            let var_name = self.intern(&var.1.get_str());
+            let id = self.decl_local(var_name.clone()).unwrap();
+            self.define_symbol(var_name, var.1 .0, Some(id));
         }
 
         // write the body
@@ -46,15 +53,21 @@ impl Codegen {
                 }
             }
             FunctionBody::Expression(expr) => {
-                self.gen_expr(expr)?;
-                self.push_bytecode(SpanOf(expr.span(), Bytecode::Return));
+                self.gen_return(expr, expr.span())?;
                 debug_assert_eq!(self.stack_size(), 0);
             }
         }
 
         // resulting frame
-        let frame = self.pop_frame().unwrap();
+        self.close_symbols(0, decl.span().end);
+        let mut frame = self.pop_frame().unwrap();
+        let debug = DebugInfo {
+            name,
+            span: decl.span(),
+            ..frame.debug_info()
+        };
         Ok(FnSignature {
+            debug,
             arity: decl.params.1.len(),
             variadic: decl.variadic.is_some(),
             upvalues: frame.upvalues.into_iter().map(|(_, loc)| loc).collect(),
@@ -64,57 +77,65 @@ impl Codegen {
     fn gen_func_decl(&mut self, decl: &FuncDecl) -> Result<()> {
         // pre-declare the function name to allow recursion
         // function declaration is const by default
-        let name = ValueStr::interned(&decl.ident.get_str());
+        let name = self.intern(&decl.ident.get_str());
         let decl_id = self.decl_local(name.clone());
-        if decl_id.is_none() {
-            self.push_bytecode(SpanOf(
-                decl.fn_keyword,
-                Bytecode::GlobalDeclare(name.clone()),
-            ));
+        let symbol = self.define_symbol(name.clone(), decl.ident.0, decl_id);
+        self.define_function(symbol, &decl.closure);
+        let slot = decl_id.is_none().then(|| self.global_slot(name.clone()));
+        if let Some(slot) = slot {
+            self.push_bytecode(SpanOf(decl.fn_keyword, Bytecode::GlobalDeclare(slot)));
         }
 
-        let sig = self.create_func_sig(&decl.closure)?;
+        let sig = self.create_func_sig(&decl.closure, Some(name))?;
         self.push_bytecode(SpanOf(decl.closure.span(), Bytecode::LoadFn(Rc::new(sig))));
         debug_assert_eq!(self.stack_size(), 1);
         self.push_bytecode(SpanOf(
             decl.span(),
-            match decl_id {
-                Some(id) => Bytecode::StoreLocal(id),
-                None => Bytecode::StoreGlobal(name.clone()),
+            match (decl_id, slot) {
+                (Some(id), _) => Bytecode::StoreLocal(id),
+                (None, slot) => Bytecode::StoreGlobal(slot.unwrap()),
             },
         ));
 
         // mark constant
-        if decl_id.is_none() {
-            self.push_bytecode(SpanOf(decl.fn_keyword, Bytecode::GlobalReadOnly(name)));
+        if let Some(slot) = slot {
+            self.push_bytecode(SpanOf(decl.fn_keyword, Bytecode::GlobalReadOnly(slot)));
         }
 
         Ok(())
     }
     pub(crate) fn gen_var_decl(&mut self, decl: &VarDecl) -> Result<()> {
-        let name = ValueStr::interned(&decl.ident.get_str());
+        let name = self.intern(&decl.ident.get_str());
         let decl_id = self.decl_local(name.clone());
-        if decl_id.is_none() {
-            self.push_bytecode(SpanOf(
-                decl.keyword.0,
-                Bytecode::GlobalDeclare(name.clone()),
-            ));
+        let symbol = self.define_symbol(name.clone(), decl.ident.0, decl_id);
+        if let Expression::Closure(closure) = &decl.assigner {
+            self.define_function(symbol, closure);
+        }
+        let slot = decl_id.is_none().then(|| self.global_slot(name.clone()));
+        if let Some(slot) = slot {
+            self.push_bytecode(SpanOf(decl.keyword.0, Bytecode::GlobalDeclare(slot)));
         }
 
-        self.gen_expr(&decl.assigner)?;
+        match &decl.assigner {
+            Expression::Closure(closure) => {
+                let sig = self.create_func_sig(closure, Some(name))?;
+                self.push_bytecode(SpanOf(closure.span(), Bytecode::LoadFn(Rc::new(sig))));
+            }
+            assigner => self.gen_expr(assigner)?,
+        }
         debug_assert_eq!(self.stack_size(), 1);
         self.push_bytecode(SpanOf(
             decl.span(),
-            match decl_id {
-                Some(id) => Bytecode::StoreLocal(id),
-                None => Bytecode::StoreGlobal(name.clone()),
+            match (decl_id, slot) {
+                (Some(id), _) => Bytecode::StoreLocal(id),
+                (None, slot) => Bytecode::StoreGlobal(slot.unwrap()),
             },
         ));
 
         // TODO: Implement compile-time constant check for local variables!
-        if decl_id.is_none() {
+        if let Some(slot) = slot {
             if &*decl.keyword.get_str() == "const" {
-                self.push_bytecode(SpanOf(decl.keyword.0, Bytecode::GlobalReadOnly(name)));
+                self.push_bytecode(SpanOf(decl.keyword.0, Bytecode::GlobalReadOnly(slot)));
             }
         }
         Ok(())
@@ -130,6 +151,7 @@ mod tests {
         codegen::Codegen,
         interpreter::{
             bytecode::{BinaryOp, Bytecode},
+            debug::DebugInfo,
             FnBody, FnSignature,
         },
         span::{Span, SpanOf},
@@ -149,14 +171,14 @@ fn vector2(x, y) setbase({x, y}, vec2_base)
             "#
             .as_bytes(),
         );
-        let mut codegen = Codegen::with_source(parser.source());
+        let mut codegen = Codegen::with_context(parser.sources(), Default::default());
 
         while let Some(stmt) = parser.next_statement().unwrap() {
             codegen.gen_statement(&stmt).unwrap();
         }
 
         let expected = [
-            Bytecode::GlobalDeclare("vec2_base".into()),
+            Bytecode::GlobalDeclare(0),
             Bytecode::LoadStr("x".into()),
             Bytecode::LoadNum(0.0),
             Bytecode::LoadStr("y".into()),
@@ -166,6 +188,7 @@ fn vector2(x, y) setbase({x, y}, vec2_base)
                 arity: 1,
                 variadic: false,
                 upvalues: vec![],
+                debug: DebugInfo::default(),
                 body: FnBody::Bytecode(
                     [
                         Bytecode::LoadLocal(0),
@@ -189,14 +212,14 @@ fn vector2(x, y) setbase({x, y}, vec2_base)
                 arity: 1,
                 variadic: false,
                 upvalues: vec![],
+                debug: DebugInfo::default(),
                 body: FnBody::Bytecode(
                     [
-                        Bytecode::LoadGlobal("sqrt".into()),
+                        Bytecode::LoadGlobal(1),
                         Bytecode::LoadLocal(0),
                         Bytecode::LoadMethod("sqr_len".into()),
                         Bytecode::Call(1),
-                        Bytecode::Call(0),
-                        Bytecode::Return,
+                        Bytecode::TailCall(0),
                     ]
                     .into_iter()
                     .map(|bc| SpanOf(Span::default(), bc))
@@ -204,32 +227,32 @@ fn vector2(x, y) setbase({x, y}, vec2_base)
                 ),
             })),
             Bytecode::StackToObj(0),
-            Bytecode::StoreGlobal("vec2_base".into()),
-            Bytecode::GlobalReadOnly("vec2_base".into()),
-            Bytecode::GlobalDeclare("vector2".into()),
+            Bytecode::StoreGlobal(0),
+            Bytecode::GlobalReadOnly(0),
+            Bytecode::GlobalDeclare(2),
             Bytecode::LoadFn(Rc::new(FnSignature {
                 arity: 2,
                 variadic: false,
                 upvalues: vec![],
+                debug: DebugInfo::default(),
                 body: FnBody::Bytecode(
                     [
-                        Bytecode::LoadGlobal("setbase".into()),
+                        Bytecode::LoadGlobal(3),
                         Bytecode::LoadStr("x".into()),
                         Bytecode::LoadLocal(0),
                         Bytecode::LoadStr("y".into()),
                         Bytecode::LoadLocal(1),
                         Bytecode::StackToObj(1),
-                        Bytecode::LoadGlobal("vec2_base".into()),
-                        Bytecode::Call(0),
-                        Bytecode::Return,
+                        Bytecode::LoadGlobal(0),
+                        Bytecode::TailCall(0),
                     ]
                     .into_iter()
                     .map(|bc| SpanOf(Span::default(), bc))
                     .collect(),
                 ),
             })),
-            Bytecode::StoreGlobal("vector2".into()),
-            Bytecode::GlobalReadOnly("vector2".into()),
+            Bytecode::StoreGlobal(2),
+            Bytecode::GlobalReadOnly(2),
         ];
 
         for (bc, expected) in codegen.bytecodes().iter().zip(expected) {
@@ -2,9 +2,9 @@ use std::rc::Rc;
 
 use crate::{
     ast::expression::{Element, Expression, Pair},
-    codegen::Codegen,
+    codegen::{Codegen, Resolved},
     error::Result,
-    interpreter::{bytecode::Bytecode, string::ValueStr},
+    interpreter::bytecode::Bytecode,
     span::{GetSpan, SpanOf},
 };
 
@@ -44,14 +44,14 @@ impl Codegen {
                     if !dynamic {
                         self.push_bytecode(SpanOf(
                             key.0,
-                            Bytecode::LoadStr(ValueStr::interned(&key.get_str())),
+                            Bytecode::LoadStr(self.intern(&key.get_str())),
                         ));
                     } // only beneficial on non-dynamic initialization
                     self.gen_expr(value)?;
                     if dynamic {
                         self.push_bytecode(SpanOf(
                             key.0.concat(value.span()),
-                            Bytecode::AppendObj(ValueStr::interned(&key.get_str())),
+                            Bytecode::AppendObj(self.intern(&key.get_str())),
                         ));
                     }
                 }
@@ -90,22 +90,20 @@ impl Codegen {
                 self.push_bytecode(SpanOf(n.0, Bytecode::LoadNum(n.1.to_f64())))
             }
             Expression::String(str) => {
-                self.push_bytecode(SpanOf(str.0, Bytecode::LoadStr(ValueStr::interned(&str.1))))
+                self.push_bytecode(SpanOf(str.0, Bytecode::LoadStr(self.intern(&str.1))))
             }
             Expression::Closure(closure) => {
-                let sig = self.create_func_sig(closure)?;
+                let sig = self.create_func_sig(closure, None)?;
                 self.push_bytecode(SpanOf(closure.span(), Bytecode::LoadFn(Rc::new(sig))));
             }
             Expression::Array(arr) => self.gen_array(arr)?,
             Expression::Object(obj) => self.gen_object(obj)?,
             Expression::Ident(ident) => {
-                let name = ValueStr::interned(&ident.get_str());
-                let bytecode = if let Some(id) = self.get_local_var(name.clone()) {
-                    Bytecode::LoadLocal(id)
-                } else if let Some(id) = self.get_upvalue(name.clone()) {
-                    Bytecode::LoadUpvalue(id)
-                } else {
-                    Bytecode::LoadGlobal(name)
+                let name = self.intern(&ident.get_str());
+                let bytecode = match self.resolve(name, ident.0) {
+                    Resolved::Local(id) => Bytecode::LoadLocal(id),
+                    Resolved::Upvalue(id) => Bytecode::LoadUpvalue(id),
+                    Resolved::Global(slot) => Bytecode::LoadGlobal(slot),
                 };
                 self.push_bytecode(SpanOf(ident.0, bytecode));
             }
@@ -148,18 +146,18 @@ mod tests {
         LoadStr("b")
         LoadNum(1.0)
         StackToObj(1)
-        LoadGlobal("c")
+        LoadGlobal(0)
         ExtendObj
-        LoadGlobal("d")
+        LoadGlobal(1)
         LoadNum(3.0)
         AppendObjIndirect"#
             .split('\n')
             .map(str::trim)
             .chain(repeat(""));
-        let mut codegen = Codegen::with_source(parser.source());
+        let mut codegen = Codegen::with_context(parser.sources(), Default::default());
         codegen.gen_expr(&arr_result).unwrap();
         codegen.gen_expr(&obj_result).unwrap();
-        for (bc, expected) in codegen.bytecodes().into_iter().zip(expected) {
+        for (bc, expected) in codegen.bytecodes().iter().zip(expected) {
             println!("{:?}", bc.1);
             assert_eq!(expected, format!("{:?}", bc.1));
         }
@@ -1,14 +1,24 @@
 use std::{cell::RefCell, rc::Rc};
 
 use crate::{
-    interpreter::{bytecode::Bytecode, string::ValueStr, FnBody, FnSignature, UpvalueLoc},
-    span::SpanOf,
+    interpreter::{
+        bytecode::Bytecode,
+        context::Context,
+        debug::{DebugInfo, LocalName},
+        string::ValueStr,
+        FnBody, FnSignature, UpvalueLoc,
+    },
+    source_map::SourceMap,
+    span::{Span, SpanOf},
 };
 
+use self::symbol::SymbolTable;
+
 mod binary;
 mod decl;
 mod expression;
 mod statement;
+pub mod symbol;
 mod unary;
 
 #[derive(PartialEq, Eq)]
@@ -23,23 +33,16 @@ struct Scope {
     break_locs: Vec<usize>, // bytecode locations at which break statements occurred
     continue_locs: Vec<usize>, // bytecode locations at which continue statements occurred
 }
+#[derive(Default)]
 struct FnFrame {
     locals: Vec<ValueStr>,
     scopes: Vec<Scope>,
     stack_size: usize,
     upvalues: Vec<(ValueStr, UpvalueLoc)>,
     bytecodes: Vec<SpanOf<Bytecode>>,
-}
-impl Default for FnFrame {
-    fn default() -> Self {
-        Self {
-            locals: vec![],
-            scopes: vec![],
-            stack_size: 0,
-            upvalues: vec![],
-            bytecodes: vec![],
-        }
-    }
+    local_names: Vec<LocalName>, // every local declared so far, still open ones end at usize::MAX
+    local_symbols: Vec<Option<usize>>, // by local id, while recording symbols
+    upvalue_symbols: Vec<Option<usize>>, // by upvalue index, while recording symbols
 }
 impl FnFrame {
     fn get_upvalue(&self, name: ValueStr) -> Option<usize> {
@@ -50,9 +53,43 @@ impl FnFrame {
     }
     fn decl_local(&mut self, name: ValueStr) -> usize {
         let id = self.locals.len();
+        // hidden locals have no name
+        if !name.as_str().is_empty() {
+            self.local_names.push(LocalName {
+                name: name.clone(),
+                slot: id,
+                live: self.bytecodes.len()..usize::MAX,
+            });
+        }
         self.locals.push(name);
+        self.local_symbols.push(None);
         id
     }
+    fn add_upvalue(&mut self, name: ValueStr, loc: UpvalueLoc, symbol: Option<usize>) -> usize {
+        self.upvalues.push((name, loc));
+        self.upvalue_symbols.push(symbol);
+        self.upvalues.len() - 1
+    }
+    /// Ends the scope of locals from `base_local_size` on at the current instruction.
+    fn truncate_locals(&mut self, base_local_size: usize) {
+        let end = self.bytecodes.len();
+        for local in &mut self.local_names {
+            if local.slot >= base_local_size && local.live.end == usize::MAX {
+                local.live.end = end;
+            }
+        }
+        self.locals.truncate(base_local_size);
+        self.local_symbols.truncate(base_local_size);
+    }
+    fn debug_info(&mut self) -> DebugInfo {
+        self.truncate_locals(0);
+        DebugInfo {
+            name: None,
+            span: Span::default(),
+            locals: std::mem::take(&mut self.local_names),
+            upvalues: self.upvalues.iter().map(|(name, _)| name.clone()).collect(),
+        }
+    }
     fn push_scope(&mut self, kind: ScopeKind, base_loc: usize) {
         self.scopes.push(Scope {
             kind,
@@ -67,19 +104,39 @@ impl FnFrame {
     }
 }
 
+/// How a variable name resolves, seen from the current frame.
+enum Resolved {
+    Local(usize),
+    Upvalue(usize),
+    Global(usize),
+}
+
 pub struct Codegen {
     frames: Vec<FnFrame>,
     global_frame: FnFrame,
-    source: Rc<RefCell<String>>,
+    sources: SourceMap,
+    context: Rc<RefCell<Context>>,
+    symbols: Option<SymbolTable>,
 }
 impl Codegen {
-    pub fn with_source(source: Rc<RefCell<String>>) -> Self {
+    /// Creates codegen that resolves globals and interns strings through the given context, usually `Interpreter::context`.
+    ///
+    /// Code is only run correctly by an interpreter with the same context, since global slots are allocated in it.
+    pub fn with_context(sources: SourceMap, context: Rc<RefCell<Context>>) -> Self {
         Self {
             frames: vec![],
             global_frame: FnFrame::default(),
-            source,
+            sources,
+            context,
+            symbols: None,
         }
     }
+    fn intern(&self, str: &str) -> ValueStr {
+        self.context.borrow_mut().intern(str)
+    }
+    fn global_slot(&self, name: ValueStr) -> usize {
+        self.context.borrow_mut().globals.slot(name)
+    }
     fn last_frame(&self) -> &FnFrame {
         self.frames.last().unwrap_or(&self.global_frame)
     }
@@ -127,39 +184,50 @@ impl Codegen {
             Some(idx)
         } else {
             for idx in (0..(self.frames.len() - 1)).rev() {
-                if let Some(mut id) = self.frames[idx].get_upvalue(name.clone()) {
+                let frame = &self.frames[idx];
+                let (loc, symbol) = if let Some(id) = frame.get_upvalue(name.clone()) {
                     // found id in parent frame's upvalue, propagate
-                    for i in (idx + 1)..self.frames.len() {
-                        let f = &mut self.frames[i];
-                        f.upvalues.push((name.clone(), UpvalueLoc::Shared(id)));
-                        id = f.upvalues.len() - 1;
-                    }
-                    return Some(id);
-                }
-                if let Some(mut id) = self.frames[idx].get_local_var(name.clone()) {
+                    (UpvalueLoc::Shared(id), frame.upvalue_symbols[id])
+                } else if let Some(id) = frame.get_local_var(name.clone()) {
                     // found id, add upvalue to the inner frame
-                    let f = &mut self.frames[idx + 1];
-                    f.upvalues.push((name.clone(), UpvalueLoc::Local(id)));
-                    // now propagate inner by each parent frame's indices
-                    id = f.upvalues.len() - 1;
-                    for i in (idx + 2)..self.frames.len() {
-                        let f = &mut self.frames[i];
-                        f.upvalues.push((name.clone(), UpvalueLoc::Shared(id)));
-                        id = f.upvalues.len() - 1;
-                    }
-                    return Some(id);
+                    (UpvalueLoc::Local(id), frame.local_symbols[id])
+                } else {
+                    continue;
+                };
+                let mut id = self.frames[idx + 1].add_upvalue(name.clone(), loc, symbol);
+                // now propagate inner by each parent frame's indices
+                for i in (idx + 2)..self.frames.len() {
+                    id = self.frames[i].add_upvalue(name.clone(), UpvalueLoc::Shared(id), symbol);
                 }
+                return Some(id);
             }
             None
         }
     }
+    /// Resolves a variable the way loads and stores of it do, recording the reference at `span`.
+    fn resolve(&mut self, name: ValueStr, span: Span) -> Resolved {
+        if let Some(id) = self.get_local_var(name.clone()) {
+            let symbol = self.last_frame().local_symbols[id];
+            self.reference_symbol(symbol, span);
+            Resolved::Local(id)
+        } else if let Some(id) = self.get_upvalue(name.clone()) {
+            let symbol = self.last_frame().upvalue_symbols[id];
+            self.reference_symbol(symbol, span);
+            Resolved::Upvalue(id)
+        } else {
+            let symbol = self.global_symbol(name.clone());
+            self.reference_symbol(symbol, span);
+            Resolved::Global(self.global_slot(name))
+        }
+    }
     pub fn pop_init_sig(self) -> FnSignature {
         assert!(self.frames.is_empty(), "Incomplete function frames exist!");
-        let frame = self.global_frame;
+        let mut frame = self.global_frame;
         FnSignature {
             arity: 0,
             variadic: false,
             upvalues: vec![],
+            debug: frame.debug_info(),
             body: FnBody::Bytecode(frame.bytecodes),
         }
     }
@@ -190,6 +258,7 @@ impl Codegen {
             | Bytecode::AppendObj(..)
             | Bytecode::ExtendObj => stack - 1,
             Bytecode::Return => stack.saturating_sub(1), // return without parameter will try to pop an empty stack which is still valid
+            Bytecode::TailCall(base) => *base,
             Bytecode::StoreProperty(..) | Bytecode::AppendObjIndirect => stack - 2,
             Bytecode::StorePropertyIndirect => stack - 3,
             Bytecode::Truncate(..)
@@ -212,7 +281,7 @@ mod tests {
         codegen::Codegen,
         interpreter::{
             bytecode::{BinaryOp, Bytecode},
-            string::ValueStr,
+            debug::DebugInfo,
             FnBody, FnSignature,
         },
         span::{Span, SpanOf},
@@ -237,18 +306,18 @@ mod tests {
             "#
             .as_bytes(),
         );
-        let mut codegen = Codegen::with_source(parser.source());
+        let mut codegen = Codegen::with_context(parser.sources(), Default::default());
         codegen
             .gen_statement(&parser.next_statement().unwrap().unwrap())
             .unwrap();
 
-        let fib = ValueStr::interned("fib");
         let expected = [
-            Bytecode::GlobalDeclare(fib.clone()),
+            Bytecode::GlobalDeclare(0),
             Bytecode::LoadFn(Rc::new(FnSignature {
                 arity: 1,
                 variadic: false,
                 upvalues: vec![],
+                debug: DebugInfo::default(),
                 body: FnBody::Bytecode(
                     [
                         Bytecode::LoadNum(0.0),
@@ -289,8 +358,8 @@ mod tests {
                     .collect(),
                 ),
             })),
-            Bytecode::StoreGlobal(fib.clone()),
-            Bytecode::GlobalReadOnly(fib),
+            Bytecode::StoreGlobal(0),
+            Bytecode::GlobalReadOnly(0),
         ];
 
         for (bc, expected) in codegen.bytecodes().iter().zip(expected) {
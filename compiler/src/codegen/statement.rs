@@ -1,12 +1,11 @@
 use crate::{
-    ast::statement::Statement,
+    ast::{
+        expression::{Expression, PostfixOperator},
+        statement::Statement,
+    },
     codegen::{Codegen, ScopeKind},
     error::{Error, ErrorKind, Result},
-    interpreter::{
-        builtin,
-        bytecode::{BinaryOp, Bytecode},
-        string::ValueStr,
-    },
+    interpreter::bytecode::{BinaryOp, Bytecode},
     span::{GetSpan, Span, SpanOf},
 };
 
@@ -16,7 +15,8 @@ impl Codegen {
         let f = self.last_frame_mut();
         f.push_scope(kind, loc);
     }
-    fn pop_scope(&mut self) {
+    /// Closes the innermost scope, which ends at the source offset `end`.
+    fn pop_scope(&mut self, end: usize) {
         let end_loc = self.bytecodes().len();
         let scope = self.last_frame_mut().pop_scope().unwrap();
 
@@ -35,10 +35,29 @@ impl Codegen {
                 Span::default(),
                 Bytecode::Truncate(scope.base_local_size),
             ));
-            self.last_frame_mut().locals.truncate(scope.base_local_size);
+            self.close_symbols(scope.base_local_size, end);
+            self.last_frame_mut().truncate_locals(scope.base_local_size);
         }
     }
 
+    /// Generates `return expr`. Calls in tail position reuse the current frame instead of nesting a new one.
+    pub(crate) fn gen_return(&mut self, expr: &Expression, span: Span) -> Result<()> {
+        self.gen_expr(expr)?;
+        if let Expression::Postfix {
+            operator: PostfixOperator::Call(..),
+            ..
+        } = expr
+        {
+            if let Some(SpanOf(_, Bytecode::Call(base))) = self.bytecodes().last() {
+                let base = *base;
+                let call = self.last_frame_mut().bytecodes.pop().unwrap();
+                self.push_bytecode(SpanOf(call.0, Bytecode::TailCall(base)));
+                return Ok(());
+            }
+        }
+        self.push_bytecode(SpanOf(span, Bytecode::Return));
+        Ok(())
+    }
     pub fn gen_statement(&mut self, statement: &Statement) -> Result<()> {
         match statement {
             Statement::Declaration(decl) => self.gen_decl(decl)?,
@@ -83,7 +102,7 @@ impl Codegen {
                     self.bytecodes_mut()[idx].1 = Bytecode::Jump(current as isize - idx as isize);
                 }
 
-                self.pop_scope();
+                self.pop_scope(statement.span().end);
             }
             Statement::While {
                 condition, block, ..
@@ -106,7 +125,7 @@ impl Codegen {
                 self.bytecodes_mut()[break_start].1 =
                     Bytecode::BranchIf(false, break_until as isize - break_start as isize);
 
-                self.pop_scope();
+                self.pop_scope(statement.span().end);
             }
             Statement::Break(span) | Statement::Continue(span) => {
                 let Some(loop_idx) = self
@@ -115,14 +134,11 @@ impl Codegen {
                     .iter()
                     .rposition(|scope| matches!(scope.kind, ScopeKind::Loop))
                 else {
-                    return Err(Error {
-                        kind: match statement {
-                            Statement::Break(..) => ErrorKind::IllegalBreak,
-                            _ => ErrorKind::IllegalContinue,
-                        },
-                        span: *span,
-                        source: self.source.clone(),
-                    });
+                    let kind = match statement {
+                        Statement::Break(..) => ErrorKind::IllegalBreak,
+                        _ => ErrorKind::IllegalContinue,
+                    };
+                    return Err(Error::new(kind, *span, self.sources.clone()));
                 };
                 let id = self.bytecodes().len();
                 self.push_bytecode(SpanOf(*span, Bytecode::Jump(0)));
@@ -132,12 +148,10 @@ impl Codegen {
                     _ => scope.continue_locs.push(id),
                 }
             }
-            Statement::Return(expr) => {
-                if let Some(expr) = &expr.1 {
-                    self.gen_expr(expr)?;
-                }
-                self.push_bytecode(SpanOf(expr.0, Bytecode::Return));
-            }
+            Statement::Return(expr) => match &expr.1 {
+                Some(value) => self.gen_return(value, expr.0)?,
+                None => self.push_bytecode(SpanOf(expr.0, Bytecode::Return)),
+            },
             Statement::For {
                 ident, expr, block, ..
             } => {
@@ -156,23 +170,17 @@ impl Codegen {
 
                 // Iter setup
                 self.gen_expr(expr)?;
-                self.push_bytecode(SpanOf(
-                    expr.span(),
-                    Bytecode::CallBuiltin(
-                        0,
-                        builtin::GLOBALS
-                            .with(|globals| globals[&ValueStr::interned("iter")].clone()),
-                    ),
-                ));
+                let iter = self.context.borrow().builtin("iter").unwrap();
+                self.push_bytecode(SpanOf(expr.span(), Bytecode::CallBuiltin(0, iter)));
                 self.push_bytecode(SpanOf(expr.span(), Bytecode::StoreLocal(iter_id)));
 
                 // While setup
 
                 self.push_scope(ScopeKind::Loop);
 
-                let ident_id = self
-                    .decl_local(ValueStr::interned(&ident.get_str()))
-                    .unwrap();
+                let name = self.intern(&ident.get_str());
+                let ident_id = self.decl_local(name.clone()).unwrap();
+                self.define_symbol(name, ident.0, Some(ident_id));
 
                 self.push_bytecode(SpanOf(ident.0, Bytecode::LoadLocal(iter_id)));
                 self.push_bytecode(SpanOf(ident.0, Bytecode::Call(0)));
@@ -199,9 +207,9 @@ impl Codegen {
                 *self.stack_size_mut() = cond_stack; // To keep the stack same as the condition failed state.
                 self.push_bytecode(SpanOf(block.0, Bytecode::Dup(0)));
 
-                self.pop_scope();
+                self.pop_scope(statement.span().end);
 
-                self.pop_scope();
+                self.pop_scope(statement.span().end);
             }
         }
         debug_assert_eq!(self.stack_size(), 0);
@@ -211,7 +219,11 @@ impl Codegen {
 
 #[cfg(test)]
 mod tests {
-    use crate::{ast::Parser, codegen::Codegen};
+    use crate::{
+        ast::Parser,
+        codegen::Codegen,
+        interpreter::{bytecode::Bytecode, FnBody},
+    };
 
     #[test]
     fn test_while_stmt() {
@@ -227,43 +239,43 @@ mod tests {
             "#
             .as_bytes(),
         );
-        let mut codegen = Codegen::with_source(parser.source());
+        let mut codegen = Codegen::with_context(parser.sources(), Default::default());
 
         while let Some(stmt) = parser.next_statement().unwrap() {
             codegen.gen_statement(&stmt).unwrap();
         }
 
-        let expected = r#"GlobalDeclare("i")
+        let expected = r#"GlobalDeclare(0)
             LoadNum(0.0)
-            StoreGlobal("i")
-            GlobalDeclare("j")
+            StoreGlobal(0)
+            GlobalDeclare(1)
             LoadNum(30.0)
-            StoreGlobal("j")
+            StoreGlobal(1)
             LoadBool(true)
             BranchIf(false, 26)
-            LoadGlobal("i")
-            LoadGlobal("j")
+            LoadGlobal(0)
+            LoadGlobal(1)
             Binary(SetEq)
             BranchIf(false, 2)
             Jump(-6)
-            LoadGlobal("i")
+            LoadGlobal(0)
             LoadNum(2.0)
             Binary(Pow)
-            LoadGlobal("j")
+            LoadGlobal(1)
             Binary(SetEq)
             BranchIf(false, 2)
             Jump(14)
-            LoadGlobal("i")
+            LoadGlobal(0)
             LoadNum(1.0)
             Binary(Add)
             Dup(2)
-            StoreGlobal("i")
+            StoreGlobal(0)
             Dup(0)
-            LoadGlobal("j")
+            LoadGlobal(1)
             LoadNum(20.0)
             Binary(Add)
             Dup(2)
-            StoreGlobal("j")
+            StoreGlobal(1)
             Dup(0)
             Jump(-26)"#
             .split("\n")
@@ -278,17 +290,17 @@ mod tests {
     #[test]
     fn test_for_stmt() {
         let mut parser = Parser::new(r#"for i in range(0, 4, 0.25) do print(i) end"#.as_bytes());
-        let mut codegen = Codegen::with_source(parser.source());
+        let mut codegen = Codegen::with_context(parser.sources(), Default::default());
         codegen
             .gen_statement(&parser.next_statement().unwrap().unwrap())
             .unwrap();
 
-        let expected = r#"LoadGlobal("range")
+        let expected = r#"LoadGlobal(0)
         LoadNum(0.0)
         LoadNum(4.0)
         LoadNum(0.25)
         Call(0)
-        CallBuiltin(0, Function { signature: FnSignature { arity: 1, variadic: false, upvalues: [], body: Builtin("..") }, upvalues: [] })
+        CallBuiltin(0, Function { signature: FnSignature { arity: 1, variadic: false, upvalues: [], body: Builtin(".."), debug: DebugInfo { .. } }, upvalues: [] })
         StoreLocal(0)
         LoadLocal(0)
         Call(0)
@@ -297,7 +309,7 @@ mod tests {
         Binary(SetNe)
         BranchIf(false, 7)
         StoreLocal(1)
-        LoadGlobal("print")
+        LoadGlobal(1)
         LoadLocal(1)
         Call(0)
         Dup(0)
@@ -325,46 +337,46 @@ mod tests {
             end"#
                 .as_bytes(),
         );
-        let mut codegen = Codegen::with_source(parser.source());
+        let mut codegen = Codegen::with_context(parser.sources(), Default::default());
 
         codegen
             .gen_statement(&parser.next_statement().unwrap().unwrap())
             .unwrap();
 
-        let expected = r#"LoadGlobal("n")
+        let expected = r#"LoadGlobal(0)
             LoadNum(6.0)
             Binary(Rem)
             LoadNum(0.0)
             Binary(SetEq)
             BranchIf(false, 6)
-            LoadGlobal("print")
+            LoadGlobal(1)
             LoadStr("fizz buzz")
             Call(0)
             Dup(0)
             Jump(26)
-            LoadGlobal("n")
+            LoadGlobal(0)
             LoadNum(2.0)
             Binary(Rem)
             LoadNum(0.0)
             Binary(SetEq)
             BranchIf(false, 6)
-            LoadGlobal("print")
+            LoadGlobal(1)
             LoadStr("fizz")
             Call(0)
             Dup(0)
             Jump(15)
-            LoadGlobal("n")
+            LoadGlobal(0)
             LoadNum(3.0)
             Binary(Rem)
             LoadNum(0.0)
             Binary(SetEq)
             BranchIf(false, 6)
-            LoadGlobal("print")
+            LoadGlobal(1)
             LoadStr("buzz")
             Call(0)
             Dup(0)
             Jump(4)
-            LoadGlobal("print")
+            LoadGlobal(1)
             Call(0)
             Dup(0)"#
             .split("\n")
@@ -376,4 +388,48 @@ mod tests {
             assert_eq!(expected, format!("{:?}", bc.1));
         }
     }
+    #[test]
+    fn test_tail_call() {
+        let mut parser = Parser::new(
+            r#"fn count(n, acc) do
+                if n == 0 then return acc end
+                return count(n - 1, acc + 1)
+            end"#
+                .as_bytes(),
+        );
+        let mut codegen = Codegen::with_context(parser.sources(), Default::default());
+        codegen
+            .gen_statement(&parser.next_statement().unwrap().unwrap())
+            .unwrap();
+
+        let Bytecode::LoadFn(count) = &codegen.bytecodes()[1].1 else {
+            panic!("Expected function");
+        };
+        let FnBody::Bytecode(bytecodes) = &count.body else {
+            panic!("Expected bytecode body");
+        };
+        let expected = r#"LoadLocal(0)
+            LoadNum(0.0)
+            Binary(SetEq)
+            BranchIf(false, 3)
+            LoadLocal(1)
+            Return
+            LoadGlobal(0)
+            LoadLocal(0)
+            LoadNum(1.0)
+            Binary(Sub)
+            LoadLocal(1)
+            LoadNum(1.0)
+            Binary(Add)
+            TailCall(0)"#
+            .split('\n')
+            .map(str::trim)
+            .collect::<Vec<_>>();
+
+        assert_eq!(bytecodes.len(), expected.len());
+        for (bc, expected) in bytecodes.iter().zip(expected) {
+            println!("{:?}", bc.1);
+            assert_eq!(expected, format!("{:?}", bc.1));
+        }
+    }
 }
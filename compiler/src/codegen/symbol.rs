@@ -0,0 +1,180 @@
+use std::ops::Range;
+
+use rustc_hash::FxHashMap;
+
+use crate::{
+    ast::expression::Closure, codegen::Codegen, interpreter::string::ValueStr, span::Span,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SymbolKind {
+    Global,
+    Local,
+}
+
+/// A variable as resolved by codegen, with every place it's referenced. Recorded for tooling like the language server.
+#[derive(Debug, Clone)]
+pub struct Symbol {
+    pub name: ValueStr,
+    pub kind: SymbolKind,
+    /// Span of the declared name, none for globals that are only referenced, like builtins.
+    pub definition: Option<Span>,
+    pub references: Vec<Span>,
+    /// Source range a local is in scope, globals are in scope everywhere.
+    pub scope: Option<Range<usize>>,
+    /// Arity and whether it's variadic, for variables declared as functions.
+    pub function: Option<(usize, bool)>,
+}
+impl Symbol {
+    /// Whether the name is declared or referenced at `offset`, counting the end of the name.
+    pub fn is_at(&self, offset: usize) -> bool {
+        self.definition
+            .iter()
+            .chain(&self.references)
+            .any(|span| span.start <= offset && offset <= span.end)
+    }
+}
+
+#[derive(Default)]
+pub(super) struct SymbolTable {
+    symbols: Vec<Symbol>,
+    globals: FxHashMap<ValueStr, usize>,
+}
+
+impl Codegen {
+    /// Records the symbols of the code generated from now on, available through `symbols`.
+    pub fn record_symbols(&mut self) {
+        self.symbols.get_or_insert_with(SymbolTable::default);
+    }
+    pub fn symbols(&self) -> &[Symbol] {
+        self.symbols
+            .as_ref()
+            .map_or(&[], |table| table.symbols.as_slice())
+    }
+    /// Symbol of the global `name`, which is created undeclared if it hasn't been seen yet.
+    pub(super) fn global_symbol(&mut self, name: ValueStr) -> Option<usize> {
+        let table = self.symbols.as_mut()?;
+        let id = *table.globals.entry(name.clone()).or_insert_with(|| {
+            table.symbols.push(Symbol {
+                name,
+                kind: SymbolKind::Global,
+                definition: None,
+                references: vec![],
+                scope: None,
+                function: None,
+            });
+            table.symbols.len() - 1
+        });
+        Some(id)
+    }
+    /// Records the declaration of `name` at `span`, as the local `local` or as a global.
+    /// A global that's declared again is counted as referenced there.
+    pub(super) fn define_symbol(
+        &mut self,
+        name: ValueStr,
+        span: Span,
+        local: Option<usize>,
+    ) -> Option<usize> {
+        let Some(local) = local else {
+            let id = self.global_symbol(name)?;
+            let symbol = &mut self.symbols.as_mut()?.symbols[id];
+            match symbol.definition {
+                Some(_) => symbol.references.push(span),
+                None => symbol.definition = Some(span),
+            }
+            return Some(id);
+        };
+        let table = self.symbols.as_mut()?;
+        table.symbols.push(Symbol {
+            name,
+            kind: SymbolKind::Local,
+            definition: Some(span),
+            references: vec![],
+            scope: Some(span.start..usize::MAX),
+            function: None,
+        });
+        let id = table.symbols.len() - 1;
+        self.last_frame_mut().local_symbols[local] = Some(id);
+        Some(id)
+    }
+    pub(super) fn define_function(&mut self, symbol: Option<usize>, closure: &Closure) {
+        if let (Some(table), Some(id)) = (self.symbols.as_mut(), symbol) {
+            table.symbols[id].function = Some((closure.params.1.len(), closure.variadic.is_some()));
+        }
+    }
+    pub(super) fn reference_symbol(&mut self, symbol: Option<usize>, span: Span) {
+        if let (Some(table), Some(id)) = (self.symbols.as_mut(), symbol) {
+            table.symbols[id].references.push(span);
+        }
+    }
+    /// Ends the scope of the locals from `base_local_size` on at the source offset `end`.
+    pub(super) fn close_symbols(&mut self, base_local_size: usize, end: usize) {
+        let Some(table) = self.symbols.as_mut() else {
+            return;
+        };
+        let frame = self.frames.last().unwrap_or(&self.global_frame);
+        for id in frame.local_symbols.iter().skip(base_local_size).flatten() {
+            if let Some(scope) = &mut table.symbols[*id].scope {
+                scope.end = scope.end.min(end);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        ast::Parser,
+        codegen::{symbol::SymbolKind, Codegen},
+        span::Span,
+    };
+
+    #[test]
+    fn test_symbols() {
+        let source = "\
+fn counter(step) do
+    let count = 0
+    return \\ -> do
+        count = count + step
+        return count
+    end
+end
+let next = counter(2)
+println(next(), next())
+";
+        let mut parser = Parser::new(source.as_bytes());
+        let mut codegen = Codegen::with_context(parser.sources(), Default::default());
+        codegen.record_symbols();
+        while let Some(statement) = parser.next_statement().unwrap() {
+            codegen.gen_statement(&statement).unwrap();
+        }
+        let at = |str: &str, nth: usize| {
+            let start = source.match_indices(str).nth(nth).unwrap().0;
+            Span::from_len(start, str.len())
+        };
+        let symbols = codegen.symbols();
+        let symbol = |name: &str| symbols.iter().find(|s| s.name.as_str() == name).unwrap();
+
+        let counter = symbol("counter");
+        assert_eq!(counter.kind, SymbolKind::Global);
+        assert_eq!(counter.definition, Some(at("counter", 0)));
+        assert_eq!(counter.references, [at("counter", 1)]);
+        assert_eq!(counter.function, Some((1, false)));
+
+        // referenced as an upvalue of the closure
+        let count = symbol("count");
+        assert_eq!(count.kind, SymbolKind::Local);
+        assert_eq!(count.definition, Some(at("count", 1)));
+        let mut references = count.references.clone();
+        references.sort_by_key(|span| span.start);
+        assert_eq!(references, [at("count", 2), at("count", 3), at("count", 4)]);
+        let scope = count.scope.clone().unwrap();
+        assert!(scope.contains(&at("return count", 0).start));
+        assert!(!scope.contains(&at("let next", 0).start));
+        assert_eq!(symbol("step").references, [at("step", 1)]);
+
+        let println = symbol("println");
+        assert_eq!(println.definition, None);
+        assert!(println.is_at(at("println", 0).end));
+    }
+}
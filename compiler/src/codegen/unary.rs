@@ -2,10 +2,7 @@ use crate::{
     ast::expression::{Element, Expression, PostfixOperator},
     codegen::Codegen,
     error::Result,
-    interpreter::{
-        bytecode::{Bytecode, UnaryOp},
-        string::ValueStr,
-    },
+    interpreter::bytecode::{Bytecode, UnaryOp},
     span::SpanOf,
 };
 
@@ -47,13 +44,13 @@ impl Codegen {
             PostfixOperator::Property(prop) => {
                 self.push_bytecode(SpanOf(
                     prop.0,
-                    Bytecode::LoadProperty(ValueStr::interned(&prop.get_str())),
+                    Bytecode::LoadProperty(self.intern(&prop.get_str())),
                 ));
             }
             PostfixOperator::Method(prop) => {
                 self.push_bytecode(SpanOf(
                     prop.0,
-                    Bytecode::LoadMethod(ValueStr::interned(&prop.get_str())),
+                    Bytecode::LoadMethod(self.intern(&prop.get_str())),
                 ));
             }
         }
@@ -95,11 +92,11 @@ mod tests {
         let mut parser = Parser::new("-~!foo[0].test:method(1, 2, 3)(4, 5, *rest)".as_bytes());
         let result = parser.next_expression(false).unwrap().unwrap();
         let expected = [
-            Bytecode::LoadGlobal(ValueStr::interned("foo")),
+            Bytecode::LoadGlobal(0),
             Bytecode::LoadNum(0.0),
             Bytecode::LoadPropertyIndirect,
-            Bytecode::LoadProperty(ValueStr::interned("test")),
-            Bytecode::LoadMethod(ValueStr::interned("method")),
+            Bytecode::LoadProperty(ValueStr::from("test")),
+            Bytecode::LoadMethod(ValueStr::from("method")),
             Bytecode::LoadNum(1.0),
             Bytecode::LoadNum(2.0),
             Bytecode::LoadNum(3.0),
@@ -107,7 +104,7 @@ mod tests {
             Bytecode::LoadNum(4.0),
             Bytecode::LoadNum(5.0),
             Bytecode::StackToArray(1),
-            Bytecode::LoadGlobal(ValueStr::interned("rest")),
+            Bytecode::LoadGlobal(1),
             Bytecode::ExtendArray,
             Bytecode::CallVariadic,
             Bytecode::Unary(UnaryOp::SetFalse),
@@ -116,9 +113,9 @@ mod tests {
         ]
         .into_iter()
         .chain(std::iter::repeat(Bytecode::Dup(1)));
-        let mut codegen = Codegen::with_source(parser.source());
+        let mut codegen = Codegen::with_context(parser.sources(), Default::default());
         codegen.gen_expr(&result).unwrap();
-        for (bc, expected) in codegen.bytecodes().into_iter().zip(expected) {
+        for (bc, expected) in codegen.bytecodes().iter().zip(expected) {
             println!("{:?}", bc.1);
             assert_eq!(format!("{:?}", expected), format!("{:?}", bc.1));
         }
@@ -0,0 +1,508 @@
+use std::{
+    collections::BTreeSet,
+    io::{BufRead, Write},
+    rc::Rc,
+};
+
+use crate::{
+    ast::{declaration::Declaration, statement::Statement, Parser},
+    codegen::Codegen,
+    error::ErrorKind,
+    interpreter::{
+        bytecode::Bytecode, hook::Hook, string::ValueStr, value::Value, FnBody, FnSignature,
+        Interpreter,
+    },
+    source_map::SourceMap,
+    span::{FileId, GetSpan, Span},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    Entry,
+    Breakpoint,
+    Step,
+    Pause,
+}
+
+/// How a paused program continues.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resume {
+    Continue,
+    StepIn,
+    StepOver,
+    StepOut,
+}
+
+#[derive(Clone, Copy)]
+enum Mode {
+    Continue,
+    StepIn,
+    StepOver(usize), // frame count to step over in
+    StepOut(usize),  // frame count to return from
+}
+
+/// User interface of a debugger, like the console of `rlox debug` or the DAP server.
+pub trait DebugFrontend {
+    /// Called when execution pauses at `line`. Returns how to resume, or an error to abort the program.
+    fn paused(
+        &mut self,
+        debugger: &mut Debugger,
+        interpreter: &mut Interpreter,
+        reason: StopReason,
+        line: usize,
+    ) -> Result<Resume, ErrorKind>;
+    /// Polled at every new line while running, to pause the program or change breakpoints.
+    fn pause_requested(&mut self, _debugger: &mut Debugger) -> bool {
+        false
+    }
+}
+
+/// Line debugger driven through an interpreter's hook.
+///
+/// Execution pauses at breakpoints and after stepping, where control passes to a `DebugFrontend`.
+/// Lines are resolved through the spans of the bytecode, so only lines with code can be stopped at.
+pub struct Debugger {
+    sources: SourceMap,
+    file: FileId,
+    code_lines: BTreeSet<usize>,
+    breakpoints: BTreeSet<usize>,
+    mode: Mode,
+    // line each frame is on, outermost first
+    lines: Vec<usize>,
+    entry: bool,
+}
+// debugger installed as a hook, with the frontend it hands control to
+struct Attached<F> {
+    debugger: Debugger,
+    frontend: F,
+}
+impl<F: DebugFrontend> Hook for Attached<F> {
+    fn on_instruction(
+        &mut self,
+        interpreter: &mut Interpreter,
+        _frame: usize,
+        _index: usize,
+        _bytecode: &Bytecode,
+        span: Span,
+    ) -> Result<(), ErrorKind> {
+        self.debugger.on_step(interpreter, span, &mut self.frontend)
+    }
+}
+
+impl Debugger {
+    /// Debugger for `main` and the functions declared in it, whose code is in `file` of `sources`.
+    /// Lines are those of that file, code from other files has none.
+    /// It pauses before the first line, unless `stop_on_entry` is turned off.
+    pub fn new(sources: SourceMap, file: FileId, main: &FnSignature) -> Self {
+        let mut debugger = Self {
+            sources,
+            file,
+            code_lines: BTreeSet::new(),
+            breakpoints: BTreeSet::new(),
+            mode: Mode::StepIn,
+            lines: vec![],
+            entry: true,
+        };
+        debugger.add_code_lines(main);
+        debugger
+    }
+    pub fn stop_on_entry(&mut self, stop: bool) {
+        self.mode = if stop { Mode::StepIn } else { Mode::Continue };
+    }
+    fn add_code_lines(&mut self, signature: &FnSignature) {
+        let FnBody::Bytecode(code) = &signature.body else {
+            return;
+        };
+        for bc in code {
+            if let Some(line) = self.line_of(bc.0) {
+                self.code_lines.insert(line);
+            }
+            if let Bytecode::LoadFn(signature) = &bc.1 {
+                self.add_code_lines(signature);
+            }
+        }
+    }
+    /// Installs the debugger as the hook of `interpreter`.
+    pub fn attach(self, interpreter: &mut Interpreter, frontend: impl DebugFrontend + 'static) {
+        interpreter.set_hook(Some(Box::new(Attached {
+            debugger: self,
+            frontend,
+        })));
+    }
+    /// 1-based line of the span, none for code without a location or in another file.
+    pub fn line_of(&self, span: Span) -> Option<usize> {
+        if span == Span::default() || span.file != self.file {
+            return None;
+        }
+        Some(self.sources.line_index(self.file, span.start) + 1)
+    }
+    /// 1-based column of the span's start, in characters.
+    pub fn column_of(&self, span: Span) -> usize {
+        self.sources.line_col(span.file, span.start).1
+    }
+    pub fn line_text(&self, line: usize) -> String {
+        self.sources.line_text(self.file, line).trim().to_owned()
+    }
+    /// Line the frame is on, none for builtins.
+    pub fn frame_line(&self, interpreter: &Interpreter, frame: usize) -> Option<usize> {
+        interpreter
+            .frame_span(frame)
+            .and_then(|span| self.line_of(span))
+    }
+    /// Sets a breakpoint at the first line with code at or after `line`, returning that line.
+    pub fn set_breakpoint(&mut self, line: usize) -> Option<usize> {
+        let line = self.code_lines.range(line..).next().copied()?;
+        self.breakpoints.insert(line);
+        Some(line)
+    }
+    pub fn remove_breakpoint(&mut self, line: usize) -> bool {
+        self.breakpoints.remove(&line)
+    }
+    pub fn clear_breakpoints(&mut self) {
+        self.breakpoints.clear();
+    }
+    fn on_step(
+        &mut self,
+        interpreter: &mut Interpreter,
+        span: Span,
+        frontend: &mut impl DebugFrontend,
+    ) -> Result<(), ErrorKind> {
+        let Some(line) = self.line_of(span) else {
+            return Ok(());
+        };
+        let depth = interpreter.frame_count();
+        self.lines.truncate(depth);
+        self.lines.resize(depth, 0);
+        let new_line = self.lines[depth - 1] != line;
+        self.lines[depth - 1] = line;
+
+        let reason = match self.mode {
+            _ if new_line && self.breakpoints.contains(&line) => StopReason::Breakpoint,
+            Mode::StepIn if new_line && self.entry => StopReason::Entry,
+            Mode::StepIn if new_line => StopReason::Step,
+            Mode::StepOver(frames) if depth < frames || (depth == frames && new_line) => {
+                StopReason::Step
+            }
+            Mode::StepOut(frames) if depth < frames => StopReason::Step,
+            _ if !new_line => return Ok(()),
+            _ => match frontend.pause_requested(self) {
+                true => StopReason::Pause,
+                false => return Ok(()),
+            },
+        };
+        self.entry = false;
+        self.mode = match frontend.paused(self, interpreter, reason, line)? {
+            Resume::Continue => Mode::Continue,
+            Resume::StepIn => Mode::StepIn,
+            Resume::StepOver => Mode::StepOver(depth),
+            Resume::StepOut => Mode::StepOut(depth),
+        };
+        Ok(())
+    }
+    /// Evaluates `expr` as the body of a closure, whose parameters are the variables in scope in `frame`.
+    /// Assigning to those variables doesn't change them in the frame.
+    pub fn eval(
+        &self,
+        interpreter: &mut Interpreter,
+        frame: usize,
+        expr: &str,
+    ) -> Result<Value, String> {
+        let (names, values): (Vec<_>, Vec<_>) = interpreter
+            .frame_upvalues(frame)
+            .into_iter()
+            .chain(interpreter.frame_locals(frame))
+            .unzip();
+        let names = names.iter().map(ValueStr::as_str).collect::<Vec<_>>();
+        let code = format!("\\{} -> ({})", names.join(", "), expr);
+
+        let mut parser = Parser::new(code.as_bytes());
+        let closure = match parser
+            .next_statement()
+            .map_err(|err| err.kind.to_string())?
+        {
+            Some(Statement::Declaration(Declaration::Expression(closure))) => closure,
+            _ => return Err(ErrorKind::ExpectedExpr.to_string()),
+        };
+        if parser
+            .next_statement()
+            .map_err(|err| err.kind.to_string())?
+            .is_some()
+        {
+            return Err(ErrorKind::ExpectedExpr.to_string());
+        }
+        let mut codegen = Codegen::with_context(parser.sources(), interpreter.context());
+        codegen
+            .gen_return(&closure, closure.span())
+            .map_err(|err| err.kind.to_string())?;
+        let init = Rc::new(interpreter.create_function(Rc::new(codegen.pop_init_sig())));
+        let result = interpreter
+            .call_function_args(init, [])
+            .and_then(|closure| closure.try_function())
+            .and_then(|closure| interpreter.call_function_args(closure, values));
+        result.map_err(|err| {
+            interpreter.clear_error_span();
+            err.to_string()
+        })
+    }
+}
+
+/// Declared globals, leaving out builtins that haven't been replaced.
+pub fn user_globals(interpreter: &Interpreter) -> Vec<(ValueStr, Value)> {
+    let context = interpreter.context();
+    let context = context.borrow();
+    let mut globals = interpreter.globals();
+    globals.retain(|(name, value)| {
+        !matches!((value, context.builtin(name.as_str())),
+            (Value::Function(f), Some(builtin)) if Rc::ptr_eq(f, &builtin))
+    });
+    // and builtin modules, such as `fs`, which can't be replaced
+    let modules = context
+        .builtins()
+        .filter_map(|(name, _)| Some(name.as_str().split_once('.')?.0))
+        .collect::<Vec<_>>();
+    globals.retain(|(name, _)| !modules.contains(&name.as_str()));
+    globals
+}
+
+const HELP: &str = "\
+break <line>     set a breakpoint, or `b`
+delete <line>    remove a breakpoint
+continue         run until the next breakpoint, or `c`
+step             run until the next line, stepping into calls, or `s`
+next             run until the next line of this function, or `n`
+out              run until this function returns, or `o`
+backtrace        list the call frames, or `bt`
+frame <n>        select the call frame to inspect, 0 being the innermost
+locals           print the locals of the selected frame
+upvalues         print the upvalues of the selected frame
+globals          print the declared globals
+print <expr>     evaluate an expression in the selected frame, or `p`
+quit             stop the program, or `q`";
+
+/// Command line frontend of `rlox debug`, reading commands from `input`.
+pub struct Console {
+    input: Box<dyn BufRead>,
+    output: Box<dyn Write>,
+}
+impl Console {
+    pub fn new(input: impl BufRead + 'static, output: impl Write + 'static) -> Self {
+        Self {
+            input: Box::new(input),
+            output: Box::new(output),
+        }
+    }
+    fn print_vars(&mut self, vars: Vec<(ValueStr, Value)>) -> Result<(), ErrorKind> {
+        if vars.is_empty() {
+            writeln!(self.output, "<none>")?;
+        }
+        for (name, value) in vars {
+            writeln!(self.output, "{} = {}", name, value)?;
+        }
+        Ok(())
+    }
+}
+impl DebugFrontend for Console {
+    /// Reads commands until one resumes execution.
+    fn paused(
+        &mut self,
+        debugger: &mut Debugger,
+        interpreter: &mut Interpreter,
+        reason: StopReason,
+        line: usize,
+    ) -> Result<Resume, ErrorKind> {
+        let reason = match reason {
+            StopReason::Entry | StopReason::Pause => "Paused",
+            StopReason::Breakpoint => "Breakpoint",
+            StopReason::Step => "Step",
+        };
+        writeln!(
+            self.output,
+            "{} at line {}: {}",
+            reason,
+            line,
+            debugger.line_text(line)
+        )?;
+
+        let innermost = interpreter.frame_count() - 1;
+        let mut frame = innermost;
+        loop {
+            write!(self.output, "(rlox) ")?;
+            self.output.flush()?;
+            let mut command = String::new();
+            if self.input.read_line(&mut command)? == 0 {
+                // end of input
+                writeln!(self.output)?;
+                return Err(ErrorKind::Interrupted);
+            }
+            let (command, arg) = match command.trim().split_once(' ') {
+                Some((command, arg)) => (command, arg.trim()),
+                None => (command.trim(), ""),
+            };
+            match command {
+                "" => {}
+                "break" | "b" => match arg
+                    .parse()
+                    .ok()
+                    .and_then(|line| debugger.set_breakpoint(line))
+                {
+                    Some(line) => writeln!(self.output, "Breakpoint set at line {}", line)?,
+                    None => writeln!(self.output, "No code at or after line `{}`", arg)?,
+                },
+                "delete" | "d" => match arg.parse() {
+                    Ok(line) if debugger.remove_breakpoint(line) => {
+                        writeln!(self.output, "Breakpoint at line {} deleted", line)?
+                    }
+                    _ => writeln!(self.output, "No breakpoint at line `{}`", arg)?,
+                },
+                "continue" | "c" => return Ok(Resume::Continue),
+                "step" | "s" => return Ok(Resume::StepIn),
+                "next" | "n" => return Ok(Resume::StepOver),
+                "out" | "o" => return Ok(Resume::StepOut),
+                "backtrace" | "bt" => {
+                    for i in (0..=innermost).rev() {
+                        let location = match debugger.frame_line(interpreter, i) {
+                            Some(line) => format!("line {}: {}", line, debugger.line_text(line)),
+                            None => "<builtin>".to_owned(),
+                        };
+                        let marker = if i == frame { '*' } else { ' ' };
+                        writeln!(self.output, "{}#{} {}", marker, innermost - i, location)?;
+                    }
+                }
+                "frame" | "f" => match arg.parse::<usize>() {
+                    Ok(n) if n <= innermost => frame = innermost - n,
+                    _ => writeln!(self.output, "No frame `{}`", arg)?,
+                },
+                "locals" => self.print_vars(interpreter.frame_locals(frame))?,
+                "upvalues" => self.print_vars(interpreter.frame_upvalues(frame))?,
+                "globals" => self.print_vars(user_globals(interpreter))?,
+                "print" | "p" => match debugger.eval(interpreter, frame, arg) {
+                    Ok(value) => writeln!(self.output, "{}", value)?,
+                    Err(message) => writeln!(self.output, "{}", message)?,
+                },
+                "help" | "h" => writeln!(self.output, "{}", HELP)?,
+                "quit" | "q" => return Err(ErrorKind::Interrupted),
+                _ => writeln!(self.output, "Unknown command `{}`, try `help`", command)?,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use crate::{
+        ast::Parser,
+        codegen::Codegen,
+        debugger::{Console, Debugger},
+        error::ErrorKind,
+        interpreter::output::OutputBuffer,
+        interpreter::Interpreter,
+    };
+
+    /// Runs `source` under the debugger, returning the transcript and the program's output.
+    fn debug(source: &str, commands: &'static str) -> (String, String, Result<(), ErrorKind>) {
+        let mut parser = Parser::new(source.as_bytes());
+        let mut interpreter = Interpreter::default();
+        let stdout = interpreter.capture_stdout();
+        let mut codegen = Codegen::with_context(parser.sources(), interpreter.context());
+        while let Some(stmt) = parser.next_statement().unwrap() {
+            codegen.gen_statement(&stmt).unwrap();
+        }
+        let main = Rc::new(codegen.pop_init_sig());
+        let transcript = OutputBuffer::default();
+        let console = Console::new(commands.as_bytes(), transcript.clone());
+        Debugger::new(parser.sources(), parser.file(), &main).attach(&mut interpreter, console);
+        let main = Rc::new(interpreter.create_function(main));
+        let result = interpreter.call_function_args(main, []).map(|_| ());
+        (transcript.contents(), stdout.contents(), result)
+    }
+
+    const COUNTER: &str = "\
+fn counter() do
+    let count = 0
+    return \\ -> do
+        count = count + 1
+        return count
+    end
+end
+let next = counter()
+next()
+println(next())
+";
+
+    #[test]
+    fn breakpoints_and_inspection() {
+        let (transcript, stdout, result) = debug(
+            COUNTER,
+            "b 4\nc\nupvalues\nbt\np count * 10\no\nc\nupvalues\nd 4\nc\n",
+        );
+        assert_eq!(
+            transcript,
+            "\
+Paused at line 1: fn counter() do
+(rlox) Breakpoint set at line 4
+(rlox) Breakpoint at line 4: count = count + 1
+(rlox) count = 0
+(rlox) *#0 line 4: count = count + 1
+ #1 line 9: next()
+(rlox) 0
+(rlox) Step at line 9: next()
+(rlox) Breakpoint at line 4: count = count + 1
+(rlox) count = 1
+(rlox) Breakpoint at line 4 deleted
+(rlox) "
+        );
+        assert_eq!(stdout, "2\n");
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn globals() {
+        let source = "let a = 1\nlet b = [a]\nprintln(b)\n";
+        let (transcript, stdout, _) = debug(source, "b 3\nc\nglobals\np b[0] + a\nc\n");
+        assert_eq!(
+            transcript,
+            "\
+Paused at line 1: let a = 1
+(rlox) Breakpoint set at line 3
+(rlox) Breakpoint at line 3: println(b)
+(rlox) a = 1
+b = [1]
+(rlox) 2
+(rlox) "
+        );
+        assert_eq!(stdout, "[1]\n");
+    }
+
+    #[test]
+    fn stepping() {
+        let source = "\
+fn square(x) do
+    let y = x * x
+    return y
+end
+let a = square(2)
+let b = square(3)
+";
+        let (transcript, _, result) =
+            debug(source, "n\nn\ns\nlocals\nn\np y + 1\nframe 1\np a\nbt\nq\n");
+        assert_eq!(
+            transcript,
+            "\
+Paused at line 1: fn square(x) do
+(rlox) Step at line 5: let a = square(2)
+(rlox) Step at line 6: let b = square(3)
+(rlox) Step at line 2: let y = x * x
+(rlox) x = 3
+y = nil
+(rlox) Step at line 3: return y
+(rlox) 10
+(rlox) (rlox) 4
+(rlox)  #0 line 3: return y
+*#1 line 6: let b = square(3)
+(rlox) "
+        );
+        assert!(matches!(result, Err(ErrorKind::Interrupted)));
+    }
+}
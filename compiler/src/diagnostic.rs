@@ -0,0 +1,305 @@
+//! Rendering of errors with the source they point at, like
+//!
+//! ```text
+//! error: Expected `end` terminator at the end of block
+//!  --> main.rlox:1:14
+//!   |
+//! 1 | while true do
+//!   |            -- block opened here
+//!   |              ^
+//!   = help: blocks opened with `do`, `then` or `else` are closed with `end`
+//! ```
+use std::{collections::BTreeSet, fmt::Write};
+
+use crate::{
+    source_map::SourceMap,
+    span::{FileId, Span},
+};
+
+// spans over more lines show only the first and last ones
+const MAX_SPAN_LINES: usize = 4;
+
+const RED: &str = "\x1b[1;31m";
+const BLUE: &str = "\x1b[1;34m";
+const CYAN: &str = "\x1b[1;36m";
+const BOLD: &str = "\x1b[1m";
+const RESET: &str = "\x1b[0m";
+
+pub struct Label {
+    pub span: Span,
+    pub message: String,
+    /// Primary labels point at the error itself, secondary ones at related code.
+    pub primary: bool,
+}
+
+pub struct Diagnostic {
+    pub message: String,
+    pub labels: Vec<Label>,
+    pub help: Vec<String>,
+}
+impl Diagnostic {
+    /// Diagnostic pointing at `span`.
+    pub fn new(message: impl Into<String>, span: Span) -> Self {
+        Self {
+            message: message.into(),
+            labels: vec![Label {
+                span,
+                message: String::new(),
+                primary: true,
+            }],
+            help: vec![],
+        }
+    }
+    pub fn with_label(mut self, span: Span, message: impl Into<String>) -> Self {
+        self.labels.push(Label {
+            span,
+            message: message.into(),
+            primary: false,
+        });
+        self
+    }
+    pub fn with_help(mut self, help: impl Into<String>) -> Self {
+        self.help.push(help.into());
+        self
+    }
+    /// Renders the diagnostic with the source lines of its labels, with ANSI colors if `color` is set.
+    pub fn render(&self, sources: &SourceMap, color: bool) -> String {
+        let paint = |style: &str, text: &str| match color {
+            true => format!("{style}{text}{RESET}"),
+            false => text.to_string(),
+        };
+        let mut out = format!(
+            "{}{}\n",
+            paint(RED, "error"),
+            paint(BOLD, &format!(": {}", self.message))
+        );
+
+        // files in order of their first label, the primary label first, each with the lines of its labels, with the
+        // ones in the middle of long spans left out
+        let mut labels = self.labels.iter().collect::<Vec<_>>();
+        labels.sort_by_key(|label| !label.primary);
+        let mut files: Vec<(FileId, BTreeSet<usize>)> = vec![];
+        for label in &labels {
+            let index = match files.iter().position(|(file, _)| *file == label.span.file) {
+                Some(index) => index,
+                None => {
+                    files.push((label.span.file, BTreeSet::new()));
+                    files.len() - 1
+                }
+            };
+            let (first, last) = line_range(sources, label.span);
+            if last - first < MAX_SPAN_LINES {
+                files[index].1.extend(first..=last);
+            } else {
+                files[index].1.extend([first, first + 1, last - 1, last]);
+            }
+        }
+        let width = files
+            .iter()
+            .filter_map(|(_, lines)| lines.last())
+            .map(|line| (line + 1).to_string().len())
+            .max()
+            .unwrap_or(1);
+        let gutter = |line: &str| paint(BLUE, &format!("{line:>width$} |"));
+
+        for (file, shown) in files {
+            let first = labels.iter().find(|label| label.span.file == file).unwrap();
+            let (line, column) = sources.line_col(file, first.span.start);
+            let arrow = paint(BLUE, &format!("{:width$}-->", ""));
+            match &*sources.name(file) {
+                "" => writeln!(out, "{arrow} {line}:{column}").unwrap(),
+                name => writeln!(out, "{arrow} {name}:{line}:{column}").unwrap(),
+            }
+            writeln!(out, "{}", gutter("")).unwrap();
+            let mut previous = None;
+            for line in shown {
+                if previous.is_some_and(|previous| line > previous + 1) {
+                    writeln!(out, "{}", paint(BLUE, "...")).unwrap();
+                }
+                previous = Some(line);
+                let text = sources.line_text(file, line + 1);
+                writeln!(out, "{} {text}", gutter(&(line + 1).to_string())).unwrap();
+
+                let mut underlines = labels
+                    .iter()
+                    .filter(|label| label.span.file == file)
+                    .filter_map(|label| Some((underline(sources, label.span, line)?, label)))
+                    .collect::<Vec<_>>();
+                underlines.sort_by_key(|((start, _), _)| *start);
+                for ((start, end), label) in underlines {
+                    // tabs are kept so the underline lines up with the text
+                    let padding = text
+                        .chars()
+                        .take(start)
+                        .map(|ch| if ch == '\t' { '\t' } else { ' ' })
+                        .collect::<String>();
+                    let (marker, style) = match label.primary {
+                        true => (format!("^{}", "~".repeat(end - start - 1)), RED),
+                        false => ("-".repeat(end - start), BLUE),
+                    };
+                    let mut underline = marker;
+                    if line_range(sources, label.span).1 == line && !label.message.is_empty() {
+                        write!(underline, " {}", label.message).unwrap();
+                    }
+                    writeln!(out, "{} {padding}{}", gutter(""), paint(style, &underline)).unwrap();
+                }
+            }
+        }
+        for help in &self.help {
+            let equals = paint(BLUE, &format!("{:width$} =", ""));
+            writeln!(out, "{equals} {}: {help}", paint(CYAN, "help")).unwrap();
+        }
+        out
+    }
+}
+
+/// First and last zero based line of the span.
+fn line_range(sources: &SourceMap, span: Span) -> (usize, usize) {
+    let last = span.end.max(span.start + 1) - 1;
+    (
+        sources.line_index(span.file, span.start),
+        sources.line_index(span.file, last),
+    )
+}
+
+/// Columns of the span's underline on the zero based `line`, in characters, if the span is on it.
+fn underline(sources: &SourceMap, span: Span, line: usize) -> Option<(usize, usize)> {
+    let (first, last) = line_range(sources, span);
+    if !(first..=last).contains(&line) {
+        return None;
+    }
+    let range = sources.line_range(span.file, line);
+    let source = sources.source(span.file);
+    let text = &source.borrow()[range.clone()];
+    let column = |offset: usize| {
+        let offset = offset.clamp(range.start, range.end) - range.start;
+        text[..offset].chars().count()
+    };
+    let start = match line == first {
+        true => column(span.start),
+        false => text.chars().take_while(|ch| ch.is_whitespace()).count(),
+    };
+    let end = match line == last {
+        true => column(span.end),
+        false => text.chars().count(),
+    };
+    Some((start, end.max(start + 1)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, rc::Rc};
+
+    use super::*;
+    use crate::ast::Parser;
+
+    fn add(sources: &SourceMap, name: &str, source: &str) -> FileId {
+        sources.add(name, Rc::new(RefCell::new(source.to_owned())))
+    }
+
+    #[test]
+    fn test_render() {
+        let sources = SourceMap::new();
+        let source = "let a = 1\nwhile a < 10 do\n\tprint(a)\n";
+        let file = add(&sources, "main.rlox", source);
+        let start = source.find("do").unwrap();
+        let diagnostic = Diagnostic::new(
+            "Expected `end` terminator at the end of block",
+            Span::from_len(source.len(), 0).in_file(file),
+        )
+        .with_label(Span::from_len(start, 2).in_file(file), "block opened here")
+        .with_help("blocks are closed with `end`");
+        assert_eq!(
+            diagnostic.render(&sources, false),
+            "\
+error: Expected `end` terminator at the end of block
+ --> main.rlox:3:10
+  |
+2 | while a < 10 do
+  |              -- block opened here
+3 | \tprint(a)
+  | \t        ^
+  = help: blocks are closed with `end`
+"
+        );
+
+        // multi-line spans are underlined on every line, long ones are cut short
+        let source = "f(1,\n  2,\n  3,\n  4,\n  5)\n";
+        let file = add(&sources, "call.rlox", source);
+        let diagnostic = Diagnostic::new("Bad call", Span::new(0, source.len() - 1).in_file(file));
+        assert_eq!(
+            diagnostic.render(&sources, false),
+            "\
+error: Bad call
+ --> call.rlox:1:1
+  |
+1 | f(1,
+  | ^~~~
+2 |   2,
+  |   ^~
+...
+4 |   4,
+  |   ^~
+5 |   5)
+  |   ^~
+"
+        );
+
+        let colored = Diagnostic::new("Bad", Span::from_len(0, 1)).render(&sources, true);
+        assert!(colored.starts_with("\x1b[1;31merror\x1b[0m"));
+    }
+
+    #[test]
+    fn test_files() {
+        let sources = SourceMap::new();
+        let lib = add(&sources, "lib.rlox", "fn add(a, b) a + b\n");
+        let main = add(&sources, "main.rlox", "print(add(1))\n");
+        let diagnostic = Diagnostic::new(
+            "Expected 2 arguments but got 1",
+            Span::new(6, 12).in_file(main),
+        )
+        .with_label(Span::new(3, 6).in_file(lib), "declared here");
+        assert_eq!(
+            diagnostic.render(&sources, false),
+            "\
+error: Expected 2 arguments but got 1
+ --> main.rlox:1:7
+  |
+1 | print(add(1))
+  |       ^~~~~~
+ --> lib.rlox:1:4
+  |
+1 | fn add(a, b) a + b
+  |    --- declared here
+"
+        );
+    }
+
+    #[test]
+    fn test_error() {
+        let sources = SourceMap::new();
+        let mut parser = Parser::with_file(
+            "if true then\n    print(1)\n".as_bytes(),
+            &sources,
+            "main.rlox",
+        );
+        let err = parser.next_statement().unwrap_err();
+        assert_eq!(
+            err.render(false),
+            "\
+error: Expected `else` or `end` terminator at the end of block
+ --> main.rlox:2:13
+  |
+1 | if true then
+  | ------------ block opened here
+2 |     print(1)
+  |             ^
+  = help: blocks opened with `do`, `then` or `else` are closed with `end`
+"
+        );
+        assert_eq!(
+            err.to_string(),
+            "Error: Expected `else` or `end` terminator at the end of block"
+        );
+    }
+}
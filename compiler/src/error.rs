@@ -1,6 +1,11 @@
-use std::{cell::RefCell, fmt, io, rc::Rc};
+use std::{fmt, io};
 
-use crate::{interpreter::string::ValueStr, span::Span};
+use crate::{
+    diagnostic::Diagnostic,
+    interpreter::string::ValueStr,
+    source_map::SourceMap,
+    span::{Span, SpanOf},
+};
 
 #[derive(Debug, thiserror::Error)]
 pub enum ErrorKind {
@@ -38,6 +43,8 @@ pub enum ErrorKind {
     ExpectedIdent,
     #[error("Expected expression")]
     ExpectedExpr,
+    #[error("Unexpected token")]
+    UnexpectedToken,
     #[error("Array splitting already used")]
     RepeatingSplit,
     #[error("Expected `else` or `end` terminator at the end of block")]
@@ -56,6 +63,36 @@ pub enum ErrorKind {
     ExpectedFuncBody,
     #[error("Stack overflow")]
     StackOverflow,
+    #[error("Instruction limit of {0} exceeded")]
+    InstructionLimit(u64),
+    #[error("Memory limit of {0} cells exceeded")]
+    MemoryLimit(usize),
+    #[error("Stack limit of {0} cells exceeded")]
+    StackLimit(usize),
+    #[error("Collection size limit of {0} elements exceeded")]
+    CollectionLimit(usize),
+    #[error("Execution interrupted")]
+    Interrupted,
+    #[error("Expected {0} arguments but got {1}")]
+    ArityMismatch(usize, usize),
+    #[error("`{0}` is not a variant of `{1}`")]
+    InvalidVariant(ValueStr, &'static str),
+    #[error("Value of type `{0}` cannot be sent to another isolate")]
+    Unsendable(&'static str),
+    #[error("Cyclic value cannot be sent to another isolate")]
+    CyclicSend,
+    #[error("Invalid JSON at line {0}, column {1}: {2}")]
+    InvalidJson(usize, usize, &'static str),
+    #[error("Value of type `{0}` cannot be converted to JSON")]
+    Unserializable(&'static str),
+    #[error("Object key of type `{0}` cannot be converted to JSON, only strings can")]
+    InvalidJsonKey(&'static str),
+    #[error("Cyclic value cannot be converted to JSON")]
+    CyclicJson,
+    #[error("Value nested deeper than {0} arrays and objects cannot be converted to JSON")]
+    JsonTooDeep(usize),
+    #[error("Isolate failed: {0}")]
+    IsolateFailed(String),
     #[error("Stack underflow")]
     StackUnderflow,
     #[error("Local id out of range")]
@@ -74,6 +111,8 @@ pub enum ErrorKind {
     NanIndexing,
     #[error("Attempted to write to read-only global `{0}`")]
     ConstGlobal(ValueStr),
+    #[error("Attempted to write to read-only object")]
+    ReadOnlyObject,
     #[error("Attempted to write to undeclared global variable `{0}`")]
     UndeclaredGlobal(ValueStr),
     #[error("Attempted to redeclare global variable `{0}`")]
@@ -92,16 +131,88 @@ pub enum ErrorKind {
     IllegalBreak,
     #[error("Continue statement outside of while/for loop")]
     IllegalContinue,
+    #[error("Assertion failed: {0}")]
+    AssertionFailed(String),
+    #[error("Assertion failed: `{}` != `{}`{}", .0.left, .0.right, differs_at(&.0.path))]
+    AssertEqFailed(Box<Mismatch>),
+    #[error("Replay diverged from the recording at instruction {0}")]
+    ReplayDiverged(u64),
     #[error("{0}")]
     RuntimeError(String),
 }
 
+/// Values that `assert_eq` found unequal, as written in source.
+#[derive(Debug)]
+pub struct Mismatch {
+    pub left: String,
+    pub right: String,
+    pub path: String, // where the values first differ, empty if they differ as a whole
+}
+
+fn differs_at(path: &str) -> String {
+    match path {
+        "" => String::new(),
+        path => format!(", first differing at `{path}`"),
+    }
+}
+
+impl ErrorKind {
+    /// Hint on how to fix the error, shown with the diagnostic.
+    pub fn help(&self) -> Option<&'static str> {
+        Some(match self {
+            Self::ExpectedEnd | Self::ExpectedElse => {
+                "blocks opened with `do`, `then` or `else` are closed with `end`"
+            }
+            Self::ExpectedThen => "the condition of `if` is followed by `then`",
+            Self::ExpectedDoBlock => "loop bodies are written as `do ... end`",
+            Self::UndeclaredGlobal(_) => "declare the variable with `let` before assigning to it",
+            Self::ConstGlobal(_) => {
+                "declare the variable with `let` instead of `const` to reassign it"
+            }
+            Self::IllegalBreak | Self::IllegalContinue => {
+                "`break` and `continue` can only be used inside `while` and `for` loops"
+            }
+            _ => return None,
+        })
+    }
+}
+
 #[derive(thiserror::Error)]
 pub struct Error {
     #[source]
     pub kind: ErrorKind,
     pub span: Span,
-    pub source: Rc<RefCell<String>>,
+    pub sources: SourceMap,
+    /// Secondary locations related to the error, like where an unterminated block starts.
+    pub labels: Vec<SpanOf<String>>,
+}
+impl Error {
+    pub fn new(kind: ErrorKind, span: Span, sources: SourceMap) -> Self {
+        Self {
+            kind,
+            span,
+            sources,
+            labels: vec![],
+        }
+    }
+    pub fn with_label(mut self, span: Span, message: impl Into<String>) -> Self {
+        self.labels.push(SpanOf(span, message.into()));
+        self
+    }
+    pub fn diagnostic(&self) -> Diagnostic {
+        let mut diagnostic = Diagnostic::new(self.kind.to_string(), self.span);
+        for label in &self.labels {
+            diagnostic = diagnostic.with_label(label.0, label.1.clone());
+        }
+        if let Some(help) = self.kind.help() {
+            diagnostic = diagnostic.with_help(help);
+        }
+        diagnostic
+    }
+    /// Renders the error with the source it points at, see `Diagnostic::render`.
+    pub fn render(&self, color: bool) -> String {
+        self.diagnostic().render(&self.sources, color)
+    }
 }
 impl fmt::Debug for Error {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -109,35 +220,27 @@ impl fmt::Debug for Error {
             .field("kind", &self.kind)
             .field(
                 "span",
-                &(
-                    self.span.start..self.span.end,
-                    &self.source.borrow()[self.span.start..self.span.end],
-                ),
+                &(self.span.start..self.span.end, self.sources.text(self.span)),
             )
             .finish()
     }
 }
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let mut col = 0;
-        let mut row = 1;
-        let mut prev_ch = '\0';
-        let source = self.source.borrow();
-        for (i, ch) in source.char_indices() {
-            if prev_ch == '\n' || prev_ch == '\r' {
-                col = 1;
-                if prev_ch == '\n' {
-                    row += 1;
-                }
-            } else {
-                col += 1;
-            }
-            if i == self.span.start {
-                return write!(f, "Error [line:{}, col:{}]: {}", row, col, self.kind);
-            }
-            prev_ch = ch;
+        let file = self.span.file;
+        // errors at the end of the source have no position
+        if self.span.start >= self.sources.source(file).borrow().len() {
+            return write!(f, "Error: {}", self.kind);
+        }
+        let (line, col) = self.sources.line_col(file, self.span.start);
+        match &*self.sources.name(file) {
+            "" => write!(f, "Error [line:{}, col:{}]: {}", line, col, self.kind),
+            name => write!(
+                f,
+                "Error [{}, line:{}, col:{}]: {}",
+                name, line, col, self.kind
+            ),
         }
-        write!(f, "Error: {}", self.kind)
     }
 }
 pub type Result<T> = std::result::Result<T, Error>;
@@ -0,0 +1,611 @@
+//! Pretty printer of `rlox fmt`.
+//!
+//! Statements go on their own lines with four spaces of indentation per block, and expressions get only the
+//! parentheses that precedence requires. Literals, names and operators are printed the way they're spelled in the
+//! source. Comments are kept: the ones between statements on their own lines, the ones following a statement on its
+//! line. Formatting formatted code gives back the same code.
+use crate::{
+    ast::{
+        binary::precedence,
+        declaration::{Declaration, FunctionBody},
+        expression::*,
+        statement::Statement,
+        Parser,
+    },
+    error::Result,
+    source_map::SourceMap,
+    span::{GetSpan, Span, SpanOf},
+};
+
+const WIDTH: usize = 100;
+const INDENT: &str = "    ";
+
+// binding strength of expressions, higher binding tighter, see `Formatter::expr`
+const ASSIGN: usize = 0;
+const BINARY: usize = 1; // plus the operator's precedence
+const PREFIX: usize = BINARY + 11;
+const POSTFIX: usize = PREFIX + 1;
+const PRIMARY: usize = POSTFIX + 1;
+
+/// Formats a whole program.
+pub fn format(source: &str) -> Result<String> {
+    format_file("", source)
+}
+/// Like `format`, with errors pointing into a file named `name`.
+pub fn format_file(name: &str, source: &str) -> Result<String> {
+    let mut parser = Parser::with_file(source.as_bytes(), &SourceMap::new(), name);
+    let mut statements = vec![];
+    while let Some(statement) = parser.next_statement()? {
+        statements.push(statement);
+    }
+    let source = parser.source().borrow().clone();
+    let mut formatter = Formatter {
+        source,
+        comments: parser.comments(),
+        next_comment: 0,
+        out: String::new(),
+        indent: 0,
+    };
+    formatter.block(&statements, usize::MAX);
+    Ok(formatter.out)
+}
+
+fn binding(expr: &Expression) -> usize {
+    match expr {
+        Expression::Assign { .. } | Expression::Closure(_) => ASSIGN,
+        Expression::Binary { operator, .. } => BINARY + precedence(operator.1).unwrap_or(0),
+        Expression::Prefix { .. } => PREFIX,
+        Expression::Postfix { .. } => POSTFIX,
+        _ => PRIMARY,
+    }
+}
+
+/// List items of the formatter, which is elements or object pairs.
+trait Item {
+    fn span(&self) -> Span;
+    fn format(&self, formatter: &mut Formatter);
+}
+impl Item for Element {
+    fn span(&self) -> Span {
+        match self {
+            Self::Regular(expr) => expr.span(),
+            Self::Unpack(unpack) => unpack.0,
+        }
+    }
+    fn format(&self, formatter: &mut Formatter) {
+        match self {
+            Self::Regular(expr) => formatter.expr(expr, ASSIGN),
+            Self::Unpack(unpack) => {
+                formatter.out.push('*');
+                formatter.expr(&unpack.1, ASSIGN);
+            }
+        }
+    }
+}
+impl Item for Pair {
+    fn span(&self) -> Span {
+        match self {
+            Self::Ident(key, value) => key.0.concat(value.span()),
+            Self::Index(key, value) => key.0.concat(value.span()),
+            Self::Unpack(unpack) => unpack.0,
+        }
+    }
+    fn format(&self, formatter: &mut Formatter) {
+        match self {
+            // shorthand `{x}` gets the key as its value
+            Self::Ident(key, Expression::Ident(value)) if value.0 == key.0 => {
+                formatter.text(key.0);
+            }
+            Self::Ident(key, value) => {
+                formatter.text(key.0);
+                formatter.out.push_str(": ");
+                formatter.expr(value, ASSIGN);
+            }
+            Self::Index(key, value) => {
+                formatter.out.push('[');
+                formatter.expr(&key.1, ASSIGN);
+                formatter.out.push_str("]: ");
+                formatter.expr(value, ASSIGN);
+            }
+            Self::Unpack(unpack) => {
+                formatter.out.push('*');
+                formatter.expr(&unpack.1, ASSIGN);
+            }
+        }
+    }
+}
+
+struct Formatter {
+    source: String,
+    comments: Vec<Span>,
+    next_comment: usize, // first comment that isn't printed yet
+    out: String,
+    indent: usize,
+}
+impl Formatter {
+    /// Source text of the span.
+    fn text(&mut self, span: Span) {
+        self.out.push_str(&self.source[span.start..span.end]);
+    }
+    fn newline(&mut self) {
+        let trimmed = self.out.trim_end_matches([' ', '\t']).len();
+        self.out.truncate(trimmed);
+        self.out.push('\n');
+    }
+    fn write_indent(&mut self) {
+        for _ in 0..self.indent {
+            self.out.push_str(INDENT);
+        }
+    }
+    fn pending_comment(&self) -> Option<Span> {
+        self.comments.get(self.next_comment).copied()
+    }
+    fn comment(&mut self, comment: Span) {
+        self.next_comment += 1;
+        let text = self.source[comment.start..comment.end].trim_end();
+        self.out.push_str(text);
+    }
+    /// Whether there's an empty line between `end` and `start` in the source.
+    fn blank_line(&self, end: Option<usize>, start: usize) -> bool {
+        end.is_some_and(|end| self.source[end..start].matches('\n').count() > 1)
+    }
+    /// Prints the comments before `offset` on their own lines, `end` being where the last thing printed ended.
+    fn comments_before(&mut self, offset: usize, end: &mut Option<usize>) {
+        while let Some(comment) = self.pending_comment().filter(|c| c.start < offset) {
+            if self.blank_line(*end, comment.start) {
+                self.newline();
+            }
+            self.write_indent();
+            self.comment(comment);
+            self.newline();
+            *end = Some(comment.end);
+        }
+    }
+    /// Prints a comment following `end` on the same source line.
+    fn trailing_comment(&mut self, end: &mut usize) {
+        let Some(comment) = self.pending_comment() else {
+            return;
+        };
+        if comment.start >= *end && !self.source[*end..comment.start].contains('\n') {
+            self.out.push(' ');
+            self.comment(comment);
+            *end = comment.end;
+        }
+    }
+    /// Prints statements at the current indentation, along with the comments before `end`.
+    fn block(&mut self, statements: &[Statement], end: usize) {
+        let mut last_end = None;
+        for statement in statements {
+            let span = statement.span();
+            self.comments_before(span.start, &mut last_end);
+            if self.blank_line(last_end, span.start) {
+                self.newline();
+            }
+            self.write_indent();
+            self.statement(statement);
+            let mut statement_end = span.end;
+            self.trailing_comment(&mut statement_end);
+            self.newline();
+            last_end = Some(statement_end);
+        }
+        self.comments_before(end, &mut last_end);
+    }
+    /// Prints an indented block, ending with `end`. A comment after the keyword opening it stays on its line.
+    fn do_block(&mut self, statements: &[Statement], end: usize) {
+        let first = statements
+            .first()
+            .map_or(end, |statement| statement.span().start);
+        if let Some(comment) = self
+            .pending_comment()
+            .filter(|comment| comment.start < first)
+        {
+            let line_start = self.source[..comment.start]
+                .rfind('\n')
+                .map_or(0, |i| i + 1);
+            if !self.source[line_start..comment.start].trim().is_empty() {
+                self.out.push(' ');
+                self.comment(comment);
+            }
+        }
+        self.newline();
+        self.indent += 1;
+        self.block(statements, end);
+        self.indent -= 1;
+        self.write_indent();
+    }
+    fn statement(&mut self, statement: &Statement) {
+        match statement {
+            Statement::Declaration(decl) => self.declaration(decl),
+            Statement::If { .. } => self.if_statement(statement),
+            Statement::While {
+                condition, block, ..
+            } => {
+                self.out.push_str("while ");
+                self.expr(condition, ASSIGN);
+                self.out.push_str(" do");
+                self.do_block(&block.1, block.0.end);
+                self.out.push_str("end");
+            }
+            Statement::For {
+                ident, expr, block, ..
+            } => {
+                self.out.push_str("for ");
+                self.text(ident.0);
+                self.out.push_str(" in ");
+                self.expr(expr, ASSIGN);
+                self.out.push_str(" do");
+                self.do_block(&block.1, block.0.end);
+                self.out.push_str("end");
+            }
+            Statement::Break(_) => self.out.push_str("break"),
+            Statement::Continue(_) => self.out.push_str("continue"),
+            Statement::Return(expr) => {
+                self.out.push_str("return");
+                if let Some(expr) = &expr.1 {
+                    self.out.push(' ');
+                    self.expr(expr, ASSIGN);
+                }
+            }
+        }
+    }
+    fn if_statement(&mut self, statement: &Statement) {
+        let Statement::If {
+            condition,
+            met_block,
+            else_block,
+            ..
+        } = statement
+        else {
+            unreachable!()
+        };
+        self.out.push_str("if ");
+        self.expr(condition, ASSIGN);
+        self.out.push_str(" then");
+        let met_end = else_block
+            .as_ref()
+            .map_or(met_block.0.end, |block| block.0.start);
+        self.do_block(&met_block.1, met_end);
+        let Some(else_block) = else_block else {
+            self.out.push_str("end");
+            return;
+        };
+        match else_block.1.as_slice() {
+            // `else if`, whose block ends with the inner if
+            [elif @ Statement::If { span, .. }] if span.end == else_block.0.end => {
+                self.out.push_str("else ");
+                self.if_statement(elif);
+            }
+            statements => {
+                self.out.push_str("else");
+                self.do_block(statements, else_block.0.end);
+                self.out.push_str("end");
+            }
+        }
+    }
+    fn declaration(&mut self, decl: &Declaration) {
+        match decl {
+            Declaration::VarDecl(decl) => {
+                self.text(decl.keyword.0);
+                self.out.push(' ');
+                self.text(decl.ident.0);
+                self.out.push_str(" = ");
+                self.expr(&decl.assigner, ASSIGN);
+            }
+            Declaration::FuncDecl(decl) => {
+                self.out.push_str("fn ");
+                self.text(decl.ident.0);
+                self.out.push('(');
+                self.params(&decl.closure);
+                self.out.push_str(") ");
+                self.body(&decl.closure.body);
+            }
+            Declaration::Expression(expr) => self.expr(expr, ASSIGN),
+        }
+    }
+    fn params(&mut self, closure: &Closure) {
+        let params = closure.params.1.iter().map(|param| param.0);
+        let variadic = closure.variadic.iter().map(|variadic| variadic.1 .0);
+        for (i, param) in params.chain(variadic).enumerate() {
+            if i != 0 {
+                self.out.push_str(", ");
+            }
+            if i == closure.params.1.len() {
+                self.out.push('*');
+            }
+            self.text(param);
+        }
+    }
+    fn body(&mut self, body: &FunctionBody) {
+        match body {
+            FunctionBody::Block(block) => {
+                self.out.push_str("do");
+                self.do_block(&block.1, block.0.end);
+                self.out.push_str("end");
+            }
+            FunctionBody::Expression(expr) => self.expr(expr, ASSIGN),
+        }
+    }
+    /// Prints the expression, in parentheses if it binds looser than `min`.
+    fn expr(&mut self, expr: &Expression, min: usize) {
+        if binding(expr) < min {
+            self.out.push('(');
+            self.expr(expr, ASSIGN);
+            self.out.push(')');
+            return;
+        }
+        match expr {
+            Expression::Ident(ident) => self.text(ident.0),
+            Expression::String(SpanOf(span, _))
+            | Expression::Number(SpanOf(span, _))
+            | Expression::Boolean(SpanOf(span, _))
+            | Expression::Nil(span) => self.text(*span),
+            Expression::Array(array) => self.list('[', &array.1, ']', array.0),
+            Expression::Object(object) => self.list('{', &object.1, '}', object.0),
+            Expression::Closure(closure) => {
+                self.out.push('\\');
+                self.params(closure);
+                self.out.push_str(" -> ");
+                self.body(&closure.body);
+            }
+            Expression::Prefix { operator, operand } => {
+                self.text(operator.0);
+                if self.out.ends_with(|ch: char| ch.is_alphabetic()) {
+                    // `not`
+                    self.out.push(' ');
+                }
+                let start = self.out.len();
+                self.expr(operand, PREFIX);
+                // `- -a` rather than a comment
+                if self.out[..start].ends_with('-') && self.out[start..].starts_with('-') {
+                    self.out.insert(start, ' ');
+                }
+            }
+            Expression::Postfix { operator, operand } => {
+                match (operator, &**operand) {
+                    // `1.x` would be read as the number `1.` followed by `x`
+                    (
+                        PostfixOperator::Property(_) | PostfixOperator::Method(_),
+                        Expression::Number(_),
+                    ) => self.expr(operand, PRIMARY + 1),
+                    _ => self.expr(operand, POSTFIX),
+                }
+                match operator {
+                    PostfixOperator::Property(ident) => {
+                        self.out.push('.');
+                        self.text(ident.0);
+                    }
+                    PostfixOperator::Method(ident) => {
+                        self.out.push(':');
+                        self.text(ident.0);
+                    }
+                    PostfixOperator::Call(args) => self.list('(', &args.1, ')', args.0),
+                    PostfixOperator::Index(index) => {
+                        self.out.push('[');
+                        self.expr(&index.1, ASSIGN);
+                        self.out.push(']');
+                    }
+                }
+            }
+            Expression::Binary {
+                left_operand,
+                operator,
+                right_operand,
+            } => {
+                let binding = binding(expr);
+                self.expr(left_operand, binding);
+                self.out.push(' ');
+                self.text(operator.0);
+                self.out.push(' ');
+                self.expr(right_operand, binding + 1);
+            }
+            Expression::Assign { assignee, assigner } => {
+                match assignee {
+                    Assignee::Ident(ident) => self.text(ident.0),
+                    Assignee::Property { ident, operand } => {
+                        self.expr(operand, POSTFIX);
+                        self.out.push('.');
+                        self.text(ident.0);
+                    }
+                    Assignee::Index { arg, operand } => {
+                        self.expr(operand, POSTFIX);
+                        self.out.push('[');
+                        self.expr(&arg.1, ASSIGN);
+                        self.out.push(']');
+                    }
+                }
+                self.out.push_str(" = ");
+                self.expr(assigner, ASSIGN);
+            }
+        }
+    }
+    /// Prints a comma separated list on one line if it fits, otherwise with an item per line.
+    fn list(&mut self, open: char, items: &[impl Item], close: char, span: Span) {
+        let (start, next_comment) = (self.out.len(), self.next_comment);
+        // comments can only be kept with the items on their own lines
+        let has_comments = self
+            .pending_comment()
+            .is_some_and(|comment| comment.start < span.end);
+        if !has_comments {
+            self.out.push(open);
+            for (i, item) in items.iter().enumerate() {
+                if i != 0 {
+                    self.out.push_str(", ");
+                }
+                item.format(self);
+            }
+            self.out.push(close);
+            let line_start = self.out[..start].rfind('\n').map_or(0, |i| i + 1);
+            let line_end = self.out[start..]
+                .find('\n')
+                .map_or(self.out.len(), |i| start + i);
+            if items.is_empty() || self.out[line_start..line_end].chars().count() <= WIDTH {
+                return;
+            }
+            self.out.truncate(start);
+            self.next_comment = next_comment;
+        }
+
+        self.out.push(open);
+        self.newline();
+        self.indent += 1;
+        let mut last_end = None;
+        for item in items {
+            let item_span = item.span();
+            self.comments_before(item_span.start, &mut last_end);
+            self.write_indent();
+            item.format(self);
+            self.out.push(',');
+            let mut item_end = item_span.end;
+            self.trailing_comment(&mut item_end);
+            self.newline();
+            last_end = Some(item_end);
+        }
+        self.comments_before(span.end, &mut last_end);
+        self.indent -= 1;
+        self.write_indent();
+        self.out.push(close);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{format, format_file};
+
+    fn assert_formats(source: &str, expected: &str) {
+        let formatted = format(source).unwrap();
+        assert_eq!(formatted, expected);
+        assert_eq!(format(&formatted).unwrap(), expected, "not idempotent");
+    }
+
+    #[test]
+    fn test_layout() {
+        assert_formats(
+            r#"
+let a=1;let b = { x:1 ,y , [ "k" ]:2,*rest, }
+fn add( a,b ) a+b
+if a==1 then print( "one" ) else if a==2 then print("two") else print(a)end
+while true do if a>60 then break end;a=a*2
+end
+for i in range(0,10) do
+  print(i)
+
+
+  print(-(i))
+end
+const f = \*args -> do return args end
+"#,
+            r#"let a = 1
+let b = {x: 1, y, ["k"]: 2, *rest}
+fn add(a, b) a + b
+if a == 1 then
+    print("one")
+else if a == 2 then
+    print("two")
+else
+    print(a)
+end
+while true do
+    if a > 60 then
+        break
+    end
+    a = a * 2
+end
+for i in range(0, 10) do
+    print(i)
+
+    print(-i)
+end
+const f = \*args -> do
+    return args
+end
+"#,
+        );
+    }
+
+    #[test]
+    fn test_parentheses() {
+        assert_formats(
+            "(a + b) * c\na + (b * c)\na - (b - c)\n(a - b) - c\n(-a).b\n-(a.b)\n(1).x\n\
+            (a = b) + 1\nx = (y = z)\n(\\x -> x)(1)\nnot (a and b)\n-(-a)\n(a or b) and c\n",
+            "(a + b) * c\na + b * c\na - (b - c)\na - b - c\n(-a).b\n-a.b\n(1).x\n\
+            (a = b) + 1\nx = y = z\n(\\x -> x)(1)\nnot (a and b)\n- -a\n(a or b) and c\n",
+        );
+    }
+
+    #[test]
+    fn test_comments() {
+        assert_formats(
+            r#"-- header
+
+let a = 1 -- trailing
+--[[ block
+     comment ]]
+fn f(x) do
+    -- inside
+    return x -- result
+    -- before end
+end
+fn g() do -- after do
+    if x then -- after then
+        return 1
+    else -- after else
+    end
+    while x do -- empty
+    end
+end
+print(
+    a, -- first
+    -- second
+    f(a)
+)
+-- footer
+"#,
+            r#"-- header
+
+let a = 1 -- trailing
+--[[ block
+     comment ]]
+fn f(x) do
+    -- inside
+    return x -- result
+    -- before end
+end
+fn g() do -- after do
+    if x then -- after then
+        return 1
+    else -- after else
+    end
+    while x do -- empty
+    end
+end
+print(
+    a, -- first
+    -- second
+    f(a),
+)
+-- footer
+"#,
+        );
+    }
+
+    #[test]
+    fn test_errors() {
+        let error = format_file("bad.rlox", "let a = 1\nfn f( do\n").unwrap_err();
+        assert!(error.render(false).contains("bad.rlox:2:5"));
+    }
+
+    #[test]
+    fn test_wrapping() {
+        let long = format!(
+            "let values = [{}]\nprint(values)\n",
+            (0..30)
+                .map(|i| format!("item{i}"))
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+        let formatted = format(&long).unwrap();
+        assert!(formatted.starts_with("let values = [\n    item0,\n    item1,\n"));
+        assert!(formatted.ends_with("    item29,\n]\nprint(values)\n"));
+        assert_eq!(format(&formatted).unwrap(), formatted);
+    }
+}
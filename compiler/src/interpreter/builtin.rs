@@ -1,10 +1,9 @@
 use std::{cell::RefCell, fmt::Write, mem::replace, rc::Rc};
 
-use rustc_hash::FxHashMap;
-
 use crate::{
-    error::ErrorKind,
+    error::{ErrorKind, Mismatch},
     interpreter::{
+        fs, isolate, json,
         string::ValueStr,
         value::{Function, Value},
         Interpreter,
@@ -14,13 +13,13 @@ use crate::{
 fn print(interpreter: &mut Interpreter) -> Result<Value, ErrorKind> {
     let args = interpreter.get_local(0).try_array().unwrap();
     for arg in args.borrow().iter() {
-        print!("{}", arg);
+        write!(interpreter.stdout(), "{}", arg)?;
     }
     Ok(Value::Nil)
 }
 fn println(interpreter: &mut Interpreter) -> Result<Value, ErrorKind> {
     print(interpreter)?;
-    println!();
+    writeln!(interpreter.stdout())?;
     Ok(Value::Nil)
 }
 fn str(interpreter: &mut Interpreter) -> Result<Value, ErrorKind> {
@@ -46,6 +45,9 @@ fn length(interpreter: &mut Interpreter) -> Result<Value, ErrorKind> {
 fn set_base_obj(interpreter: &mut Interpreter) -> Result<Value, ErrorKind> {
     let src = interpreter.get_local(0).try_object()?;
     let base = interpreter.get_local(1).try_object()?;
+    if src.borrow().read_only {
+        return Err(ErrorKind::ReadOnlyObject);
+    }
     src.borrow_mut().base_obj = Some(base);
     Ok(Value::Object(src))
 }
@@ -65,7 +67,10 @@ fn sqrt(interpreter: &mut Interpreter) -> Result<Value, ErrorKind> {
         .map(|n| Value::Number(n.sqrt()))
 }
 fn iter(interpreter: &mut Interpreter) -> Result<Value, ErrorKind> {
-    let iterable = interpreter.get_local(0);
+    make_iter(interpreter.get_local(0)).map(Value::Function)
+}
+/// Iterator function over `iterable`, which returns nil once exhausted.
+fn make_iter(iterable: Value) -> Result<Rc<Function>, ErrorKind> {
     let iter_fn = match iterable {
         Value::Array(arr) => {
             let len = arr.borrow().len();
@@ -115,7 +120,7 @@ fn iter(interpreter: &mut Interpreter) -> Result<Value, ErrorKind> {
         Value::Function(func) => func,
         t => return Err(ErrorKind::UniterableType(t.type_str())),
     };
-    Ok(Value::Function(iter_fn))
+    Ok(iter_fn)
 }
 fn range(interpreter: &mut Interpreter) -> Result<Value, ErrorKind> {
     let mut start = interpreter.get_local(0).try_num().ok().unwrap_or_default();
@@ -143,16 +148,10 @@ fn range(interpreter: &mut Interpreter) -> Result<Value, ErrorKind> {
     )));
     Ok(iter)
 }
-thread_local! {
-    static ITER_FN: Rc<Function> = GLOBALS
-        .with(|globals| globals.get(&ValueStr::interned("iter")).unwrap().clone());
-}
 fn map(interpreter: &mut Interpreter) -> Result<Value, ErrorKind> {
     let iterable = interpreter.get_local(0);
     let functor = interpreter.get_local(1).try_function()?;
-    let iterator = interpreter
-        .call_function_args(ITER_FN.with(|iter| iter.clone()), [iterable])?
-        .try_function()?;
+    let iterator = make_iter(iterable)?;
 
     let mapped_iter_fn = Interpreter::create_builtin_function(0, false, move |interpreter| {
         let item = interpreter.call_function_args(iterator.clone(), [])?;
@@ -167,9 +166,7 @@ fn map(interpreter: &mut Interpreter) -> Result<Value, ErrorKind> {
 fn filter(interpreter: &mut Interpreter) -> Result<Value, ErrorKind> {
     let iterable = interpreter.get_local(0);
     let predicate = interpreter.get_local(1).try_function()?;
-    let iterator = interpreter
-        .call_function_args(ITER_FN.with(|f| f.clone()), [iterable])?
-        .try_function()?;
+    let iterator = make_iter(iterable)?;
 
     let filtered_iter_fn =
         Interpreter::create_builtin_function(0, false, move |interpreter| loop {
@@ -186,9 +183,147 @@ fn filter(interpreter: &mut Interpreter) -> Result<Value, ErrorKind> {
 
     Ok(Value::Function(Rc::new(filtered_iter_fn)))
 }
+fn assert(interpreter: &mut Interpreter) -> Result<Value, ErrorKind> {
+    if interpreter.get_local(0).as_bool() {
+        return Ok(Value::Nil);
+    }
+    let message = interpreter
+        .get_local(1)
+        .try_array()?
+        .borrow()
+        .first()
+        .cloned();
+    Err(ErrorKind::AssertionFailed(match message {
+        Some(message) => message.to_string(),
+        None => "condition is false".to_string(),
+    }))
+}
+fn assert_eq(interpreter: &mut Interpreter) -> Result<Value, ErrorKind> {
+    let (left, right) = (interpreter.get_local(0), interpreter.get_local(1));
+    match first_difference(&left, &right, &mut String::new(), 0) {
+        None => Ok(Value::Nil),
+        Some(path) => Err(ErrorKind::AssertEqFailed(Box::new(Mismatch {
+            left: repr(&left, 0),
+            right: repr(&right, 0),
+            path,
+        }))),
+    }
+}
+/// Calls the function and returns the message of the error it fails with. Exceeding a limit of the interpreter or
+/// being interrupted isn't caught, since those stop the whole run rather than test what the script does.
+fn assert_error(interpreter: &mut Interpreter) -> Result<Value, ErrorKind> {
+    let function = interpreter.get_local(0).try_function()?;
+    // the failing instruction isn't where the top-level call goes wrong, if it does
+    let error_span = interpreter.error_span;
+    match interpreter.call_function_args(function, []) {
+        Ok(value) => Err(ErrorKind::AssertionFailed(format!(
+            "expected an error, but the function returned `{}`",
+            repr(&value, 0)
+        ))),
+        // every error of `Limits`
+        Err(
+            err @ (ErrorKind::Interrupted
+            | ErrorKind::InstructionLimit(_)
+            | ErrorKind::MemoryLimit(_)
+            | ErrorKind::StackLimit(_)
+            | ErrorKind::CollectionLimit(_)
+            | ErrorKind::StackOverflow),
+        ) => Err(err),
+        Err(err) => {
+            interpreter.error_span = error_span;
+            Ok(Value::String(ValueStr::from(err.to_string().as_str())))
+        }
+    }
+}
+
+// nesting at which values are compared and printed no further, which also stops at cycles
+const MAX_ASSERT_DEPTH: usize = 32;
+
+/// Path to where the values first differ, comparing arrays and objects by contents, None if they are equal.
+fn first_difference(
+    left: &Value,
+    right: &Value,
+    path: &mut String,
+    depth: usize,
+) -> Option<String> {
+    if depth >= MAX_ASSERT_DEPTH {
+        return None;
+    }
+    let len = path.len();
+    let nested = |path: &mut String, key: &Value, left: &Value, right: &Value| {
+        match key {
+            Value::String(key) => write!(path, ".{}", key).unwrap(),
+            key => write!(path, "[{}]", repr(key, 0)).unwrap(),
+        }
+        let difference = first_difference(left, right, path, depth + 1);
+        path.truncate(len);
+        difference
+    };
+    match (left, right) {
+        (Value::Array(left), Value::Array(right)) => {
+            let (left, right) = (left.borrow(), right.borrow());
+            let difference = (0..left.len().min(right.len()))
+                .find_map(|i| nested(path, &Value::Number(i as f64), &left[i], &right[i]));
+            match difference {
+                None if left.len() != right.len() => {
+                    Some(format!("{path}[{}]", left.len().min(right.len())))
+                }
+                difference => difference,
+            }
+        }
+        (Value::Object(left), Value::Object(right)) => {
+            let (left, right) = (left.borrow(), right.borrow());
+            let mut keys = left.map.keys().chain(right.map.keys()).collect::<Vec<_>>();
+            keys.sort_by_key(|key| repr(key, 0));
+            keys.dedup();
+            keys.into_iter().find_map(|key| {
+                let (l, r) = (left.map.get(key), right.map.get(key));
+                nested(
+                    path,
+                    key,
+                    l.unwrap_or(&Value::Nil),
+                    r.unwrap_or(&Value::Nil),
+                )
+            })
+        }
+        (left, right) if left == right => None,
+        _ => Some(path.clone()),
+    }
+}
+/// Value as written in source, with strings quoted.
+fn repr(value: &Value, depth: usize) -> String {
+    if depth >= MAX_ASSERT_DEPTH {
+        return "...".to_string();
+    }
+    match value {
+        Value::String(str) => format!("{:?}", str.as_str()),
+        Value::Array(arr) => {
+            let elements = arr
+                .borrow()
+                .iter()
+                .map(|elem| repr(elem, depth + 1))
+                .collect::<Vec<_>>();
+            format!("[{}]", elements.join(", "))
+        }
+        Value::Object(obj) => {
+            let mut pairs = obj
+                .borrow()
+                .map
+                .iter()
+                .map(|(key, value)| format!("{}: {}", repr(key, depth + 1), repr(value, depth + 1)))
+                .collect::<Vec<_>>();
+            pairs.sort();
+            format!("{{{}}}", pairs.join(", "))
+        }
+        value => value.to_string(),
+    }
+}
 
-thread_local! {
-    pub static GLOBALS: FxHashMap<ValueStr, Rc<Function>> = [
+/// Builtin functions every interpreter starts with, as read-only globals. Those named `module.name` are
+/// properties of the `module` global instead.
+#[rustfmt::skip]
+pub fn builtins() -> Vec<(&'static str, Function)> {
+    [
         ("print", 0, true, print as fn(&mut Interpreter) -> Result<Value, ErrorKind>),
         ("println", 0, true, println),
         ("len", 1, false, length),
@@ -200,8 +335,29 @@ thread_local! {
         ("str", 0, true, str),
         ("map", 2, false, map),
         ("filter", 2, false, filter),
-    ].into_iter().map(|(name, arity, variadic, ptr)| (
-        ValueStr::interned(name),
-        Rc::new(Interpreter::create_builtin_function(arity, variadic, ptr))
-    )).collect();
+        ("channel", 0, false, isolate::channel),
+        ("spawn", 2, false, isolate::spawn),
+        ("assert", 1, true, assert),
+        ("assert_eq", 2, false, assert_eq),
+        ("assert_error", 1, false, assert_error),
+        ("fs.read", 1, false, fs::read),
+        ("fs.read_lines", 1, false, fs::read_lines),
+        ("fs.write", 2, false, fs::write),
+        ("fs.append", 2, false, fs::append),
+        ("fs.exists", 1, false, fs::exists),
+        ("fs.remove", 1, false, fs::remove),
+        ("fs.rename", 2, false, fs::rename),
+        ("fs.mkdir", 1, false, fs::mkdir),
+        ("fs.list_dir", 1, false, fs::list_dir),
+        ("fs.stat", 1, false, fs::stat),
+        ("fs.join", 1, true, fs::join),
+        ("fs.basename", 1, false, fs::basename),
+        ("fs.extension", 1, false, fs::extension),
+        ("json.parse", 1, false, json::parse),
+        ("json.stringify", 1, true, json::stringify),
+    ].into_iter().map(|(name, arity, variadic, ptr)| {
+        let mut function = Interpreter::create_builtin_function(arity, variadic, ptr);
+        Rc::get_mut(&mut function.signature).unwrap().debug.name = Some(name.into());
+        (name, function)
+    }).collect()
 }
@@ -3,7 +3,7 @@ use rustc_hash::FxHashMap;
 use crate::error::ErrorKind;
 use crate::interpreter::string::ValueStr;
 use crate::interpreter::value::{Function, Object, Value};
-use crate::interpreter::{FnSignature, Interpreter};
+use crate::interpreter::{FnBody, FnSignature, Interpreter};
 use std::cell::RefCell;
 use std::mem::replace;
 use std::rc::Rc;
@@ -52,6 +52,7 @@ pub enum UnaryOp {
 #[rustfmt::skip]
 /// Bytecode for the language. It assumes a linear memory made up of cell that can accept any value.
 /// Constants, and globals have their own unique IDs so from the codegen perspective, global and constant identifiers needs to be interned before being used.
+/// Globals are resolved to slot indices of the interpreter's `GlobalTable` at codegen time.
 ///
 /// Operational instructions only access the local memory, where it's relative to the base function pointer.
 /// The memory automatically grows if the memory index is past the stack pointer.
@@ -63,11 +64,11 @@ pub enum Bytecode {
     Unary(UnaryOp), // s0 -> <UNARY> s0
     // Branching operations
     BranchIf(bool, isize), // s0 -> if s0 == .0 then <JMP> .1 else <NOP>;
-    // Global memory
-    GlobalDeclare(ValueStr), // declare global
-    GlobalReadOnly(ValueStr), // make global readonly
-    LoadGlobal(ValueStr), // () -> <GET_GLOBAL> .0
-    StoreGlobal(ValueStr), // s0 -> <SET_GLOBAL> .0 s0;
+    // Global memory, addressed by slots resolved through `GlobalTable`
+    GlobalDeclare(usize), // declare global
+    GlobalReadOnly(usize), // make global readonly
+    LoadGlobal(usize), // () -> <GET_GLOBAL> .0
+    StoreGlobal(usize), // s0 -> <SET_GLOBAL> .0 s0;
     // Local memory
     Truncate(usize), // truncate till current length
     LoadLocal(usize), // () -> <LOAD_LOCAL> .0
@@ -103,17 +104,25 @@ pub enum Bytecode {
     Call(usize), // starting at .0 offset: func, p0, p1, p2, ... -> func(p0, p1, p2, ...)
     CallVariadic, // func, params -> func(*params)
     CallBuiltin(usize, Rc<Function>), // starting at .0 offset: p0, p1, p2, ... -> .1(p0, p1, p2, ...)
+    TailCall(usize), // starting at .0 offset: func, p0, p1, p2, ... -> return func(p0, p1, p2, ...), reusing the current frame
     // Return
     Return, // v0 -> return(v0);
 }
+
+/// What the function frame should do after an instruction has been interpreted.
+pub enum Control {
+    Next(usize),            // continue at instruction index
+    Return(Value),          // leave the frame with value
+    Call(Rc<Function>), // a frame has been pushed for the function, start from its first instruction
+    TailCall(Rc<Function>), // frame has been reused for the function, restart from its first instruction
+}
+
 impl Bytecode {
-    // None -> return
-    // Some(i) -> next instruction index
     pub fn interpret(
         &self,
         interpreter: &mut Interpreter,
         index: usize,
-    ) -> Result<Result<usize, Value>, ErrorKind> {
+    ) -> Result<Control, ErrorKind> {
         match self {
             Bytecode::Dup(n) => {
                 let v = interpreter.pop_stack();
@@ -124,17 +133,19 @@ impl Bytecode {
             Bytecode::ExtendArray => {
                 let iter = interpreter.pop_stack();
                 let array = interpreter.pop_stack().try_array()?;
-                iter.try_iterate(interpreter, |_, v| Ok(array.borrow_mut().push(v)))?;
+                iter.try_iterate(interpreter, |int, v| {
+                    array.borrow_mut().push(v);
+                    int.limits.check_collection_len(array.borrow().len())
+                })?;
                 interpreter.push_stack(Value::Array(array));
             }
             Bytecode::ExtendObj => {
                 let iter = interpreter.pop_stack();
                 let object = interpreter.pop_stack();
-                iter.try_iterate(interpreter, |_, pair| {
+                iter.try_iterate(interpreter, |int, pair| {
                     let k = pair.get_property(&Value::Number(0.0))?;
                     let v = pair.get_property(&Value::Number(1.0))?;
-                    object.set_property(k, v)?;
-                    Ok(())
+                    int.set_property(&object, k, v)
                 })?;
                 interpreter.push_stack(object);
             }
@@ -143,13 +154,15 @@ impl Bytecode {
                 let array = interpreter.pop_stack().try_array()?;
 
                 array.borrow_mut().push(value);
-                interpreter.push_stack(Value::Array(array));
+                let array = Value::Array(array);
+                interpreter.limits.check_collection(&array)?;
+                interpreter.push_stack(array);
             }
             Bytecode::AppendObj(str) => {
                 let value = interpreter.pop_stack();
                 let obj = interpreter.pop_stack();
 
-                obj.set_property(Value::String(str.clone()), value)?;
+                interpreter.set_property(&obj, Value::String(str.clone()), value)?;
                 interpreter.push_stack(obj);
             }
             Bytecode::AppendObjIndirect => {
@@ -157,7 +170,7 @@ impl Bytecode {
                 let key = interpreter.pop_stack();
                 let obj = interpreter.pop_stack();
 
-                obj.set_property(key, value)?;
+                interpreter.set_property(&obj, key, value)?;
                 interpreter.push_stack(obj);
             }
             Bytecode::Binary(op) => {
@@ -187,45 +200,41 @@ impl Bytecode {
                     UnaryOp::SetTrue => Value::Bool(a.as_bool()),
                 });
             }
-            Bytecode::GlobalReadOnly(name) => interpreter.make_global_read_only(name.clone()),
-            Bytecode::GlobalDeclare(name) => interpreter.declare_global(name.clone())?,
+            Bytecode::GlobalReadOnly(slot) => interpreter.make_global_read_only(*slot),
+            Bytecode::GlobalDeclare(slot) => interpreter.declare_global(*slot)?,
             Bytecode::LoadProperty(name) => {
-                let prop = interpreter
-                    .pop_stack()
-                    .get_property(&Value::String(name.clone()))?;
+                let obj = interpreter.pop_stack();
+                let prop = interpreter.get_property(&obj, &Value::String(name.clone()))?;
                 interpreter.push_stack(prop);
             }
             Bytecode::LoadMethod(name) => {
                 let obj = interpreter.pop_stack();
-                let method = interpreter.method_currying(
-                    obj.clone(),
-                    obj.get_property(&Value::String(name.clone()))?
-                        .try_function()?,
-                );
+                let method = interpreter
+                    .get_property(&obj, &Value::String(name.clone()))?
+                    .try_function()?;
+                let method = interpreter.bind_method(obj, method);
                 interpreter.push_stack(Value::Function(Rc::new(method)));
             }
             Bytecode::LoadPropertyIndirect => {
                 let prop = interpreter.pop_stack();
                 let obj = interpreter.pop_stack();
-                interpreter.push_stack(obj.get_property(&prop)?);
+                interpreter.push_stack(interpreter.get_property(&obj, &prop)?);
             }
             Bytecode::StoreProperty(prop) => {
                 let obj = interpreter.pop_stack();
                 let value = interpreter.pop_stack();
-                obj.set_property(Value::String(prop.clone()), value)?;
+                interpreter.set_property(&obj, Value::String(prop.clone()), value)?;
             }
             Bytecode::StorePropertyIndirect => {
                 let prop = interpreter.pop_stack();
                 let obj = interpreter.pop_stack();
                 let value = interpreter.pop_stack();
-                obj.set_property(prop, value)?;
-            }
-            Bytecode::LoadGlobal(name) => {
-                interpreter.push_stack(interpreter.get_global(name.clone()))
+                interpreter.set_property(&obj, prop, value)?;
             }
-            Bytecode::StoreGlobal(name) => {
+            Bytecode::LoadGlobal(slot) => interpreter.push_stack(interpreter.get_global(*slot)),
+            Bytecode::StoreGlobal(slot) => {
                 let v = interpreter.pop_stack();
-                interpreter.set_global(name.clone(), v)?
+                interpreter.set_global(*slot, v)?
             }
             Bytecode::LoadLocal(id) => {
                 interpreter.push_stack(interpreter.get_local(*id));
@@ -257,10 +266,12 @@ impl Bytecode {
                     .collect::<Vec<_>>();
 
                 interpreter.stack.truncate(abs_base);
+                interpreter.limits.check_collection_len(vec.len())?;
                 interpreter.push_stack(Value::Array(Rc::new(RefCell::new(vec))));
             }
             Bytecode::StackToObj(base) => {
                 let abs_base = *base + interpreter.base_stack();
+                #[allow(clippy::mutable_key_type)]
                 let map = interpreter.stack[abs_base..]
                     .chunks_exact_mut(2)
                     .map(|pair| {
@@ -272,35 +283,55 @@ impl Bytecode {
                     .collect::<FxHashMap<_, _>>();
 
                 interpreter.stack.truncate(abs_base);
+                interpreter.limits.check_collection_len(map.len())?;
                 interpreter.push_stack(Value::Object(Rc::new(RefCell::new(Object::new(map)?))));
             }
             Bytecode::BranchIf(cond, offset) => {
                 let a = interpreter.pop_stack();
                 if a.as_bool() == *cond {
-                    return Ok(Ok(index.wrapping_add_signed(*offset)));
+                    return Ok(Control::Next(index.wrapping_add_signed(*offset)));
                 }
             }
-            Bytecode::Jump(offset) => return Ok(Ok(index.wrapping_add_signed(*offset))),
+            Bytecode::Jump(offset) => return Ok(Control::Next(index.wrapping_add_signed(*offset))),
             Bytecode::Truncate(new_len) => interpreter.truncate(*new_len),
-            Bytecode::Return => return Ok(Err(interpreter.pop_stack())),
+            Bytecode::Return => return Ok(Control::Return(interpreter.pop_stack())),
             Bytecode::Call(base) => {
-                let v = interpreter.call_on_stack(*base)?;
-                interpreter.push_stack(v);
+                let abs_stack = *base + interpreter.base_stack();
+                let function = interpreter.stack[abs_stack].try_function()?;
+                if let Some(callee) = interpreter.enter_call(function, abs_stack + 1, abs_stack)? {
+                    return Ok(Control::Call(callee));
+                }
             }
             Bytecode::CallVariadic => {
                 let params = interpreter.pop_stack();
                 let func = interpreter.pop_stack().try_function()?;
-                let stack = interpreter.base_stack();
+                let abs_stack = interpreter.stack.len();
 
-                params.try_iterate(interpreter, |int, v| Ok(int.push_stack(v)))?;
-                let ret = interpreter.call_stack_args(func, stack)?;
-                interpreter.push_stack(ret);
+                params.try_iterate(interpreter, |int, v| {
+                    int.push_stack(v);
+                    int.check_cells()
+                })?;
+                if let Some(callee) = interpreter.enter_call(func, abs_stack, abs_stack)? {
+                    return Ok(Control::Call(callee));
+                }
             }
             Bytecode::CallBuiltin(base, func) => {
                 let v = interpreter.call_stack_args(func.clone(), *base)?;
                 interpreter.push_stack(v);
             }
+            Bytecode::TailCall(base) => {
+                let abs_stack = *base + interpreter.base_stack();
+                let function = interpreter.stack[abs_stack].try_function()?;
+                let function = interpreter.unbind(function, abs_stack + 1);
+                // builtins have no bytecode to restart, so they are simply called and returned
+                if let FnBody::Builtin(..) = function.signature.body {
+                    let value = interpreter.call_native(function, abs_stack + 1, abs_stack)?;
+                    return Ok(Control::Return(value));
+                }
+                interpreter.reuse_frame(function.clone(), abs_stack + 1)?;
+                return Ok(Control::TailCall(function));
+            }
         }
-        Ok(Ok(index + 1))
+        Ok(Control::Next(index + 1))
     }
 }
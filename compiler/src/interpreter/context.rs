@@ -0,0 +1,47 @@
+use std::rc::Rc;
+
+use rustc_hash::FxHashMap;
+
+use crate::interpreter::{
+    builtin, global::GlobalTable, string::StrInterner, string::ValueStr, value::Function,
+};
+
+/// Runtime state shared between an interpreter and the codegen producing its bytecode:
+/// the global name table, the string interner and the builtin functions.
+///
+/// Every interpreter owns its own context, so nothing ties it or its compiled code to a thread.
+pub struct Context {
+    pub globals: GlobalTable,
+    pub interner: StrInterner,
+    builtins: FxHashMap<ValueStr, Rc<Function>>,
+}
+impl Default for Context {
+    fn default() -> Self {
+        let mut interner = StrInterner::default();
+        let builtins = builtin::builtins()
+            .into_iter()
+            .map(|(name, function)| (interner.intern(name), Rc::new(function)))
+            .collect();
+        Self {
+            globals: GlobalTable::default(),
+            interner,
+            builtins,
+        }
+    }
+}
+impl Context {
+    pub fn intern(&mut self, str: &str) -> ValueStr {
+        self.interner.intern(str)
+    }
+    /// Slot of the global `name`, allocating one if the name hasn't been seen yet.
+    pub fn global_slot(&mut self, name: &str) -> usize {
+        let name = self.interner.intern(name);
+        self.globals.slot(name)
+    }
+    pub fn builtin(&self, name: &str) -> Option<Rc<Function>> {
+        self.builtins.get(&ValueStr::from(name)).cloned()
+    }
+    pub fn builtins(&self) -> impl Iterator<Item = (&ValueStr, &Rc<Function>)> {
+        self.builtins.iter()
+    }
+}
@@ -0,0 +1,292 @@
+use std::{
+    collections::BTreeMap,
+    io::{self, Write},
+    rc::Rc,
+};
+
+use rustc_hash::FxHashMap;
+
+use crate::{
+    error::ErrorKind,
+    interpreter::{bytecode::Bytecode, hook::Hook, FnBody, FnSignature, Interpreter},
+    source_map::SourceMap,
+    span::{FileId, Span},
+};
+
+struct FunctionData {
+    signature: Rc<FnSignature>,
+    // times each instruction ran
+    hits: Vec<u64>,
+    // times each `BranchIf` jumped and didn't, by instruction index
+    branches: FxHashMap<usize, [u64; 2]>,
+}
+
+/// Instructions and branches run while coverage is turned on with `Interpreter::start_coverage`. It's a hook that
+/// runs after the profile, see `Interpreter::set_hook`.
+///
+/// Every function compiled along with a function that ran is included, so code that never ran shows up as missed.
+#[derive(Default)]
+pub struct Coverage {
+    functions: Vec<FunctionData>,
+    ids: FxHashMap<*const FnSignature, usize>,
+    // function of the last instruction, which is looked up again only after calls and returns
+    last: Option<(*const FnSignature, usize)>,
+}
+
+/// Coverage of one source line, see `Coverage::files`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LineCoverage {
+    /// One based.
+    pub line: usize,
+    /// Most times an instruction on the line ran.
+    pub hits: u64,
+    /// Times each branch on the line was taken and not taken, None if its condition never ran.
+    pub branches: Vec<Option<[u64; 2]>>,
+}
+
+/// Coverage of the lines with code in one file, see `Coverage::files`.
+#[derive(Debug, Clone)]
+pub struct FileCoverage {
+    pub file: FileId,
+    pub name: String,
+    pub lines: Vec<LineCoverage>,
+}
+impl FileCoverage {
+    /// Lines that ran and lines with code.
+    pub fn line_totals(&self) -> (usize, usize) {
+        let hit = self.lines.iter().filter(|line| line.hits > 0).count();
+        (hit, self.lines.len())
+    }
+    /// Branch edges that were taken and branch edges.
+    pub fn branch_totals(&self) -> (usize, usize) {
+        let edges = self.lines.iter().flat_map(|line| &line.branches);
+        edges.fold((0, 0), |(hit, total), branch| {
+            let hit_edges = branch.map_or(0, |edges| edges.iter().filter(|&&n| n > 0).count());
+            (hit + hit_edges, total + 2)
+        })
+    }
+}
+
+impl Coverage {
+    fn add_function(&mut self, signature: &Rc<FnSignature>) -> usize {
+        if let Some(&id) = self.ids.get(&Rc::as_ptr(signature)) {
+            return id;
+        }
+        let FnBody::Bytecode(code) = &signature.body else {
+            unreachable!("Only bytecode functions run instructions");
+        };
+        self.functions.push(FunctionData {
+            signature: signature.clone(),
+            hits: vec![0; code.len()],
+            branches: FxHashMap::default(),
+        });
+        let id = self.functions.len() - 1;
+        self.ids.insert(Rc::as_ptr(signature), id);
+        // closures defined in the function, which may never run
+        for bc in code {
+            if let Bytecode::LoadFn(closure) = &bc.1 {
+                self.add_function(closure);
+            }
+        }
+        id
+    }
+    fn function_id(&mut self, signature: &Rc<FnSignature>) -> usize {
+        let ptr = Rc::as_ptr(signature);
+        match self.last {
+            Some((last, id)) if last == ptr => id,
+            _ => {
+                let id = self.add_function(signature);
+                self.last = Some((ptr, id));
+                id
+            }
+        }
+    }
+    fn instruction(&mut self, signature: &Rc<FnSignature>, index: usize) {
+        let id = self.function_id(signature);
+        self.functions[id].hits[index] += 1;
+    }
+    /// Records whether the `BranchIf` at `index` jumped.
+    fn branch(&mut self, signature: &Rc<FnSignature>, index: usize, taken: bool) {
+        let id = self.function_id(signature);
+        let edges = self.functions[id].branches.entry(index).or_default();
+        edges[!taken as usize] += 1;
+    }
+
+    /// Coverage of every file with code in a covered function, by file id, each by line.
+    pub fn files(&self, sources: &SourceMap) -> Vec<FileCoverage> {
+        let mut files = BTreeMap::<FileId, BTreeMap<usize, LineCoverage>>::new();
+        for data in &self.functions {
+            let FnBody::Bytecode(code) = &data.signature.body else {
+                continue;
+            };
+            for (index, (bc, hits)) in code.iter().zip(&data.hits).enumerate() {
+                if bc.0 == Span::default() {
+                    continue;
+                }
+                let line = sources.line_index(bc.0.file, bc.0.start) + 1;
+                let coverage =
+                    files
+                        .entry(bc.0.file)
+                        .or_default()
+                        .entry(line)
+                        .or_insert(LineCoverage {
+                            line,
+                            hits: 0,
+                            branches: vec![],
+                        });
+                coverage.hits = coverage.hits.max(*hits);
+                if let Bytecode::BranchIf(..) = bc.1 {
+                    coverage.branches.push(data.branches.get(&index).copied());
+                }
+            }
+        }
+        files
+            .into_iter()
+            .map(|(file, lines)| FileCoverage {
+                file,
+                name: sources.name(file),
+                lines: lines.into_values().collect(),
+            })
+            .collect()
+    }
+    /// Writes the coverage as an lcov tracefile, as read by `genhtml` and CI coverage services.
+    pub fn write_lcov(&self, sources: &SourceMap, out: &mut impl Write) -> io::Result<()> {
+        writeln!(out, "TN:")?;
+        for file in self.files(sources) {
+            writeln!(out, "SF:{}", file.name)?;
+            for line in &file.lines {
+                for (block, branch) in line.branches.iter().enumerate() {
+                    for edge in 0..2 {
+                        let hits = branch.map_or("-".to_string(), |hits| hits[edge].to_string());
+                        writeln!(out, "BRDA:{},{block},{edge},{hits}", line.line)?;
+                    }
+                }
+            }
+            let (branches_hit, branches) = file.branch_totals();
+            writeln!(out, "BRF:{branches}")?;
+            writeln!(out, "BRH:{branches_hit}")?;
+            for line in &file.lines {
+                writeln!(out, "DA:{},{}", line.line, line.hits)?;
+            }
+            let (lines_hit, lines) = file.line_totals();
+            writeln!(out, "LF:{lines}")?;
+            writeln!(out, "LH:{lines_hit}")?;
+            writeln!(out, "end_of_record")?;
+        }
+        Ok(())
+    }
+    /// Writes the percentage of lines and branches covered in each file.
+    pub fn write_summary(&self, sources: &SourceMap, out: &mut impl Write) -> io::Result<()> {
+        let percent = |(hit, total): (usize, usize)| match total {
+            0 => format!("{:>8}", "-"),
+            _ => format!("{:>7.1}%", hit as f64 * 100.0 / total as f64),
+        };
+        writeln!(out, "{:>8} {:>8}  file", "lines", "branches")?;
+        for file in self.files(sources) {
+            writeln!(
+                out,
+                "{} {}  {}",
+                percent(file.line_totals()),
+                percent(file.branch_totals()),
+                file.name
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl Hook for Coverage {
+    fn on_instruction(
+        &mut self,
+        interpreter: &mut Interpreter,
+        frame: usize,
+        index: usize,
+        _bytecode: &Bytecode,
+        _span: Span,
+    ) -> Result<(), ErrorKind> {
+        self.instruction(&interpreter.frames[frame].function.signature, index);
+        Ok(())
+    }
+    fn on_branch(
+        &mut self,
+        interpreter: &mut Interpreter,
+        frame: usize,
+        index: usize,
+        taken: bool,
+    ) {
+        self.branch(&interpreter.frames[frame].function.signature, index, taken);
+    }
+}
+
+impl Interpreter {
+    /// Starts recording coverage of everything run from now on, replacing the coverage being recorded.
+    pub fn start_coverage(&mut self) {
+        self.coverage = Some(Box::default());
+        self.update_hooked();
+    }
+    /// Stops recording coverage and returns the coverage recorded so far.
+    pub fn take_coverage(&mut self) -> Option<Coverage> {
+        let coverage = self.coverage.take();
+        self.update_hooked();
+        coverage.map(|coverage| *coverage)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use super::LineCoverage;
+    use crate::{ast::Parser, codegen::Codegen, interpreter::Interpreter};
+
+    #[test]
+    fn test_coverage() {
+        let source = "\
+fn sign(n) do
+    if n < 0 then
+        return -1
+    end
+    return 1
+end
+let unused = \\ -> 0
+sign(1)
+sign(2)
+";
+        let mut parser = Parser::with_file(source.as_bytes(), &Default::default(), "sign.rlox");
+        let mut interpreter = Interpreter::default();
+        let mut codegen = Codegen::with_context(parser.sources(), interpreter.context());
+        while let Some(statement) = parser.next_statement().unwrap() {
+            codegen.gen_statement(&statement).unwrap();
+        }
+        let main = Rc::new(interpreter.create_function(Rc::new(codegen.pop_init_sig())));
+        interpreter.start_coverage();
+        interpreter.call_function_args(main, []).unwrap();
+        let coverage = interpreter.take_coverage().unwrap();
+
+        let sources = parser.sources();
+        let files = coverage.files(&sources);
+        assert_eq!(files.len(), 1);
+        let line = |n: usize| files[0].lines.iter().find(|line| line.line == n).unwrap();
+        // the condition jumps past `return -1` both times
+        assert_eq!(
+            line(2),
+            &LineCoverage {
+                line: 2,
+                hits: 2,
+                branches: vec![Some([2, 0])]
+            }
+        );
+        assert_eq!(line(3).hits, 0);
+        assert_eq!(line(5).hits, 2);
+        // the closure's body is on a line that ran
+        assert_eq!(line(7).hits, 1);
+        assert_eq!(files[0].branch_totals(), (1, 2));
+
+        let mut lcov = vec![];
+        coverage.write_lcov(&sources, &mut lcov).unwrap();
+        let lcov = String::from_utf8(lcov).unwrap();
+        assert!(lcov.starts_with("TN:\nSF:sign.rlox\nBRDA:2,0,0,2\nBRDA:2,0,1,0\n"));
+        assert!(lcov.contains("DA:3,0\n"));
+        assert!(lcov.ends_with("LF:7\nLH:6\nend_of_record\n"));
+    }
+}
@@ -0,0 +1,124 @@
+use std::{fmt, ops::Range, rc::Rc};
+
+use crate::{
+    interpreter::{
+        string::ValueStr,
+        value::{Function, Value},
+        Cell, FnBody, FnSignature, FunctionFrame, Interpreter,
+    },
+    source_map::SourceMap,
+    span::Span,
+};
+
+/// Names of a function and its variables, recorded by codegen for debuggers and profilers.
+#[derive(Clone, Default)]
+pub struct DebugInfo {
+    pub name: Option<ValueStr>, // declared name, None for closures and the top-level code
+    pub span: Span,             // where the function is written, default for the top-level code
+    pub locals: Vec<LocalName>,
+    pub upvalues: Vec<ValueStr>, // by upvalue index
+}
+// left out of bytecode dumps
+impl fmt::Debug for DebugInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DebugInfo").finish_non_exhaustive()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct LocalName {
+    pub name: ValueStr,
+    pub slot: usize,
+    pub live: Range<usize>, // instructions during which the local is in scope
+}
+
+impl FnSignature {
+    /// Name to show for the function: its declared name, or where a closure is written.
+    pub fn name(&self, sources: &SourceMap) -> String {
+        match &self.debug.name {
+            Some(name) => name.to_string(),
+            None if !matches!(self.body, FnBody::Bytecode(..)) => "<builtin>".to_string(),
+            None if self.debug.span == Span::default() => "<main>".to_string(),
+            None => {
+                let span = self.debug.span;
+                let (line, column) = sources.line_col(span.file, span.start);
+                match &*sources.name(span.file) {
+                    "" => format!("<closure {line}:{column}>"),
+                    file => format!("<closure {file}:{line}:{column}>"),
+                }
+            }
+        }
+    }
+}
+
+impl Interpreter {
+    pub fn frame_count(&self) -> usize {
+        self.frames.len()
+    }
+    /// Function of the call frame at `frame`, outermost first.
+    pub fn frame_function(&self, frame: usize) -> Rc<Function> {
+        self.frames[frame].function.clone()
+    }
+    /// Instruction the frame is executing, which is the call for frames below the innermost one.
+    /// Only kept up to date while a hook is set.
+    pub fn frame_pc(&self, frame: usize) -> usize {
+        self.frames[frame].pc
+    }
+    /// Span of the instruction the frame is executing, none for builtins.
+    pub fn frame_span(&self, frame: usize) -> Option<Span> {
+        let frame = &self.frames[frame];
+        match &frame.function.signature.body {
+            FnBody::Bytecode(code) => code.get(frame.pc).map(|bc| bc.0),
+            _ => None,
+        }
+    }
+    /// Locals in scope at the frame's current instruction, in declaration order.
+    pub fn frame_locals(&self, frame: usize) -> Vec<(ValueStr, Value)> {
+        let FunctionFrame {
+            base_pointer,
+            function,
+            pc,
+            ..
+        } = &self.frames[frame];
+        function
+            .signature
+            .debug
+            .locals
+            .iter()
+            .filter(|local| local.live.contains(pc))
+            .map(|local| {
+                let value = match self.memory.get(base_pointer + local.slot) {
+                    Some(Cell::Value(value)) => value.clone(),
+                    Some(Cell::Upvalue(cell)) => cell.borrow().clone(),
+                    None => Value::Nil,
+                };
+                (local.name.clone(), value)
+            })
+            .collect()
+    }
+    pub fn frame_upvalues(&self, frame: usize) -> Vec<(ValueStr, Value)> {
+        let function = &self.frames[frame].function;
+        let names = &function.signature.debug.upvalues;
+        names
+            .iter()
+            .zip(&function.upvalues)
+            .map(|(name, cell)| (name.clone(), cell.borrow().clone()))
+            .collect()
+    }
+    /// Declared globals, builtins included.
+    pub fn globals(&self) -> Vec<(ValueStr, Value)> {
+        let context = self.context.borrow();
+        self.globals
+            .iter()
+            .enumerate()
+            .filter_map(|(slot, global)| {
+                let (value, _) = global.as_ref()?;
+                Some((context.globals.name(slot).clone(), value.clone()))
+            })
+            .collect()
+    }
+    /// Forgets the span of a failed call made from a hook, so it isn't taken for the span of a later error.
+    pub(crate) fn clear_error_span(&mut self) {
+        self.error_span = None;
+    }
+}
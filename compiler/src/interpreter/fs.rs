@@ -0,0 +1,264 @@
+//! The `fs` builtin module, whose functions are properties of the read-only `fs` global, e.g. `fs.read(path)`.
+//!
+//! Failures are `ErrorKind::IoError`s naming the path, which scripts can catch like any other error.
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, Write},
+    path::Path,
+    rc::Rc,
+    time::UNIX_EPOCH,
+};
+
+use crate::{
+    error::ErrorKind,
+    interpreter::{
+        string::ValueStr,
+        value::{FromValue, IntoValue, Object, Value},
+        Interpreter,
+    },
+};
+
+fn io_error(path: &str, err: io::Error) -> ErrorKind {
+    ErrorKind::IoError(io::Error::new(err.kind(), format!("{path}: {err}")))
+}
+fn string(interpreter: &Interpreter, id: usize) -> Result<ValueStr, ErrorKind> {
+    ValueStr::from_value(interpreter.get_local(id))
+}
+
+pub(crate) fn read(interpreter: &mut Interpreter) -> Result<Value, ErrorKind> {
+    let path = string(interpreter, 0)?;
+    let path = path.as_str();
+    let contents = fs::read_to_string(path).map_err(|err| io_error(path, err))?;
+    Ok(contents.into_value())
+}
+/// Iterator over the lines of the file, without their line endings, read as the iteration goes.
+///
+/// Opening the file and each line read are inputs, so replays neither open the file nor read from it.
+pub(crate) fn read_lines(interpreter: &mut Interpreter) -> Result<Value, ErrorKind> {
+    let path = string(interpreter, 0)?.as_str().to_owned();
+    let mut lines = None;
+    interpreter.input(|_| {
+        let file = File::open(&path).map_err(|err| io_error(&path, err))?;
+        lines = Some(BufReader::new(file).lines());
+        Ok(Value::Nil)
+    })?;
+    let next_line = Interpreter::create_builtin_function(0, false, move |_| {
+        match lines.as_mut().and_then(Iterator::next) {
+            Some(Ok(line)) => Ok(line.into_value()),
+            Some(Err(err)) => Err(io_error(&path, err)),
+            None => Ok(Value::Nil),
+        }
+    });
+    let next_line = Rc::new(next_line);
+    interpreter.mark_input(&next_line);
+    Ok(Value::Function(next_line))
+}
+pub(crate) fn write(interpreter: &mut Interpreter) -> Result<Value, ErrorKind> {
+    let (path, contents) = (string(interpreter, 0)?, string(interpreter, 1)?);
+    let path = path.as_str();
+    fs::write(path, contents.as_str()).map_err(|err| io_error(path, err))?;
+    Ok(Value::Nil)
+}
+/// Writes at the end of the file, creating it if needed.
+pub(crate) fn append(interpreter: &mut Interpreter) -> Result<Value, ErrorKind> {
+    let (path, contents) = (string(interpreter, 0)?, string(interpreter, 1)?);
+    let path = path.as_str();
+    OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(path)
+        .and_then(|mut file| file.write_all(contents.as_str().as_bytes()))
+        .map_err(|err| io_error(path, err))?;
+    Ok(Value::Nil)
+}
+pub(crate) fn exists(interpreter: &mut Interpreter) -> Result<Value, ErrorKind> {
+    let path = string(interpreter, 0)?;
+    Ok(Value::Bool(Path::new(path.as_str()).exists()))
+}
+/// Removes a file or an empty directory.
+pub(crate) fn remove(interpreter: &mut Interpreter) -> Result<Value, ErrorKind> {
+    let path = string(interpreter, 0)?;
+    let path = path.as_str();
+    let result = match Path::new(path).is_dir() {
+        true => fs::remove_dir(path),
+        false => fs::remove_file(path),
+    };
+    result.map_err(|err| io_error(path, err))?;
+    Ok(Value::Nil)
+}
+pub(crate) fn rename(interpreter: &mut Interpreter) -> Result<Value, ErrorKind> {
+    let (from, to) = (string(interpreter, 0)?, string(interpreter, 1)?);
+    fs::rename(from.as_str(), to.as_str()).map_err(|err| io_error(from.as_str(), err))?;
+    Ok(Value::Nil)
+}
+/// Creates the directory along with its missing parents.
+pub(crate) fn mkdir(interpreter: &mut Interpreter) -> Result<Value, ErrorKind> {
+    let path = string(interpreter, 0)?;
+    let path = path.as_str();
+    fs::create_dir_all(path).map_err(|err| io_error(path, err))?;
+    Ok(Value::Nil)
+}
+/// Names of the entries of the directory, sorted.
+pub(crate) fn list_dir(interpreter: &mut Interpreter) -> Result<Value, ErrorKind> {
+    let path = string(interpreter, 0)?;
+    let path = path.as_str();
+    let mut names = fs::read_dir(path)
+        .and_then(|entries| {
+            entries
+                .map(|entry| Ok(entry?.file_name().to_string_lossy().into_owned()))
+                .collect::<io::Result<Vec<_>>>()
+        })
+        .map_err(|err| io_error(path, err))?;
+    names.sort();
+    Ok(names.into_value())
+}
+/// `{size, is_file, is_dir, modified}`, modified in seconds since the Unix epoch.
+pub(crate) fn stat(interpreter: &mut Interpreter) -> Result<Value, ErrorKind> {
+    let path = string(interpreter, 0)?;
+    let path = path.as_str();
+    let metadata = fs::metadata(path).map_err(|err| io_error(path, err))?;
+    let modified = metadata
+        .modified()
+        .ok()
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map(|time| time.as_secs_f64());
+    Ok(Object::from_entries([
+        ("size".into_value(), (metadata.len() as f64).into_value()),
+        ("is_file".into_value(), metadata.is_file().into_value()),
+        ("is_dir".into_value(), metadata.is_dir().into_value()),
+        ("modified".into_value(), modified.into_value()),
+    ])
+    .into_value())
+}
+/// Joins the first path with the rest, a rooted one replacing what comes before it.
+pub(crate) fn join(interpreter: &mut Interpreter) -> Result<Value, ErrorKind> {
+    let first = string(interpreter, 0)?;
+    let rest = Vec::<ValueStr>::from_value(interpreter.get_local(1))?;
+    let path = rest
+        .iter()
+        .fold(Path::new(first.as_str()).to_owned(), |path, part| {
+            path.join(part.as_str())
+        });
+    Ok(path.to_string_lossy().into_owned().into_value())
+}
+/// Last component of the path, nil if there is none.
+pub(crate) fn basename(interpreter: &mut Interpreter) -> Result<Value, ErrorKind> {
+    let path = string(interpreter, 0)?;
+    let name = Path::new(path.as_str()).file_name();
+    Ok(name
+        .map(|name| name.to_string_lossy().into_owned())
+        .into_value())
+}
+/// Extension of the last component without the dot, nil if it has none.
+pub(crate) fn extension(interpreter: &mut Interpreter) -> Result<Value, ErrorKind> {
+    let path = string(interpreter, 0)?;
+    let extension = Path::new(path.as_str()).extension();
+    Ok(extension
+        .map(|extension| extension.to_string_lossy().into_owned())
+        .into_value())
+}
+
+/// Marks the functions that read the file system as inputs for recording, see `Interpreter::mark_input`. So are
+/// those that change it, whose recorded results replays take instead of changing files again.
+pub(crate) fn mark_inputs(interpreter: &mut Interpreter) {
+    for name in [
+        "fs.read",
+        "fs.exists",
+        "fs.list_dir",
+        "fs.stat",
+        "fs.write",
+        "fs.append",
+        "fs.remove",
+        "fs.rename",
+        "fs.mkdir",
+    ] {
+        let function = interpreter.context.borrow().builtin(name).unwrap();
+        interpreter.mark_input(&function);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, io, path::PathBuf};
+
+    use crate::{error::ErrorKind, vm::Vm};
+
+    /// Directory removed with its contents once the test is over, passed or not.
+    struct TempDir(PathBuf);
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn test_fs() {
+        let dir = TempDir(env::temp_dir().join(format!("rlox-fs-{}", std::process::id())));
+        let mut vm = Vm::new();
+        vm.set_global("dir", dir.0.display().to_string()).unwrap();
+        let result = vm
+            .eval(
+                r#"
+let notes = fs.join(dir, "nested", "notes.txt")
+fs.mkdir(fs.join(dir, "nested"))
+fs.write(notes, "one\ntwo\n")
+fs.append(notes, "three")
+let lines = []
+for line in fs.read_lines(notes) do
+    lines[len(lines)] = line
+end
+let moved = fs.join(dir, "moved.txt")
+fs.rename(notes, moved)
+let stat = fs.stat(moved)
+[lines, fs.read(moved), fs.exists(notes), fs.list_dir(dir), stat.size, stat.is_file, stat.is_dir,
+    fs.basename(moved), fs.extension(moved), fs.extension(dir)]
+"#,
+            )
+            .unwrap();
+        assert_eq!(
+            result.to_string(),
+            "[[one, two, three], one\ntwo\nthree, false, [moved.txt, nested], 13, true, false, moved.txt, txt, nil]"
+        );
+
+        let result = vm
+            .eval(
+                r#"
+fs.remove(fs.join(dir, "moved.txt"))
+fs.remove(fs.join(dir, "nested"))
+[fs.list_dir(dir), fs.join("a", "/b"), assert_error(\ -> fs.read(fs.join(dir, "missing")))]
+"#,
+            )
+            .unwrap();
+        let missing = dir.0.join("missing").display().to_string();
+        assert!(result
+            .to_string()
+            .starts_with(&format!("[[], /b, IOError: {missing}: ")));
+        let error = vm.eval("fs.write(dir, 1)").unwrap_err();
+        assert!(matches!(error.kind, ErrorKind::InvalidType(..)));
+        for source in [
+            "fs.read(fs.join(dir, \"missing\"))",
+            "fs.remove(fs.join(dir, \"missing\"))",
+        ] {
+            let error = vm.eval(source).unwrap_err();
+            assert!(
+                matches!(&error.kind, ErrorKind::IoError(err) if err.kind() == io::ErrorKind::NotFound)
+            );
+        }
+    }
+
+    #[test]
+    fn test_read_only_module() {
+        let mut vm = Vm::new();
+        for source in [
+            "fs.read = nil",
+            "fs.other = 1",
+            "setbase(fs, {})",
+            "fs = {}",
+        ] {
+            assert!(vm.eval(source).is_err(), "{source}");
+        }
+        let error = vm.eval("fs.read = nil").unwrap_err();
+        assert!(matches!(error.kind, ErrorKind::ReadOnlyObject));
+        assert_eq!(vm.eval("fs.basename(\"a/b\")").unwrap().to_string(), "b");
+    }
+}
@@ -0,0 +1,41 @@
+use rustc_hash::FxHashMap;
+
+use crate::interpreter::string::ValueStr;
+
+/// Name-to-slot table for global variables.
+///
+/// Codegen resolves every global identifier through this table, so global bytecodes only carry the slot index.
+/// The table lives in the interpreter's `Context`, shared with codegen, which keeps the names around for declarations, error messages and the embedding API.
+/// A slot is allocated on first use and never changes, even before the global is declared.
+#[derive(Debug, Default)]
+pub struct GlobalTable {
+    slots: FxHashMap<ValueStr, usize>,
+    names: Vec<ValueStr>,
+}
+impl GlobalTable {
+    /// Returns the slot of `name`, allocating a new one if it hasn't been seen yet.
+    pub fn slot(&mut self, name: ValueStr) -> usize {
+        if let Some(slot) = self.slots.get(&name) {
+            return *slot;
+        }
+        let slot = self.names.len();
+        self.names.push(name.clone());
+        self.slots.insert(name, slot);
+        slot
+    }
+    pub fn get(&self, name: &ValueStr) -> Option<usize> {
+        self.slots.get(name).copied()
+    }
+    pub fn name(&self, slot: usize) -> &ValueStr {
+        &self.names[slot]
+    }
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+    pub fn iter(&self) -> impl Iterator<Item = (usize, &ValueStr)> {
+        self.names.iter().enumerate()
+    }
+}
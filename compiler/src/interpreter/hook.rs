@@ -0,0 +1,268 @@
+use std::{io::Write, rc::Rc};
+
+use crate::{
+    error::ErrorKind,
+    interpreter::{
+        bytecode::Bytecode,
+        value::{Function, Value},
+        Interpreter,
+    },
+    source_map::SourceMap,
+    span::Span,
+};
+
+/// Callbacks into tooling such as debuggers, tracers, profiles and coverage, see `Interpreter::set_hook`.
+///
+/// Every callback gets the interpreter to inspect the frames with, and may call back into it. A hook pauses execution
+/// by not returning until it should go on, and aborts it by returning an error, usually `ErrorKind::Interrupted`,
+/// which fails the running top-level call.
+pub trait Hook {
+    /// Called before the instruction at `index` of the innermost frame, `frame`, is run.
+    fn on_instruction(
+        &mut self,
+        _interpreter: &mut Interpreter,
+        _frame: usize,
+        _index: usize,
+        _bytecode: &Bytecode,
+        _span: Span,
+    ) -> Result<(), ErrorKind> {
+        Ok(())
+    }
+    /// Called once `function` has its frame, before it runs. Tail calls replace the frame of the caller.
+    fn on_call(
+        &mut self,
+        _interpreter: &mut Interpreter,
+        _function: &Rc<Function>,
+    ) -> Result<(), ErrorKind> {
+        Ok(())
+    }
+    /// Called after the `BranchIf` at `index` of the innermost frame, `frame`, ran, with whether it jumped.
+    fn on_branch(
+        &mut self,
+        _interpreter: &mut Interpreter,
+        _frame: usize,
+        _index: usize,
+        _taken: bool,
+    ) {
+    }
+    /// Called when the innermost frame returns `value`, before the frame is popped.
+    fn on_return(
+        &mut self,
+        _interpreter: &mut Interpreter,
+        _value: &Value,
+    ) -> Result<(), ErrorKind> {
+        Ok(())
+    }
+    /// Called when `frame`, the innermost one, is popped by an error instead of returning, before it's popped.
+    fn on_unwind(&mut self, _interpreter: &mut Interpreter, _frame: usize) {}
+    /// Called when an instruction fails, before any frame is unwound. Errors caught and failing again further out
+    /// are only reported where they first happened.
+    fn on_error(&mut self, _interpreter: &mut Interpreter, _error: &ErrorKind) {}
+}
+
+/// Hook writing every instruction with the stack it runs on to the interpreter's stderr, along with where it is in
+/// the source if the trace has the source map.
+#[derive(Default)]
+pub struct Trace {
+    sources: Option<SourceMap>,
+}
+impl Trace {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn with_sources(sources: SourceMap) -> Self {
+        Self {
+            sources: Some(sources),
+        }
+    }
+}
+impl Hook for Trace {
+    fn on_instruction(
+        &mut self,
+        interpreter: &mut Interpreter,
+        _frame: usize,
+        _index: usize,
+        bytecode: &Bytecode,
+        span: Span,
+    ) -> Result<(), ErrorKind> {
+        let stack = interpreter
+            .stack
+            .iter()
+            .map(|v| v.to_string())
+            .collect::<Vec<_>>()
+            .join(", ");
+        match &self.sources {
+            Some(sources) if span != Span::default() => {
+                let (line, column) = sources.line_col(span.file, span.start);
+                let name = sources.name(span.file);
+                writeln!(
+                    interpreter.stderr,
+                    "{name}:{line}:{column} {bytecode:?}: [{stack}]"
+                )?;
+            }
+            _ => writeln!(interpreter.stderr, "{bytecode:?}: [{stack}]")?,
+        }
+        Ok(())
+    }
+}
+
+impl Interpreter {
+    /// Installs the hook that is called on every instruction, call, return and error, replacing the previous one.
+    /// It runs after the profile and coverage being recorded, which are hooks as well. Without any of them, these
+    /// cost a branch each.
+    pub fn set_hook(&mut self, hook: Option<Box<dyn Hook>>) {
+        self.hook = hook;
+        self.update_hooked();
+    }
+    pub(super) fn update_hooked(&mut self) {
+        self.hooked = self.hook.is_some() || self.profile.is_some() || self.coverage.is_some();
+    }
+    /// Runs `f` on the profile, the coverage and the hook, those that are set. Each is taken out meanwhile so that
+    /// calls made by the hook don't re-enter it. All of them run, the first error is returned.
+    pub(super) fn call_hooks(
+        &mut self,
+        mut f: impl FnMut(&mut dyn Hook, &mut Self) -> Result<(), ErrorKind>,
+    ) -> Result<(), ErrorKind> {
+        let mut result = Ok(());
+        if let Some(mut profile) = self.profile.take() {
+            result = result.and(f(&mut *profile, self));
+            self.profile.get_or_insert(profile);
+        }
+        if let Some(mut coverage) = self.coverage.take() {
+            result = result.and(f(&mut *coverage, self));
+            self.coverage.get_or_insert(coverage);
+        }
+        if let Some(mut hook) = self.hook.take() {
+            result = result.and(f(&mut *hook, self));
+            // the hook may have installed another one
+            self.hook.get_or_insert(hook);
+        }
+        self.update_hooked();
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, rc::Rc};
+
+    use super::*;
+    use crate::{ast::Parser, codegen::Codegen, source_map::SourceMap};
+
+    #[derive(Default)]
+    struct Recorder {
+        events: Rc<RefCell<Vec<String>>>,
+        abort_at: Option<usize>,
+    }
+    impl Hook for Recorder {
+        fn on_instruction(
+            &mut self,
+            interpreter: &mut Interpreter,
+            frame: usize,
+            index: usize,
+            _bytecode: &Bytecode,
+            _span: Span,
+        ) -> Result<(), ErrorKind> {
+            assert_eq!(frame, interpreter.frame_count() - 1);
+            assert_eq!(interpreter.frame_pc(frame), index);
+            let mut events = self.events.borrow_mut();
+            events.push("step".to_string());
+            match self.abort_at == Some(events.len()) {
+                true => Err(ErrorKind::Interrupted),
+                false => Ok(()),
+            }
+        }
+        fn on_call(
+            &mut self,
+            interpreter: &mut Interpreter,
+            function: &Rc<Function>,
+        ) -> Result<(), ErrorKind> {
+            let name = function.signature.name(&SourceMap::new());
+            let depth = interpreter.frame_count();
+            self.events
+                .borrow_mut()
+                .push(format!("call {name} {depth}"));
+            Ok(())
+        }
+        fn on_return(&mut self, _: &mut Interpreter, value: &Value) -> Result<(), ErrorKind> {
+            self.events.borrow_mut().push(format!("return {value}"));
+            Ok(())
+        }
+        fn on_error(&mut self, _: &mut Interpreter, error: &ErrorKind) {
+            self.events.borrow_mut().push(format!("error {error}"));
+        }
+    }
+
+    fn compile(interpreter: &mut Interpreter, source: &str) -> Rc<Function> {
+        let mut parser = Parser::new(source.as_bytes());
+        let mut codegen = Codegen::with_context(parser.sources(), interpreter.context());
+        while let Some(statement) = parser.next_statement().unwrap() {
+            codegen.gen_statement(&statement).unwrap();
+        }
+        Rc::new(interpreter.create_function(Rc::new(codegen.pop_init_sig())))
+    }
+
+    #[test]
+    fn test_hook() {
+        let mut interpreter = Interpreter::default();
+        let main = compile(
+            &mut interpreter,
+            "fn one() 1\nlet a = len([one()])\n1 + nil",
+        );
+        let recorder = Recorder::default();
+        let events = recorder.events.clone();
+        interpreter.set_hook(Some(Box::new(recorder)));
+        let result = interpreter.call_function_args(main, []);
+        assert!(matches!(result, Err(ErrorKind::InvalidBinary(..))));
+        let events = events.take();
+        let events = events
+            .iter()
+            .filter(|event| *event != "step")
+            .collect::<Vec<_>>();
+        assert_eq!(
+            events,
+            [
+                "call <main> 1",
+                "call one 2",
+                "return 1",
+                "call len 2",
+                "return 1",
+                "error Binary operator `+` cannot be applied to value of type `number` and `nil`",
+            ]
+        );
+
+        // aborting fails the call, and the interpreter can run again
+        let main = compile(&mut interpreter, "println(1)");
+        let output = interpreter.capture_stdout();
+        let recorder = Recorder {
+            abort_at: Some(3),
+            ..Default::default()
+        };
+        interpreter.set_hook(Some(Box::new(recorder)));
+        let result = interpreter.call_function_args(main.clone(), []);
+        assert!(matches!(result, Err(ErrorKind::Interrupted)));
+        assert_eq!(interpreter.frame_count(), 0);
+        interpreter.set_hook(None);
+        interpreter.call_function_args(main, []).unwrap();
+        assert_eq!(output.take(), "1\n");
+    }
+
+    #[test]
+    fn test_trace() {
+        let mut interpreter = Interpreter::default();
+        let sources = SourceMap::new();
+        let mut parser = Parser::with_file("let a = 1 + 2".as_bytes(), &sources, "add.rlox");
+        let mut codegen = Codegen::with_context(parser.sources(), interpreter.context());
+        codegen
+            .gen_statement(&parser.next_statement().unwrap().unwrap())
+            .unwrap();
+        let main = Rc::new(interpreter.create_function(Rc::new(codegen.pop_init_sig())));
+        let trace = crate::interpreter::output::OutputBuffer::default();
+        interpreter.set_stderr(trace.clone());
+        interpreter.set_hook(Some(Box::new(Trace::with_sources(sources))));
+        interpreter.call_function_args(main, []).unwrap();
+        let trace = trace.take();
+        assert!(trace.contains("add.rlox:1:9 LoadNum(1.0): []\n"));
+        assert!(trace.contains("add.rlox:1:13 LoadNum(2.0): [1]\n"));
+    }
+}
@@ -0,0 +1,594 @@
+use std::{
+    cell::RefCell,
+    collections::VecDeque,
+    fs,
+    rc::Rc,
+    sync::{Arc, Condvar, Mutex},
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use rustc_hash::{FxHashMap, FxHashSet};
+
+use crate::{
+    error::ErrorKind,
+    interpreter::{
+        bytecode::Bytecode,
+        debug::{DebugInfo, LocalName},
+        native::{native_function, NativeType, NativeValue},
+        string::ValueStr,
+        value::{FromValue, Function, IntoValue, Object, Value},
+        FnBody, FnSignature, Interpreter,
+    },
+    span::SpanOf,
+    vm::{Detached, Vm},
+};
+
+/// How often a blocked `recv` checks whether the interpreter has been interrupted.
+const RECV_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Thread-safe deep copy of a value, used for everything that crosses an isolate boundary.
+///
+/// Functions and native values other than channels can't be sent, and neither can cyclic arrays or objects.
+/// Shared arrays and objects arrive as separate copies.
+#[derive(Debug, Clone)]
+pub enum SendValue {
+    Nil,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<SendValue>),
+    Object {
+        entries: Vec<(SendValue, SendValue)>,
+        base: Option<Box<SendValue>>,
+    },
+    Channel(Arc<ChannelQueue>),
+}
+impl SendValue {
+    fn copy(value: &Value, path: &mut Vec<*const ()>) -> Result<Self, ErrorKind> {
+        let ptr = match value {
+            Value::Array(arr) => Rc::as_ptr(arr) as *const (),
+            Value::Object(obj) => Rc::as_ptr(obj) as *const (),
+            _ => std::ptr::null(),
+        };
+        if !ptr.is_null() {
+            if path.contains(&ptr) {
+                return Err(ErrorKind::CyclicSend);
+            }
+            path.push(ptr);
+        }
+        let copy = match value {
+            Value::Nil => Self::Nil,
+            Value::Bool(bool) => Self::Bool(*bool),
+            Value::Number(num) => Self::Number(*num),
+            Value::String(str) => Self::String(str.as_str().to_owned()),
+            Value::Array(arr) => Self::Array(
+                arr.borrow()
+                    .iter()
+                    .map(|item| Self::copy(item, path))
+                    .collect::<Result<_, _>>()?,
+            ),
+            Value::Object(obj) => {
+                let obj = obj.borrow();
+                let entries = obj
+                    .map
+                    .iter()
+                    .map(|(k, v)| Ok::<_, ErrorKind>((Self::copy(k, path)?, Self::copy(v, path)?)))
+                    .collect::<Result<_, _>>()?;
+                let base = match &obj.base_obj {
+                    Some(base) => Some(Box::new(Self::copy(&Value::Object(base.clone()), path)?)),
+                    None => None,
+                };
+                Self::Object { entries, base }
+            }
+            Value::Native(native) => match native.downcast::<Channel>() {
+                Some(channel) => Self::Channel(channel.0.clone()),
+                None => return Err(ErrorKind::Unsendable(native.type_name())),
+            },
+            Value::Function(_) => return Err(ErrorKind::Unsendable(value.type_str())),
+        };
+        if !ptr.is_null() {
+            path.pop();
+        }
+        Ok(copy)
+    }
+}
+impl FromValue for SendValue {
+    fn from_value(value: Value) -> Result<Self, ErrorKind> {
+        Self::copy(&value, &mut vec![])
+    }
+}
+impl IntoValue for SendValue {
+    fn into_value(self) -> Value {
+        match self {
+            Self::Nil => Value::Nil,
+            Self::Bool(bool) => Value::Bool(bool),
+            Self::Number(num) => Value::Number(num),
+            Self::String(str) => Value::String(ValueStr::from(str.as_str())),
+            Self::Array(arr) => arr.into_value(),
+            Self::Object { entries, base } => {
+                #[allow(clippy::mutable_key_type)]
+                let map = entries
+                    .into_iter()
+                    .map(|(k, v)| (k.into_value(), v.into_value()))
+                    .collect();
+                let base_obj = base.and_then(|base| base.into_value().try_object().ok());
+                Value::Object(Rc::new(RefCell::new(Object {
+                    map,
+                    base_obj,
+                    read_only: false,
+                })))
+            }
+            Self::Channel(queue) => Rc::new(Channel(queue)).into_value(),
+        }
+    }
+}
+
+/// Message queue of a channel, shared by every handle to it across isolates.
+#[derive(Debug, Default)]
+pub struct ChannelQueue {
+    messages: Mutex<VecDeque<SendValue>>,
+    ready: Condvar,
+}
+
+/// Script handle to a channel, created by the `channel` builtin.
+///
+/// Any number of isolates can send to and receive from the same channel.
+pub struct Channel(Arc<ChannelQueue>);
+impl NativeType for Channel {
+    const TYPE_NAME: &'static str = "Channel";
+}
+impl Drop for Channel {
+    fn drop(&mut self) {
+        // wakes receivers up, which may now be the only ones left
+        self.0.ready.notify_all();
+    }
+}
+impl Channel {
+    fn send(&self, value: SendValue) {
+        self.0.messages.lock().unwrap().push_back(value);
+        self.0.ready.notify_one();
+    }
+    fn try_recv(&self) -> Value {
+        let message = self.0.messages.lock().unwrap().pop_front();
+        message.into_value()
+    }
+    /// Blocks until a message arrives. Returns nil once no other handle is left to send one.
+    fn recv(&self, interpreter: &Interpreter) -> Result<Value, ErrorKind> {
+        let mut messages = self.0.messages.lock().unwrap();
+        loop {
+            if let Some(message) = messages.pop_front() {
+                return Ok(message.into_value());
+            }
+            if Arc::strong_count(&self.0) == 1 {
+                return Ok(Value::Nil);
+            }
+            if interpreter.interrupt.take() {
+                return Err(ErrorKind::Interrupted);
+            }
+            messages = self
+                .0
+                .ready
+                .wait_timeout(messages, RECV_POLL_INTERVAL)
+                .unwrap()
+                .0;
+        }
+    }
+}
+
+type IsolateResult = Result<SendValue, String>;
+
+/// Script handle to a running isolate, returned by `spawn`.
+pub struct Isolate(RefCell<Option<JoinHandle<IsolateResult>>>);
+impl NativeType for Isolate {
+    const TYPE_NAME: &'static str = "Isolate";
+}
+impl Isolate {
+    /// Waits for the isolate to finish and returns its result, or fails with its error.
+    fn join(&self) -> Result<Value, ErrorKind> {
+        let handle =
+            self.0.borrow_mut().take().ok_or_else(|| {
+                ErrorKind::RuntimeError("Isolate has already been joined".to_owned())
+            })?;
+        match handle.join() {
+            Ok(Ok(result)) => Ok(result.into_value()),
+            Ok(Err(message)) => Err(ErrorKind::IsolateFailed(message)),
+            Err(_) => Err(ErrorKind::IsolateFailed("panicked".to_owned())),
+        }
+    }
+}
+
+pub(crate) fn channel(_: &mut Interpreter) -> Result<Value, ErrorKind> {
+    Ok(Rc::new(Channel(Arc::default())).into_value())
+}
+
+/// `spawn(fn_or_module_path, args)` runs a function or a script file on a new interpreter in its own thread.
+///
+/// A function is copied into the new interpreter together with the globals it uses, as they are at spawn time.
+/// A module is run from the top, then its `main` function is called if it defines one.
+/// Arguments are passed as `SendValue`s. The returned handle's `join` gives back the result.
+pub(crate) fn spawn(interpreter: &mut Interpreter) -> Result<Value, ErrorKind> {
+    let target = interpreter.get_local(0);
+    let args = Option::<Vec<SendValue>>::from_value(interpreter.get_local(1))?.unwrap_or_default();
+    let limits = interpreter.limits().clone();
+
+    let handle = match target {
+        Value::Function(function) => {
+            let mut isolate = Interpreter::default();
+            isolate.set_limits(limits);
+            let function = Copier::new(interpreter, &mut isolate).copy(&function)?;
+            // SAFETY: the copier only builds new values in the isolate, nothing in it is shared with this interpreter,
+            // and a fresh isolate has no hook and writes to the process's stdout and stderr
+            let detached = unsafe { Detached::new((isolate, function)) };
+            thread::spawn(move || {
+                let (mut isolate, function) = detached.into_inner();
+                let args = args.into_iter().map(SendValue::into_value);
+                let result = isolate
+                    .call_function_args(function, args)
+                    .map_err(|err| err.to_string())?;
+                SendValue::from_value(result).map_err(|err| err.to_string())
+            })
+        }
+        Value::String(path) => {
+            let path = path.as_str().to_owned();
+            thread::spawn(move || {
+                let source =
+                    fs::read_to_string(&path).map_err(|err| format!("{}: {}", path, err))?;
+                let mut vm = Vm::new();
+                vm.interpreter().set_limits(limits);
+                vm.eval(&source).map_err(|err| err.to_string())?;
+                let result = match vm.get_global("main") {
+                    Value::Nil => Value::Nil,
+                    _ => vm
+                        .call_global("main", args.into_iter().map(SendValue::into_value))
+                        .map_err(|err| err.to_string())?,
+                };
+                SendValue::from_value(result).map_err(|err| err.to_string())
+            })
+        }
+        t => return Err(ErrorKind::InvalidType(t.type_str(), "function")),
+    };
+    Ok(Rc::new(Isolate(RefCell::new(Some(handle)))).into_value())
+}
+
+/// Adds the methods of channels and isolate handles.
+pub(crate) fn register_methods(interpreter: &mut Interpreter) {
+    let send = native_function(|ch: Rc<Channel>, value: SendValue| ch.send(value));
+    interpreter.register_native_method::<Channel>("send", Value::Function(Rc::new(send)));
+    // what other isolates send is input to this one, see `Interpreter::mark_input`
+    let try_recv = Rc::new(native_function(|ch: Rc<Channel>| ch.try_recv()));
+    // needs the interpreter to notice interrupts while blocked
+    let recv = Rc::new(Interpreter::create_builtin_function(
+        1,
+        false,
+        |interpreter| {
+            let channel = Rc::<Channel>::from_value(interpreter.get_local(0))?;
+            channel.recv(interpreter)
+        },
+    ));
+    let join = Rc::new(native_function(|isolate: Rc<Isolate>| isolate.join()));
+    for function in [&try_recv, &recv, &join] {
+        interpreter.mark_input(function);
+    }
+    interpreter.register_native_method::<Channel>("try_recv", Value::Function(try_recv));
+    interpreter.register_native_method::<Channel>("recv", Value::Function(recv));
+    interpreter.register_native_method::<Isolate>("join", Value::Function(join));
+}
+
+/// Deep copies functions and values from one interpreter into another.
+///
+/// Strings are re-interned and global slots remapped through the target's context, builtins are matched by name.
+/// Globals used by copied functions are copied over as well. Sharing and cycles are preserved within the copy.
+struct Copier<'a> {
+    source: &'a Interpreter,
+    target: &'a mut Interpreter,
+    values: FxHashMap<*const (), Value>,
+    cells: FxHashMap<*const RefCell<Value>, Rc<RefCell<Value>>>,
+    signatures: FxHashMap<*const FnSignature, Rc<FnSignature>>,
+    globals: FxHashSet<usize>,
+    pending_globals: Vec<usize>,
+}
+impl<'a> Copier<'a> {
+    fn new(source: &'a Interpreter, target: &'a mut Interpreter) -> Self {
+        Self {
+            source,
+            target,
+            values: FxHashMap::default(),
+            cells: FxHashMap::default(),
+            signatures: FxHashMap::default(),
+            globals: FxHashSet::default(),
+            pending_globals: vec![],
+        }
+    }
+    /// Copies `function` and every global it depends on.
+    fn copy(mut self, function: &Rc<Function>) -> Result<Rc<Function>, ErrorKind> {
+        let copy = self.function(function)?;
+        // globals are copied last, since a function's globals can refer back to it
+        while let Some(slot) = self.pending_globals.pop() {
+            if let Some(Some((value, read_only))) = self.source.globals.get(slot) {
+                let value = self.value(value)?;
+                let target_slot = self
+                    .target
+                    .global_slot(self.source.global_name(slot).as_str());
+                self.target.define_global(target_slot, value, *read_only);
+            }
+        }
+        Ok(copy)
+    }
+    fn string(&mut self, str: &ValueStr) -> ValueStr {
+        self.target.context.borrow_mut().intern(str.as_str())
+    }
+    /// Target slot of the source global `slot`, queueing the global to be copied on first use.
+    fn global(&mut self, slot: usize) -> usize {
+        if self.globals.insert(slot) {
+            self.pending_globals.push(slot);
+        }
+        self.target
+            .global_slot(self.source.global_name(slot).as_str())
+    }
+    fn value(&mut self, value: &Value) -> Result<Value, ErrorKind> {
+        let ptr = match value {
+            Value::Array(arr) => Rc::as_ptr(arr) as *const (),
+            Value::Object(obj) => Rc::as_ptr(obj) as *const (),
+            Value::Function(function) => Rc::as_ptr(function) as *const (),
+            Value::Native(native) => return self.native(native),
+            value => return Ok(SendValue::from_value(value.clone())?.into_value()),
+        };
+        if let Some(copy) = self.values.get(&ptr) {
+            return Ok(copy.clone());
+        }
+        match value {
+            Value::Array(arr) => {
+                let copy = Rc::new(RefCell::new(Vec::new()));
+                self.values.insert(ptr, Value::Array(copy.clone()));
+                let items = arr
+                    .borrow()
+                    .iter()
+                    .map(|item| self.value(item))
+                    .collect::<Result<_, _>>()?;
+                *copy.borrow_mut() = items;
+                Ok(Value::Array(copy))
+            }
+            Value::Object(obj) => {
+                let copy = Rc::new(RefCell::new(Object::from_entries([])));
+                self.values.insert(ptr, Value::Object(copy.clone()));
+                let obj = obj.borrow();
+                for (k, v) in obj.map.iter() {
+                    let (k, v) = (self.value(k)?, self.value(v)?);
+                    copy.borrow_mut().map.insert(k, v);
+                }
+                if let Some(base) = &obj.base_obj {
+                    copy.borrow_mut().base_obj =
+                        Some(self.value(&Value::Object(base.clone()))?.try_object()?);
+                }
+                copy.borrow_mut().read_only = obj.read_only;
+                Ok(Value::Object(copy))
+            }
+            Value::Function(function) => self.function(function).map(Value::Function),
+            _ => unreachable!(),
+        }
+    }
+    fn native(&mut self, native: &NativeValue) -> Result<Value, ErrorKind> {
+        match native.downcast::<Channel>() {
+            Some(channel) => Ok(Rc::new(Channel(channel.0.clone())).into_value()),
+            None => Err(ErrorKind::Unsendable(native.type_name())),
+        }
+    }
+    fn function(&mut self, function: &Rc<Function>) -> Result<Rc<Function>, ErrorKind> {
+        let ptr = Rc::as_ptr(function) as *const ();
+        if let Some(Value::Function(copy)) = self.values.get(&ptr) {
+            return Ok(copy.clone());
+        }
+        if let FnBody::Builtin(_) = function.signature.body {
+            return self.builtin(function);
+        }
+        // cells are created empty first, as they may hold the function itself
+        let mut pending = vec![];
+        let upvalues = function
+            .upvalues
+            .iter()
+            .map(|cell| {
+                let ptr = Rc::as_ptr(cell);
+                self.cells
+                    .entry(ptr)
+                    .or_insert_with(|| {
+                        pending.push(cell.clone());
+                        Rc::default()
+                    })
+                    .clone()
+            })
+            .collect();
+        let signature = self.signature(&function.signature)?;
+        let copy = Rc::new(Function {
+            signature,
+            upvalues,
+        });
+        self.values.insert(ptr, Value::Function(copy.clone()));
+        for cell in pending {
+            let value = self.value(&cell.borrow())?;
+            *self.cells[&Rc::as_ptr(&cell)].borrow_mut() = value;
+        }
+        Ok(copy)
+    }
+    /// The target's builtin with the same name as `function`.
+    fn builtin(&mut self, function: &Rc<Function>) -> Result<Rc<Function>, ErrorKind> {
+        let source = self.source.context.borrow();
+        let name = source
+            .builtins()
+            .find(|(_, builtin)| Rc::ptr_eq(builtin, function))
+            .map(|(name, _)| name.as_str())
+            .ok_or(ErrorKind::Unsendable("native function"))?;
+        Ok(self.target.context.borrow().builtin(name).unwrap())
+    }
+    fn signature(&mut self, signature: &Rc<FnSignature>) -> Result<Rc<FnSignature>, ErrorKind> {
+        let ptr = Rc::as_ptr(signature);
+        if let Some(copy) = self.signatures.get(&ptr) {
+            return Ok(copy.clone());
+        }
+        let body = match &signature.body {
+            FnBody::Bytecode(code) => FnBody::Bytecode(
+                code.iter()
+                    .map(|SpanOf(span, bc)| Ok(SpanOf(*span, self.bytecode(bc)?)))
+                    .collect::<Result<_, ErrorKind>>()?,
+            ),
+            FnBody::Bound(receiver, method) => {
+                FnBody::Bound(self.value(receiver)?, self.function(method)?)
+            }
+            FnBody::Builtin(_) => return Err(ErrorKind::Unsendable("native function")),
+        };
+        let copy = Rc::new(FnSignature {
+            arity: signature.arity,
+            variadic: signature.variadic,
+            upvalues: signature.upvalues.clone(),
+            body,
+            debug: self.debug_info(&signature.debug),
+        });
+        self.signatures.insert(ptr, copy.clone());
+        Ok(copy)
+    }
+    fn debug_info(&mut self, debug: &DebugInfo) -> DebugInfo {
+        let name = debug.name.as_ref().map(|name| self.string(name));
+        let locals = debug.locals.iter().map(|local| LocalName {
+            name: self.string(&local.name),
+            ..local.clone()
+        });
+        DebugInfo {
+            name,
+            span: debug.span,
+            locals: locals.collect(),
+            upvalues: debug
+                .upvalues
+                .iter()
+                .map(|name| self.string(name))
+                .collect(),
+        }
+    }
+    fn bytecode(&mut self, bc: &Bytecode) -> Result<Bytecode, ErrorKind> {
+        Ok(match bc {
+            Bytecode::GlobalDeclare(slot) => Bytecode::GlobalDeclare(self.global(*slot)),
+            Bytecode::GlobalReadOnly(slot) => Bytecode::GlobalReadOnly(self.global(*slot)),
+            Bytecode::LoadGlobal(slot) => Bytecode::LoadGlobal(self.global(*slot)),
+            Bytecode::StoreGlobal(slot) => Bytecode::StoreGlobal(self.global(*slot)),
+            Bytecode::LoadProperty(key) => Bytecode::LoadProperty(self.string(key)),
+            Bytecode::StoreProperty(key) => Bytecode::StoreProperty(self.string(key)),
+            Bytecode::LoadMethod(key) => Bytecode::LoadMethod(self.string(key)),
+            Bytecode::AppendObj(key) => Bytecode::AppendObj(self.string(key)),
+            Bytecode::LoadStr(str) => Bytecode::LoadStr(self.string(str)),
+            Bytecode::LoadFn(signature) => Bytecode::LoadFn(self.signature(signature)?),
+            Bytecode::CallBuiltin(base, function) => {
+                Bytecode::CallBuiltin(*base, self.builtin(function)?)
+            }
+            bc => bc.clone(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs};
+
+    use crate::{error::ErrorKind, interpreter::value::Value, vm::Vm};
+
+    #[test]
+    fn spawn_function() {
+        let mut vm = Vm::new();
+        vm.eval(
+            r#"
+const base = {offset: 1}
+fn fib(n) do
+    if n < 2 then return n end
+    return fib(n - 1) + fib(n - 2)
+end
+fn worker(n, scale) fib(n) * scale + base.offset
+"#,
+        )
+        .unwrap();
+        let result = vm.eval("spawn(worker, [10, 2]):join()").unwrap();
+        assert_eq!(result, Value::Number(111.0));
+
+        // closures take a copy of their upvalues
+        let result = vm
+            .eval("let xs = [1, 2]\nlet f = \\-> do xs[0] = 5\nreturn xs end\nlet t = spawn(f, nil)\n[t:join(), xs]")
+            .unwrap();
+        assert_eq!(result.to_string(), "[[5, 2], [1, 2]]");
+    }
+
+    #[test]
+    fn channels() {
+        let mut vm = Vm::new();
+        vm.eval(
+            r#"
+fn producer(ch, n) do
+    for i in range(n) do
+        ch:send({i: i, sq: i * i})
+    end
+    ch:send(nil)
+    return "done"
+end
+let ch = channel()
+let workers = [spawn(producer, [ch, 5]), spawn(producer, [ch, 5])]
+let total = 0
+let finished = 0
+while finished < 2 do
+    let msg = ch:recv()
+    if msg == nil then
+        finished = finished + 1
+    else
+        total = total + msg.sq
+    end
+end
+"#,
+        )
+        .unwrap();
+        assert_eq!(vm.get_global("total"), Value::Number(60.0));
+        assert_eq!(vm.eval("workers[0]:join()").unwrap().to_string(), "done");
+        assert_eq!(vm.eval("ch:try_recv()").unwrap(), Value::Nil);
+
+        // a channel with no other handles left can't receive anything
+        assert_eq!(vm.eval("channel():recv()").unwrap(), Value::Nil);
+    }
+
+    #[test]
+    fn send_errors() {
+        let mut vm = Vm::new();
+        vm.eval("let ch = channel()").unwrap();
+        let err = vm.eval("ch:send(\\-> 1)").unwrap_err();
+        assert!(matches!(err.kind, ErrorKind::Unsendable("function")));
+        let err = vm.eval("let a = [1]\na[1] = a\nch:send(a)").unwrap_err();
+        assert!(matches!(err.kind, ErrorKind::CyclicSend));
+        let err = vm.eval("spawn(\\-> print, nil):join()").unwrap_err();
+        assert!(matches!(err.kind, ErrorKind::IsolateFailed(_)));
+
+        vm.register_fn("native", || 1);
+        let err = vm.eval("spawn(\\-> native(), nil)").unwrap_err();
+        assert!(matches!(err.kind, ErrorKind::Unsendable("native function")));
+
+        vm.eval("let t = spawn(\\-> 1 + nil, nil)").unwrap();
+        let err = vm.eval("t:join()").unwrap_err();
+        assert_eq!(
+            err.kind.to_string(),
+            "Isolate failed: Binary operator `+` cannot be applied to value of type `number` and `nil`"
+        );
+        let err = vm.eval("t:join()").unwrap_err();
+        assert!(matches!(err.kind, ErrorKind::RuntimeError(_)));
+    }
+
+    #[test]
+    fn spawn_module() {
+        let path = env::temp_dir().join(format!("rlox-isolate-{}.rlox", std::process::id()));
+        fs::write(
+            &path,
+            "let greeting = \"hello \"\nfn main(name) greeting + name\n",
+        )
+        .unwrap();
+
+        let mut vm = Vm::new();
+        vm.set_global("path", path.to_str().unwrap()).unwrap();
+        let result = vm.eval("spawn(path, [\"isolate\"]):join()").unwrap();
+        assert_eq!(result.to_string(), "hello isolate");
+        fs::remove_file(&path).unwrap();
+
+        let err = vm.eval("spawn(path, nil):join()").unwrap_err();
+        assert!(matches!(err.kind, ErrorKind::IsolateFailed(_)));
+    }
+}
@@ -0,0 +1,459 @@
+//! The `json` builtin module: `json.parse(str)` and `json.stringify(value, indent?)`.
+//!
+//! JSON objects are objects, arrays are arrays and `null` is nil. Since objects leave out properties set to nil,
+//! `json.parse("{\"a\": null}")` is `{}` and the key is lost, while nulls within arrays are kept. Stringifying
+//! writes the own properties of objects sorted by key, and nan and infinities as `null`.
+use std::{fmt::Write, rc::Rc};
+
+use rustc_hash::FxHashSet;
+
+use crate::{
+    error::ErrorKind,
+    interpreter::{
+        limits::Limits,
+        string::ValueStr,
+        value::{FromValue, IntoValue, Object, Value},
+        Interpreter,
+    },
+};
+
+/// Nesting of arrays and objects past which parsing and stringifying fail, rather than overflowing the stack.
+const MAX_DEPTH: usize = 512;
+/// Spaces per level that `stringify` indents by at most.
+const MAX_INDENT: f64 = 10.0;
+
+pub(crate) fn parse(interpreter: &mut Interpreter) -> Result<Value, ErrorKind> {
+    let source = ValueStr::from_value(interpreter.get_local(0))?;
+    let mut parser = Parser {
+        source: source.as_str(),
+        pos: 0,
+        limits: interpreter.limits(),
+    };
+    parser.skip_whitespace();
+    let value = parser.value(0)?;
+    parser.skip_whitespace();
+    if parser.pos < parser.source.len() {
+        return Err(parser.error("unexpected characters after the value"));
+    }
+    Ok(value)
+}
+/// The value as JSON, indented by the optional number of spaces per level or on a single line without one. The
+/// indent is rounded down and clamped between 0 and 10, the most JavaScript indents by.
+pub(crate) fn stringify(interpreter: &mut Interpreter) -> Result<Value, ErrorKind> {
+    let value = interpreter.get_local(0);
+    let indent = match interpreter.get_local(1).try_array()?.borrow().first() {
+        Some(indent) => Some(indent.clone().try_num()?.clamp(0.0, MAX_INDENT) as usize),
+        None => None,
+    };
+    let mut out = String::new();
+    write_value(&value, indent, 0, &mut FxHashSet::default(), &mut out)?;
+    Ok(out.into_value())
+}
+
+struct Parser<'a> {
+    source: &'a str,
+    pos: usize, // in bytes
+    limits: &'a Limits,
+}
+impl Parser<'_> {
+    /// Error at the current position, with 1-based line and column in characters.
+    fn error(&self, message: &'static str) -> ErrorKind {
+        let before = &self.source[..self.pos];
+        let line = before.matches('\n').count() + 1;
+        let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+        let column = before[line_start..].chars().count() + 1;
+        ErrorKind::InvalidJson(line, column, message)
+    }
+    fn peek(&self) -> Option<u8> {
+        self.source.as_bytes().get(self.pos).copied()
+    }
+    fn skip_whitespace(&mut self) {
+        while let Some(b' ' | b'\t' | b'\n' | b'\r') = self.peek() {
+            self.pos += 1;
+        }
+    }
+    fn expect(&mut self, byte: u8, message: &'static str) -> Result<(), ErrorKind> {
+        self.skip_whitespace();
+        match self.peek() {
+            Some(next) if next == byte => {
+                self.pos += 1;
+                Ok(())
+            }
+            _ => Err(self.error(message)),
+        }
+    }
+    fn value(&mut self, depth: usize) -> Result<Value, ErrorKind> {
+        if depth >= MAX_DEPTH {
+            return Err(self.error("arrays and objects are nested too deeply"));
+        }
+        match self.peek() {
+            Some(b'{') => self.object(depth),
+            Some(b'[') => self.array(depth),
+            Some(b'"') => Ok(self.string()?.into_value()),
+            Some(b'-' | b'0'..=b'9') => self.number(),
+            Some(b't') => self.keyword("true", Value::Bool(true)),
+            Some(b'f') => self.keyword("false", Value::Bool(false)),
+            Some(b'n') => self.keyword("null", Value::Nil),
+            Some(_) => Err(self.error("expected a value")),
+            None => Err(self.error("unexpected end of input")),
+        }
+    }
+    fn keyword(&mut self, keyword: &str, value: Value) -> Result<Value, ErrorKind> {
+        if !self.source[self.pos..].starts_with(keyword) {
+            return Err(self.error("expected a value"));
+        }
+        self.pos += keyword.len();
+        Ok(value)
+    }
+    fn array(&mut self, depth: usize) -> Result<Value, ErrorKind> {
+        self.pos += 1;
+        let mut elements = vec![];
+        self.skip_whitespace();
+        if self.peek() == Some(b']') {
+            self.pos += 1;
+            return Ok(elements.into_value());
+        }
+        loop {
+            self.skip_whitespace();
+            elements.push(self.value(depth + 1)?);
+            self.limits.check_collection_len(elements.len())?;
+            self.skip_whitespace();
+            match self.peek() {
+                Some(b',') => self.pos += 1,
+                Some(b']') => break,
+                _ => return Err(self.error("expected `,` or `]`")),
+            }
+        }
+        self.pos += 1;
+        Ok(elements.into_value())
+    }
+    fn object(&mut self, depth: usize) -> Result<Value, ErrorKind> {
+        self.pos += 1;
+        let mut object = Object::with_capacity(0);
+        self.skip_whitespace();
+        if self.peek() == Some(b'}') {
+            self.pos += 1;
+            return Ok(object.into_value());
+        }
+        loop {
+            self.skip_whitespace();
+            if self.peek() != Some(b'"') {
+                return Err(self.error("expected a string key"));
+            }
+            let key = self.string()?.into_value();
+            self.expect(b':', "expected `:`")?;
+            self.skip_whitespace();
+            let value = self.value(depth + 1)?;
+            // a null value leaves the key out, like any property set to nil
+            object.set_property(key, value)?;
+            self.limits.check_collection_len(object.map.len())?;
+            self.skip_whitespace();
+            match self.peek() {
+                Some(b',') => self.pos += 1,
+                Some(b'}') => break,
+                _ => return Err(self.error("expected `,` or `}`")),
+            }
+        }
+        self.pos += 1;
+        Ok(object.into_value())
+    }
+    fn string(&mut self) -> Result<String, ErrorKind> {
+        self.pos += 1;
+        let mut string = String::new();
+        loop {
+            let rest = &self.source[self.pos..];
+            let Some(c) = rest.chars().next() else {
+                return Err(self.error("unterminated string"));
+            };
+            match c {
+                '"' => {
+                    self.pos += 1;
+                    return Ok(string);
+                }
+                '\\' => {
+                    self.pos += 1;
+                    string.push(self.escape()?);
+                }
+                '\0'..='\x1f' => return Err(self.error("control character in string")),
+                c => {
+                    self.pos += c.len_utf8();
+                    string.push(c);
+                }
+            }
+        }
+    }
+    /// Character of the escape sequence after a backslash.
+    fn escape(&mut self) -> Result<char, ErrorKind> {
+        let c = match self.peek() {
+            Some(b'"') => '"',
+            Some(b'\\') => '\\',
+            Some(b'/') => '/',
+            Some(b'b') => '\u{8}',
+            Some(b'f') => '\u{c}',
+            Some(b'n') => '\n',
+            Some(b'r') => '\r',
+            Some(b't') => '\t',
+            Some(b'u') => {
+                self.pos += 1;
+                let high = self.hex4()?;
+                let code = match high {
+                    // surrogate pairs encode characters outside the basic multilingual plane
+                    0xD800..=0xDBFF if self.source[self.pos..].starts_with("\\u") => {
+                        self.pos += 2;
+                        let low = self.hex4()?;
+                        if !(0xDC00..=0xDFFF).contains(&low) {
+                            return Err(self.error("invalid unicode escape"));
+                        }
+                        0x10000 + ((high - 0xD800) << 10) + (low - 0xDC00)
+                    }
+                    code => code,
+                };
+                return char::from_u32(code).ok_or_else(|| self.error("invalid unicode escape"));
+            }
+            _ => return Err(self.error("invalid escape sequence")),
+        };
+        self.pos += 1;
+        Ok(c)
+    }
+    fn hex4(&mut self) -> Result<u32, ErrorKind> {
+        let digits = self.source.get(self.pos..self.pos + 4);
+        let code = digits
+            .filter(|digits| digits.bytes().all(|b| b.is_ascii_hexdigit()))
+            .and_then(|digits| u32::from_str_radix(digits, 16).ok())
+            .ok_or_else(|| self.error("invalid unicode escape"))?;
+        self.pos += 4;
+        Ok(code)
+    }
+    fn number(&mut self) -> Result<Value, ErrorKind> {
+        let start = self.pos;
+        let digits = |parser: &mut Self| {
+            let from = parser.pos;
+            while let Some(b'0'..=b'9') = parser.peek() {
+                parser.pos += 1;
+            }
+            parser.pos - from
+        };
+        if self.peek() == Some(b'-') {
+            self.pos += 1;
+        }
+        match self.peek() {
+            // no leading zeros
+            Some(b'0') => self.pos += 1,
+            Some(b'1'..=b'9') => _ = digits(self),
+            _ => return Err(self.error("invalid number")),
+        }
+        if self.peek() == Some(b'.') {
+            self.pos += 1;
+            if digits(self) == 0 {
+                return Err(self.error("invalid number"));
+            }
+        }
+        if let Some(b'e' | b'E') = self.peek() {
+            self.pos += 1;
+            if let Some(b'+' | b'-') = self.peek() {
+                self.pos += 1;
+            }
+            if digits(self) == 0 {
+                return Err(self.error("invalid number"));
+            }
+        }
+        let number = self.source[start..self.pos].parse::<f64>().unwrap();
+        Ok(Value::Number(number))
+    }
+}
+
+/// Writes `value` as JSON. `path` holds the arrays and objects being written, to catch cycles.
+fn write_value(
+    value: &Value,
+    indent: Option<usize>,
+    depth: usize,
+    path: &mut FxHashSet<*const ()>,
+    out: &mut String,
+) -> Result<(), ErrorKind> {
+    let ptr = match value {
+        Value::Array(arr) => Rc::as_ptr(arr) as *const (),
+        Value::Object(obj) => Rc::as_ptr(obj) as *const (),
+        _ => std::ptr::null(),
+    };
+    if !ptr.is_null() {
+        if !path.insert(ptr) {
+            return Err(ErrorKind::CyclicJson);
+        }
+        if depth >= MAX_DEPTH {
+            return Err(ErrorKind::JsonTooDeep(MAX_DEPTH));
+        }
+    }
+    let newline = |out: &mut String, depth: usize| {
+        if let Some(indent) = indent {
+            out.push('\n');
+            out.extend(std::iter::repeat_n(' ', indent * depth));
+        }
+    };
+    match value {
+        Value::Nil => out.push_str("null"),
+        Value::Bool(bool) => write!(out, "{bool}").unwrap(),
+        Value::Number(num) if num.is_finite() => write!(out, "{num}").unwrap(),
+        Value::Number(_) => out.push_str("null"),
+        Value::String(str) => write_string(str.as_str(), out),
+        Value::Array(arr) => {
+            let arr = arr.borrow();
+            out.push('[');
+            for (i, element) in arr.iter().enumerate() {
+                if i != 0 {
+                    out.push(',');
+                }
+                newline(out, depth + 1);
+                write_value(element, indent, depth + 1, path, out)?;
+            }
+            if !arr.is_empty() {
+                newline(out, depth);
+            }
+            out.push(']');
+        }
+        Value::Object(obj) => {
+            let obj = obj.borrow();
+            let mut entries = obj
+                .map
+                .iter()
+                .map(|(key, value)| match key {
+                    Value::String(key) => Ok((key.as_str(), value)),
+                    key => Err(ErrorKind::InvalidJsonKey(key.type_str())),
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            entries.sort_by_key(|(key, _)| *key);
+            out.push('{');
+            for (i, (key, value)) in entries.iter().enumerate() {
+                if i != 0 {
+                    out.push(',');
+                }
+                newline(out, depth + 1);
+                write_string(key, out);
+                out.push_str(if indent.is_some() { ": " } else { ":" });
+                write_value(value, indent, depth + 1, path, out)?;
+            }
+            if !entries.is_empty() {
+                newline(out, depth);
+            }
+            out.push('}');
+        }
+        Value::Function(_) | Value::Native(_) => {
+            return Err(ErrorKind::Unserializable(value.type_str()))
+        }
+    }
+    if !ptr.is_null() {
+        path.remove(&ptr);
+    }
+    Ok(())
+}
+fn write_string(str: &str, out: &mut String) {
+    out.push('"');
+    for c in str.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            '\0'..='\x1f' => write!(out, "\\u{:04x}", c as u32).unwrap(),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{error::ErrorKind, vm::Vm};
+
+    #[test]
+    fn test_json() {
+        let mut vm = Vm::new();
+        let result = vm
+            .eval(
+                r#"
+let data = json.parse("{\"name\": \"rlox\", \"tags\": [1, -2.5e1, true, null, \"\\u00e9\\ud83d\\ude00\\n\"],
+    \"nested\": {\"empty\": [], \"none\": null}}")
+[data.name, data.tags, len(data.nested), json.stringify(data), json.stringify(json.parse(json.stringify(data)))]
+"#,
+            )
+            .unwrap();
+        assert_eq!(
+            result.to_string(),
+            "[rlox, [1, -25, true, nil, é😀\n], 1, \
+            {\"name\":\"rlox\",\"nested\":{\"empty\":[]},\"tags\":[1,-25,true,null,\"é😀\\n\"]}, \
+            {\"name\":\"rlox\",\"nested\":{\"empty\":[]},\"tags\":[1,-25,true,null,\"é😀\\n\"]}]"
+        );
+        let result = vm
+            .eval(r#"json.stringify({a: [1, {}], b: "x"}, 2)"#)
+            .unwrap();
+        assert_eq!(
+            result.to_string(),
+            "{\n  \"a\": [\n    1,\n    {}\n  ],\n  \"b\": \"x\"\n}"
+        );
+        let shared = vm
+            .eval(r#"let s = [1]; json.stringify([s, s, 0 / 0])"#)
+            .unwrap();
+        assert_eq!(shared.to_string(), "[[1],[1],null]");
+        for (indent, spaces) in [
+            ("1e12", 10),
+            ("1 / 0", 10),
+            ("2.7", 2),
+            ("-3", 0),
+            ("0 / 0", 0),
+        ] {
+            let result = vm
+                .eval(&format!("json.stringify([[1]], {indent})"))
+                .unwrap();
+            let pad = " ".repeat(spaces);
+            let expected = format!("[\n{pad}[\n{pad}{pad}1\n{pad}]\n]");
+            assert_eq!(result.to_string(), expected, "indent {indent}");
+        }
+    }
+
+    #[test]
+    fn test_json_errors() {
+        let mut vm = Vm::new();
+        let cases = [
+            ("{\"a\": 1,}", 1, 9, "expected a string key"),
+            ("[1,\n  2 3]", 2, 5, "expected `,` or `]`"),
+            ("[01]", 1, 3, "expected `,` or `]`"),
+            ("{\"é\" 1}", 1, 6, "expected `:`"),
+            ("\"abc", 1, 5, "unterminated string"),
+            ("\"\\x\"", 1, 3, "invalid escape sequence"),
+            ("1.", 1, 3, "invalid number"),
+            ("nul", 1, 1, "expected a value"),
+            ("", 1, 1, "unexpected end of input"),
+            ("[] []", 1, 4, "unexpected characters after the value"),
+        ];
+        for (source, line, column, message) in cases {
+            vm.set_global("source", source).unwrap();
+            let err = vm.eval("json.parse(source)").unwrap_err();
+            match err.kind {
+                ErrorKind::InvalidJson(l, c, m) => assert_eq!((l, c, m), (line, column, message)),
+                kind => panic!("unexpected error for {source:?}: {kind}"),
+            }
+        }
+        let deep = "[".repeat(1000);
+        vm.set_global("source", deep.as_str()).unwrap();
+        let err = vm.eval("json.parse(source)").unwrap_err();
+        assert!(matches!(err.kind, ErrorKind::InvalidJson(1, 513, _)));
+
+        let err = vm
+            .eval("let cyclic = [1]\ncyclic[1] = cyclic\njson.stringify(cyclic)")
+            .unwrap_err();
+        assert!(matches!(err.kind, ErrorKind::CyclicJson));
+        let err = vm
+            .eval("let deep = []\nfor _ in range(0, 5000, 1) do deep = [deep] end\njson.stringify(deep)")
+            .unwrap_err();
+        assert!(matches!(err.kind, ErrorKind::JsonTooDeep(512)));
+        let err = vm.eval("json.stringify({[1]: 2})").unwrap_err();
+        assert!(matches!(err.kind, ErrorKind::InvalidJsonKey("number")));
+        let err = vm.eval("json.stringify([print])").unwrap_err();
+        assert!(matches!(err.kind, ErrorKind::Unserializable("function")));
+        let result = vm.eval("assert_error(\\ -> json.parse(\"{\"))").unwrap();
+        assert_eq!(
+            result.to_string(),
+            "Invalid JSON at line 1, column 2: expected a string key"
+        );
+    }
+}
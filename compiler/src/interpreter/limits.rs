@@ -0,0 +1,227 @@
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+use crate::error::ErrorKind;
+use crate::interpreter::value::Value;
+
+pub const DEFAULT_MAX_CALL_DEPTH: usize = 0x10000;
+
+/// Resource limits of an `Interpreter`. Everything except the call depth is unlimited by default.
+///
+/// Exceeding a limit aborts the running call with its own `ErrorKind`,
+/// after which the interpreter can be used again.
+#[derive(Debug, Clone)]
+pub struct Limits {
+    pub max_instructions: u64, // instructions per top-level call, ErrorKind::InstructionLimit
+    pub max_memory: usize,     // cells of local variable memory, ErrorKind::MemoryLimit
+    pub max_stack: usize,      // cells of the value stack, ErrorKind::StackLimit
+    pub max_collection_len: usize, // elements of a single array or object, ErrorKind::CollectionLimit
+    pub max_call_depth: usize,     // active function frames, ErrorKind::StackOverflow
+}
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_instructions: u64::MAX,
+            max_memory: usize::MAX,
+            max_stack: usize::MAX,
+            max_collection_len: usize::MAX,
+            max_call_depth: DEFAULT_MAX_CALL_DEPTH,
+        }
+    }
+}
+impl Limits {
+    /// Fails if an array or object would hold more than `max_collection_len` elements.
+    pub fn check_collection(&self, value: &Value) -> Result<(), ErrorKind> {
+        let len = match value {
+            Value::Array(array) => array.borrow().len(),
+            Value::Object(object) => object.borrow().map.len(),
+            _ => return Ok(()),
+        };
+        self.check_collection_len(len)
+    }
+    pub fn check_collection_len(&self, len: usize) -> Result<(), ErrorKind> {
+        if len > self.max_collection_len {
+            return Err(ErrorKind::CollectionLimit(self.max_collection_len));
+        }
+        Ok(())
+    }
+}
+
+/// Aborts execution of an `Interpreter` from another thread.
+///
+/// The running call fails with `ErrorKind::Interrupted` shortly after `interrupt` is called.
+/// The request is consumed by that error, so later calls run normally.
+#[derive(Debug, Clone, Default)]
+pub struct InterruptHandle(Arc<AtomicBool>);
+impl InterruptHandle {
+    pub fn interrupt(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+    pub(crate) fn take(&self) -> bool {
+        self.0.swap(false, Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{rc::Rc, thread, time::Duration};
+
+    use crate::{
+        ast::Parser,
+        codegen::Codegen,
+        error::ErrorKind,
+        interpreter::{limits::Limits, value::Value, Interpreter},
+    };
+
+    fn run(interpreter: &mut Interpreter, source: &str) -> Result<Value, ErrorKind> {
+        let mut parser = Parser::new(source.as_bytes());
+        let mut codegen = Codegen::with_context(parser.sources(), interpreter.context());
+        while let Some(stmt) = parser.next_statement().unwrap() {
+            codegen.gen_statement(&stmt).unwrap();
+        }
+        let init_fn = Rc::new(interpreter.create_function(Rc::new(codegen.pop_init_sig())));
+        interpreter.call_function_args(init_fn, [])
+    }
+
+    #[test]
+    fn instruction_limit() {
+        let mut interpreter = Interpreter::default();
+        interpreter.set_limits(Limits {
+            max_instructions: 10000,
+            ..Default::default()
+        });
+
+        let result = run(&mut interpreter, "while true do end");
+        assert!(matches!(result, Err(ErrorKind::InstructionLimit(10000))));
+        assert_eq!(interpreter.instruction_count(), 10000);
+
+        // every top-level call gets a fresh budget
+        assert!(run(&mut interpreter, "let a = 1 + 2").is_ok());
+    }
+
+    #[test]
+    fn interrupt() {
+        let mut interpreter = Interpreter::default();
+        let handle = interpreter.interrupt_handle();
+        let interrupter = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            handle.interrupt();
+        });
+
+        let result = run(&mut interpreter, "while true do end");
+        assert!(matches!(result, Err(ErrorKind::Interrupted)));
+        interrupter.join().unwrap();
+
+        // the interrupt is consumed
+        assert!(run(&mut interpreter, "let a = 1 + 2").is_ok());
+    }
+
+    #[test]
+    fn cell_limits() {
+        let mut interpreter = Interpreter::default();
+        interpreter.set_limits(Limits {
+            max_memory: 100,
+            max_stack: 1000,
+            ..Default::default()
+        });
+
+        let result = run(
+            &mut interpreter,
+            r#"
+fn deep(n) do
+    if n == 0 then return 0 end
+    return 1 + deep(n - 1)
+end
+deep(200)
+            "#,
+        );
+        assert!(matches!(result, Err(ErrorKind::MemoryLimit(100))));
+
+        interpreter.set_limits(Limits {
+            max_stack: 100,
+            ..Default::default()
+        });
+        let result = run(&mut interpreter, "fn f(*args) args\nf(*range(0, 200))");
+        assert!(matches!(result, Err(ErrorKind::StackLimit(100))));
+
+        assert!(run(&mut interpreter, "deep(50)").is_ok());
+    }
+
+    #[test]
+    fn collection_limit() {
+        let mut interpreter = Interpreter::default();
+        interpreter.set_limits(Limits {
+            max_collection_len: 3,
+            ..Default::default()
+        });
+
+        assert!(run(&mut interpreter, "let a = [1, 2, 3]").is_ok());
+        let result = run(&mut interpreter, "a[1e12] = 1");
+        assert!(matches!(result, Err(ErrorKind::CollectionLimit(3))));
+        let result = run(&mut interpreter, "let b = [1, 2, 3, 4]");
+        assert!(matches!(result, Err(ErrorKind::CollectionLimit(3))));
+        let result = run(&mut interpreter, "let c = {a: 1, b: 2}\nc.d = 3\nc.e = 4");
+        assert!(matches!(result, Err(ErrorKind::CollectionLimit(3))));
+        let result = run(&mut interpreter, "let d = [*range(0, 1e12)]");
+        assert!(matches!(result, Err(ErrorKind::CollectionLimit(3))));
+    }
+
+    #[test]
+    fn assert_error_passes_limits() {
+        let deep =
+            "fn deep(n) do\n    if n == 0 then return 0 end\n    return 1 + deep(n - 1)\nend\nfn f(*args) args\n";
+        let cases = [
+            (
+                "while true do end",
+                Limits {
+                    max_instructions: 10000,
+                    ..Default::default()
+                },
+                ErrorKind::InstructionLimit(10000),
+            ),
+            (
+                "deep(200)",
+                Limits {
+                    max_memory: 100,
+                    ..Default::default()
+                },
+                ErrorKind::MemoryLimit(100),
+            ),
+            (
+                "f(*range(0, 200))",
+                Limits {
+                    max_stack: 100,
+                    ..Default::default()
+                },
+                ErrorKind::StackLimit(100),
+            ),
+            (
+                "[1, 2, 3, 4]",
+                Limits {
+                    max_collection_len: 3,
+                    ..Default::default()
+                },
+                ErrorKind::CollectionLimit(3),
+            ),
+            (
+                "deep(200)",
+                Limits {
+                    max_call_depth: 50,
+                    ..Default::default()
+                },
+                ErrorKind::StackOverflow,
+            ),
+        ];
+        for (source, limits, expected) in cases {
+            let mut interpreter = Interpreter::default();
+            interpreter.set_limits(limits);
+            let source = format!("{deep}assert_error(\\ -> do {source} end)");
+            let result = run(&mut interpreter, &source).unwrap_err();
+            assert_eq!(result.to_string(), expected.to_string(), "{source}");
+            // other errors are caught
+            assert!(run(&mut interpreter, "assert_error(\\ -> 1 + nil)").is_ok());
+        }
+    }
+}
@@ -1,18 +1,45 @@
 use core::fmt;
+use std::any::TypeId;
 use std::cell::RefCell;
-use std::mem::{self, replace};
-use std::rc::Rc;
-use std::sync::atomic::Ordering;
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+use std::mem::replace;
+use std::rc::{Rc, Weak};
+
+use rustc_hash::FxHashMap;
 
 use crate::error::ErrorKind;
+use crate::interpreter::bytecode::{Bytecode, Control};
+use crate::interpreter::context::Context;
+use crate::interpreter::limits::{InterruptHandle, Limits};
+use crate::interpreter::native::{NativeType, NativeValue};
+use crate::interpreter::output::OutputBuffer;
 use crate::interpreter::string::ValueStr;
-use crate::interpreter::{bytecode::Bytecode, value::Function, value::Value};
-use crate::span::SpanOf;
-use crate::DEBUG_MODE;
-use rustc_hash::FxHashMap;
+use crate::interpreter::{
+    coverage::Coverage, debug::DebugInfo, hook::Hook, profile::Profile, replay::InputLog,
+};
+use crate::interpreter::{
+    value::Function,
+    value::{IntoValue, Object, Value},
+};
+use crate::span::{Span, SpanOf};
 
 pub mod builtin;
 pub mod bytecode;
+pub mod context;
+pub mod coverage;
+pub mod debug;
+pub mod fs;
+pub mod global;
+pub mod hook;
+pub mod isolate;
+pub mod json;
+pub mod limits;
+pub mod native;
+pub mod output;
+pub mod profile;
+pub mod replay;
+pub mod snapshot;
 pub mod string;
 pub mod value;
 
@@ -20,9 +47,11 @@ struct FunctionFrame {
     base_pointer: usize,
     base_stack: usize,
     function: Rc<Function>,
+    index: usize, // instruction to resume from once the frame above it returns
+    pc: usize,    // instruction being executed, only tracked while hooked, see `call_hooks`
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub enum UpvalueLoc {
     Local(usize),  // Get upvalue from parent frame's local memory
     Shared(usize), // Get upvalue from parent frame's upvalue storage
@@ -34,16 +63,14 @@ pub struct FnSignature {
     pub variadic: bool, // if true, function has variadic parameter.
     pub upvalues: Vec<UpvalueLoc>,
     pub body: FnBody,
+    pub debug: DebugInfo,
 }
-impl FnSignature {
-    fn required_arity(&self) -> usize {
-        self.arity + if self.variadic { 1 } else { 0 }
-    }
-}
+pub type BuiltinFn = dyn FnMut(&mut Interpreter) -> Result<Value, ErrorKind>;
 
 pub enum FnBody {
     Bytecode(Vec<SpanOf<Bytecode>>),
-    Builtin(Box<RefCell<dyn FnMut(&mut Interpreter) -> Result<Value, ErrorKind>>>),
+    Builtin(Box<RefCell<BuiltinFn>>),
+    Bound(Value, Rc<Function>), // method with its receiver, which gets passed as the first argument
 }
 impl fmt::Debug for FnBody {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -56,6 +83,9 @@ impl fmt::Debug for FnBody {
                 write!(f, "]")
             }
             Self::Builtin(..) => f.debug_tuple("Builtin").field(&"..").finish(),
+            Self::Bound(itself, method) => {
+                f.debug_tuple("Bound").field(itself).field(method).finish()
+            }
         }
     }
 }
@@ -72,35 +102,100 @@ impl Default for Cell {
 }
 
 const INIT_MEM_SIZE: usize = 0x10000;
+// How many instructions run between checks of the interrupt flag
+const CHECK_INTERVAL: u64 = 0x400;
+// Builtins that call back into scripts nest a dispatch loop on the native stack, which needs a much tighter bound.
+const MAX_NATIVE_DEPTH: usize = 128;
 
 pub struct Interpreter {
     memory: Vec<Cell>,
     stack: Vec<Value>,
-    current_frame: Option<FunctionFrame>,
-    globals: FxHashMap<ValueStr, (Value, bool)>, // true - read-only
+    frames: Vec<FunctionFrame>,
+    native_depth: usize,
+    limits: Limits,
+    interrupt: InterruptHandle,
+    // instructions executed by the current top-level call
+    instructions: u64,
+    // instruction count of the next interrupt and instruction limit check
+    next_check: u64,
+    // innermost failed instruction of the current top-level call
+    error_span: Option<Span>,
+    globals: Vec<Option<(Value, bool)>>, // indexed by slot, None - undeclared, true - read-only
+    context: Rc<RefCell<Context>>,
+    native_methods: FxHashMap<TypeId, FxHashMap<ValueStr, Value>>,
+    stdout: Box<dyn Write>, // `print` and `println`
+    stderr: Box<dyn Write>, // debug trace
+    hook: Option<Box<dyn Hook>>,
+    profile: Option<Box<Profile>>,
+    coverage: Option<Box<Coverage>>,
+    hooked: bool,                   // any of the three is set, see `call_hooks`
+    inputs: Vec<Weak<FnSignature>>, // builtins marked with `mark_input`
+    input_log: Option<Box<InputLog>>,
 }
 impl Default for Interpreter {
     fn default() -> Self {
-        let globals = builtin::GLOBALS.with(|globals| {
-            globals
-                .iter()
-                .map(|(name, function)| (name.clone(), (Value::Function(function.clone()), true)))
-                .collect()
-        });
-        Self {
+        let mut context = Context::default();
+        let builtins = context
+            .builtins()
+            .map(|(name, function)| (name.clone(), function.clone()))
+            .collect::<Vec<_>>();
+        let mut globals = vec![];
+        let mut define = |context: &mut Context, name: ValueStr, value: Value| {
+            let slot = context.globals.slot(name);
+            if globals.len() <= slot {
+                globals.resize_with(slot + 1, || None);
+            }
+            globals[slot] = Some((value, true));
+        };
+        // functions of builtin modules, such as `fs.read`, are properties of the module's global, which is read-only
+        // along with its properties
+        let mut modules = BTreeMap::<String, Vec<(Value, Value)>>::new();
+        for (name, function) in builtins {
+            match name.as_str().split_once('.') {
+                Some((module, key)) => {
+                    let key = Value::String(context.intern(key));
+                    let functions = modules.entry(module.to_owned()).or_default();
+                    functions.push((key, Value::Function(function)));
+                }
+                None => define(&mut context, name, Value::Function(function)),
+            }
+        }
+        for (module, functions) in modules {
+            let name = context.intern(&module);
+            let mut module = Object::from_entries(functions);
+            module.read_only = true;
+            define(&mut context, name, module.into_value());
+        }
+        let mut interpreter = Self {
             memory: Vec::with_capacity(INIT_MEM_SIZE),
             stack: Vec::new(),
-            current_frame: None,
+            frames: Vec::new(),
+            native_depth: 0,
+            limits: Limits::default(),
+            interrupt: InterruptHandle::default(),
+            instructions: 0,
+            next_check: 0,
+            error_span: None,
             globals,
-        }
+            context: Rc::new(RefCell::new(context)),
+            native_methods: FxHashMap::default(),
+            stdout: Box::new(io::stdout()),
+            stderr: Box::new(io::stderr()),
+            hook: None,
+            profile: None,
+            coverage: None,
+            hooked: false,
+            inputs: vec![],
+            input_log: None,
+        };
+        isolate::register_methods(&mut interpreter);
+        fs::mark_inputs(&mut interpreter);
+        interpreter
     }
 }
 impl Interpreter {
     fn base_stack(&self) -> usize {
-        self.current_frame
-            .as_ref()
-            .map(|f| f.base_stack)
-            .unwrap_or(0)
+        self.frames.last().map(|f| f.base_stack).unwrap_or(0)
     }
     fn push_stack(&mut self, value: Value) {
         self.stack.push(value);
@@ -111,8 +206,8 @@ impl Interpreter {
         }
         self.stack.pop().unwrap_or_default()
     }
-    fn get_local(&self, id: usize) -> Value {
-        let absolute_id = self.base_pointer() + id as usize;
+    pub(crate) fn get_local(&self, id: usize) -> Value {
+        let absolute_id = self.base_pointer() + id;
         match self.memory.get(absolute_id) {
             Some(Cell::Value(v)) => v.clone(),
             Some(Cell::Upvalue(up)) => up.borrow().clone(),
@@ -120,7 +215,7 @@ impl Interpreter {
         }
     }
     fn set_local(&mut self, id: usize, new_value: Value) {
-        let index = self.base_pointer() + id as usize;
+        let index = self.base_pointer() + id;
         if index >= self.memory.len() {
             self.memory.resize_with(index + 1, Cell::default);
         }
@@ -130,7 +225,7 @@ impl Interpreter {
         }
     }
     fn make_local_upvalue(&mut self, id: usize) -> Rc<RefCell<Value>> {
-        let index = self.base_pointer() + id as usize;
+        let index = self.base_pointer() + id;
         if self.memory.len() <= index {
             self.memory.resize_with(index + 1, Cell::default);
         }
@@ -145,66 +240,186 @@ impl Interpreter {
         }
     }
     fn get_upvalue(&self, id: usize) -> Value {
-        let fun = self.current_frame.as_ref().unwrap().function.as_ref();
+        let fun = self.frames.last().unwrap().function.as_ref();
         fun.upvalues[id].borrow().clone()
     }
     fn set_upvalue(&self, id: usize, new_value: Value) {
-        let fun = self.current_frame.as_ref().unwrap().function.as_ref();
+        let fun = self.frames.last().unwrap().function.as_ref();
         *fun.upvalues[id].borrow_mut() = new_value;
     }
-    fn make_global_read_only(&mut self, name: ValueStr) {
-        self.globals.get_mut(&name).map(|global| global.1 = true);
+    /// Sink for script output of `print` and `println`. Defaults to the process stdout.
+    pub fn set_stdout(&mut self, stdout: impl Write + 'static) {
+        self.stdout = Box::new(stdout);
+    }
+    /// Sink for diagnostics such as the instruction trace of `hook::Trace`. Defaults to the process stderr.
+    pub fn set_stderr(&mut self, stderr: impl Write + 'static) {
+        self.stderr = Box::new(stderr);
+    }
+    pub fn stdout(&mut self) -> &mut dyn Write {
+        &mut self.stdout
+    }
+    pub fn stderr(&mut self) -> &mut dyn Write {
+        &mut self.stderr
+    }
+    /// Redirects script output into a buffer, which is returned for reading it.
+    pub fn capture_stdout(&mut self) -> OutputBuffer {
+        let buffer = OutputBuffer::default();
+        self.set_stdout(buffer.clone());
+        buffer
+    }
+    /// Redirects diagnostics into a buffer, which is returned for reading it.
+    pub fn capture_stderr(&mut self) -> OutputBuffer {
+        let buffer = OutputBuffer::default();
+        self.set_stderr(buffer.clone());
+        buffer
+    }
+    pub fn limits(&self) -> &Limits {
+        &self.limits
+    }
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
     }
-    fn get_global(&self, name: ValueStr) -> Value {
-        self.globals.get(&name).cloned().unwrap_or_default().0
+    /// Handle which lets other threads abort the running call with `ErrorKind::Interrupted`.
+    pub fn interrupt_handle(&self) -> InterruptHandle {
+        self.interrupt.clone()
+    }
+    /// Span of the instruction where the last top-level call failed, if it failed inside bytecode.
+    pub fn error_span(&self) -> Option<Span> {
+        self.error_span
+    }
+    /// Instructions executed by the current or last top-level call.
+    pub fn instruction_count(&self) -> u64 {
+        self.instructions
+    }
+    /// Slow path of the per-instruction budget, taken once every `CHECK_INTERVAL` instructions.
+    fn check_budget(&mut self) -> Result<(), ErrorKind> {
+        if self.interrupt.take() {
+            return Err(ErrorKind::Interrupted);
+        }
+        if self.instructions >= self.limits.max_instructions {
+            return Err(ErrorKind::InstructionLimit(self.limits.max_instructions));
+        }
+        self.next_check = self
+            .limits
+            .max_instructions
+            .min(self.instructions.saturating_add(CHECK_INTERVAL));
+        Ok(())
+    }
+    /// Adds `method` to the method table of the native type `T`, replacing any method with the same name.
+    pub fn register_native_method<T: NativeType>(&mut self, name: &str, method: Value) {
+        self.native_methods
+            .entry(TypeId::of::<T>())
+            .or_default()
+            .insert(ValueStr::from(name), method);
+    }
+    fn native_method(&self, native: &NativeValue, key: &Value) -> Value {
+        let Value::String(name) = key else {
+            return Value::Nil;
+        };
+        self.native_methods
+            .get(&native.type_id())
+            .and_then(|methods| methods.get(name))
+            .cloned()
+            .unwrap_or_default()
+    }
+    /// Reads a property, looking up native values in their method table.
+    fn get_property(&self, object: &Value, key: &Value) -> Result<Value, ErrorKind> {
+        match object {
+            Value::Native(native) => Ok(self.native_method(native, key)),
+            object => object.get_property(key),
+        }
+    }
+    /// Sets a property while keeping arrays and objects within `max_collection_len`.
+    fn set_property(&self, object: &Value, key: Value, value: Value) -> Result<(), ErrorKind> {
+        if let (Value::Array(_), Value::Number(index)) = (object, &key) {
+            // checked up front, since a far out of bounds index resizes the array
+            let len = (*index as usize).saturating_add(1);
+            self.limits.check_collection_len(len)?;
+        }
+        object.set_property(key, value)?;
+        self.limits.check_collection(object)
+    }
+    fn check_cells(&self) -> Result<(), ErrorKind> {
+        if self.memory.len() > self.limits.max_memory {
+            return Err(ErrorKind::MemoryLimit(self.limits.max_memory));
+        }
+        if self.stack.len() > self.limits.max_stack {
+            return Err(ErrorKind::StackLimit(self.limits.max_stack));
+        }
+        Ok(())
+    }
+    pub fn call_depth(&self) -> usize {
+        self.frames.len()
+    }
+    /// Shared runtime context. Codegen that targets this interpreter must resolve globals and intern strings through it.
+    pub fn context(&self) -> Rc<RefCell<Context>> {
+        self.context.clone()
+    }
+    /// Resolves `name` to its global slot, allocating one if the name hasn't been seen yet.
+    pub fn global_slot(&self, name: &str) -> usize {
+        self.context.borrow_mut().global_slot(name)
+    }
+    fn global_name(&self, slot: usize) -> ValueStr {
+        self.context.borrow().globals.name(slot).clone()
+    }
+    fn make_global_read_only(&mut self, slot: usize) {
+        if let Some(Some(global)) = self.globals.get_mut(slot) {
+            global.1 = true;
+        }
+    }
+    pub(crate) fn get_global(&self, slot: usize) -> Value {
+        match self.globals.get(slot) {
+            Some(Some((value, _))) => value.clone(),
+            _ => Value::Nil,
+        }
     }
-    fn set_global(&mut self, name: ValueStr, new_value: Value) -> Result<(), ErrorKind> {
-        match self.globals.get_mut(&name) {
-            Some((_, true)) => Err(ErrorKind::ConstGlobal(name)),
-            Some((value, _)) => {
+    pub(crate) fn set_global(&mut self, slot: usize, new_value: Value) -> Result<(), ErrorKind> {
+        match self.globals.get_mut(slot) {
+            Some(Some((_, true))) => Err(ErrorKind::ConstGlobal(self.global_name(slot))),
+            Some(Some((value, _))) => {
                 *value = new_value;
                 Ok(())
             }
-            None => Err(ErrorKind::UndeclaredGlobal(name)),
+            _ => Err(ErrorKind::UndeclaredGlobal(self.global_name(slot))),
         }
     }
-    fn declare_global(&mut self, name: ValueStr) -> Result<(), ErrorKind> {
-        if self.globals.contains_key(&name) {
-            return Err(ErrorKind::RedeclareGlobal(name));
+    /// Declares the global or overwrites it if it already exists, ignoring read-only state.
+    pub(crate) fn define_global(&mut self, slot: usize, value: Value, read_only: bool) {
+        if self.globals.len() <= slot {
+            self.globals.resize_with(slot + 1, || None);
         }
-        self.globals.insert(name, (Value::Nil, false));
+        self.globals[slot] = Some((value, read_only));
+    }
+    fn declare_global(&mut self, slot: usize) -> Result<(), ErrorKind> {
+        if self.globals.len() <= slot {
+            self.globals.resize_with(slot + 1, || None);
+        }
+        if self.globals[slot].is_some() {
+            return Err(ErrorKind::RedeclareGlobal(self.global_name(slot)));
+        }
+        self.globals[slot] = Some((Value::Nil, false));
         Ok(())
     }
     fn truncate(&mut self, new_len: usize) {
         self.memory.truncate(new_len + self.base_pointer());
     }
     fn base_pointer(&self) -> usize {
-        self.current_frame
-            .as_ref()
+        self.frames
+            .last()
             .map(|frame| frame.base_pointer)
             .unwrap_or(0)
     }
-    fn method_currying(&mut self, itself: Value, function: Rc<Function>) -> Function {
-        let function1 = function.clone();
-        let curried_method = move |interpreter: &mut Self| -> Result<Value, ErrorKind> {
-            // shift all arguments forward, while inserting itself
-            let current_frame = interpreter.current_frame.as_ref().unwrap();
-            let arity = current_frame.function.signature.required_arity();
-            let base_pointer = current_frame.base_pointer;
-
-            interpreter.set_local(arity, itself.clone());
-            interpreter.memory[base_pointer..].rotate_right(1);
-            interpreter.call_with_frame(FunctionFrame {
-                base_pointer,
-                base_stack: interpreter.stack.len(),
-                function: function1.clone(),
-            })
-        };
-        Self::create_builtin_function(
-            function.signature.arity + 1,
-            function.signature.variadic,
-            curried_method,
-        )
+    fn bind_method(&mut self, itself: Value, method: Rc<Function>) -> Function {
+        Function {
+            signature: Rc::new(FnSignature {
+                arity: method.signature.arity.saturating_sub(1),
+                variadic: method.signature.variadic,
+                upvalues: vec![],
+                body: FnBody::Bound(itself, method),
+                debug: DebugInfo::default(),
+            }),
+            upvalues: vec![],
+        }
     }
     pub fn create_function(&mut self, signature: Rc<FnSignature>) -> Function {
         let upvalues = signature
@@ -212,7 +427,7 @@ impl Interpreter {
             .iter()
             .map(|loc| match loc {
                 UpvalueLoc::Shared(id) => {
-                    self.current_frame.as_ref().unwrap().function.upvalues[*id].clone()
+                    self.frames.last().unwrap().function.upvalues[*id].clone()
                 }
                 UpvalueLoc::Local(id) => self.make_local_upvalue(*id),
             })
@@ -233,57 +448,93 @@ impl Interpreter {
                 variadic,
                 upvalues: vec![],
                 body: FnBody::Builtin(Box::new(RefCell::new(builtin))),
+                debug: DebugInfo::default(),
             }),
             upvalues: vec![],
         }
     }
-    fn call_with_frame(&mut self, frame: FunctionFrame) -> Result<Value, ErrorKind> {
-        let function = frame.function.clone();
-        let mut old_frame = Some(frame);
-        mem::swap(&mut old_frame, &mut self.current_frame);
-
-        let return_value = match &function.signature.body {
-            FnBody::Builtin(builtin) => builtin.borrow_mut()(self)?,
-            FnBody::Bytecode(bytecodes) => {
-                let mut index = 0;
-                loop {
-                    let Some(bc) = bytecodes.get(index) else {
-                        break Value::Nil;
-                    };
-                    if DEBUG_MODE.load(Ordering::Relaxed) {
-                        println!(
-                            "{:?}: [{}]",
-                            bc.1,
-                            self.stack
-                                .iter()
-                                .map(|v| v.to_string())
-                                .collect::<Vec<_>>()
-                                .join(", ")
-                        );
+    /// Dispatch loop. Runs the bytecode frame at `depth` along with every bytecode function it calls, until that frame returns.
+    /// Script-level calls push frames onto `frames` instead of recursing on the native stack.
+    /// It starts at `pc` of the innermost frame, which is past `depth` only when resuming a restored call.
+    fn run(&mut self, depth: usize) -> Result<Value, ErrorKind> {
+        let frame = self.frames.last().unwrap();
+        let mut function = frame.function.clone();
+        let mut index = frame.pc;
+        loop {
+            let FnBody::Bytecode(bytecodes) = &function.signature.body else {
+                unreachable!("Only bytecode functions get a frame in the dispatch loop");
+            };
+            let control = match bytecodes.get(index) {
+                Some(bc) => {
+                    if self.instructions >= self.next_check {
+                        self.check_budget()?;
+                    }
+                    self.instructions += 1;
+                    self.check_cells()?;
+                    if let Some(log) = &mut self.input_log {
+                        log.instruction(self.frames.len(), self.stack.len())?;
+                    }
+                    if self.hooked {
+                        let frame = self.frames.len() - 1;
+                        self.frames[frame].pc = index;
+                        self.call_hooks(|hook, interpreter| {
+                            hook.on_instruction(interpreter, frame, index, &bc.1, bc.0)
+                        })?;
                     }
-                    match bc.1.interpret(self, index)? {
-                        Ok(next) => index = next,
-                        Err(ret) => break ret,
+                    let control = bc.1.interpret(self, index).inspect_err(|err| {
+                        if self.error_span.is_none() && self.hooked {
+                            let _ = self.call_hooks(|hook, interpreter| {
+                                hook.on_error(interpreter, err);
+                                Ok(())
+                            });
+                        }
+                        self.error_span.get_or_insert(bc.0);
+                    })?;
+                    if let (true, Bytecode::BranchIf(..), Control::Next(next)) =
+                        (self.hooked, &bc.1, &control)
+                    {
+                        let (frame, taken) = (self.frames.len() - 1, *next != index + 1);
+                        let _ = self.call_hooks(|hook, interpreter| {
+                            hook.on_branch(interpreter, frame, index, taken);
+                            Ok(())
+                        });
                     }
+                    control
+                }
+                None => Control::Return(Value::Nil),
+            };
+            match control {
+                Control::Next(next) => index = next,
+                Control::Call(callee) => {
+                    let caller = self.frames.len() - 2;
+                    self.frames[caller].index = index + 1;
+                    function = callee;
+                    index = 0;
+                }
+                Control::TailCall(callee) => {
+                    function = callee;
+                    index = 0;
+                }
+                Control::Return(value) => {
+                    if self.hooked {
+                        self.call_hooks(|hook, interpreter| hook.on_return(interpreter, &value))?;
+                    }
+                    self.pop_frame();
+                    if self.frames.len() == depth {
+                        return Ok(value);
+                    }
+                    let frame = self.frames.last().unwrap();
+                    function = frame.function.clone();
+                    index = frame.index;
+                    self.push_stack(value);
                 }
             }
-        };
-
-        self.truncate(0);
-        self.current_frame = old_frame;
-
-        Ok(return_value)
+        }
     }
-    fn call_stack_args(
-        &mut self,
-        function: Rc<Function>,
-        stack_base: usize,
-    ) -> Result<Value, ErrorKind> {
-        let abs_stack = stack_base + self.base_stack();
-        let abs_ptr = self.memory.len();
+    /// Moves arguments starting at the absolute stack index into a new memory window at the end of the memory.
+    fn push_args(&mut self, signature: &FnSignature, abs_stack: usize) {
         let stack_len = self.stack.len();
-        let arity = function.signature.arity;
-        let variadic = function.signature.variadic;
+        let arity = signature.arity;
 
         let iter = self.stack[abs_stack..(abs_stack + arity).min(stack_len)]
             .iter_mut()
@@ -292,40 +543,181 @@ impl Interpreter {
             .take(arity);
         self.memory.extend(iter);
 
-        if variadic {
+        if signature.variadic {
             let array = Value::Array(Rc::new(RefCell::new(
-                self.stack[(abs_stack + arity)..]
+                self.stack[(abs_stack + arity).min(stack_len)..]
                     .iter_mut()
                     .map(|elem| replace(elem, Value::Nil))
                     .collect::<Vec<_>>(),
             )));
             self.memory.push(Cell::Value(array));
         }
-        self.stack.truncate(abs_stack);
-
-        self.call_with_frame(FunctionFrame {
-            base_pointer: abs_ptr,
-            base_stack: abs_stack,
+    }
+    /// Inserts the receivers of bound methods in front of the arguments at `abs_args`, returning the function to actually call.
+    fn unbind(&mut self, mut function: Rc<Function>, abs_args: usize) -> Rc<Function> {
+        while let FnBody::Bound(itself, method) = &function.signature.body {
+            let (itself, method) = (itself.clone(), method.clone());
+            self.stack.insert(abs_args, itself);
+            function = method;
+        }
+        function
+    }
+    /// Moves the arguments at `abs_args` into a new memory window and pushes a frame for `function`,
+    /// whose stack begins at `abs_base`.
+    fn push_frame(
+        &mut self,
+        function: Rc<Function>,
+        abs_args: usize,
+        abs_base: usize,
+    ) -> Result<(), ErrorKind> {
+        if self.frames.len() >= self.limits.max_call_depth {
+            return Err(ErrorKind::StackOverflow);
+        }
+        let base_pointer = self.memory.len();
+        self.push_args(&function.signature, abs_args);
+        self.stack.truncate(abs_base);
+        self.frames.push(FunctionFrame {
+            base_pointer,
+            base_stack: abs_base,
             function,
-        })
+            index: 0,
+            pc: 0,
+        });
+        if self.hooked {
+            let function = self.frames.last().unwrap().function.clone();
+            self.call_hooks(|hook, interpreter| hook.on_call(interpreter, &function))?;
+        }
+        Ok(())
+    }
+    fn pop_frame(&mut self) {
+        let frame = self.frames.pop().unwrap();
+        self.memory.truncate(frame.base_pointer);
+        self.stack.truncate(frame.base_stack);
     }
-    fn call_on_stack(&mut self, stack_base: usize) -> Result<Value, ErrorKind> {
+    /// Calls `function` from the dispatch loop. Bytecode functions get a new frame which the loop continues in,
+    /// and the function is returned. Builtins run to completion and their result is pushed onto the stack.
+    fn enter_call(
+        &mut self,
+        function: Rc<Function>,
+        abs_args: usize,
+        abs_base: usize,
+    ) -> Result<Option<Rc<Function>>, ErrorKind> {
+        let function = self.unbind(function, abs_args);
+        if let FnBody::Builtin(..) = function.signature.body {
+            let value = self.call_native(function, abs_args, abs_base)?;
+            self.push_stack(value);
+            return Ok(None);
+        }
+        self.push_frame(function.clone(), abs_args, abs_base)?;
+        Ok(Some(function))
+    }
+    /// Calls `function` with the arguments at `abs_args` to completion, leaving the stack at `abs_base`.
+    /// On error, every frame the call pushed is unwound so the interpreter stays usable.
+    fn call_native(
+        &mut self,
+        function: Rc<Function>,
+        abs_args: usize,
+        abs_base: usize,
+    ) -> Result<Value, ErrorKind> {
+        let function = self.unbind(function, abs_args);
+        let depth = self.frames.len();
+        if depth == 0 {
+            self.start_top_level();
+        }
+        if let Err(err) = self.push_frame(function.clone(), abs_args, abs_base) {
+            self.stack.truncate(abs_base);
+            return Err(err);
+        }
+
+        let result = match &function.signature.body {
+            FnBody::Builtin(builtin) => {
+                let result = self.call_builtin(&function, builtin);
+                match (result, self.hooked) {
+                    (Ok(value), true) => self
+                        .call_hooks(|hook, interpreter| hook.on_return(interpreter, &value))
+                        .map(|_| value),
+                    (result, _) => result,
+                }
+            }
+            FnBody::Bytecode(..) if self.native_depth >= MAX_NATIVE_DEPTH => {
+                Err(ErrorKind::StackOverflow)
+            }
+            FnBody::Bytecode(..) => {
+                self.native_depth += 1;
+                let result = self.run(depth);
+                self.native_depth -= 1;
+                result
+            }
+            FnBody::Bound(..) => unreachable!("Bound methods are unbound before the call"),
+        };
+        self.unwind(depth, result.is_err());
+        result
+    }
+    // a new top-level call gets a fresh instruction budget
+    fn start_top_level(&mut self) {
+        self.instructions = 0;
+        self.next_check = 0;
+        self.error_span = None;
+    }
+    /// Pops the frames above `depth` that a call left behind, which it does when it `failed`.
+    fn unwind(&mut self, depth: usize, failed: bool) {
+        while self.frames.len() > depth {
+            if failed && self.hooked {
+                let frame = self.frames.len() - 1;
+                let _ = self.call_hooks(|hook, interpreter| {
+                    hook.on_unwind(interpreter, frame);
+                    Ok(())
+                });
+            }
+            self.pop_frame();
+        }
+    }
+    /// Replaces the current frame's function and locals with a call to `function`, whose arguments start at `abs_args`.
+    /// Everything the frame had on the stack and in its memory window is discarded.
+    fn reuse_frame(&mut self, function: Rc<Function>, abs_args: usize) -> Result<(), ErrorKind> {
+        let frame = self.frames.last().unwrap();
+        let (base_pointer, base_stack) = (frame.base_pointer, frame.base_stack);
+
+        self.memory.truncate(base_pointer);
+        self.push_args(&function.signature, abs_args);
+        self.stack.truncate(base_stack);
+        self.frames.last_mut().unwrap().function = function.clone();
+        if self.hooked {
+            self.call_hooks(|hook, interpreter| hook.on_call(interpreter, &function))?;
+        }
+        Ok(())
+    }
+    fn call_stack_args(
+        &mut self,
+        function: Rc<Function>,
+        stack_base: usize,
+    ) -> Result<Value, ErrorKind> {
         let abs_stack = stack_base + self.base_stack();
-        let function = self.stack[abs_stack].try_function()?;
-        self.call_stack_args(function, stack_base + 1).inspect(|_| {
-            self.stack.truncate(abs_stack);
-        })
+        self.call_native(function, abs_stack, abs_stack)
     }
     pub fn call_function_args(
         &mut self,
         function: Rc<Function>,
         args: impl IntoIterator<Item = Value>,
     ) -> Result<Value, ErrorKind> {
-        let next_base = self.stack.len() - self.base_stack();
+        let abs_stack = self.stack.len();
         for arg in args {
             self.stack.push(arg);
         }
-        self.call_stack_args(function, next_base)
+        self.call_native(function, abs_stack, abs_stack)
+    }
+    /// Continues the call restored from a snapshot taken while it ran until it returns, see `snapshot`. Returns nil
+    /// right away if no call was restored.
+    pub fn resume(&mut self) -> Result<Value, ErrorKind> {
+        if self.frames.is_empty() {
+            return Ok(Value::Nil);
+        }
+        self.start_top_level();
+        self.native_depth += 1;
+        let result = self.run(0);
+        self.native_depth -= 1;
+        self.unwind(0, result.is_err());
+        result
     }
 }
 
@@ -334,15 +726,42 @@ mod tests {
     use std::rc::Rc;
 
     use crate::{
+        error::ErrorKind,
         interpreter::{
             bytecode::{BinaryOp, Bytecode},
+            debug::DebugInfo,
+            limits::Limits,
             string::ValueStr,
-            value::Value,
+            value::{Function, Value},
             FnBody, FnSignature, Interpreter, UpvalueLoc,
         },
         span::{Span, SpanOf},
     };
 
+    #[test]
+    fn global_slots() {
+        let mut interpreter = Interpreter::default();
+        let x = interpreter.global_slot("x");
+        assert_eq!(x, interpreter.global_slot("x"));
+        assert_ne!(x, interpreter.global_slot("println"));
+
+        // late-declared globals read as nil and reject writes
+        assert_eq!(interpreter.get_global(x), Value::Nil);
+        assert!(interpreter.set_global(x, Value::Number(1.0)).is_err());
+
+        interpreter.declare_global(x).unwrap();
+        interpreter.set_global(x, Value::Number(1.0)).unwrap();
+        assert_eq!(interpreter.get_global(x), Value::Number(1.0));
+        assert!(interpreter.declare_global(x).is_err());
+
+        interpreter.make_global_read_only(x);
+        assert!(interpreter.set_global(x, Value::Nil).is_err());
+
+        let println = interpreter.global_slot("println");
+        assert!(interpreter.declare_global(println).is_err());
+        assert!(interpreter.set_global(println, Value::Nil).is_err());
+    }
+
     #[test]
     fn basic_function() {
         #[rustfmt::skip]
@@ -357,6 +776,7 @@ mod tests {
             variadic: false,
             body: FnBody::Bytecode(bytecode.map(|bc| SpanOf(Span::default(), bc)).to_vec()),
             upvalues: vec![],
+            debug: DebugInfo::default(),
         });
         let mut interpreter = Interpreter::default();
         let function = Rc::new(interpreter.create_function(signature));
@@ -407,6 +827,7 @@ mod tests {
             variadic: false,
             upvalues: vec![],
             body: FnBody::Bytecode(bytecode.map(|bc| SpanOf(Span::default(), bc)).to_vec()),
+            debug: DebugInfo::default(),
         });
         let mut interpreter = Interpreter::default();
         let function = Rc::new(interpreter.create_function(signature));
@@ -428,7 +849,8 @@ mod tests {
     }
     #[test]
     fn fibonacci_recursive() {
-        let name = ValueStr::interned("fib");
+        let mut interpreter = Interpreter::default();
+        let name = interpreter.global_slot("fib");
 
         #[rustfmt::skip]
         let bytecode = [
@@ -437,13 +859,13 @@ mod tests {
             Bytecode::Binary(BinaryOp::SetLe),
             Bytecode::BranchIf(true, 13),
 
-            Bytecode::LoadGlobal(name.clone()),
+            Bytecode::LoadGlobal(name),
             Bytecode::LoadLocal(0),
             Bytecode::LoadNum(1.0),
             Bytecode::Binary(BinaryOp::Sub),
             Bytecode::Call(0),
 
-            Bytecode::LoadGlobal(name.clone()),
+            Bytecode::LoadGlobal(name),
             Bytecode::LoadLocal(0),
             Bytecode::LoadNum(2.0),
             Bytecode::Binary(BinaryOp::Sub),
@@ -460,10 +882,10 @@ mod tests {
             upvalues: vec![],
             variadic: false,
             body: FnBody::Bytecode(bytecode.map(|bc| SpanOf(Span::default(), bc)).to_vec()),
+            debug: DebugInfo::default(),
         });
-        let mut interpreter = Interpreter::default();
         let function = Rc::new(interpreter.create_function(signature));
-        interpreter.declare_global(name.clone()).unwrap();
+        interpreter.declare_global(name).unwrap();
         interpreter
             .set_global(name, Value::Function(function.clone()))
             .unwrap();
@@ -487,8 +909,8 @@ mod tests {
     }
     #[test]
     fn upvalue_test() {
-        let inc_name = ValueStr::interned("inc");
-        let dec_name = ValueStr::interned("dec");
+        let inc_name = ValueStr::from("inc");
+        let dec_name = ValueStr::from("dec");
 
         #[rustfmt::skip]
         let inc_bytecode = [
@@ -504,6 +926,7 @@ mod tests {
             variadic: false,
             upvalues: vec![UpvalueLoc::Local(0)],
             body: FnBody::Bytecode(inc_bytecode.map(|bc| SpanOf(Span::default(), bc)).to_vec()),
+            debug: DebugInfo::default(),
         });
         #[rustfmt::skip]
         let dec_bytecode = [
@@ -519,6 +942,7 @@ mod tests {
             variadic: false,
             upvalues: vec![UpvalueLoc::Local(0)],
             body: FnBody::Bytecode(dec_bytecode.map(|bc| SpanOf(Span::default(), bc)).to_vec()),
+            debug: DebugInfo::default(),
         });
         #[rustfmt::skip]
         let bytecode = [
@@ -536,6 +960,7 @@ mod tests {
             variadic: false,
             upvalues: vec![],
             body: FnBody::Bytecode(bytecode.map(|bc| SpanOf(Span::default(), bc)).to_vec()),
+            debug: DebugInfo::default(),
         });
         let mut interpreter = Interpreter::default();
         let function = Rc::new(interpreter.create_function(signature));
@@ -562,4 +987,120 @@ mod tests {
         );
         println!("{}", result);
     }
+    #[test]
+    fn tail_call() {
+        let mut interpreter = Interpreter::default();
+        let name = interpreter.global_slot("count");
+
+        #[rustfmt::skip]
+        let bytecode = [
+            Bytecode::LoadLocal(0),
+            Bytecode::LoadNum(0.0),
+            Bytecode::Binary(BinaryOp::SetEq),
+            Bytecode::BranchIf(false, 3),
+            Bytecode::LoadLocal(1),
+            Bytecode::Return,
+
+            Bytecode::LoadGlobal(name),
+            Bytecode::LoadLocal(0),
+            Bytecode::LoadNum(1.0),
+            Bytecode::Binary(BinaryOp::Sub),
+            Bytecode::LoadLocal(1),
+            Bytecode::LoadNum(1.0),
+            Bytecode::Binary(BinaryOp::Add),
+            Bytecode::TailCall(0),
+        ];
+        let signature = Rc::new(FnSignature {
+            arity: 2,
+            variadic: false,
+            upvalues: vec![],
+            body: FnBody::Bytecode(bytecode.map(|bc| SpanOf(Span::default(), bc)).to_vec()),
+            debug: DebugInfo::default(),
+        });
+        let function = Rc::new(interpreter.create_function(signature));
+        interpreter.declare_global(name).unwrap();
+        interpreter
+            .set_global(name, Value::Function(function.clone()))
+            .unwrap();
+
+        // deep enough to overflow the native stack if every call nested a frame
+        let result = interpreter
+            .call_function_args(function, [Value::Number(1e6), Value::Number(0.0)])
+            .unwrap();
+        assert_eq!(result, Value::Number(1e6));
+        assert!(interpreter.memory.is_empty());
+        assert!(interpreter.stack.is_empty());
+    }
+
+    fn recursive_sum(interpreter: &mut Interpreter) -> Rc<Function> {
+        let name = interpreter.global_slot("sum");
+
+        // sum(n) = n == 0 ? 0 : n + sum(n - 1), which can't be turned into a tail call
+        #[rustfmt::skip]
+        let bytecode = [
+            Bytecode::LoadLocal(0),
+            Bytecode::LoadNum(0.0),
+            Bytecode::Binary(BinaryOp::SetEq),
+            Bytecode::BranchIf(false, 3),
+            Bytecode::LoadNum(0.0),
+            Bytecode::Return,
+
+            Bytecode::LoadLocal(0),
+            Bytecode::LoadGlobal(name),
+            Bytecode::LoadLocal(0),
+            Bytecode::LoadNum(1.0),
+            Bytecode::Binary(BinaryOp::Sub),
+            Bytecode::Call(1),
+            Bytecode::Binary(BinaryOp::Add),
+            Bytecode::Return,
+        ];
+        let signature = Rc::new(FnSignature {
+            arity: 1,
+            variadic: false,
+            upvalues: vec![],
+            body: FnBody::Bytecode(bytecode.map(|bc| SpanOf(Span::default(), bc)).to_vec()),
+            debug: DebugInfo::default(),
+        });
+        let function = Rc::new(interpreter.create_function(signature));
+        interpreter.declare_global(name).unwrap();
+        interpreter
+            .set_global(name, Value::Function(function.clone()))
+            .unwrap();
+        function
+    }
+
+    #[test]
+    fn deep_recursion() {
+        let mut interpreter = Interpreter::default();
+        let function = recursive_sum(&mut interpreter);
+
+        // frames live on the heap, so this doesn't touch the native stack
+        let result = interpreter
+            .call_function_args(function, [Value::Number(50000.0)])
+            .unwrap();
+        assert_eq!(result, Value::Number(1250025000.0));
+        assert!(interpreter.frames.is_empty());
+    }
+
+    #[test]
+    fn stack_overflow() {
+        let mut interpreter = Interpreter::default();
+        interpreter.set_limits(Limits {
+            max_call_depth: 1000,
+            ..Default::default()
+        });
+        let function = recursive_sum(&mut interpreter);
+
+        let result = interpreter.call_function_args(function.clone(), [Value::Number(1000.0)]);
+        assert!(matches!(result, Err(ErrorKind::StackOverflow)));
+        assert!(interpreter.frames.is_empty());
+        assert!(interpreter.memory.is_empty());
+        assert!(interpreter.stack.is_empty());
+
+        // the interpreter is still usable after the overflow
+        let result = interpreter
+            .call_function_args(function, [Value::Number(998.0)])
+            .unwrap();
+        assert_eq!(result, Value::Number(498501.0));
+    }
 }
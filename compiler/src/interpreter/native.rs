@@ -0,0 +1,266 @@
+use std::{
+    any::{Any, TypeId},
+    fmt,
+    rc::Rc,
+};
+
+use crate::error::ErrorKind;
+use crate::interpreter::{
+    value::{FromValue, Function, IntoValue, Value},
+    Interpreter,
+};
+
+/// Rust type that scripts can hold as a `Value::Native` without copying it.
+///
+/// Methods are registered per interpreter with `Interpreter::register_native_method`,
+/// and are looked up by `LoadMethod` and `LoadProperty` like the properties of an object.
+pub trait NativeType: Any {
+    /// Name used in type errors and by the default display.
+    const TYPE_NAME: &'static str;
+
+    fn fmt_native(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "<{}>", Self::TYPE_NAME)
+    }
+    /// Equality of two values of this type. By default, values are only equal to themselves.
+    fn eq_native(&self, other: &Self) -> bool {
+        std::ptr::eq(self, other)
+    }
+}
+
+struct NativeVTable {
+    type_name: &'static str,
+    fmt: fn(&dyn Any, &mut fmt::Formatter<'_>) -> fmt::Result,
+    eq: fn(&dyn Any, &dyn Any) -> bool,
+}
+trait HasVTable {
+    const VTABLE: NativeVTable;
+}
+impl<T: NativeType> HasVTable for T {
+    const VTABLE: NativeVTable = NativeVTable {
+        type_name: T::TYPE_NAME,
+        fmt: fmt_native::<T>,
+        eq: eq_native::<T>,
+    };
+}
+fn fmt_native<T: NativeType>(data: &dyn Any, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    data.downcast_ref::<T>().unwrap().fmt_native(f)
+}
+fn eq_native<T: NativeType>(a: &dyn Any, b: &dyn Any) -> bool {
+    match (a.downcast_ref::<T>(), b.downcast_ref::<T>()) {
+        (Some(a), Some(b)) => a.eq_native(b),
+        _ => false,
+    }
+}
+
+/// Shared handle to Rust data of a `NativeType`.
+#[derive(Clone)]
+pub struct NativeValue {
+    data: Rc<dyn Any>,
+    vtable: &'static NativeVTable,
+}
+impl NativeValue {
+    pub fn new<T: NativeType>(data: Rc<T>) -> Self {
+        Self {
+            data,
+            vtable: &T::VTABLE,
+        }
+    }
+    pub fn type_name(&self) -> &'static str {
+        self.vtable.type_name
+    }
+    pub fn type_id(&self) -> TypeId {
+        (*self.data).type_id()
+    }
+    pub fn downcast<T: NativeType>(&self) -> Option<Rc<T>> {
+        self.data.clone().downcast().ok()
+    }
+    pub fn ptr_eq(&self, other: &Self) -> bool {
+        Rc::ptr_eq(&self.data, &other.data)
+    }
+}
+impl PartialEq for NativeValue {
+    fn eq(&self, other: &Self) -> bool {
+        self.ptr_eq(other) || (self.vtable.eq)(&*self.data, &*other.data)
+    }
+}
+impl fmt::Display for NativeValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        (self.vtable.fmt)(&*self.data, f)
+    }
+}
+impl fmt::Debug for NativeValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Native").field(&self.type_name()).finish()
+    }
+}
+
+impl<T: NativeType> FromValue for Rc<T> {
+    fn from_value(value: Value) -> Result<Self, ErrorKind> {
+        match &value {
+            Value::Native(native) => native.downcast(),
+            _ => None,
+        }
+        .ok_or(ErrorKind::InvalidType(value.type_str(), T::TYPE_NAME))
+    }
+}
+impl<T: NativeType> IntoValue for Rc<T> {
+    fn into_value(self) -> Value {
+        Value::Native(NativeValue::new(self))
+    }
+}
+
+/// Result of a native function created with `native_function`.
+pub trait NativeResult {
+    fn into_result(self) -> Result<Value, ErrorKind>;
+}
+impl<T: IntoValue> NativeResult for T {
+    fn into_result(self) -> Result<Value, ErrorKind> {
+        Ok(self.into_value())
+    }
+}
+impl<T: IntoValue> NativeResult for Result<T, ErrorKind> {
+    fn into_result(self) -> Result<Value, ErrorKind> {
+        self.map(T::into_value)
+    }
+}
+
+/// Rust closure that can be called from scripts. `Args` is the tuple of its argument types.
+pub trait NativeFn<Args>: 'static {
+    fn arity(&self) -> usize;
+    /// Called with exactly `arity` arguments.
+    fn call(&mut self, args: Vec<Value>) -> Result<Value, ErrorKind>;
+}
+macro_rules! impl_native_fn {
+    ($($arg:ident),*) => {
+        #[allow(non_snake_case)]
+        impl<Func, Ret, $($arg),*> NativeFn<($($arg,)*)> for Func
+        where
+            Func: FnMut($($arg),*) -> Ret + 'static,
+            Ret: NativeResult,
+            $($arg: FromValue),*
+        {
+            fn arity(&self) -> usize {
+                <[&str]>::len(&[$(stringify!($arg)),*])
+            }
+            #[allow(unused_mut, unused_variables)]
+            fn call(&mut self, args: Vec<Value>) -> Result<Value, ErrorKind> {
+                let mut args = args.into_iter();
+                $(let $arg = $arg::from_value(args.next().unwrap_or_default())?;)*
+                self($($arg),*).into_result()
+            }
+        }
+    };
+}
+impl_native_fn!();
+impl_native_fn!(A);
+impl_native_fn!(A, B);
+impl_native_fn!(A, B, C);
+impl_native_fn!(A, B, C, D);
+impl_native_fn!(A, B, C, D, E);
+impl_native_fn!(A, B, C, D, E, F);
+impl_native_fn!(A, B, C, D, E, F, G);
+impl_native_fn!(A, B, C, D, E, F, G, H);
+
+/// Wraps a typed Rust closure into a builtin function.
+///
+/// Arguments are converted with `FromValue` and the result with `IntoValue`.
+/// Calls with the wrong number of arguments fail with `ErrorKind::ArityMismatch`.
+pub fn native_function<Args>(mut function: impl NativeFn<Args>) -> Function {
+    let arity = function.arity();
+    // registered as fully variadic, so that missing and extra arguments can be told apart from nil
+    Interpreter::create_builtin_function(0, true, move |interpreter| {
+        let args = Vec::<Value>::from_value(interpreter.get_local(0))?;
+        if args.len() != arity {
+            return Err(ErrorKind::ArityMismatch(arity, args.len()));
+        }
+        function.call(args)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fmt, rc::Rc};
+
+    use crate::{
+        error::ErrorKind,
+        interpreter::{native::NativeType, value::Value},
+        vm::Vm,
+    };
+
+    struct Image {
+        width: usize,
+        height: usize,
+    }
+    impl NativeType for Image {
+        const TYPE_NAME: &'static str = "Image";
+
+        fn fmt_native(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "<Image {}x{}>", self.width, self.height)
+        }
+    }
+
+    struct Point(f64);
+    impl NativeType for Point {
+        const TYPE_NAME: &'static str = "Point";
+
+        fn eq_native(&self, other: &Self) -> bool {
+            self.0 == other.0
+        }
+    }
+
+    #[test]
+    fn native_methods() {
+        let mut vm = Vm::new();
+        vm.register_method::<Image, _>("width", |img: Rc<Image>| img.width);
+        vm.register_method::<Image, _>("area", |img: Rc<Image>, scale: f64| {
+            (img.width * img.height) as f64 * scale
+        });
+        vm.register_fn("blank", |width: usize, height: usize| {
+            Rc::new(Image { width, height })
+        });
+
+        let image = Rc::new(Image {
+            width: 4,
+            height: 3,
+        });
+        vm.set_global("img", image.clone()).unwrap();
+        assert_eq!(vm.eval("img:width()").unwrap(), Value::Number(4.0));
+        assert_eq!(vm.eval("img:area(2)").unwrap(), Value::Number(24.0));
+        assert_eq!(vm.eval("img.width(img)").unwrap(), Value::Number(4.0));
+        assert_eq!(vm.eval("img.missing").unwrap(), Value::Nil);
+        assert_eq!(vm.eval("str(img)").unwrap().to_string(), "<Image 4x3>");
+        assert_eq!(vm.eval("blank(1, 2):width()").unwrap(), Value::Number(1.0));
+
+        // the script holds the same Rust value rather than a copy
+        let Value::Native(native) = vm.get_global("img") else {
+            panic!("expected a native value");
+        };
+        assert!(Rc::ptr_eq(&native.downcast::<Image>().unwrap(), &image));
+
+        let err = vm.eval("img:width(1)").unwrap_err();
+        assert!(matches!(err.kind, ErrorKind::ArityMismatch(1, 2)));
+        let err = vm.eval("img.width = 1").unwrap_err();
+        assert!(matches!(err.kind, ErrorKind::InvalidPropertyAccess));
+    }
+
+    #[test]
+    fn native_equality() {
+        let mut vm = Vm::new();
+        vm.register_fn("point", |x: f64| Rc::new(Point(x)));
+        vm.register_method::<Point, _>("x", |p: Rc<Point>| p.0);
+
+        assert_eq!(vm.eval("point(1) == point(1)").unwrap(), Value::Bool(true));
+        assert_eq!(vm.eval("point(1) == point(2)").unwrap(), Value::Bool(false));
+        assert_eq!(vm.eval("str(point(1))").unwrap().to_string(), "<Point>");
+
+        let image = Rc::new(Image {
+            width: 1,
+            height: 1,
+        });
+        vm.set_global("img", image).unwrap();
+        assert_eq!(vm.eval("img == img").unwrap(), Value::Bool(true));
+        assert_eq!(vm.eval("img == point(1)").unwrap(), Value::Bool(false));
+        let err = vm.eval("point(1).x(img)").unwrap_err();
+        assert!(matches!(err.kind, ErrorKind::InvalidType("Image", "Point")));
+    }
+}
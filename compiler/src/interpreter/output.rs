@@ -0,0 +1,31 @@
+use std::{
+    io::{self, Write},
+    sync::{Arc, Mutex},
+};
+
+/// In-memory sink that collects script output, see `Interpreter::capture_stdout`.
+///
+/// Clones share the same buffer, so one clone can be handed to the interpreter while another reads from it,
+/// also from another thread than the one running the interpreter.
+#[derive(Debug, Clone, Default)]
+pub struct OutputBuffer(Arc<Mutex<Vec<u8>>>);
+impl OutputBuffer {
+    /// Everything written so far, with invalid UTF-8 replaced.
+    pub fn contents(&self) -> String {
+        String::from_utf8_lossy(&self.0.lock().unwrap()).into_owned()
+    }
+    /// Like `contents`, but also clears the buffer.
+    pub fn take(&self) -> String {
+        let bytes = std::mem::take(&mut *self.0.lock().unwrap());
+        String::from_utf8_lossy(&bytes).into_owned()
+    }
+}
+impl Write for OutputBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,450 @@
+use std::{
+    collections::BTreeMap,
+    io::{self, Write},
+    rc::Rc,
+    time::{Duration, Instant},
+};
+
+use rustc_hash::FxHashMap;
+
+use crate::{
+    error::ErrorKind,
+    interpreter::{
+        bytecode::Bytecode,
+        hook::Hook,
+        value::{Function, Value},
+        FnBody, FnSignature, Interpreter,
+    },
+    source_map::SourceMap,
+    span::{FileId, Span},
+};
+
+struct FunctionData {
+    signature: Rc<FnSignature>,
+    calls: u64,
+    inclusive: Duration,
+    exclusive: Duration,
+    // calls of the function that haven't returned, so recursive calls only count towards inclusive time once
+    active: usize,
+    // instruction count and time by instruction index
+    instructions: Vec<(u64, Duration)>,
+}
+
+// a call that hasn't returned yet
+struct Call {
+    frame: usize, // index of the interpreter frame it runs in
+    function: usize,
+    node: usize,
+    start: Instant,
+    children: Duration,
+}
+
+// node of the call tree, for folded stacks
+struct Node {
+    function: usize,
+    children: FxHashMap<usize, usize>,
+    exclusive: Duration,
+}
+
+/// Time and instructions spent in each function and instruction, recorded while profiling is turned on with
+/// `Interpreter::start_profile`. It's the first hook to run, see `Interpreter::set_hook`.
+pub struct Profile {
+    functions: Vec<FunctionData>,
+    ids: FxHashMap<*const FnSignature, usize>,
+    calls: Vec<Call>,
+    nodes: Vec<Node>, // the first one is the root, above every top-level call
+    // instruction being executed and when it started
+    current: Option<(usize, usize, Instant)>,
+}
+impl Default for Profile {
+    fn default() -> Self {
+        Self {
+            functions: vec![],
+            ids: FxHashMap::default(),
+            calls: vec![],
+            nodes: vec![Node {
+                function: usize::MAX,
+                children: FxHashMap::default(),
+                exclusive: Duration::ZERO,
+            }],
+            current: None,
+        }
+    }
+}
+
+/// Totals of one function, see `Profile::functions`.
+#[derive(Debug, Clone)]
+pub struct FunctionStats {
+    pub name: String,
+    pub calls: u64,
+    pub instructions: u64,
+    /// Time from call to return, counted once for recursive calls.
+    pub inclusive: Duration,
+    /// Inclusive time without the time spent in other calls.
+    pub exclusive: Duration,
+}
+
+/// Totals of the instructions on one source line, see `Profile::lines`.
+#[derive(Debug, Clone)]
+pub struct LineStats {
+    pub file: FileId,
+    pub line: usize,
+    pub instructions: u64,
+    /// Time spent executing the instructions themselves, without the functions they call.
+    pub time: Duration,
+}
+
+impl Profile {
+    fn function_id(&mut self, function: &Function) -> usize {
+        let signature = &function.signature;
+        *self.ids.entry(Rc::as_ptr(signature)).or_insert_with(|| {
+            let instructions = match &signature.body {
+                FnBody::Bytecode(code) => vec![(0, Duration::ZERO); code.len()],
+                _ => vec![],
+            };
+            self.functions.push(FunctionData {
+                signature: signature.clone(),
+                calls: 0,
+                inclusive: Duration::ZERO,
+                exclusive: Duration::ZERO,
+                active: 0,
+                instructions,
+            });
+            self.functions.len() - 1
+        })
+    }
+    /// Ends the instruction being timed.
+    fn finish_instruction(&mut self, now: Instant) {
+        if let Some((function, index, start)) = self.current.take() {
+            self.functions[function].instructions[index].1 += now - start;
+        }
+    }
+    /// Starts a call of `function` in `frame`, ending the calls that ran in it or in frames above it, which tail calls
+    /// replace.
+    fn enter(&mut self, function: &Function, frame: usize) {
+        self.exit(frame);
+        let now = Instant::now();
+        self.finish_instruction(now);
+        let id = self.function_id(function);
+        let data = &mut self.functions[id];
+        data.calls += 1;
+        data.active += 1;
+
+        let parent = self.calls.last().map_or(0, |call| call.node);
+        let node = match self.nodes[parent].children.get(&id) {
+            Some(&node) => node,
+            None => {
+                self.nodes.push(Node {
+                    function: id,
+                    children: FxHashMap::default(),
+                    exclusive: Duration::ZERO,
+                });
+                let node = self.nodes.len() - 1;
+                self.nodes[parent].children.insert(id, node);
+                node
+            }
+        };
+        self.calls.push(Call {
+            frame,
+            function: id,
+            node,
+            start: now,
+            children: Duration::ZERO,
+        });
+    }
+    /// Ends the calls running in `frame` and in frames above it.
+    fn exit(&mut self, frame: usize) {
+        while self.calls.last().is_some_and(|call| call.frame >= frame) {
+            self.exit_call();
+        }
+    }
+    fn exit_call(&mut self) {
+        let now = Instant::now();
+        self.finish_instruction(now);
+        let Some(call) = self.calls.pop() else {
+            return;
+        };
+        let inclusive = now - call.start;
+        let exclusive = inclusive.saturating_sub(call.children);
+        let data = &mut self.functions[call.function];
+        data.active -= 1;
+        if data.active == 0 {
+            data.inclusive += inclusive;
+        }
+        data.exclusive += exclusive;
+        self.nodes[call.node].exclusive += exclusive;
+        if let Some(caller) = self.calls.last_mut() {
+            caller.children += inclusive;
+        }
+    }
+    /// Records the instruction at `index` of the call in `frame`, which is about to run.
+    fn instruction(&mut self, frame: usize, index: usize) {
+        let now = Instant::now();
+        self.finish_instruction(now);
+        // frames entered before profiling started have no call
+        if let Some(call) = self.calls.last().filter(|call| call.frame == frame) {
+            self.functions[call.function].instructions[index].0 += 1;
+            self.current = Some((call.function, index, now));
+        }
+    }
+
+    /// Totals of every function that was called, the most exclusive time first.
+    pub fn functions(&self, sources: &SourceMap) -> Vec<FunctionStats> {
+        let mut functions = self
+            .functions
+            .iter()
+            .map(|data| FunctionStats {
+                name: data.signature.name(sources),
+                calls: data.calls,
+                instructions: data.instructions.iter().map(|(count, _)| count).sum(),
+                inclusive: data.inclusive,
+                exclusive: data.exclusive,
+            })
+            .collect::<Vec<_>>();
+        functions.sort_by(|a, b| b.exclusive.cmp(&a.exclusive).then(a.name.cmp(&b.name)));
+        functions
+    }
+    /// Totals of every source line with executed instructions, the most time first.
+    pub fn lines(&self, sources: &SourceMap) -> Vec<LineStats> {
+        let mut lines = BTreeMap::<(FileId, usize), (u64, Duration)>::new();
+        for data in &self.functions {
+            let FnBody::Bytecode(code) = &data.signature.body else {
+                continue;
+            };
+            for (bc, (count, time)) in code.iter().zip(&data.instructions) {
+                if *count == 0 || bc.0 == Default::default() {
+                    continue;
+                }
+                let line = sources.line_index(bc.0.file, bc.0.start) + 1;
+                let totals = lines.entry((bc.0.file, line)).or_default();
+                totals.0 += count;
+                totals.1 += *time;
+            }
+        }
+        let mut lines = lines
+            .into_iter()
+            .map(|((file, line), (instructions, time))| LineStats {
+                file,
+                line,
+                instructions,
+                time,
+            })
+            .collect::<Vec<_>>();
+        lines.sort_by(|a, b| {
+            b.time
+                .cmp(&a.time)
+                .then(b.instructions.cmp(&a.instructions))
+        });
+        lines
+    }
+    /// Writes the per-function and per-line tables, with at most `max_lines` lines.
+    pub fn write_report(
+        &self,
+        sources: &SourceMap,
+        max_lines: usize,
+        out: &mut impl Write,
+    ) -> io::Result<()> {
+        let ms = |time: Duration| time.as_secs_f64() * 1000.0;
+        writeln!(
+            out,
+            "{:>12} {:>12} {:>8} {:>14}  function",
+            "inclusive ms", "exclusive ms", "calls", "instructions"
+        )?;
+        for function in self.functions(sources) {
+            writeln!(
+                out,
+                "{:>12.3} {:>12.3} {:>8} {:>14}  {}",
+                ms(function.inclusive),
+                ms(function.exclusive),
+                function.calls,
+                function.instructions,
+                function.name
+            )?;
+        }
+        writeln!(out)?;
+        writeln!(out, "{:>12} {:>14}  line", "time ms", "instructions")?;
+        for line in self.lines(sources).into_iter().take(max_lines) {
+            writeln!(
+                out,
+                "{:>12.3} {:>14}  {}:{}: {}",
+                ms(line.time),
+                line.instructions,
+                sources.name(line.file),
+                line.line,
+                sources.line_text(line.file, line.line).trim()
+            )?;
+        }
+        Ok(())
+    }
+    /// Writes the call stacks in the folded format of flamegraph tools, `outer;inner microseconds` per line, with
+    /// the exclusive time of the innermost function.
+    pub fn write_folded(&self, sources: &SourceMap, out: &mut impl Write) -> io::Result<()> {
+        let names = self
+            .functions
+            .iter()
+            .map(|data| data.signature.name(sources).replace(';', ","))
+            .collect::<Vec<_>>();
+        let mut stack = vec![];
+        self.write_node(0, &names, &mut stack, out)
+    }
+    fn write_node(
+        &self,
+        node: usize,
+        names: &[String],
+        stack: &mut Vec<usize>,
+        out: &mut impl Write,
+    ) -> io::Result<()> {
+        let micros = self.nodes[node].exclusive.as_micros();
+        if micros > 0 {
+            let path = stack.iter().map(|&id| names[id].as_str());
+            writeln!(out, "{} {}", path.collect::<Vec<_>>().join(";"), micros)?;
+        }
+        let mut children = self.nodes[node]
+            .children
+            .values()
+            .copied()
+            .collect::<Vec<_>>();
+        children.sort();
+        for child in children {
+            stack.push(self.nodes[child].function);
+            self.write_node(child, names, stack, out)?;
+            stack.pop();
+        }
+        Ok(())
+    }
+}
+
+impl Hook for Profile {
+    fn on_instruction(
+        &mut self,
+        _interpreter: &mut Interpreter,
+        frame: usize,
+        index: usize,
+        _bytecode: &Bytecode,
+        _span: Span,
+    ) -> Result<(), ErrorKind> {
+        self.instruction(frame, index);
+        Ok(())
+    }
+    fn on_call(
+        &mut self,
+        interpreter: &mut Interpreter,
+        function: &Rc<Function>,
+    ) -> Result<(), ErrorKind> {
+        self.enter(function, interpreter.frame_count() - 1);
+        Ok(())
+    }
+    fn on_return(
+        &mut self,
+        interpreter: &mut Interpreter,
+        _value: &Value,
+    ) -> Result<(), ErrorKind> {
+        self.exit(interpreter.frame_count() - 1);
+        Ok(())
+    }
+    fn on_unwind(&mut self, _interpreter: &mut Interpreter, frame: usize) {
+        self.exit(frame);
+    }
+}
+
+impl Interpreter {
+    /// Starts recording a profile of everything run from now on, replacing the one being recorded.
+    pub fn start_profile(&mut self) {
+        self.profile = Some(Box::default());
+        self.update_hooked();
+    }
+    /// Stops profiling and returns the profile recorded so far.
+    pub fn take_profile(&mut self) -> Option<Profile> {
+        let profile = self.profile.take();
+        self.update_hooked();
+        profile.map(|profile| *profile)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::Cell, rc::Rc};
+
+    use super::*;
+    use crate::{ast::Parser, codegen::Codegen};
+
+    #[test]
+    fn test_profile() {
+        let source = "\
+fn fib(n) do
+    if n < 2 then return n end
+    return fib(n - 1) + fib(n - 2)
+end
+let twice = \\f -> f() + f()
+println(twice(\\ -> fib(10)))
+";
+        let mut parser = Parser::with_file(source.as_bytes(), &Default::default(), "fib.rlox");
+        let mut interpreter = Interpreter::default();
+        let output = interpreter.capture_stdout();
+        let mut codegen = Codegen::with_context(parser.sources(), interpreter.context());
+        while let Some(statement) = parser.next_statement().unwrap() {
+            codegen.gen_statement(&statement).unwrap();
+        }
+        let main = Rc::new(interpreter.create_function(Rc::new(codegen.pop_init_sig())));
+        interpreter.start_profile();
+        interpreter.call_function_args(main, []).unwrap();
+        let profile = interpreter.take_profile().unwrap();
+        assert_eq!(output.take(), "110\n");
+
+        let sources = parser.sources();
+        let functions = profile.functions(&sources);
+        let function = |name: &str| functions.iter().find(|f| f.name == name).unwrap();
+        assert_eq!(function("fib").calls, 2 * 177);
+        assert_eq!(function("twice").calls, 1);
+        assert_eq!(function("<closure fib.rlox:6:15>").calls, 2);
+        assert_eq!(function("println").calls, 1);
+        let main = function("<main>");
+        assert!(main.inclusive >= function("twice").inclusive);
+        assert!(main.inclusive >= main.exclusive);
+
+        // the recursive line runs most often
+        let lines = profile.lines(&sources);
+        let hottest = lines.iter().max_by_key(|line| line.instructions).unwrap();
+        assert_eq!(hottest.line, 3);
+
+        let mut folded = vec![];
+        profile.write_folded(&sources, &mut folded).unwrap();
+        let folded = String::from_utf8(folded).unwrap();
+        assert!(folded
+            .lines()
+            .all(|line| line.starts_with("<main>") && line.rsplit_once(' ').is_some()));
+        let mut report = vec![];
+        profile.write_report(&sources, 5, &mut report).unwrap();
+        let report = String::from_utf8(report).unwrap();
+        assert!(report.contains("fib.rlox:3: return fib(n - 1) + fib(n - 2)"));
+    }
+
+    #[test]
+    fn test_profile_unwind() {
+        struct Calls(Rc<Cell<usize>>);
+        impl Hook for Calls {
+            fn on_call(&mut self, _: &mut Interpreter, _: &Rc<Function>) -> Result<(), ErrorKind> {
+                self.0.set(self.0.get() + 1);
+                Ok(())
+            }
+        }
+
+        let mut parser = Parser::new("fn fail() 1 + nil\nfn outer() fail()\nouter()".as_bytes());
+        let mut interpreter = Interpreter::default();
+        let mut codegen = Codegen::with_context(parser.sources(), interpreter.context());
+        while let Some(statement) = parser.next_statement().unwrap() {
+            codegen.gen_statement(&statement).unwrap();
+        }
+        let main = Rc::new(interpreter.create_function(Rc::new(codegen.pop_init_sig())));
+        let calls = Rc::new(Cell::new(0));
+        interpreter.set_hook(Some(Box::new(Calls(calls.clone()))));
+        interpreter.start_profile();
+        assert!(interpreter.call_function_args(main, []).is_err());
+        let profile = interpreter.take_profile().unwrap();
+        // the profile runs alongside the hook, and the error ends every call it unwound
+        assert_eq!(calls.get(), 3);
+        assert!(profile.calls.is_empty());
+        let functions = profile.functions(&parser.sources());
+        assert!(functions.iter().all(|function| function.calls == 1));
+    }
+}
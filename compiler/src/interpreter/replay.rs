@@ -0,0 +1,820 @@
+use std::{
+    cell::RefCell,
+    collections::BTreeMap,
+    io::{self, Read, Write},
+    rc::Rc,
+};
+
+use crate::{
+    error::{Error, ErrorKind},
+    interpreter::{
+        bytecode::Bytecode,
+        hook::Hook,
+        isolate::SendValue,
+        value::{FromValue, Function, IntoValue, Value},
+        BuiltinFn, Interpreter,
+    },
+    source_map::SourceMap,
+    span::Span,
+    vm::Vm,
+};
+
+// instructions between checkpoints
+const CHECKPOINT_INTERVAL: u64 = 0x1000;
+const HEADER: &[u8] = b"rlox-recording 1\n";
+
+/// State of the interpreter every `CHECKPOINT_INTERVAL` instructions, which a replay has to match to be in sync.
+/// Replays also save the whole interpreter there to resume from later, see `Replay`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Checkpoint {
+    pub instruction: u64,
+    pub frames: usize,
+    pub stack: usize,
+    /// Inputs read so far.
+    pub inputs: usize,
+}
+
+/// Results of the input builtins a run called, in order, along with checkpoints of the run.
+/// See `Interpreter::start_recording` and `Interpreter::mark_input`.
+#[derive(Debug, Clone, Default)]
+pub struct Recording {
+    inputs: Vec<Result<SendValue, String>>, // errors by their message
+    checkpoints: Vec<Checkpoint>,
+    instructions: u64,
+}
+
+// builtin results being recorded or replayed, see `Interpreter::call_native`
+pub(super) struct InputLog {
+    recording: Recording,
+    replaying: bool,
+    next_input: usize,
+    next_checkpoint: usize,
+    instructions: u64,
+}
+impl InputLog {
+    fn new(recording: Recording, replaying: bool) -> Self {
+        Self {
+            recording,
+            replaying,
+            next_input: 0,
+            next_checkpoint: 0,
+            instructions: 0,
+        }
+    }
+    /// Counts an instruction, taking a checkpoint or checking against the recorded one when it's time.
+    pub(super) fn instruction(&mut self, frames: usize, stack: usize) -> Result<(), ErrorKind> {
+        self.instructions += 1;
+        if !self.instructions.is_multiple_of(CHECKPOINT_INTERVAL) {
+            return Ok(());
+        }
+        let checkpoint = Checkpoint {
+            instruction: self.instructions,
+            frames,
+            stack,
+            inputs: self.next_input,
+        };
+        if !self.replaying {
+            self.recording.checkpoints.push(checkpoint);
+            return Ok(());
+        }
+        let recorded = self.recording.checkpoints.get(self.next_checkpoint);
+        self.next_checkpoint += 1;
+        match recorded {
+            Some(recorded) if *recorded != checkpoint => {
+                Err(ErrorKind::ReplayDiverged(self.instructions))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Interpreter {
+    /// Marks the builtin as reading input from outside the script, such as the time, random numbers or files.
+    /// Its results are recorded while recording, and taken from the recording instead of calling it while replaying.
+    /// Builtins with effects outside the script, such as writing files, are marked too so replays don't repeat them.
+    ///
+    /// Input builtins should return plain data and not call back into the script, since replays skip their calls.
+    pub fn mark_input(&mut self, function: &Rc<Function>) {
+        // functions made at runtime, such as iterators over files, are forgotten once dropped
+        self.inputs.retain(|input| input.strong_count() > 0);
+        self.inputs.push(Rc::downgrade(&function.signature));
+    }
+    fn is_input(&self, function: &Function) -> bool {
+        let signature = Rc::as_ptr(&function.signature);
+        self.inputs.iter().any(|input| input.as_ptr() == signature)
+    }
+    /// Starts recording the inputs of everything run from now on, see `mark_input`.
+    pub fn start_recording(&mut self) {
+        self.input_log = Some(Box::new(InputLog::new(Recording::default(), false)));
+    }
+    /// Stops recording and returns what was recorded.
+    pub fn take_recording(&mut self) -> Option<Recording> {
+        let log = self.input_log.take().filter(|log| !log.replaying)?;
+        Some(Recording {
+            instructions: log.instructions,
+            ..log.recording
+        })
+    }
+    /// Runs everything from now on with the inputs of `recording`, failing with `ErrorKind::ReplayDiverged` where the
+    /// run stops matching it.
+    pub fn start_replay(&mut self, recording: Recording) {
+        self.input_log = Some(Box::new(InputLog::new(recording, true)));
+    }
+    /// Like `start_replay`, for a run restored from a snapshot taken right before the instruction of the recording's
+    /// checkpoint at `checkpoint` ran, which `resume` continues.
+    pub fn start_replay_at(&mut self, recording: Recording, checkpoint: usize) {
+        let Checkpoint {
+            instruction,
+            inputs,
+            ..
+        } = recording.checkpoints[checkpoint];
+        let mut log = InputLog::new(recording, true);
+        // the instruction is counted and checked against the checkpoint again once it runs
+        log.instructions = instruction - 1;
+        log.next_input = inputs;
+        log.next_checkpoint = checkpoint;
+        self.input_log = Some(Box::new(log));
+    }
+    pub fn stop_replay(&mut self) {
+        self.input_log = None;
+    }
+    /// Instructions run since recording or replaying started.
+    pub fn replay_position(&self) -> Option<u64> {
+        self.input_log.as_ref().map(|log| log.instructions)
+    }
+    /// Calls the builtin, unless it's an input being replayed.
+    pub(super) fn call_builtin(
+        &mut self,
+        function: &Function,
+        builtin: &RefCell<BuiltinFn>,
+    ) -> Result<Value, ErrorKind> {
+        if self.input_log.is_none() || !self.is_input(function) {
+            return builtin.borrow_mut()(self);
+        }
+        self.input(|interpreter| builtin.borrow_mut()(interpreter))
+    }
+    /// Runs `read` as an input, recording its result while recording and taking it from the recording instead of
+    /// running it while replaying. For builtins whose results can't be recorded, such as iterators, to record the
+    /// plain data they read.
+    pub(super) fn input(
+        &mut self,
+        read: impl FnOnce(&mut Self) -> Result<Value, ErrorKind>,
+    ) -> Result<Value, ErrorKind> {
+        let Some(log) = &self.input_log else {
+            return read(self);
+        };
+        if log.replaying {
+            let log = self.input_log.as_mut().unwrap();
+            let input = log.recording.inputs.get(log.next_input).cloned();
+            log.next_input += 1;
+            return match input {
+                Some(Ok(value)) => Ok(value.into_value()),
+                Some(Err(message)) => Err(ErrorKind::RuntimeError(message)),
+                None => Err(ErrorKind::ReplayDiverged(log.instructions)),
+            };
+        }
+        let result = read(self);
+        let input = match &result {
+            Ok(value) => Ok(SendValue::from_value(value.clone())?),
+            Err(err) => Err(err.to_string()),
+        };
+        // recording may have been stopped by the builtin
+        if let Some(log) = &mut self.input_log {
+            log.recording.inputs.push(input);
+            log.next_input += 1;
+        }
+        result
+    }
+}
+
+impl Recording {
+    /// Instructions the recorded run took.
+    pub fn instructions(&self) -> u64 {
+        self.instructions
+    }
+    pub fn inputs(&self) -> usize {
+        self.inputs.len()
+    }
+    pub fn checkpoints(&self) -> &[Checkpoint] {
+        &self.checkpoints
+    }
+    /// Writes the recording in a compact text format, read back by `Recording::read`. Channels can't be written.
+    pub fn write(&self, out: &mut impl Write) -> io::Result<()> {
+        out.write_all(HEADER)?;
+        writeln!(out, "{}", self.instructions)?;
+        for input in &self.inputs {
+            match input {
+                Ok(value) => {
+                    out.write_all(b"i")?;
+                    write_value(value, out)?;
+                }
+                Err(message) => write!(out, "e{}:{message}", message.len())?,
+            }
+            writeln!(out)?;
+        }
+        for checkpoint in &self.checkpoints {
+            let Checkpoint {
+                instruction,
+                frames,
+                stack,
+                inputs,
+            } = checkpoint;
+            writeln!(out, "c{instruction};{frames};{stack};{inputs};")?;
+        }
+        Ok(())
+    }
+    pub fn read(input: &mut impl Read) -> io::Result<Self> {
+        let mut bytes = vec![];
+        input.read_to_end(&mut bytes)?;
+        let mut reader = Reader(bytes.strip_prefix(HEADER).ok_or_else(invalid)?);
+        let instructions = reader.number(b'\n')?;
+        let mut recording = Recording {
+            instructions,
+            ..Default::default()
+        };
+        while let Some((&kind, rest)) = reader.0.split_first() {
+            reader.0 = rest;
+            match kind {
+                b'i' => recording.inputs.push(Ok(reader.value()?)),
+                b'e' => recording.inputs.push(Err(reader.string()?)),
+                b'c' => recording.checkpoints.push(Checkpoint {
+                    instruction: reader.number(b';')?,
+                    frames: reader.number(b';')?,
+                    stack: reader.number(b';')?,
+                    inputs: reader.number(b';')?,
+                }),
+                _ => return Err(invalid()),
+            }
+            reader.expect(b'\n')?;
+        }
+        Ok(recording)
+    }
+}
+
+// values are written as `n`, `t`, `f`, `d<bits>;`, `s<len>:<utf8>`, `a<len>;<items>` and `o<len>;<key value
+// pairs><base>`, the base being `-` or a value
+fn write_value(value: &SendValue, out: &mut impl Write) -> io::Result<()> {
+    match value {
+        SendValue::Nil => write!(out, "n"),
+        SendValue::Bool(true) => write!(out, "t"),
+        SendValue::Bool(false) => write!(out, "f"),
+        SendValue::Number(num) => write!(out, "d{:x};", num.to_bits()),
+        SendValue::String(str) => write!(out, "s{}:{str}", str.len()),
+        SendValue::Array(items) => {
+            write!(out, "a{};", items.len())?;
+            items.iter().try_for_each(|item| write_value(item, out))
+        }
+        SendValue::Object { entries, base } => {
+            write!(out, "o{};", entries.len())?;
+            for (key, value) in entries {
+                write_value(key, out)?;
+                write_value(value, out)?;
+            }
+            match base {
+                Some(base) => write_value(base, out),
+                None => write!(out, "-"),
+            }
+        }
+        SendValue::Channel(_) => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            ErrorKind::Unsendable("channel").to_string(),
+        )),
+    }
+}
+
+fn invalid() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "Invalid recording")
+}
+
+struct Reader<'a>(&'a [u8]);
+impl Reader<'_> {
+    fn take(&mut self, len: usize) -> io::Result<&[u8]> {
+        if self.0.len() < len {
+            return Err(invalid());
+        }
+        let (taken, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(taken)
+    }
+    fn expect(&mut self, byte: u8) -> io::Result<()> {
+        match self.take(1)? == [byte] {
+            true => Ok(()),
+            false => Err(invalid()),
+        }
+    }
+    fn until(&mut self, end: u8) -> io::Result<&str> {
+        let len = self.0.iter().position(|&b| b == end).ok_or_else(invalid)?;
+        let text = std::str::from_utf8(&self.0[..len]).map_err(|_| invalid())?;
+        self.0 = &self.0[len + 1..];
+        Ok(text)
+    }
+    fn number<T: std::str::FromStr>(&mut self, end: u8) -> io::Result<T> {
+        self.until(end)?.parse().map_err(|_| invalid())
+    }
+    fn string(&mut self) -> io::Result<String> {
+        let len = self.number(b':')?;
+        let bytes = self.take(len)?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| invalid())
+    }
+    fn value(&mut self) -> io::Result<SendValue> {
+        Ok(match self.take(1)?[0] {
+            b'n' => SendValue::Nil,
+            b't' => SendValue::Bool(true),
+            b'f' => SendValue::Bool(false),
+            b'd' => {
+                let bits = u64::from_str_radix(self.until(b';')?, 16).map_err(|_| invalid())?;
+                SendValue::Number(f64::from_bits(bits))
+            }
+            b's' => SendValue::String(self.string()?),
+            b'a' => {
+                let len = self.number(b';')?;
+                SendValue::Array((0..len).map(|_| self.value()).collect::<io::Result<_>>()?)
+            }
+            b'o' => {
+                let len = self.number(b';')?;
+                let entries = (0..len)
+                    .map(|_| Ok((self.value()?, self.value()?)))
+                    .collect::<io::Result<_>>()?;
+                let base = match self.0.first() {
+                    Some(b'-') => {
+                        self.take(1)?;
+                        None
+                    }
+                    _ => Some(Box::new(self.value()?)),
+                };
+                SendValue::Object { entries, base }
+            }
+            _ => return Err(invalid()),
+        })
+    }
+}
+
+type Setup = dyn Fn(&mut Vm);
+type Paused = dyn FnOnce(&mut Interpreter, &SourceMap);
+// snapshots by the index of the checkpoint they were taken at, None where the state couldn't be written
+type States = Rc<RefCell<BTreeMap<usize, Option<Rc<[u8]>>>>>;
+
+/// Time travel over a recorded run of a script, which is replayed in a fresh VM to get to any point.
+///
+/// Replays save a snapshot of the interpreter at every checkpoint they pass, and later ones resume from the nearest
+/// snapshot before where they go instead of from the start. Checkpoints where the state can't be saved, such as
+/// while a builtin like `map` calls back into the script or while an iterator is on the stack, are skipped. The
+/// snapshots are kept in memory for as long as the replay.
+///
+/// Only input builtins are skipped, which include those of `fs` changing files. Others run again along with their
+/// side effects, except for script output, so native builtins with effects should be marked as inputs.
+pub struct Replay {
+    name: String,
+    source: String,
+    recording: Recording,
+    setup: Option<Box<Setup>>,
+    states: States,
+}
+impl Replay {
+    /// Replay of `source`, whose errors point into a file named `name`, as it ran when `recording` was made.
+    pub fn new(name: impl Into<String>, source: impl Into<String>, recording: Recording) -> Self {
+        Self {
+            name: name.into(),
+            source: source.into(),
+            recording,
+            setup: None,
+            states: States::default(),
+        }
+    }
+    /// Prepares every VM before the script runs, e.g. to register and mark the native inputs it was recorded with.
+    pub fn with_setup(mut self, setup: impl Fn(&mut Vm) + 'static) -> Self {
+        self.setup = Some(Box::new(setup));
+        self
+    }
+    pub fn recording(&self) -> &Recording {
+        &self.recording
+    }
+    /// Checkpoint with the last saved state before `instruction` runs.
+    fn nearest_state(&self, instruction: u64) -> Option<usize> {
+        let end = self
+            .recording
+            .checkpoints
+            .partition_point(|checkpoint| checkpoint.instruction <= instruction);
+        let states = self.states.borrow();
+        let mut saved = states.range(..end).filter(|(_, state)| state.is_some());
+        saved.next_back().map(|(&checkpoint, _)| checkpoint)
+    }
+    /// Replays from the state saved at checkpoint `from`, or from the start without one, with the hook made for the
+    /// VM until it aborts or the script ends. Returns the VM in the state it stopped in.
+    fn replay(
+        &self,
+        from: Option<usize>,
+        hook: impl FnOnce(&Vm) -> Box<dyn Hook>,
+    ) -> (Vm, Result<(), Error>) {
+        let mut vm = Vm::new();
+        if let Some(setup) = &self.setup {
+            setup(&mut vm);
+        }
+        // the output was seen when recording
+        vm.interpreter().capture_stdout();
+        let hook = Save {
+            hook: hook(&vm),
+            checkpoints: self.recording.checkpoints.clone(),
+            states: self.states.clone(),
+            sources: vm.sources().clone(),
+        };
+        vm.interpreter().set_hook(Some(Box::new(hook)));
+        let state = from.and_then(|from| self.states.borrow()[&from].clone());
+        let result = match (from, state) {
+            (Some(from), Some(state)) => {
+                let sources = vm.sources().clone();
+                match vm.interpreter().restore(&sources, &mut &*state) {
+                    Ok(()) => {
+                        vm.interpreter()
+                            .start_replay_at(self.recording.clone(), from);
+                        vm.resume()
+                    }
+                    Err(err) => Err(Error::new(err.into(), Span::default(), sources)),
+                }
+            }
+            _ => {
+                vm.interpreter().start_replay(self.recording.clone());
+                vm.eval_file(&self.name, &self.source)
+            }
+        };
+        vm.interpreter().set_hook(None);
+        vm.interpreter().stop_replay();
+        (vm, result.map(|_| ()))
+    }
+    /// Replays until right before instruction `instruction` runs, counted from 1, and calls `paused` with the
+    /// interpreter and the sources there. Returns whether the run got there.
+    pub fn run_to(
+        &self,
+        instruction: u64,
+        paused: impl FnOnce(&mut Interpreter, &SourceMap) + 'static,
+    ) -> Result<bool, Error> {
+        let reached = Rc::new(RefCell::new(false));
+        let (_, result) = self.replay(self.nearest_state(instruction), |vm| {
+            Box::new(Stop {
+                instruction,
+                paused: Some(Box::new(paused)),
+                sources: vm.sources().clone(),
+                reached: reached.clone(),
+            })
+        });
+        let reached = *reached.borrow();
+        match result {
+            Err(err) if reached && matches!(err.kind, ErrorKind::Interrupted) => Ok(true),
+            Ok(()) => Ok(reached),
+            Err(err) => Err(err),
+        }
+    }
+    /// The last instruction before `before` that assigns a variable named `name`, be it a global, a local or an
+    /// upvalue.
+    pub fn previous_write(&self, name: &str, before: u64) -> Result<Option<u64>, Error> {
+        // from the nearest saved state first, going further back while there's no write
+        let mut before = before;
+        loop {
+            let from = self.nearest_state(before.saturating_sub(1));
+            let last = Rc::new(RefCell::new(None));
+            let hook = Watch {
+                name: name.to_owned(),
+                before,
+                last: last.clone(),
+            };
+            match self.replay(from, |_| Box::new(hook)).1 {
+                Err(err) if !matches!(err.kind, ErrorKind::Interrupted) => return Err(err),
+                _ => {}
+            }
+            match (last.take(), from) {
+                (None, Some(from)) => before = self.recording.checkpoints[from].instruction,
+                (last, _) => return Ok(last),
+            }
+        }
+    }
+}
+
+// saves the state at checkpoints that have none yet, then runs the hook of the replay, which only watches instructions
+struct Save {
+    hook: Box<dyn Hook>,
+    checkpoints: Vec<Checkpoint>,
+    states: States,
+    sources: SourceMap, // of the VM being replayed
+}
+impl Hook for Save {
+    fn on_instruction(
+        &mut self,
+        interpreter: &mut Interpreter,
+        frame: usize,
+        index: usize,
+        bytecode: &Bytecode,
+        span: Span,
+    ) -> Result<(), ErrorKind> {
+        let position = interpreter.replay_position().unwrap_or_default();
+        let checkpoint = self
+            .checkpoints
+            .binary_search_by_key(&position, |checkpoint| checkpoint.instruction);
+        if let Ok(checkpoint) = checkpoint {
+            let mut states = self.states.borrow_mut();
+            states.entry(checkpoint).or_insert_with(|| {
+                let mut state = vec![];
+                interpreter
+                    .snapshot(&self.sources, &mut state)
+                    .ok()
+                    .map(|_| state.into())
+            });
+        }
+        self.hook
+            .on_instruction(interpreter, frame, index, bytecode, span)
+    }
+}
+
+// pauses the replay at an instruction, then aborts it
+struct Stop {
+    instruction: u64,
+    paused: Option<Box<Paused>>,
+    sources: SourceMap, // of the VM being replayed
+    reached: Rc<RefCell<bool>>,
+}
+impl Hook for Stop {
+    fn on_instruction(
+        &mut self,
+        interpreter: &mut Interpreter,
+        _: usize,
+        _: usize,
+        _: &Bytecode,
+        _: Span,
+    ) -> Result<(), ErrorKind> {
+        if interpreter.replay_position() < Some(self.instruction) {
+            return Ok(());
+        }
+        *self.reached.borrow_mut() = true;
+        if let Some(paused) = self.paused.take() {
+            paused(interpreter, &self.sources);
+        }
+        Err(ErrorKind::Interrupted)
+    }
+}
+
+// remembers the last instruction that stores to a variable
+struct Watch {
+    name: String,
+    before: u64,
+    last: Rc<RefCell<Option<u64>>>,
+}
+impl Hook for Watch {
+    fn on_instruction(
+        &mut self,
+        interpreter: &mut Interpreter,
+        frame: usize,
+        index: usize,
+        bytecode: &Bytecode,
+        _: Span,
+    ) -> Result<(), ErrorKind> {
+        let position = interpreter.replay_position().unwrap_or_default();
+        if position >= self.before {
+            return Err(ErrorKind::Interrupted);
+        }
+        let function = interpreter.frame_function(frame);
+        let debug = &function.signature.debug;
+        let matches = match bytecode {
+            Bytecode::StoreGlobal(slot) => {
+                interpreter.context().borrow().globals.name(*slot).as_str() == self.name
+            }
+            Bytecode::StoreLocal(id) => debug.locals.iter().any(|local| {
+                local.slot == *id && local.live.contains(&index) && local.name.as_str() == self.name
+            }),
+            Bytecode::StoreUpvalue(id) => debug
+                .upvalues
+                .get(*id)
+                .is_some_and(|name| name.as_str() == self.name),
+            _ => false,
+        };
+        if matches {
+            *self.last.borrow_mut() = Some(position);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use super::*;
+
+    // a clock that never returns the same time twice, and a builtin failing with its argument
+    fn setup_inputs(vm: &mut Vm, start: f64) {
+        let time = Cell::new(start);
+        vm.register_fn("clock", move || {
+            time.set(time.get() + 1.0);
+            time.get()
+        });
+        vm.register_fn("fail", |n: f64| -> Result<Value, ErrorKind> {
+            Err(ErrorKind::RuntimeError(format!("failed at {n}")))
+        });
+        for name in ["clock", "fail"] {
+            let function = vm.get_global(name).try_function().unwrap();
+            vm.interpreter().mark_input(&function);
+        }
+    }
+
+    struct Unhooked;
+    impl Hook for Unhooked {}
+
+    const SOURCE: &str = "\
+let total = 0
+for i in range(0, 3, 1) do
+    total = total + clock()
+end
+let failed = assert_error(\\ -> fail(clock()))
+total
+";
+
+    #[test]
+    fn test_record_replay() {
+        let mut vm = Vm::new();
+        setup_inputs(&mut vm, 100.0);
+        vm.interpreter().start_recording();
+        let total = vm.eval_file("clock.rlox", SOURCE).unwrap();
+        let recording = vm.interpreter().take_recording().unwrap();
+        assert_eq!(total, Value::Number(101.0 + 102.0 + 103.0));
+        assert_eq!(recording.inputs(), 5);
+
+        let mut written = vec![];
+        recording.write(&mut written).unwrap();
+        let recording = Recording::read(&mut written.as_slice()).unwrap();
+        assert_eq!(recording.inputs(), 5);
+        assert!(Recording::read(&mut &written[1..]).is_err());
+
+        // a clock starting elsewhere still replays the recorded times and errors
+        let replay = Replay::new("clock.rlox", SOURCE, recording.clone())
+            .with_setup(|vm| setup_inputs(vm, 0.0));
+        let (vm, result) = replay.replay(None, |_| Box::new(Unhooked));
+        result.unwrap();
+        assert_eq!(vm.get_global("total"), Value::Number(306.0));
+        assert_eq!(vm.get_global("failed").to_string(), "failed at 104");
+
+        // going back to the writes of `total`
+        let end = recording.instructions();
+        let write = replay.previous_write("total", end).unwrap().unwrap();
+        let before = replay.previous_write("total", write).unwrap().unwrap();
+        assert!(before < write);
+        assert_eq!(replay.previous_write("unknown", end).unwrap(), None);
+        let total = Rc::new(RefCell::new(Value::Nil));
+        let seen = total.clone();
+        let reached = replay.run_to(write + 1, move |interpreter, _| {
+            let globals = interpreter.globals();
+            let (_, value) = globals
+                .iter()
+                .find(|(name, _)| name.as_str() == "total")
+                .unwrap();
+            *seen.borrow_mut() = value.clone();
+        });
+        assert!(reached.unwrap());
+        assert_eq!(*total.borrow(), Value::Number(306.0));
+        assert!(!replay.run_to(end + 1, |_, _| ()).unwrap());
+
+        // paused on the assignment itself
+        let line = Rc::new(Cell::new(0));
+        let seen = line.clone();
+        let reached = replay.run_to(write, move |interpreter, sources| {
+            let span = interpreter
+                .frame_span(interpreter.frame_count() - 1)
+                .unwrap();
+            seen.set(sources.line_col(span.file, span.start).0);
+        });
+        assert!(reached.unwrap());
+        assert_eq!(line.get(), 3);
+    }
+
+    #[test]
+    fn test_checkpoints() {
+        let source = "\
+let x = 1
+fn count(limit) do
+    let n = 0
+    let step = \\ -> n = n + 1
+    while n < limit do
+        step()
+        n = n + clock() * 0
+    end
+    return n
+end
+let total = count(1000)
+";
+        let mut vm = Vm::new();
+        setup_inputs(&mut vm, 0.0);
+        vm.interpreter().start_recording();
+        vm.eval_file("count.rlox", source).unwrap();
+        let recording = vm.interpreter().take_recording().unwrap();
+        let end = recording.instructions();
+        assert!(recording.checkpoints().len() >= 3);
+        let replay = Replay::new("count.rlox", source, recording.clone())
+            .with_setup(|vm| setup_inputs(vm, 0.0));
+
+        // `n` where the replay paused, and the instructions the VM ran to get there
+        let paused = |replay: &Replay, instruction| {
+            let seen = Rc::new(Cell::new((Value::Nil, 0)));
+            let shown = seen.clone();
+            let reached = replay.run_to(instruction, move |interpreter, _| {
+                let frame = interpreter.frame_count() - 1;
+                let (_, n) = interpreter
+                    .frame_locals(frame)
+                    .into_iter()
+                    .find(|(name, _)| name.as_str() == "n")
+                    .unwrap();
+                shown.set((n, interpreter.instruction_count()));
+            });
+            assert!(reached.unwrap());
+            seen.take()
+        };
+        let middle = recording.checkpoints()[1].instruction + 100;
+        let (first, from_start) = paused(&replay, middle);
+        assert_eq!(from_start, middle);
+        assert!(!replay.states.borrow().is_empty());
+        // resumed at the checkpoint before, with the upvalue still shared with the closure
+        let (n, resumed) = paused(&replay, middle);
+        assert_eq!(n, first);
+        assert_eq!(resumed, 101);
+        let (_, resumed) = paused(&replay, middle + 20);
+        assert_eq!(resumed, 121);
+        let (n, _) = paused(&replay, end - 10);
+        assert_eq!(n, Value::Number(1000.0));
+
+        // writes before the nearest saved state are found further back
+        let x = replay.previous_write("x", end).unwrap().unwrap();
+        let fresh =
+            Replay::new("count.rlox", source, recording).with_setup(|vm| setup_inputs(vm, 0.0));
+        assert_eq!(fresh.previous_write("x", end).unwrap(), Some(x));
+        assert!(x < CHECKPOINT_INTERVAL);
+        let n = replay.previous_write("n", end).unwrap().unwrap();
+        assert_eq!(fresh.previous_write("n", end).unwrap(), Some(n));
+    }
+
+    #[test]
+    fn test_replay_effects() {
+        let path = std::env::temp_dir().join(format!("rlox-replay-{}", std::process::id()));
+        let source = format!(
+            "let path = {:?}\nfs.write(path, \"recorded\")\nlet contents = fs.read(path)\nfs.remove(path)",
+            path.display().to_string()
+        );
+        let mut vm = Vm::new();
+        vm.interpreter().start_recording();
+        vm.eval_file("effects.rlox", &source).unwrap();
+        let recording = vm.interpreter().take_recording().unwrap();
+
+        // the file is neither written nor removed again, yet the replay reads what the run read
+        std::fs::write(&path, "untouched").unwrap();
+        let replay = Replay::new("effects.rlox", source, recording);
+        let (vm, result) = replay.replay(None, |_| Box::new(Unhooked));
+        let contents = std::fs::read_to_string(&path);
+        let _ = std::fs::remove_file(&path);
+        result.unwrap();
+        assert_eq!(contents.unwrap(), "untouched");
+        assert_eq!(vm.get_global("contents").to_string(), "recorded");
+    }
+
+    #[test]
+    fn test_replay_read_lines() {
+        let path = std::env::temp_dir().join(format!("rlox-lines-{}", std::process::id()));
+        let source = format!(
+            "let path = {:?}\nfs.write(path, \"a\\nb\\n\")\nlet lines = []\n\
+             for line in fs.read_lines(path) do lines = lines + [line] end\nfs.remove(path)\n\
+             let missing = assert_error(\\ -> fs.read_lines(path))",
+            path.display().to_string()
+        );
+        let mut vm = Vm::new();
+        vm.interpreter().start_recording();
+        vm.eval_file("lines.rlox", &source).unwrap();
+        let recording = vm.interpreter().take_recording().unwrap();
+        assert!(!path.exists());
+
+        // the file is gone, yet the replay reads its lines and fails to open it again like the run did
+        let replay = Replay::new("lines.rlox", source, recording);
+        let (vm, result) = replay.replay(None, |_| Box::new(Unhooked));
+        result.unwrap();
+        assert!(!path.exists());
+        assert_eq!(vm.get_global("lines").to_string(), "[a, b]");
+        let missing = vm.get_global("missing").to_string();
+        assert!(missing.contains(&path.display().to_string()));
+    }
+
+    #[test]
+    fn test_divergence() {
+        let mut vm = Vm::new();
+        setup_inputs(&mut vm, 0.0);
+        vm.interpreter().start_recording();
+        vm.eval("let n = 0\nwhile n < 2000 do n = n + 1 + clock() * 0 end")
+            .unwrap();
+        let recording = vm.interpreter().take_recording().unwrap();
+        assert!(!recording.checkpoints().is_empty());
+
+        // a script that does more per input falls out of sync
+        let source = "let n = 0\nwhile n < 2000 do n = n + 1 + clock() * 0 + 0 end";
+        let replay =
+            Replay::new("loop.rlox", source, recording).with_setup(|vm| setup_inputs(vm, 0.0));
+        assert!(matches!(
+            replay.run_to(u64::MAX, |_, _| ()),
+            Err(Error {
+                kind: ErrorKind::ReplayDiverged(_),
+                ..
+            })
+        ));
+    }
+}
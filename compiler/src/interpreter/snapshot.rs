@@ -0,0 +1,1022 @@
+use std::{
+    cell::RefCell,
+    io::{self, Read, Write},
+    ops::Range,
+    rc::Rc,
+};
+
+use rustc_hash::FxHashMap;
+
+use crate::{
+    interpreter::{
+        bytecode::{BinaryOp, Bytecode, UnaryOp},
+        debug::{DebugInfo, LocalName},
+        string::ValueStr,
+        value::{Function, Object, Value},
+        Cell, FnBody, FnSignature, FunctionFrame, Interpreter, UpvalueLoc,
+    },
+    source_map::SourceMap,
+    span::{FileId, Span, SpanOf},
+};
+
+const HEADER: &[u8] = b"rlox-snapshot 2\n";
+// arrays, objects and functions nested deeper are refused rather than overflowing the stack
+const MAX_DEPTH: usize = 512;
+
+// tags of values
+const NIL: u8 = 0;
+const FALSE: u8 = 1;
+const TRUE: u8 = 2;
+const NUMBER: u8 = 3;
+const STRING: u8 = 4;
+const SHARED: u8 = 5; // array, object or function written before, by id
+const ARRAY: u8 = 6;
+const OBJECT: u8 = 7;
+const FUNCTION: u8 = 8;
+const BUILTIN: u8 = 9; // by name among the builtins of the context
+const NATIVE: u8 = 10; // by name, as defined as a global
+
+const BINARY_OPS: [BinaryOp; 18] = [
+    BinaryOp::Add,
+    BinaryOp::Sub,
+    BinaryOp::Mul,
+    BinaryOp::Div,
+    BinaryOp::Rem,
+    BinaryOp::Pow,
+    BinaryOp::Shl,
+    BinaryOp::Shr,
+    BinaryOp::Sha,
+    BinaryOp::BitAnd,
+    BinaryOp::BitOr,
+    BinaryOp::BitXor,
+    BinaryOp::SetEq,
+    BinaryOp::SetNe,
+    BinaryOp::SetLt,
+    BinaryOp::SetLe,
+    BinaryOp::SetGt,
+    BinaryOp::SetGe,
+];
+const UNARY_OPS: [UnaryOp; 4] = [
+    UnaryOp::Negate,
+    UnaryOp::Swap,
+    UnaryOp::SetTrue,
+    UnaryOp::SetFalse,
+];
+
+fn invalid() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "Invalid snapshot")
+}
+fn too_deep() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Values nested deeper than {MAX_DEPTH} levels cannot be saved in a snapshot"),
+    )
+}
+
+/// Writes the heap reachable from the globals. Arrays, objects, functions, signatures and upvalue cells are written
+/// once and referred to by id afterwards, in the order they are first reached. So are the files spans point into.
+struct Writer<'a, W> {
+    interpreter: &'a Interpreter,
+    out: &'a mut W,
+    values: FxHashMap<*const (), usize>,
+    cells: FxHashMap<*const RefCell<Value>, usize>,
+    signatures: FxHashMap<*const FnSignature, usize>,
+    files: FxHashMap<FileId, usize>,
+    depth: usize, // arrays, objects and functions being written
+}
+impl<'a, W: Write> Writer<'a, W> {
+    fn new(interpreter: &'a Interpreter, out: &'a mut W) -> Self {
+        Self {
+            interpreter,
+            out,
+            values: FxHashMap::default(),
+            cells: FxHashMap::default(),
+            signatures: FxHashMap::default(),
+            files: FxHashMap::default(),
+            depth: 0,
+        }
+    }
+    fn byte(&mut self, byte: u8) -> io::Result<()> {
+        self.out.write_all(&[byte])
+    }
+    fn number(&mut self, number: usize) -> io::Result<()> {
+        self.out.write_all(&(number as u64).to_le_bytes())
+    }
+    fn offset(&mut self, offset: isize) -> io::Result<()> {
+        self.out.write_all(&(offset as i64).to_le_bytes())
+    }
+    fn string(&mut self, str: &str) -> io::Result<()> {
+        self.number(str.len())?;
+        self.out.write_all(str.as_bytes())
+    }
+    fn span(&mut self, span: Span) -> io::Result<()> {
+        let len = self.files.len();
+        let file = *self.files.entry(span.file).or_insert(len);
+        self.number(file)?;
+        self.number(span.start)?;
+        self.number(span.end)
+    }
+    fn range(&mut self, range: &Range<usize>) -> io::Result<()> {
+        self.number(range.start)?;
+        self.number(range.end)
+    }
+    /// Writes a reference to the array, object or function if it was written before, otherwise gives it an id.
+    fn shared(&mut self, ptr: *const ()) -> io::Result<bool> {
+        if let Some(&id) = self.values.get(&ptr) {
+            self.byte(SHARED)?;
+            self.number(id)?;
+            return Ok(true);
+        }
+        self.values.insert(ptr, self.values.len());
+        Ok(false)
+    }
+    /// Runs `f` one level deeper, failing past `MAX_DEPTH`.
+    fn nested(&mut self, f: impl FnOnce(&mut Self) -> io::Result<()>) -> io::Result<()> {
+        if self.depth >= MAX_DEPTH {
+            return Err(too_deep());
+        }
+        self.depth += 1;
+        let result = f(self);
+        self.depth -= 1;
+        result
+    }
+    fn value(&mut self, value: &Value) -> io::Result<()> {
+        match value {
+            Value::Nil => self.byte(NIL),
+            Value::Bool(false) => self.byte(FALSE),
+            Value::Bool(true) => self.byte(TRUE),
+            Value::Number(num) => {
+                self.byte(NUMBER)?;
+                self.out.write_all(&num.to_bits().to_le_bytes())
+            }
+            Value::String(str) => {
+                self.byte(STRING)?;
+                self.string(str.as_str())
+            }
+            Value::Array(arr) => {
+                if self.shared(Rc::as_ptr(arr) as *const ())? {
+                    return Ok(());
+                }
+                let arr = arr.borrow();
+                self.byte(ARRAY)?;
+                self.number(arr.len())?;
+                self.nested(|this| arr.iter().try_for_each(|item| this.value(item)))
+            }
+            Value::Object(obj) => {
+                if self.shared(Rc::as_ptr(obj) as *const ())? {
+                    return Ok(());
+                }
+                let obj = obj.borrow();
+                self.byte(OBJECT)?;
+                self.byte(obj.read_only as u8)?;
+                self.number(obj.map.len())?;
+                self.nested(|this| {
+                    for (key, value) in obj.map.iter() {
+                        this.value(key)?;
+                        this.value(value)?;
+                    }
+                    match &obj.base_obj {
+                        Some(base) => this.value(&Value::Object(base.clone())),
+                        None => this.byte(NIL),
+                    }
+                })
+            }
+            Value::Function(function) => self.function(function),
+            Value::Native(native) => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Value of type `{}` cannot be saved in a snapshot",
+                    native.type_name()
+                ),
+            )),
+        }
+    }
+    fn function(&mut self, function: &Rc<Function>) -> io::Result<()> {
+        if let FnBody::Builtin(_) = function.signature.body {
+            return self.builtin(function);
+        }
+        if self.shared(Rc::as_ptr(function) as *const ())? {
+            return Ok(());
+        }
+        self.byte(FUNCTION)?;
+        self.nested(|this| this.closure(function))
+    }
+    fn closure(&mut self, function: &Rc<Function>) -> io::Result<()> {
+        self.signature(&function.signature)?;
+        // cells come before their contents, which may refer back to the function
+        self.number(function.upvalues.len())?;
+        let mut pending = vec![];
+        for cell in &function.upvalues {
+            let ptr = Rc::as_ptr(cell);
+            match self.cells.get(&ptr) {
+                Some(&id) => {
+                    self.byte(SHARED)?;
+                    self.number(id)?;
+                }
+                None => {
+                    self.cells.insert(ptr, self.cells.len());
+                    self.byte(NIL)?;
+                    pending.push(cell);
+                }
+            }
+        }
+        pending
+            .into_iter()
+            .try_for_each(|cell| self.value(&cell.borrow()))
+    }
+    /// Writes a cell of a frame's memory, with its contents unless it was written before.
+    fn cell(&mut self, cell: &Rc<RefCell<Value>>) -> io::Result<()> {
+        let ptr = Rc::as_ptr(cell);
+        if let Some(&id) = self.cells.get(&ptr) {
+            self.byte(SHARED)?;
+            return self.number(id);
+        }
+        self.cells.insert(ptr, self.cells.len());
+        self.byte(NIL)?;
+        self.value(&cell.borrow())
+    }
+    /// Writes the stack, memory and frames of the running call.
+    fn running(&mut self) -> io::Result<()> {
+        let interpreter = self.interpreter;
+        self.number(interpreter.stack.len())?;
+        for value in &interpreter.stack {
+            self.value(value)?;
+        }
+        self.number(interpreter.memory.len())?;
+        for cell in &interpreter.memory {
+            match cell {
+                Cell::Value(value) => {
+                    self.byte(0)?;
+                    self.value(value)?;
+                }
+                Cell::Upvalue(cell) => {
+                    self.byte(1)?;
+                    self.cell(cell)?;
+                }
+            }
+        }
+        for frame in &interpreter.frames {
+            self.function(&frame.function)?;
+            self.number(frame.base_pointer)?;
+            self.number(frame.base_stack)?;
+            self.number(frame.index)?;
+            self.number(frame.pc)?;
+        }
+        Ok(())
+    }
+    /// Tag and name of the builtin, which is either one of the context or a native registered as a global.
+    fn builtin_name(&self, function: &Rc<Function>) -> io::Result<(u8, ValueStr)> {
+        let Some(name) = &function.signature.debug.name else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Native function without a name cannot be saved in a snapshot",
+            ));
+        };
+        let context = self.interpreter.context.borrow();
+        match context.builtin(name.as_str()) {
+            Some(builtin) if Rc::ptr_eq(&builtin, function) => Ok((BUILTIN, name.clone())),
+            _ => Ok((NATIVE, name.clone())),
+        }
+    }
+    fn builtin(&mut self, function: &Rc<Function>) -> io::Result<()> {
+        let (tag, name) = self.builtin_name(function)?;
+        self.byte(tag)?;
+        self.string(name.as_str())
+    }
+    fn signature(&mut self, signature: &Rc<FnSignature>) -> io::Result<()> {
+        let ptr = Rc::as_ptr(signature);
+        if let Some(&id) = self.signatures.get(&ptr) {
+            self.byte(SHARED)?;
+            return self.number(id);
+        }
+        self.signatures.insert(ptr, self.signatures.len());
+        self.byte(FUNCTION)?;
+        self.number(signature.arity)?;
+        self.byte(signature.variadic as u8)?;
+        self.number(signature.upvalues.len())?;
+        for upvalue in &signature.upvalues {
+            match upvalue {
+                UpvalueLoc::Local(id) => {
+                    self.byte(0)?;
+                    self.number(*id)?;
+                }
+                UpvalueLoc::Shared(id) => {
+                    self.byte(1)?;
+                    self.number(*id)?;
+                }
+            }
+        }
+        let DebugInfo {
+            name,
+            span,
+            locals,
+            upvalues,
+        } = &signature.debug;
+        match name {
+            Some(name) => {
+                self.byte(1)?;
+                self.string(name.as_str())?;
+            }
+            None => self.byte(0)?,
+        }
+        self.span(*span)?;
+        self.number(locals.len())?;
+        for local in locals {
+            self.string(local.name.as_str())?;
+            self.number(local.slot)?;
+            self.range(&local.live)?;
+        }
+        self.number(upvalues.len())?;
+        for name in upvalues {
+            self.string(name.as_str())?;
+        }
+        match &signature.body {
+            FnBody::Bytecode(code) => {
+                self.byte(0)?;
+                self.number(code.len())?;
+                for SpanOf(span, bc) in code {
+                    self.span(*span)?;
+                    self.bytecode(bc)?;
+                }
+                Ok(())
+            }
+            FnBody::Bound(receiver, method) => {
+                self.byte(1)?;
+                self.value(receiver)?;
+                self.function(method)
+            }
+            FnBody::Builtin(_) => unreachable!("Builtins are written by name"),
+        }
+    }
+    fn bytecode(&mut self, bc: &Bytecode) -> io::Result<()> {
+        let (tag, operand) = match bc {
+            Bytecode::Dup(n) => (0, Some(*n)),
+            Bytecode::Binary(op) => (1, BINARY_OPS.iter().position(|o| o == op)),
+            Bytecode::Unary(op) => (2, UNARY_OPS.iter().position(|o| o == op)),
+            Bytecode::BranchIf(cond, offset) => {
+                self.byte(3)?;
+                self.byte(*cond as u8)?;
+                return self.offset(*offset);
+            }
+            Bytecode::GlobalDeclare(slot) => (4, Some(*slot)),
+            Bytecode::GlobalReadOnly(slot) => (5, Some(*slot)),
+            Bytecode::LoadGlobal(slot) => (6, Some(*slot)),
+            Bytecode::StoreGlobal(slot) => (7, Some(*slot)),
+            Bytecode::Truncate(n) => (8, Some(*n)),
+            Bytecode::LoadLocal(id) => (9, Some(*id)),
+            Bytecode::StoreLocal(id) => (10, Some(*id)),
+            Bytecode::LoadUpvalue(id) => (11, Some(*id)),
+            Bytecode::StoreUpvalue(id) => (12, Some(*id)),
+            Bytecode::LoadPropertyIndirect => (14, None),
+            Bytecode::StorePropertyIndirect => (16, None),
+            Bytecode::StackToArray(n) => (18, Some(*n)),
+            Bytecode::AppendArray => (19, None),
+            Bytecode::ExtendArray => (20, None),
+            Bytecode::StackToObj(n) => (21, Some(*n)),
+            Bytecode::AppendObjIndirect => (23, None),
+            Bytecode::ExtendObj => (24, None),
+            Bytecode::LoadNil => (25, None),
+            Bytecode::LoadBool(bool) => (26, Some(*bool as usize)),
+            Bytecode::LoadNum(num) => {
+                self.byte(27)?;
+                return self.out.write_all(&num.to_bits().to_le_bytes());
+            }
+            Bytecode::LoadFn(signature) => {
+                self.byte(28)?;
+                return self.signature(signature);
+            }
+            Bytecode::Jump(offset) => {
+                self.byte(30)?;
+                return self.offset(*offset);
+            }
+            Bytecode::Call(n) => (31, Some(*n)),
+            Bytecode::CallVariadic => (32, None),
+            Bytecode::CallBuiltin(base, function) => {
+                self.byte(33)?;
+                self.number(*base)?;
+                return self.builtin(function);
+            }
+            Bytecode::TailCall(n) => (34, Some(*n)),
+            Bytecode::Return => (35, None),
+            Bytecode::LoadProperty(key)
+            | Bytecode::StoreProperty(key)
+            | Bytecode::LoadMethod(key)
+            | Bytecode::AppendObj(key)
+            | Bytecode::LoadStr(key) => {
+                let tag = match bc {
+                    Bytecode::LoadProperty(_) => 13,
+                    Bytecode::StoreProperty(_) => 15,
+                    Bytecode::LoadMethod(_) => 17,
+                    Bytecode::AppendObj(_) => 22,
+                    _ => 29,
+                };
+                self.byte(tag)?;
+                return self.string(key.as_str());
+            }
+        };
+        self.byte(tag)?;
+        match operand {
+            Some(operand) => self.number(operand),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Reads what `Writer` wrote into an interpreter, mapping global slots and strings through its context.
+struct Reader<'a, R> {
+    interpreter: &'a mut Interpreter,
+    input: &'a mut R,
+    slots: Vec<usize>, // slot in the interpreter by slot in the snapshot
+    values: Vec<Value>,
+    cells: Vec<Rc<RefCell<Value>>>,
+    signatures: Vec<Option<Rc<FnSignature>>>, // None while the signature is being read
+    files: Vec<FileId>,                       // file in the source map by file in the snapshot
+    depth: usize,                             // arrays, objects and functions being read
+}
+impl<R: Read> Reader<'_, R> {
+    fn byte(&mut self) -> io::Result<u8> {
+        let mut byte = [0];
+        self.input.read_exact(&mut byte)?;
+        Ok(byte[0])
+    }
+    fn u64(&mut self) -> io::Result<u64> {
+        let mut bytes = [0; 8];
+        self.input.read_exact(&mut bytes)?;
+        Ok(u64::from_le_bytes(bytes))
+    }
+    fn number(&mut self) -> io::Result<usize> {
+        usize::try_from(self.u64()?).map_err(|_| invalid())
+    }
+    fn offset(&mut self) -> io::Result<isize> {
+        Ok(self.u64()? as i64 as isize)
+    }
+    fn string(&mut self) -> io::Result<ValueStr> {
+        let str = self.text()?;
+        Ok(self.interpreter.context.borrow_mut().intern(&str))
+    }
+    fn text(&mut self) -> io::Result<String> {
+        let len = self.number()?;
+        let mut bytes = vec![];
+        self.input
+            .by_ref()
+            .take(len as u64)
+            .read_to_end(&mut bytes)?;
+        let str = String::from_utf8(bytes).map_err(|_| invalid())?;
+        if str.len() != len {
+            return Err(invalid());
+        }
+        Ok(str)
+    }
+    fn span(&mut self) -> io::Result<Span> {
+        let file = self.number()?;
+        Ok(Span {
+            file: *self.files.get(file).ok_or_else(invalid)?,
+            start: self.number()?,
+            end: self.number()?,
+        })
+    }
+    fn slot(&mut self) -> io::Result<usize> {
+        let slot = self.number()?;
+        self.slots.get(slot).copied().ok_or_else(invalid)
+    }
+    fn value(&mut self) -> io::Result<Value> {
+        let tag = self.byte()?;
+        if !matches!(tag, ARRAY | OBJECT | FUNCTION) {
+            return self.tagged_value(tag);
+        }
+        if self.depth >= MAX_DEPTH {
+            return Err(invalid());
+        }
+        self.depth += 1;
+        let value = self.tagged_value(tag);
+        self.depth -= 1;
+        value
+    }
+    fn tagged_value(&mut self, tag: u8) -> io::Result<Value> {
+        Ok(match tag {
+            NIL => Value::Nil,
+            FALSE => Value::Bool(false),
+            TRUE => Value::Bool(true),
+            NUMBER => Value::Number(f64::from_bits(self.u64()?)),
+            STRING => Value::String(self.string()?),
+            SHARED => {
+                let id = self.number()?;
+                self.values.get(id).cloned().ok_or_else(invalid)?
+            }
+            ARRAY => {
+                let arr = Rc::new(RefCell::new(vec![]));
+                self.values.push(Value::Array(arr.clone()));
+                let len = self.number()?;
+                for _ in 0..len {
+                    let item = self.value()?;
+                    arr.borrow_mut().push(item);
+                }
+                Value::Array(arr)
+            }
+            OBJECT => {
+                let obj = Rc::new(RefCell::new(Object::from_entries([])));
+                self.values.push(Value::Object(obj.clone()));
+                let read_only = self.byte()? != 0;
+                let len = self.number()?;
+                for _ in 0..len {
+                    let (key, value) = (self.value()?, self.value()?);
+                    obj.borrow_mut().map.insert(key, value);
+                }
+                obj.borrow_mut().base_obj = match self.value()? {
+                    Value::Nil => None,
+                    Value::Object(base) => Some(base),
+                    _ => return Err(invalid()),
+                };
+                obj.borrow_mut().read_only = read_only;
+                Value::Object(obj)
+            }
+            FUNCTION => {
+                // the id is taken before the function exists, so that nothing read meanwhile can refer to it
+                let id = self.values.len();
+                self.values.push(Value::Nil);
+                let signature = self.signature()?;
+                let len = self.number()?;
+                let mut upvalues = vec![];
+                let mut pending = vec![];
+                for _ in 0..len {
+                    let cell = match self.byte()? {
+                        SHARED => {
+                            let id = self.number()?;
+                            self.cells.get(id).cloned().ok_or_else(invalid)?
+                        }
+                        NIL => {
+                            let cell = Rc::new(RefCell::new(Value::Nil));
+                            self.cells.push(cell.clone());
+                            pending.push(cell.clone());
+                            cell
+                        }
+                        _ => return Err(invalid()),
+                    };
+                    upvalues.push(cell);
+                }
+                let function = Value::Function(Rc::new(Function {
+                    signature,
+                    upvalues,
+                }));
+                self.values[id] = function.clone();
+                for cell in pending {
+                    *cell.borrow_mut() = self.value()?;
+                }
+                function
+            }
+            BUILTIN | NATIVE => Value::Function(self.builtin(tag)?),
+            _ => return Err(invalid()),
+        })
+    }
+    fn cell(&mut self) -> io::Result<Rc<RefCell<Value>>> {
+        match self.byte()? {
+            SHARED => {
+                let id = self.number()?;
+                self.cells.get(id).cloned().ok_or_else(invalid)
+            }
+            NIL => {
+                let cell = Rc::new(RefCell::new(Value::Nil));
+                self.cells.push(cell.clone());
+                *cell.borrow_mut() = self.value()?;
+                Ok(cell)
+            }
+            _ => Err(invalid()),
+        }
+    }
+    /// Reads what `Writer::running` wrote of the `len` frames of a running call into the interpreter.
+    fn running(&mut self, len: usize) -> io::Result<()> {
+        if !self.interpreter.frames.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Cannot restore a running call while the interpreter is running",
+            ));
+        }
+        let stack = (0..self.number()?)
+            .map(|_| self.value())
+            .collect::<io::Result<Vec<_>>>()?;
+        let mut memory = vec![];
+        for _ in 0..self.number()? {
+            memory.push(match self.byte()? {
+                0 => Cell::Value(self.value()?),
+                1 => Cell::Upvalue(self.cell()?),
+                _ => return Err(invalid()),
+            });
+        }
+        let mut frames: Vec<FunctionFrame> = vec![];
+        for _ in 0..len {
+            let function = self.value()?.try_function().map_err(|_| invalid())?;
+            let frame = FunctionFrame {
+                base_pointer: self.number()?,
+                base_stack: self.number()?,
+                function,
+                index: self.number()?,
+                pc: self.number()?,
+            };
+            let (base_pointer, base_stack) = frames
+                .last()
+                .map_or((0, 0), |last| (last.base_pointer, last.base_stack));
+            if !matches!(frame.function.signature.body, FnBody::Bytecode(_))
+                || !(base_pointer..=memory.len()).contains(&frame.base_pointer)
+                || !(base_stack..=stack.len()).contains(&frame.base_stack)
+            {
+                return Err(invalid());
+            }
+            frames.push(frame);
+        }
+        self.interpreter.stack = stack;
+        self.interpreter.memory = memory;
+        self.interpreter.frames = frames;
+        Ok(())
+    }
+    fn builtin(&mut self, tag: u8) -> io::Result<Rc<Function>> {
+        let name = self.string()?;
+        let function = match tag {
+            BUILTIN => self.interpreter.context.borrow().builtin(name.as_str()),
+            _ => {
+                let slot = self.interpreter.global_slot(name.as_str());
+                match self.interpreter.globals.get(slot) {
+                    Some(Some((Value::Function(function), _))) => Some(function.clone()),
+                    _ => None,
+                }
+            }
+        };
+        function.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Unknown builtin `{name}` in snapshot"),
+            )
+        })
+    }
+    fn signature(&mut self) -> io::Result<Rc<FnSignature>> {
+        match self.byte()? {
+            SHARED => {
+                let id = self.number()?;
+                return self
+                    .signatures
+                    .get(id)
+                    .cloned()
+                    .flatten()
+                    .ok_or_else(invalid);
+            }
+            FUNCTION => {}
+            _ => return Err(invalid()),
+        }
+        let id = self.signatures.len();
+        self.signatures.push(None);
+        let arity = self.number()?;
+        let variadic = self.byte()? != 0;
+        let len = self.number()?;
+        let mut upvalues = vec![];
+        for _ in 0..len {
+            upvalues.push(match self.byte()? {
+                0 => UpvalueLoc::Local(self.number()?),
+                1 => UpvalueLoc::Shared(self.number()?),
+                _ => return Err(invalid()),
+            });
+        }
+        let name = match self.byte()? {
+            0 => None,
+            _ => Some(self.string()?),
+        };
+        let span = self.span()?;
+        let len = self.number()?;
+        let mut locals = vec![];
+        for _ in 0..len {
+            locals.push(LocalName {
+                name: self.string()?,
+                slot: self.number()?,
+                live: self.number()?..self.number()?,
+            });
+        }
+        let len = self.number()?;
+        let upvalue_names = (0..len).map(|_| self.string()).collect::<io::Result<_>>()?;
+        let debug = DebugInfo {
+            name,
+            span,
+            locals,
+            upvalues: upvalue_names,
+        };
+        let body = match self.byte()? {
+            0 => {
+                let len = self.number()?;
+                let mut code = vec![];
+                for _ in 0..len {
+                    let span = self.span()?;
+                    code.push(SpanOf(span, self.bytecode()?));
+                }
+                FnBody::Bytecode(code)
+            }
+            1 => {
+                let receiver = self.value()?;
+                let method = self.value()?.try_function().map_err(|_| invalid())?;
+                FnBody::Bound(receiver, method)
+            }
+            _ => return Err(invalid()),
+        };
+        let signature = Rc::new(FnSignature {
+            arity,
+            variadic,
+            upvalues,
+            body,
+            debug,
+        });
+        self.signatures[id] = Some(signature.clone());
+        Ok(signature)
+    }
+    fn bytecode(&mut self) -> io::Result<Bytecode> {
+        Ok(match self.byte()? {
+            0 => Bytecode::Dup(self.number()?),
+            1 => Bytecode::Binary(*BINARY_OPS.get(self.number()?).ok_or_else(invalid)?),
+            2 => Bytecode::Unary(*UNARY_OPS.get(self.number()?).ok_or_else(invalid)?),
+            3 => Bytecode::BranchIf(self.byte()? != 0, self.offset()?),
+            4 => Bytecode::GlobalDeclare(self.slot()?),
+            5 => Bytecode::GlobalReadOnly(self.slot()?),
+            6 => Bytecode::LoadGlobal(self.slot()?),
+            7 => Bytecode::StoreGlobal(self.slot()?),
+            8 => Bytecode::Truncate(self.number()?),
+            9 => Bytecode::LoadLocal(self.number()?),
+            10 => Bytecode::StoreLocal(self.number()?),
+            11 => Bytecode::LoadUpvalue(self.number()?),
+            12 => Bytecode::StoreUpvalue(self.number()?),
+            13 => Bytecode::LoadProperty(self.string()?),
+            14 => Bytecode::LoadPropertyIndirect,
+            15 => Bytecode::StoreProperty(self.string()?),
+            16 => Bytecode::StorePropertyIndirect,
+            17 => Bytecode::LoadMethod(self.string()?),
+            18 => Bytecode::StackToArray(self.number()?),
+            19 => Bytecode::AppendArray,
+            20 => Bytecode::ExtendArray,
+            21 => Bytecode::StackToObj(self.number()?),
+            22 => Bytecode::AppendObj(self.string()?),
+            23 => Bytecode::AppendObjIndirect,
+            24 => Bytecode::ExtendObj,
+            25 => Bytecode::LoadNil,
+            26 => Bytecode::LoadBool(self.number()? != 0),
+            27 => Bytecode::LoadNum(f64::from_bits(self.u64()?)),
+            28 => Bytecode::LoadFn(self.signature()?),
+            29 => Bytecode::LoadStr(self.string()?),
+            30 => Bytecode::Jump(self.offset()?),
+            31 => Bytecode::Call(self.number()?),
+            32 => Bytecode::CallVariadic,
+            33 => {
+                let base = self.number()?;
+                let tag = self.byte()?;
+                match tag {
+                    BUILTIN | NATIVE => Bytecode::CallBuiltin(base, self.builtin(tag)?),
+                    _ => return Err(invalid()),
+                }
+            }
+            34 => Bytecode::TailCall(self.number()?),
+            35 => Bytecode::Return,
+            _ => return Err(invalid()),
+        })
+    }
+}
+
+impl Interpreter {
+    /// Writes the globals and everything reachable from them, functions included, preserving sharing and cycles.
+    /// Taken by a hook while a call runs, the call's frames are written too, to be continued with `resume` once
+    /// restored, unless a builtin is running since those can't be written.
+    ///
+    /// Builtins are written by name, either that of the builtin or of the global a native function is defined as.
+    /// Native values such as channels can't be written. The files in `sources` that spans point into are written
+    /// too, so `sources` should be the source map the code was compiled with.
+    pub fn snapshot(&self, sources: &SourceMap, out: &mut impl Write) -> io::Result<()> {
+        let builtin =
+            |frame: &FunctionFrame| !matches!(frame.function.signature.body, FnBody::Bytecode(_));
+        if self.frames.iter().any(builtin) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Cannot snapshot an interpreter while a builtin is running",
+            ));
+        }
+        // the files are only known once the rest is written, but come first
+        let mut body = vec![];
+        let mut writer = Writer::new(self, &mut body);
+        let names = self
+            .context
+            .borrow()
+            .globals
+            .iter()
+            .map(|(_, name)| name.clone())
+            .collect::<Vec<_>>();
+        writer.number(names.len())?;
+        for name in &names {
+            writer.string(name.as_str())?;
+        }
+        for (slot, global) in self.globals.iter().enumerate() {
+            let Some((value, read_only)) = global else {
+                continue;
+            };
+            // builtins are defined before restoring, under their own names
+            if let Value::Function(function) = value {
+                if let FnBody::Builtin(_) = function.signature.body {
+                    if writer.builtin_name(function)?.1 == names[slot] {
+                        continue;
+                    }
+                }
+            }
+            writer.byte(1 + *read_only as u8)?;
+            writer.number(slot)?;
+            writer.value(value)?;
+        }
+        writer.byte(0)?;
+        writer.number(self.frames.len())?;
+        if !self.frames.is_empty() {
+            writer.running()?;
+        }
+        let mut files = writer.files.into_iter().collect::<Vec<_>>();
+        files.sort_by_key(|&(_, index)| index);
+
+        out.write_all(HEADER)?;
+        let mut writer = Writer::new(self, out);
+        writer.number(files.len())?;
+        for (file, _) in files {
+            writer.string(&sources.name(file))?;
+            writer.string(&sources.source(file).borrow())?;
+        }
+        out.write_all(&body)
+    }
+    /// Defines the globals of a snapshot written by `snapshot`, replacing those with the same names.
+    ///
+    /// Builtins and natives are looked up by name, so natives have to be registered with the same names as in the
+    /// interpreter the snapshot was taken of, before restoring. The source files of the snapshot are added to
+    /// `sources`, which restored spans point into. On error, the globals read so far stay defined.
+    ///
+    /// A running call in the snapshot can only be restored while nothing runs, and is then continued by `resume`.
+    pub fn restore(&mut self, sources: &SourceMap, input: &mut impl Read) -> io::Result<()> {
+        let mut header = [0; HEADER.len()];
+        input.read_exact(&mut header)?;
+        if header != HEADER {
+            return Err(invalid());
+        }
+        let mut reader = Reader {
+            interpreter: self,
+            input,
+            slots: vec![],
+            values: vec![],
+            cells: vec![],
+            signatures: vec![],
+            files: vec![],
+            depth: 0,
+        };
+        let len = reader.number()?;
+        for _ in 0..len {
+            let name = reader.text()?;
+            let source = reader.text()?;
+            let file = sources.add(name, Rc::new(RefCell::new(source)));
+            reader.files.push(file);
+        }
+        let len = reader.number()?;
+        for _ in 0..len {
+            let name = reader.string()?;
+            let slot = reader.interpreter.global_slot(name.as_str());
+            reader.slots.push(slot);
+        }
+        loop {
+            let read_only = match reader.byte()? {
+                0 => break,
+                kind => kind == 2,
+            };
+            let slot = reader.slot()?;
+            let value = reader.value()?;
+            reader.interpreter.define_global(slot, value, read_only);
+        }
+        match reader.number()? {
+            0 => Ok(()),
+            frames => reader.running(frames),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use super::{ARRAY, HEADER};
+    use crate::{error::ErrorKind, vm::Vm};
+
+    const SOURCE: &str = r#"
+let shapes = [nil, nil]
+const base = {area: \self -> self.w * self.h}
+fn shape(i, w, h) do
+    let s = setbase({w: w, h: h}, base)
+    shapes[i] = s
+    return s
+end
+fn counter() do
+    let n = 0
+    return {next: \ -> do n = n + 1
+        return n end, peek: \ -> n}
+end
+let c = counter()
+let cycle = [1, nil, nil]
+cycle[1] = cycle
+let node = {list: cycle}
+node.self = node
+let measure = len
+shape(0, 2, 3)
+c.next()
+"#;
+
+    fn snapshot(vm: &mut Vm) -> io::Result<Vec<u8>> {
+        let mut out = vec![];
+        let sources = vm.sources().clone();
+        vm.interpreter().snapshot(&sources, &mut out)?;
+        Ok(out)
+    }
+    fn restore(vm: &mut Vm, mut snapshot: &[u8]) -> io::Result<()> {
+        let sources = vm.sources().clone();
+        vm.interpreter().restore(&sources, &mut snapshot)
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let mut vm = Vm::new();
+        vm.register_fn("twice", |x: f64| x * 2.0);
+        vm.eval(SOURCE).unwrap();
+        vm.eval("let native = twice\nlet files = fs").unwrap();
+        vm.eval_file("lib.rlox", "fn fail() do\n    return 1 + nil\nend")
+            .unwrap();
+        let snapshot = snapshot(&mut vm).unwrap();
+
+        let mut restored = Vm::new();
+        restored.register_fn("twice", |x: f64| x * 2.0);
+        // takes the file ids the snapshot's files had
+        restored.eval("let unrelated = 1").unwrap();
+        restore(&mut restored, &snapshot).unwrap();
+        for vm in [&mut vm, &mut restored] {
+            let result = vm
+                .eval(
+                    r#"
+shape(1, 4, 5)
+c.next()
+cycle[1][2] = node
+[shapes[0]:area(), shapes[1]:area(), len(shapes), c.peek(), len(cycle), cycle[1][2] == node.self,
+    node.self.list[1][0], measure(shapes), native(4)]
+"#,
+                )
+                .unwrap();
+            assert_eq!(result.to_string(), "[6, 20, 2, 2, 3, true, 1, 2, 8]");
+            // constness survives
+            assert!(matches!(
+                vm.eval("base = nil").unwrap_err().kind,
+                ErrorKind::ConstGlobal(_)
+            ));
+            assert!(matches!(
+                vm.eval("files.read = nil").unwrap_err().kind,
+                ErrorKind::ReadOnlyObject
+            ));
+            // spans point into the files the functions were compiled from
+            let error = vm.eval("fail()").unwrap_err();
+            assert_eq!(error.sources.text(error.span), "+");
+            assert!(error
+                .to_string()
+                .starts_with("Error [lib.rlox, line:2, col:14]"));
+        }
+    }
+
+    #[test]
+    fn test_snapshot_errors() {
+        let mut vm = Vm::new();
+        vm.eval("let ch = channel()").unwrap();
+        assert!(snapshot(&mut vm).is_err());
+        // iterators are anonymous natives
+        let mut vm = Vm::new();
+        vm.eval("let it = map([1], \\x -> x)").unwrap();
+        let error = snapshot(&mut vm).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Native function without a name cannot be saved in a snapshot"
+        );
+
+        let mut vm = Vm::new();
+        vm.register_fn("twice", |x: f64| x * 2.0);
+        vm.eval("let f = twice").unwrap();
+        let saved = snapshot(&mut vm).unwrap();
+        let error = restore(&mut Vm::new(), &saved).unwrap_err();
+        assert_eq!(error.to_string(), "Unknown builtin `twice` in snapshot");
+        let truncated = &saved[..saved.len() - 1];
+        assert!(restore(&mut Vm::new(), truncated).is_err());
+
+        // nesting is limited both ways instead of overflowing the stack
+        let mut vm = Vm::new();
+        vm.eval("let deep = []\nfor _ in range(5000) do deep = [deep] end")
+            .unwrap();
+        let error = snapshot(&mut vm).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        let mut deep = HEADER.to_vec();
+        deep.extend(0u64.to_le_bytes());
+        deep.extend(1u64.to_le_bytes());
+        deep.extend(4u64.to_le_bytes());
+        deep.extend(b"deep");
+        deep.extend([1]);
+        deep.extend(0u64.to_le_bytes());
+        for _ in 0..5000 {
+            deep.push(ARRAY);
+            deep.extend(1u64.to_le_bytes());
+        }
+        let error = restore(&mut Vm::new(), &deep).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -1,5 +1,4 @@
 use std::{
-    cell::RefCell,
     fmt,
     hash::{Hash, Hasher},
     ops::Add,
@@ -8,25 +7,23 @@ use std::{
 
 use rustc_hash::{FxHashSet, FxHasher};
 
+/// Immutable string value with a precomputed hash.
+///
+/// Strings from the same `StrInterner` share their allocation, which makes comparing them a pointer check.
 #[derive(Clone)]
 pub struct ValueStr {
     hash: u64,
     str: Rc<str>,
-    interned: bool,
 }
 impl ValueStr {
-    fn new(str: Rc<str>, interned: bool) -> Self {
+    fn new(str: Rc<str>) -> Self {
         let mut hasher = FxHasher::default();
         str.hash(&mut hasher);
         Self {
             hash: hasher.finish(),
             str,
-            interned,
         }
     }
-    pub fn interned(string: &str) -> Self {
-        Self::new(INTERNER.with(|i| i.borrow_mut().add_str(string)), true)
-    }
     pub fn as_str(&self) -> &str {
         &self.str
     }
@@ -38,7 +35,7 @@ impl fmt::Debug for ValueStr {
 }
 impl From<Rc<str>> for ValueStr {
     fn from(value: Rc<str>) -> Self {
-        Self::new(value, false)
+        Self::new(value)
     }
 }
 impl From<&str> for ValueStr {
@@ -48,10 +45,7 @@ impl From<&str> for ValueStr {
 }
 impl PartialEq for ValueStr {
     fn eq(&self, other: &Self) -> bool {
-        if self.interned && other.interned {
-            return Rc::ptr_eq(&self.str, &other.str);
-        }
-        self.str == other.str
+        Rc::ptr_eq(&self.str, &other.str) || (self.hash == other.hash && self.str == other.str)
     }
 }
 impl Eq for ValueStr {}
@@ -75,23 +69,69 @@ impl fmt::Display for ValueStr {
     }
 }
 
-#[derive(Default)]
+// Interned strings are reclaimed once the interner grows to this many entries
+const MIN_COLLECT_LEN: usize = 0x100;
+
+/// Deduplicates identifier and property name strings. Owned by the `Context` of an interpreter.
+///
+/// Strings that are only referenced by the interner are reclaimed by `collect`,
+/// which also runs automatically whenever the interner has doubled in size since the last collection.
 pub struct StrInterner {
     strings: FxHashSet<Rc<str>>,
+    collect_len: usize,
+}
+impl Default for StrInterner {
+    fn default() -> Self {
+        Self {
+            strings: FxHashSet::default(),
+            collect_len: MIN_COLLECT_LEN,
+        }
+    }
 }
 impl StrInterner {
-    pub fn add_str(&mut self, str: &str) -> Rc<str> {
-        match self.strings.get(str) {
-            Some(str) => str.clone(),
-            None => {
-                let rc_str = Rc::<str>::from(str);
-                self.strings.insert(rc_str.clone());
-                rc_str
-            }
+    pub fn intern(&mut self, str: &str) -> ValueStr {
+        if let Some(str) = self.strings.get(str) {
+            return ValueStr::new(str.clone());
         }
+        if self.strings.len() >= self.collect_len {
+            self.collect();
+        }
+        let rc_str = Rc::<str>::from(str);
+        self.strings.insert(rc_str.clone());
+        ValueStr::new(rc_str)
+    }
+    /// Frees strings that are no longer referenced outside the interner.
+    pub fn collect(&mut self) {
+        self.strings.retain(|str| Rc::strong_count(str) > 1);
+        self.collect_len = MIN_COLLECT_LEN.max(self.strings.len() * 2);
+    }
+    pub fn len(&self) -> usize {
+        self.strings.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.strings.is_empty()
     }
 }
 
-thread_local! {
-    static INTERNER: RefCell<StrInterner> = RefCell::new(StrInterner::default());
+#[cfg(test)]
+mod tests {
+    use crate::interpreter::string::{StrInterner, ValueStr};
+
+    #[test]
+    fn interning() {
+        let mut interner = StrInterner::default();
+        let a = interner.intern("name");
+        let b = interner.intern("name");
+        assert!(std::rc::Rc::ptr_eq(&a.str, &b.str));
+        // strings from elsewhere compare by content
+        assert_eq!(a, ValueStr::from("name"));
+        assert_ne!(a, interner.intern("other"));
+
+        drop(b);
+        interner.collect();
+        assert_eq!(interner.len(), 1);
+        drop(a);
+        interner.collect();
+        assert!(interner.is_empty());
+    }
 }
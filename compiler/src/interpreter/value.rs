@@ -1,4 +1,5 @@
 use crate::error::ErrorKind;
+use crate::interpreter::native::NativeValue;
 use crate::interpreter::string::ValueStr;
 use crate::interpreter::{FnSignature, Interpreter};
 use rustc_hash::FxHashMap;
@@ -17,6 +18,7 @@ pub enum Value {
     Array(Rc<RefCell<Vec<Value>>>),
     Object(Rc<RefCell<Object>>),
     Function(Rc<Function>),
+    Native(NativeValue),
 }
 impl fmt::Display for Value {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -60,6 +62,7 @@ impl fmt::Display for Value {
                 }
                 write!(f, "]")
             }
+            Self::Native(native) => write!(f, "{}", native),
         }
     }
 }
@@ -74,25 +77,42 @@ pub struct Function {
 pub struct Object {
     pub map: FxHashMap<Value, Value>,
     pub base_obj: Option<Rc<RefCell<Object>>>,
+    pub read_only: bool, // properties and base can't be changed, like those of builtin modules
 }
 impl Object {
+    #[allow(clippy::mutable_key_type)]
     pub fn new(map: FxHashMap<Value, Value>) -> Result<Self, ErrorKind> {
         for k in map.keys() {
-            Self::validate_key(&k)?;
+            Self::validate_key(k)?;
         }
         Ok(Self {
             map,
             base_obj: None,
+            read_only: false,
         })
     }
+    /// Object from key-value pairs. Like with `set_property`, nil values are left out.
+    pub fn from_entries(entries: impl IntoIterator<Item = (Value, Value)>) -> Self {
+        #[allow(clippy::mutable_key_type)]
+        let map = entries
+            .into_iter()
+            .filter(|(_, value)| !matches!(value, Value::Nil))
+            .collect();
+        Self {
+            map,
+            base_obj: None,
+            read_only: false,
+        }
+    }
     pub fn with_capacity(capacity: usize) -> Self {
         Self {
             map: FxHashMap::with_capacity_and_hasher(capacity, Default::default()),
             base_obj: None,
+            read_only: false,
         }
     }
     pub fn get_property(&self, key: &Value) -> Result<Value, ErrorKind> {
-        Self::validate_key(&key)?;
+        Self::validate_key(key)?;
         if let Some(value) = self.map.get(key) {
             Ok(value.clone())
         } else if let Some(super_obj) = &self.base_obj {
@@ -103,6 +123,9 @@ impl Object {
     }
     pub fn set_property(&mut self, key: Value, new_value: Value) -> Result<(), ErrorKind> {
         Self::validate_key(&key)?;
+        if self.read_only {
+            return Err(ErrorKind::ReadOnlyObject);
+        }
         if matches!(new_value, Value::Nil) {
             self.map.remove(&key);
             return Ok(());
@@ -135,6 +158,9 @@ impl PartialEq for Value {
             Self::Function(fn1) => matches!(
                 other, Self::Function(fn2) if Rc::ptr_eq(fn1, fn2)
             ),
+            Self::Native(native1) => matches!(
+                other, Self::Native(native2) if native1 == native2
+            ),
         }
     }
 }
@@ -149,11 +175,13 @@ impl Hash for Value {
             Self::Array(arr) => arr.borrow().hash(state),
             Self::Object(obj) => Rc::as_ptr(obj).hash(state),
             Self::Function(function) => Rc::as_ptr(function).hash(state),
+            // native equality may be custom, so only the type is known to be consistent with it
+            Self::Native(native) => native.type_id().hash(state),
         }
     }
 }
 impl Value {
-    pub(crate) fn type_str(&self) -> &'static str {
+    pub fn type_str(&self) -> &'static str {
         match self {
             Self::Nil => "nil",
             Self::Bool(_) => "boolean",
@@ -162,6 +190,7 @@ impl Value {
             Self::Array(_) => "array",
             Self::Object(_) => "object",
             Self::Function(_) => "function",
+            Self::Native(native) => native.type_name(),
         }
     }
     pub fn try_num(self) -> Result<f64, ErrorKind> {
@@ -359,7 +388,7 @@ impl Value {
                     .unwrap_or_default()),
                 _ => Err(ErrorKind::InvalidArrayIndex),
             },
-            Self::Object(obj) => Ok(obj.borrow().get_property(&key)?),
+            Self::Object(obj) => Ok(obj.borrow().get_property(key)?),
             _ => Err(ErrorKind::InvalidPropertyAccess),
         }
     }
@@ -398,9 +427,284 @@ impl Value {
             Self::Nil => false,
             Self::Number(num) => *num != 0.0,
             Self::Array(array) => !array.borrow().is_empty(),
-            Self::String(str) => str.as_str().len() != 0,
+            Self::String(str) => !str.as_str().is_empty(),
             Self::Bool(bool) => *bool,
-            Self::Object(_) | Self::Function(_) => true,
+            Self::Object(_) | Self::Function(_) | Self::Native(_) => true,
+        }
+    }
+}
+
+pub use compiler_derive::{FromValue, IntoValue};
+
+/// Conversion from a script value, used for the arguments of native functions registered through `Vm`.
+pub trait FromValue: Sized {
+    fn from_value(value: Value) -> Result<Self, ErrorKind>;
+}
+/// Conversion into a script value, used for the results of native functions registered through `Vm`.
+pub trait IntoValue {
+    fn into_value(self) -> Value;
+}
+
+impl FromValue for Value {
+    fn from_value(value: Value) -> Result<Self, ErrorKind> {
+        Ok(value)
+    }
+}
+impl IntoValue for Value {
+    fn into_value(self) -> Value {
+        self
+    }
+}
+impl FromValue for () {
+    fn from_value(value: Value) -> Result<Self, ErrorKind> {
+        match value {
+            Value::Nil => Ok(()),
+            v => Err(ErrorKind::InvalidType(v.type_str(), "nil")),
+        }
+    }
+}
+impl IntoValue for () {
+    fn into_value(self) -> Value {
+        Value::Nil
+    }
+}
+impl FromValue for bool {
+    fn from_value(value: Value) -> Result<Self, ErrorKind> {
+        match value {
+            Value::Bool(b) => Ok(b),
+            v => Err(ErrorKind::InvalidType(v.type_str(), "boolean")),
+        }
+    }
+}
+impl IntoValue for bool {
+    fn into_value(self) -> Value {
+        Value::Bool(self)
+    }
+}
+macro_rules! impl_number_conversion {
+    ($($ty:ty),*) => {$(
+        impl IntoValue for $ty {
+            fn into_value(self) -> Value {
+                Value::Number(self as f64)
+            }
+        }
+    )*};
+}
+impl_number_conversion!(f64, f32, i64, i32, u64, u32, usize, isize);
+impl FromValue for f64 {
+    fn from_value(value: Value) -> Result<Self, ErrorKind> {
+        value.try_num()
+    }
+}
+impl FromValue for f32 {
+    fn from_value(value: Value) -> Result<Self, ErrorKind> {
+        Ok(value.try_num()? as f32)
+    }
+}
+/// Integers only convert from whole numbers within their range, rather than rounding or saturating.
+macro_rules! impl_integer_conversion {
+    ($($ty:ty),*) => {$(
+        impl FromValue for $ty {
+            fn from_value(value: Value) -> Result<Self, ErrorKind> {
+                let num = value.try_num()?;
+                // `MAX as f64 + 1.0` is exact, a power of two, for every integer type
+                if num.fract() != 0.0 || num < <$ty>::MIN as f64 || num >= <$ty>::MAX as f64 + 1.0 {
+                    return Err(ErrorKind::InvalidType("number", stringify!($ty)));
+                }
+                Ok(num as $ty)
+            }
         }
+    )*};
+}
+impl_integer_conversion!(i64, i32, u64, u32, usize, isize);
+impl FromValue for ValueStr {
+    fn from_value(value: Value) -> Result<Self, ErrorKind> {
+        match value {
+            Value::String(str) => Ok(str),
+            v => Err(ErrorKind::InvalidType(v.type_str(), "string")),
+        }
+    }
+}
+impl IntoValue for ValueStr {
+    fn into_value(self) -> Value {
+        Value::String(self)
+    }
+}
+impl FromValue for String {
+    fn from_value(value: Value) -> Result<Self, ErrorKind> {
+        Ok(ValueStr::from_value(value)?.as_str().to_owned())
+    }
+}
+impl IntoValue for String {
+    fn into_value(self) -> Value {
+        Value::String(self.as_str().into())
+    }
+}
+impl IntoValue for &str {
+    fn into_value(self) -> Value {
+        Value::String(self.into())
+    }
+}
+impl IntoValue for Object {
+    fn into_value(self) -> Value {
+        Value::Object(Rc::new(RefCell::new(self)))
+    }
+}
+impl FromValue for Rc<Function> {
+    fn from_value(value: Value) -> Result<Self, ErrorKind> {
+        value.try_function()
+    }
+}
+impl IntoValue for Rc<Function> {
+    fn into_value(self) -> Value {
+        Value::Function(self)
+    }
+}
+impl IntoValue for Function {
+    fn into_value(self) -> Value {
+        Value::Function(Rc::new(self))
+    }
+}
+/// `nil` converts to `None`, anything else to `Some`.
+impl<T: FromValue> FromValue for Option<T> {
+    fn from_value(value: Value) -> Result<Self, ErrorKind> {
+        match value {
+            Value::Nil => Ok(None),
+            v => Ok(Some(T::from_value(v)?)),
+        }
+    }
+}
+impl<T: IntoValue> IntoValue for Option<T> {
+    fn into_value(self) -> Value {
+        self.map(T::into_value).unwrap_or_default()
+    }
+}
+impl<T: FromValue> FromValue for Vec<T> {
+    fn from_value(value: Value) -> Result<Self, ErrorKind> {
+        let array = value.try_array()?;
+        let array = array.borrow();
+        array.iter().cloned().map(T::from_value).collect()
+    }
+}
+impl<T: IntoValue> IntoValue for Vec<T> {
+    fn into_value(self) -> Value {
+        let array = self.into_iter().map(T::into_value).collect();
+        Value::Array(Rc::new(RefCell::new(array)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        error::ErrorKind,
+        interpreter::value::{FromValue, IntoValue, Value},
+        vm::Vm,
+    };
+
+    #[derive(Debug, PartialEq, IntoValue, FromValue)]
+    struct Point {
+        x: f64,
+        y: f64,
+    }
+
+    #[derive(Debug, PartialEq, IntoValue, FromValue)]
+    enum Shape {
+        Empty,
+        #[rlox(rename = "circle")]
+        Circle {
+            center: Point,
+            radius: f64,
+        },
+        Polygon(Vec<Point>),
+    }
+
+    #[derive(Debug, PartialEq, IntoValue, FromValue)]
+    struct Drawing {
+        #[rlox(rename = "drawingName")]
+        name: String,
+        shapes: Vec<Shape>,
+        author: Option<String>,
+    }
+
+    #[derive(Debug, PartialEq, IntoValue, FromValue)]
+    struct Meters(f64);
+
+    #[derive(Debug, PartialEq, IntoValue, FromValue)]
+    #[rlox(tag = "kind")]
+    enum Tagged {
+        Pair(f64, String),
+    }
+
+    #[test]
+    fn derive_round_trip() {
+        let drawing = Drawing {
+            name: "sketch".into(),
+            shapes: vec![
+                Shape::Empty,
+                Shape::Circle {
+                    center: Point { x: 1.0, y: 2.0 },
+                    radius: 3.0,
+                },
+                Shape::Polygon(vec![Point { x: 0.0, y: 0.0 }]),
+            ],
+            author: None,
+        };
+        let mut vm = Vm::new();
+        vm.set_global("drawing", drawing).unwrap();
+
+        let script = r#"drawing.drawingName + " " + str(drawing.author) + " " + drawing.shapes[0] + " " + drawing.shapes[1].type + " " + str(drawing.shapes[1].center.y) + " " + drawing.shapes[2].type"#;
+        assert_eq!(
+            vm.eval(script).unwrap().to_string(),
+            "sketch nil Empty circle 2 Polygon"
+        );
+
+        let drawing = Drawing::from_value(vm.get_global("drawing")).unwrap();
+        assert_eq!(drawing.shapes[0], Shape::Empty);
+        assert_eq!(drawing.author, None);
+
+        let value = vm
+            .eval(r#"{drawingName: "new", shapes: [{type: "circle", center: {x: 0, y: 1}, radius: 2}], author: "me"}"#)
+            .unwrap();
+        assert_eq!(
+            Drawing::from_value(value).unwrap(),
+            Drawing {
+                name: "new".into(),
+                shapes: vec![Shape::Circle {
+                    center: Point { x: 0.0, y: 1.0 },
+                    radius: 2.0
+                }],
+                author: Some("me".into()),
+            }
+        );
+
+        assert_eq!(Meters(2.0).into_value(), Value::Number(2.0));
+        let tagged = Tagged::Pair(1.0, "a".into()).into_value();
+        let kind = tagged.get_property(&"kind".into_value()).unwrap();
+        assert_eq!(kind.to_string(), "Pair");
+        let second = tagged.get_property(&Value::Number(1.0)).unwrap();
+        assert_eq!(second.to_string(), "a");
+        assert_eq!(
+            Tagged::from_value(tagged).unwrap(),
+            Tagged::Pair(1.0, "a".into())
+        );
+    }
+
+    #[test]
+    fn derive_errors() {
+        let mut vm = Vm::new();
+        let value = vm.eval("{x: 1}").unwrap();
+        assert!(matches!(
+            Point::from_value(value),
+            Err(ErrorKind::InvalidType("nil", "number"))
+        ));
+        let value = vm.eval(r#""Square""#).unwrap();
+        assert!(matches!(
+            Shape::from_value(value),
+            Err(ErrorKind::InvalidVariant(_, "Shape"))
+        ));
+        assert!(matches!(
+            Shape::from_value(Value::Number(1.0)),
+            Err(ErrorKind::InvalidType("number", "Shape"))
+        ));
     }
 }
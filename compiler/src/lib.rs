@@ -1,9 +1,13 @@
-use std::sync::atomic::AtomicBool;
+// lets the derive macros refer to this crate as `::compiler` from within it
+extern crate self as compiler;
 
 pub mod ast;
 pub mod codegen;
+pub mod debugger;
+pub mod diagnostic;
 pub mod error;
+pub mod formatter;
 pub mod interpreter;
+pub mod source_map;
 pub mod span;
-
-pub static DEBUG_MODE: AtomicBool = AtomicBool::new(false);
+pub mod vm;
@@ -0,0 +1,161 @@
+use std::{cell::RefCell, ops::Range, rc::Rc};
+
+use crate::span::{FileId, Span};
+
+struct SourceFile {
+    name: String,
+    source: Rc<RefCell<String>>,
+    // the source grows as the parser reads it, `indexed` is how far it's been searched for line starts
+    line_starts: Vec<usize>,
+    indexed: usize,
+}
+impl SourceFile {
+    fn update_index(&mut self) {
+        let source = self.source.borrow();
+        let new_starts = source[self.indexed..]
+            .match_indices('\n')
+            .map(|(i, _)| self.indexed + i + 1);
+        self.line_starts.extend(new_starts);
+        self.indexed = source.len();
+    }
+}
+
+fn clamp(source: &str, offset: usize) -> usize {
+    let offset = offset.min(source.len());
+    match offset == source.len() && source.ends_with('\n') {
+        true => offset - 1,
+        false => offset,
+    }
+}
+
+/// Files that spans point into, by `FileId`, with a line index of each.
+///
+/// It's a shared handle, clones refer to the same files.
+#[derive(Clone, Default)]
+pub struct SourceMap(Rc<RefCell<Vec<SourceFile>>>);
+impl SourceMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Adds a file whose contents are `source`, which may still be appended to.
+    pub fn add(&self, name: impl Into<String>, source: Rc<RefCell<String>>) -> FileId {
+        let mut files = self.0.borrow_mut();
+        files.push(SourceFile {
+            name: name.into(),
+            source,
+            line_starts: vec![0],
+            indexed: 0,
+        });
+        FileId(files.len() as u32 - 1)
+    }
+    /// Runs `f` on the file, with its line index up to date. Unknown files are empty.
+    fn with_file<T>(&self, file: FileId, f: impl FnOnce(&SourceFile) -> T) -> T {
+        let mut files = self.0.borrow_mut();
+        match files.get_mut(file.0 as usize) {
+            Some(file) => {
+                file.update_index();
+                f(file)
+            }
+            None => f(&SourceFile {
+                name: String::new(),
+                source: Default::default(),
+                line_starts: vec![0],
+                indexed: 0,
+            }),
+        }
+    }
+    pub fn name(&self, file: FileId) -> String {
+        self.with_file(file, |file| file.name.clone())
+    }
+    pub fn source(&self, file: FileId) -> Rc<RefCell<String>> {
+        self.with_file(file, |file| file.source.clone())
+    }
+    /// Source text of the span.
+    pub fn text(&self, span: Span) -> String {
+        let source = self.source(span.file);
+        let source = source.borrow();
+        source[span.start.min(source.len())..span.end.min(source.len())].to_owned()
+    }
+    /// Zero based line of the offset. The end of a source ending with a newline is counted on the last line rather
+    /// than on an empty one after it.
+    pub fn line_index(&self, file: FileId, offset: usize) -> usize {
+        self.with_file(file, |file| {
+            let offset = clamp(&file.source.borrow(), offset);
+            file.line_starts.partition_point(|&start| start <= offset) - 1
+        })
+    }
+    /// Byte range of the zero based line, without the line break.
+    pub fn line_range(&self, file: FileId, line: usize) -> Range<usize> {
+        self.with_file(file, |file| {
+            let source = file.source.borrow();
+            let start = file.line_starts[line.min(file.line_starts.len() - 1)];
+            let end = file
+                .line_starts
+                .get(line + 1)
+                .map_or(source.len(), |next| next - 1);
+            start..start + source[start..end].trim_end_matches('\r').len()
+        })
+    }
+    /// One based line and column of the offset, the column counted in characters.
+    pub fn line_col(&self, file: FileId, offset: usize) -> (usize, usize) {
+        let line = self.line_index(file, offset);
+        let start = self.line_range(file, line).start;
+        let source = self.source(file);
+        let source = source.borrow();
+        let column = source[start..clamp(&source, offset)].chars().count();
+        (line + 1, column + 1)
+    }
+    /// Text of the one based line, without the line break.
+    pub fn line_text(&self, file: FileId, line: usize) -> String {
+        let range = self.line_range(file, line.saturating_sub(1));
+        self.source(file).borrow()[range].to_owned()
+    }
+    /// Number of lines read into the file so far.
+    pub fn line_count(&self, file: FileId) -> usize {
+        self.with_file(file, |file| file.line_starts.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ast::Parser, span::GetSpan};
+
+    #[test]
+    fn test_lines() {
+        let sources = SourceMap::new();
+        let source = Rc::new(RefCell::new("ab\r\ncd\n".to_owned()));
+        let first = sources.add("first.rlox", source.clone());
+        let second = sources.add("second.rlox", Rc::new(RefCell::new("x".to_owned())));
+        assert_eq!(sources.name(second), "second.rlox");
+
+        assert_eq!(sources.line_col(first, 0), (1, 1));
+        assert_eq!(sources.line_col(first, 4), (2, 1));
+        assert_eq!(sources.line_col(first, 5), (2, 2));
+        // the end of the source is at the end of the last line
+        assert_eq!(sources.line_col(first, 7), (2, 3));
+        assert_eq!(sources.line_text(first, 1), "ab");
+        assert_eq!(sources.line_col(second, 1), (1, 2));
+
+        // lines read later are indexed too
+        source.borrow_mut().push_str("é f\n");
+        assert_eq!(sources.line_col(first, 10), (3, 3));
+        assert_eq!(sources.line_text(first, 3), "é f");
+        assert_eq!(sources.line_count(first), 4);
+    }
+
+    #[test]
+    fn test_parser_files() {
+        let sources = SourceMap::new();
+        let mut first = Parser::with_file("let a = 1".as_bytes(), &sources, "a.rlox");
+        let mut second = Parser::with_file("\nlet b = )".as_bytes(), &sources, "b.rlox");
+        let a = first.next_statement().unwrap().unwrap();
+        assert_eq!(a.span().file, first.file());
+        let err = second.next_statement().unwrap_err();
+        assert_eq!(err.span.file, FileId(1));
+        assert_eq!(
+            err.to_string(),
+            "Error [b.rlox, line:2, col:7]: Expected expression"
+        );
+    }
+}
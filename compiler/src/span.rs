@@ -2,34 +2,47 @@ pub trait GetSpan {
     fn span(&self) -> Span;
 }
 
+/// File of a `SourceMap` a span is in.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct FileId(pub u32);
+
+/// Byte range in a file. Spans made without a file are in the first one.
 #[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
 pub struct Span {
+    pub file: FileId,
     pub start: usize,
     pub end: usize,
 }
 impl Span {
     pub const fn from_len(start: usize, len: usize) -> Self {
-        Self {
-            start,
-            end: start + len,
-        }
+        Self::new(start, start + len)
     }
     pub const fn from_char_offset(ch: (usize, char)) -> Self {
         Self::from_len(ch.0, ch.1.len_utf8())
     }
     pub const fn new(start: usize, end: usize) -> Self {
-        Self { start, end }
+        Self {
+            file: FileId(0),
+            start,
+            end,
+        }
+    }
+    pub const fn in_file(self, file: FileId) -> Self {
+        Self { file, ..self }
     }
     pub const fn len(&self) -> usize {
         self.end - self.start
     }
+    pub const fn is_empty(&self) -> bool {
+        self.start == self.end
+    }
     pub fn with_end(self, new_end: usize) -> Self {
-        Self::new(self.start, new_end)
+        Self::new(self.start, new_end).in_file(self.file)
     }
     pub fn concat(self, other: Span) -> Span {
         let start = self.start.min(other.start);
         let end = self.end.max(other.end);
-        Span::new(start, end)
+        Span::new(start, end).in_file(self.file)
     }
 }
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
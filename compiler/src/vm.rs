@@ -0,0 +1,317 @@
+use std::rc::Rc;
+
+use crate::{
+    ast::{declaration::Declaration, statement::Statement, Parser},
+    codegen::Codegen,
+    error::{Error, ErrorKind, Result},
+    interpreter::{
+        native::{native_function, NativeFn, NativeType},
+        string::ValueStr,
+        value::{Function, IntoValue, Value},
+        Interpreter,
+    },
+    source_map::SourceMap,
+    span::{FileId, GetSpan, Span},
+};
+
+/// Embedding facade over the parser, codegen and interpreter.
+///
+/// Globals persist across `eval` calls, so code can be loaded once and called into later.
+/// Every evaluated source is a file of the VM's source map, named `<eval N>`, so errors in functions from earlier
+/// calls point at the source they came from.
+#[derive(Default)]
+pub struct Vm {
+    interpreter: Interpreter,
+    sources: SourceMap,
+    evals: u32,
+    file: FileId, // of the last evaluated source
+}
+impl Vm {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Underlying interpreter, e.g. for setting limits.
+    pub fn interpreter(&mut self) -> &mut Interpreter {
+        &mut self.interpreter
+    }
+    /// Sources evaluated so far, for resolving spans.
+    pub fn sources(&self) -> &SourceMap {
+        &self.sources
+    }
+    /// Runs `source` and returns the value of its last statement if it is an expression, nil otherwise.
+    pub fn eval(&mut self, source: &str) -> Result<Value> {
+        self.evals += 1;
+        let name = format!("<eval {}>", self.evals);
+        self.eval_file(&name, source)
+    }
+    /// Like `eval`, with errors pointing into a file named `name` rather than `<eval N>`.
+    pub fn eval_file(&mut self, name: &str, source: &str) -> Result<Value> {
+        let mut parser = Parser::with_file(source.as_bytes(), &self.sources, name);
+        self.file = parser.file();
+        let mut statements = vec![];
+        while let Some(statement) = parser.next_statement()? {
+            statements.push(statement);
+        }
+
+        let mut codegen = Codegen::with_context(self.sources.clone(), self.interpreter.context());
+        if let Some(Statement::Declaration(Declaration::Expression(expr))) = statements.last() {
+            for statement in &statements[..statements.len() - 1] {
+                codegen.gen_statement(statement)?;
+            }
+            codegen.gen_return(expr, expr.span())?;
+        } else {
+            for statement in &statements {
+                codegen.gen_statement(statement)?;
+            }
+        }
+
+        let init_fn = Rc::new(
+            self.interpreter
+                .create_function(Rc::new(codegen.pop_init_sig())),
+        );
+        let result = self.interpreter.call_function_args(init_fn, []);
+        result.map_err(|kind| self.runtime_error(kind))
+    }
+    /// Continues the script restored from a snapshot taken while it ran, see `Interpreter::resume`.
+    pub fn resume(&mut self) -> Result<Value> {
+        let result = self.interpreter.resume();
+        result.map_err(|kind| self.runtime_error(kind))
+    }
+    /// Calls the global function `name` with `args`.
+    pub fn call_global(
+        &mut self,
+        name: &str,
+        args: impl IntoIterator<Item = Value>,
+    ) -> Result<Value> {
+        let result = self
+            .get_global(name)
+            .try_function()
+            .and_then(|function| self.interpreter.call_function_args(function, args));
+        result.map_err(|kind| self.runtime_error(kind))
+    }
+    /// Value of the global `name`, nil if it isn't declared.
+    pub fn get_global(&self, name: &str) -> Value {
+        let slot = self
+            .interpreter
+            .context()
+            .borrow()
+            .globals
+            .get(&ValueStr::from(name));
+        slot.map(|slot| self.interpreter.get_global(slot))
+            .unwrap_or_default()
+    }
+    /// Assigns the global `name`, declaring it first if needed. Fails for read-only globals.
+    pub fn set_global(
+        &mut self,
+        name: &str,
+        value: impl IntoValue,
+    ) -> std::result::Result<(), ErrorKind> {
+        let slot = self.interpreter.global_slot(name);
+        let value = value.into_value();
+        match self.interpreter.set_global(slot, value.clone()) {
+            Err(ErrorKind::UndeclaredGlobal(_)) => {
+                self.interpreter.define_global(slot, value, false);
+                Ok(())
+            }
+            result => result,
+        }
+    }
+    /// Defines a read-only global function backed by a typed Rust closure, see `native_function`.
+    pub fn register_fn<Args>(&mut self, name: &str, function: impl NativeFn<Args>) {
+        let slot = self.interpreter.global_slot(name);
+        let mut function = native_function(function);
+        // named like builtins, for stack traces and snapshots
+        Rc::get_mut(&mut function.signature).unwrap().debug.name = Some(name.into());
+        self.interpreter
+            .define_global(slot, Value::Function(Rc::new(function)), true);
+    }
+    /// Adds a method to values of the native type `T`. The receiver is passed as the first argument.
+    pub fn register_method<T: NativeType, Args>(
+        &mut self,
+        name: &str,
+        function: impl NativeFn<Args>,
+    ) {
+        let function = Value::Function(Rc::new(native_function(function)));
+        self.interpreter.register_native_method::<T>(name, function);
+    }
+    /// Wraps the VM so it can be moved to another thread, see `Detached`.
+    ///
+    /// # Safety
+    /// Same as `Detached::new`.
+    pub unsafe fn detach(self) -> Detached<Self> {
+        Detached::new(self)
+    }
+    fn runtime_error(&self, kind: ErrorKind) -> Error {
+        // errors without a location are put at the end of the last source, where they show without a position
+        let file = self.file;
+        let end = self.sources.source(file).borrow().len();
+        let span = self
+            .interpreter
+            .error_span()
+            .unwrap_or(Span::from_len(end, 0).in_file(file));
+        Error::new(kind, span, self.sources.clone())
+    }
+}
+
+/// Owner of a VM or interpreter that can be sent to another thread.
+///
+/// Values are reference counted without atomics, so they can't be sent on their own.
+/// Since every interpreter owns its interner, globals and builtins, a whole interpreter can still
+/// move between threads as long as nothing outside of it keeps references into it.
+pub struct Detached<T>(T);
+// SAFETY: `Detached::new` requires that nothing reachable from the interpreter is shared outside of it, and that its
+// output sinks and hook are `Send`
+unsafe impl Send for Detached<Vm> {}
+unsafe impl Send for Detached<Interpreter> {}
+// an isolate along with the function it starts with, copied into it by `spawn`
+unsafe impl Send for Detached<(Interpreter, Rc<Function>)> {}
+impl<T> Detached<T> {
+    /// # Safety
+    /// The wrapped interpreter must be the only owner of everything reachable from it:
+    /// - no `Rc` reachable from it may be referenced from elsewhere, so the caller must not keep any `Value`,
+    ///   `Function`, native value, `Context` or `SourceMap` obtained from it or passed into it, and neither may any other
+    ///   interpreter or native value
+    /// - the writers given to `set_stdout` and `set_stderr`, and the hook given to `set_hook`, must be `Send`, like
+    ///   `OutputBuffer` is
+    ///
+    /// `InterruptHandle`s and output buffers may be kept, since they are `Send`.
+    pub unsafe fn new(inner: T) -> Self {
+        Self(inner)
+    }
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use crate::{error::ErrorKind, interpreter::value::Value, vm::Vm};
+
+    #[test]
+    fn eval() {
+        let mut vm = Vm::new();
+        assert_eq!(vm.eval("1 + 2").unwrap(), Value::Number(3.0));
+        assert_eq!(vm.eval("let a = 1").unwrap(), Value::Nil);
+
+        // globals persist between evaluations
+        vm.eval("fn add(a, b) a + b").unwrap();
+        assert_eq!(vm.eval("add(a, 2)").unwrap(), Value::Number(3.0));
+        let result = vm
+            .call_global("add", [Value::Number(4.0), Value::Number(5.0)])
+            .unwrap();
+        assert_eq!(result, Value::Number(9.0));
+    }
+
+    #[test]
+    fn globals() {
+        let mut vm = Vm::new();
+        vm.set_global("x", 2.5).unwrap();
+        assert_eq!(vm.eval("x * 2").unwrap(), Value::Number(5.0));
+        vm.eval("x = \"str\"").unwrap();
+        assert_eq!(vm.get_global("x").to_string(), "str");
+        assert_eq!(vm.get_global("undeclared"), Value::Nil);
+
+        vm.eval("const c = 1").unwrap();
+        assert!(matches!(
+            vm.set_global("c", 2),
+            Err(ErrorKind::ConstGlobal(_))
+        ));
+    }
+
+    #[test]
+    fn register_fn() {
+        let mut vm = Vm::new();
+        vm.register_fn("repeat", |s: String, n: usize| s.repeat(n));
+        vm.register_fn("maybe", |n: Option<f64>| n.map(|n| n * 2.0));
+        vm.register_fn("checked_div", |a: f64, b: f64| {
+            if b == 0.0 {
+                return Err(ErrorKind::RuntimeError("division by zero".into()));
+            }
+            Ok(a / b)
+        });
+
+        assert_eq!(vm.eval("repeat(\"ab\", 3)").unwrap().to_string(), "ababab");
+        assert_eq!(vm.eval("maybe(nil)").unwrap(), Value::Nil);
+        assert_eq!(vm.eval("maybe(2)").unwrap(), Value::Number(4.0));
+
+        let err = vm.eval("repeat(\"ab\")").unwrap_err();
+        assert!(matches!(err.kind, ErrorKind::ArityMismatch(2, 1)));
+        let err = vm.eval("repeat(1, 2)").unwrap_err();
+        assert!(matches!(
+            err.kind,
+            ErrorKind::InvalidType("number", "string")
+        ));
+        // integers are only converted from whole numbers in range
+        for arg in ["-1", "2.7", "0 / 0", "1 / 0", "1e20"] {
+            let err = vm.eval(&format!("repeat(\"ab\", {arg})")).unwrap_err();
+            assert!(
+                matches!(err.kind, ErrorKind::InvalidType("number", "usize")),
+                "{arg}"
+            );
+        }
+        assert_eq!(vm.eval("repeat(\"ab\", 0)").unwrap().to_string(), "");
+        let err = vm.eval("checked_div(1, 0)").unwrap_err();
+        assert!(matches!(err.kind, ErrorKind::RuntimeError(_)));
+
+        // registered functions are read-only
+        assert!(vm.eval("repeat = nil").is_err());
+    }
+
+    #[test]
+    fn runtime_error_span() {
+        let mut vm = Vm::new();
+        vm.eval("fn fail() do\n    return 1 + nil\nend").unwrap();
+        let err = vm.eval("fail()").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!("Error [<eval 1>, line:2, col:14]: {}", err.kind)
+        );
+    }
+
+    const FIB: &str =
+        "fn fib(n) do\n    if n < 2 then return n end\n    return fib(n - 1) + fib(n - 2)\nend";
+
+    #[test]
+    fn worker_threads() {
+        let mut vm = Vm::new();
+        let output = vm.interpreter().capture_stdout();
+        vm.eval(FIB).unwrap();
+        // SAFETY: nothing from the VM is kept on this thread but the output buffer
+        let detached = unsafe { vm.detach() };
+        let result = thread::spawn(move || {
+            let mut vm = detached.into_inner();
+            vm.eval("println(\"on worker\")").unwrap();
+            vm.eval("fib(15)").unwrap().try_num().unwrap()
+        })
+        .join()
+        .unwrap();
+        assert_eq!(result, 610.0);
+        assert_eq!(output.contents(), "on worker\n");
+
+        // independent interpreters run in parallel
+        let workers: Vec<_> = (0..4)
+            .map(|i| {
+                thread::spawn(move || {
+                    let mut vm = Vm::new();
+                    vm.set_global("i", i).unwrap();
+                    vm.eval(FIB).unwrap();
+                    vm.eval("str(\"worker \", i, \": \", fib(10 + i))")
+                        .unwrap()
+                        .to_string()
+                })
+            })
+            .collect();
+        let results: Vec<_> = workers.into_iter().map(|w| w.join().unwrap()).collect();
+        assert_eq!(
+            results,
+            [
+                "worker 0: 55",
+                "worker 1: 89",
+                "worker 2: 144",
+                "worker 3: 233"
+            ]
+        );
+    }
+}
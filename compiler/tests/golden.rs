@@ -0,0 +1,58 @@
+//! Runs every `.rlox` program in `tests/programs` and compares its output with the `.out` file next to it.
+//!
+//! A program that fails has the error appended to its output.
+//! Set `UPDATE_GOLDEN=1` to rewrite the `.out` files from the current output.
+
+use std::{env, fs, path::Path};
+
+use compiler::vm::Vm;
+
+fn run_program(path: &Path) -> String {
+    let source = fs::read_to_string(path).unwrap();
+    let mut vm = Vm::new();
+    let output = vm.interpreter().capture_stdout();
+    let result = vm.eval(&source);
+
+    let mut output = output.take();
+    if let Err(err) = result {
+        if !output.is_empty() && !output.ends_with('\n') {
+            output.push('\n');
+        }
+        output += &format!("{err}\n");
+    }
+    output
+}
+
+#[test]
+fn golden_outputs() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/programs");
+    let update = env::var_os("UPDATE_GOLDEN").is_some();
+
+    let mut programs = fs::read_dir(&dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "rlox"))
+        .collect::<Vec<_>>();
+    programs.sort();
+    assert!(!programs.is_empty(), "no programs in {}", dir.display());
+
+    let mut failures = vec![];
+    for program in &programs {
+        let output = run_program(program);
+        let expected_path = program.with_extension("out");
+        if update {
+            fs::write(&expected_path, &output).unwrap();
+            continue;
+        }
+        let expected = fs::read_to_string(&expected_path).unwrap_or_default();
+        if output != expected {
+            failures.push(format!(
+                "{}\n--- expected\n{}--- actual\n{}",
+                program.display(),
+                expected,
+                output
+            ));
+        }
+    }
+    assert!(failures.is_empty(), "{}", failures.join("\n"));
+}
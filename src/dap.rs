@@ -0,0 +1,669 @@
+//! Debug Adapter Protocol server of `rlox dap`, driving the debugger over stdio.
+//!
+//! Requests are read on a separate thread, so that the program can be paused or have its breakpoints changed while
+//! it runs. Everything else is handled while the program is paused, or before and after it runs.
+use crate::transport::{read_message, write_message};
+use compiler::{
+    ast::Parser,
+    codegen::Codegen,
+    debugger::{user_globals, DebugFrontend, Debugger, Resume, StopReason},
+    error::{Error, ErrorKind},
+    interpreter::{value::Value, FnSignature, Interpreter},
+    source_map::SourceMap,
+};
+use serde_json::{json, Value as Json};
+use std::{
+    cell::RefCell,
+    collections::VecDeque,
+    fs,
+    io::{self, BufRead, BufReader, LineWriter, Write},
+    rc::Rc,
+    sync::mpsc::{self, Receiver, TryRecvError},
+    thread,
+};
+
+const THREAD_ID: i64 = 1;
+
+struct Connection {
+    requests: Receiver<Json>,
+    // requests received while running, which have to wait for the program to pause
+    deferred: VecDeque<Json>,
+    output: Box<dyn Write>,
+    seq: i64,
+    disconnected: bool,
+}
+impl Connection {
+    fn send(&mut self, mut message: Json) -> io::Result<()> {
+        self.seq += 1;
+        message["seq"] = self.seq.into();
+        write_message(&mut self.output, &message)
+    }
+    fn respond(&mut self, request: &Json, body: Result<Json, String>) -> io::Result<()> {
+        let mut response = json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": request["command"],
+            "success": body.is_ok(),
+        });
+        match body {
+            Ok(body) => response["body"] = body,
+            Err(message) => response["message"] = message.into(),
+        }
+        self.send(response)
+    }
+    fn event(&mut self, event: &str, body: Json) -> io::Result<()> {
+        self.send(json!({ "type": "event", "event": event, "body": body }))
+    }
+    /// Next request, waiting for one if none are pending. None once the client closed the input.
+    fn next_request(&mut self) -> Option<Json> {
+        self.deferred
+            .pop_front()
+            .or_else(|| self.requests.recv().ok())
+    }
+}
+
+/// Forwards program output to the client as output events.
+struct OutputEvents {
+    connection: Rc<RefCell<Connection>>,
+    category: &'static str,
+}
+impl Write for OutputEvents {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let output = String::from_utf8_lossy(buf);
+        self.connection.borrow_mut().event(
+            "output",
+            json!({ "category": self.category, "output": output }),
+        )?;
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Program compiled by the launch request.
+struct Launch {
+    path: String,
+    interpreter: Interpreter,
+    main: Rc<FnSignature>,
+    debugger: Debugger,
+    sources: SourceMap,
+}
+impl Launch {
+    fn new(args: &Json) -> Result<Self, String> {
+        let path = args["program"]
+            .as_str()
+            .ok_or("missing `program` to launch")?
+            .to_owned();
+        let file =
+            fs::File::open(&path).map_err(|err| format!("Error loading file `{path}`: {err}"))?;
+        let mut parser = Parser::with_file(BufReader::new(file), &SourceMap::new(), &path);
+        let interpreter = Interpreter::default();
+        let mut codegen = Codegen::with_context(parser.sources(), interpreter.context());
+        while let Some(statement) = parser.next_statement().map_err(|err| err.to_string())? {
+            codegen
+                .gen_statement(&statement)
+                .map_err(|err| err.to_string())?;
+        }
+        let main = Rc::new(codegen.pop_init_sig());
+        let mut debugger = Debugger::new(parser.sources(), parser.file(), &main);
+        debugger.stop_on_entry(args["stopOnEntry"].as_bool().unwrap_or(false));
+        Ok(Self {
+            path,
+            interpreter,
+            main,
+            debugger,
+            sources: parser.sources(),
+        })
+    }
+}
+
+fn capabilities() -> Json {
+    json!({
+        "supportsConfigurationDoneRequest": true,
+        "supportsEvaluateForHovers": true,
+    })
+}
+
+fn threads() -> Json {
+    json!({ "threads": [{ "id": THREAD_ID, "name": "main" }] })
+}
+
+/// Replaces the breakpoints of the launched program. Breakpoints in other sources can't be verified.
+fn set_breakpoints(debugger: &mut Debugger, path: &str, args: &Json) -> Json {
+    let own_source = args["source"]["path"].as_str() == Some(path);
+    if own_source {
+        debugger.clear_breakpoints();
+    }
+    let breakpoints = args["breakpoints"]
+        .as_array()
+        .map_or(&[][..], Vec::as_slice);
+    let breakpoints: Vec<_> = breakpoints
+        .iter()
+        .map(|breakpoint| {
+            let line = breakpoint["line"].as_u64().unwrap_or(0) as usize;
+            match own_source.then(|| debugger.set_breakpoint(line)).flatten() {
+                Some(line) => json!({ "verified": true, "line": line }),
+                None => json!({ "verified": false, "line": line }),
+            }
+        })
+        .collect();
+    json!({ "breakpoints": breakpoints })
+}
+
+/// Serves a client reading requests from `input` and writing responses and events to `output`,
+/// until it disconnects or closes the input.
+pub fn serve(input: impl BufRead + Send + 'static, output: impl Write + 'static) -> io::Result<()> {
+    let (sender, requests) = mpsc::channel();
+    thread::spawn(move || {
+        let mut input = input;
+        while let Ok(Some(message)) = read_message(&mut input) {
+            if sender.send(message).is_err() {
+                break;
+            }
+        }
+    });
+    let connection = Rc::new(RefCell::new(Connection {
+        requests,
+        deferred: VecDeque::new(),
+        output: Box::new(output),
+        seq: 0,
+        disconnected: false,
+    }));
+
+    let mut launch: Option<Launch> = None;
+    loop {
+        if connection.borrow().disconnected {
+            return Ok(());
+        }
+        let Some(request) = connection.borrow_mut().next_request() else {
+            return Ok(());
+        };
+        let args = &request["arguments"];
+        let body = match request["command"].as_str().unwrap_or_default() {
+            "initialize" => Ok(capabilities()),
+            "launch" => match Launch::new(args) {
+                Ok(program) => {
+                    launch = Some(program);
+                    let mut connection = connection.borrow_mut();
+                    connection.respond(&request, Ok(json!({})))?;
+                    connection.event("initialized", json!({}))?;
+                    continue;
+                }
+                Err(message) => Err(message),
+            },
+            "setBreakpoints" => match &mut launch {
+                Some(program) => Ok(set_breakpoints(&mut program.debugger, &program.path, args)),
+                None => Err("no program launched".to_owned()),
+            },
+            "configurationDone" => match launch.take() {
+                Some(program) => {
+                    connection.borrow_mut().respond(&request, Ok(json!({})))?;
+                    run(program, &connection)?;
+                    continue;
+                }
+                None => Err("no program launched".to_owned()),
+            },
+            "threads" => Ok(threads()),
+            "disconnect" => {
+                connection.borrow_mut().respond(&request, Ok(json!({})))?;
+                return Ok(());
+            }
+            command => Err(format!("unsupported request `{command}`")),
+        };
+        connection.borrow_mut().respond(&request, body)?;
+    }
+}
+
+/// Runs the launched program to the end, reporting its output and exit to the client.
+fn run(program: Launch, connection: &Rc<RefCell<Connection>>) -> io::Result<()> {
+    let Launch {
+        path,
+        mut interpreter,
+        main,
+        debugger,
+        sources,
+    } = program;
+    // an event per line rather than per write
+    interpreter.set_stdout(LineWriter::new(OutputEvents {
+        connection: connection.clone(),
+        category: "stdout",
+    }));
+    interpreter.set_stderr(LineWriter::new(OutputEvents {
+        connection: connection.clone(),
+        category: "stderr",
+    }));
+    let frontend = DapFrontend {
+        connection: connection.clone(),
+        path,
+        references: vec![],
+    };
+    debugger.attach(&mut interpreter, frontend);
+
+    let main = Rc::new(interpreter.create_function(main));
+    let result = interpreter.call_function_args(main, []);
+    interpreter.stdout().flush()?;
+    interpreter.stderr().flush()?;
+    let mut connection = connection.borrow_mut();
+    let exit_code = match result {
+        Ok(_) => 0,
+        Err(ErrorKind::Interrupted) if connection.disconnected => return Ok(()),
+        Err(kind) => {
+            let err = Error::new(kind, interpreter.error_span().unwrap_or_default(), sources);
+            connection.event(
+                "output",
+                json!({ "category": "stderr", "output": format!("{err}\n") }),
+            )?;
+            1
+        }
+    };
+    connection.event("exited", json!({ "exitCode": exit_code }))?;
+    connection.event("terminated", json!({}))
+}
+
+/// Variables behind a `variablesReference`.
+enum Reference {
+    Locals(usize),
+    Upvalues(usize),
+    Globals,
+    Value(Value),
+}
+
+struct DapFrontend {
+    connection: Rc<RefCell<Connection>>,
+    path: String,
+    // by `variablesReference` - 1, valid until the program resumes
+    references: Vec<Reference>,
+}
+impl DapFrontend {
+    fn reference(&mut self, reference: Reference) -> usize {
+        self.references.push(reference);
+        self.references.len()
+    }
+    fn variable(&mut self, name: String, value: Value) -> Json {
+        let shown = match &value {
+            Value::String(str) => format!("{:?}", str.as_str()),
+            value => value.to_string(),
+        };
+        let reference = match value {
+            Value::Array(_) | Value::Object(_) => self.reference(Reference::Value(value.clone())),
+            _ => 0,
+        };
+        json!({
+            "name": name,
+            "value": shown,
+            "type": value.type_str(),
+            "variablesReference": reference,
+        })
+    }
+    fn variables(&mut self, interpreter: &Interpreter, reference: usize) -> Result<Json, String> {
+        let vars = match self.references.get(reference.wrapping_sub(1)) {
+            Some(Reference::Locals(frame)) => interpreter.frame_locals(*frame),
+            Some(Reference::Upvalues(frame)) => interpreter.frame_upvalues(*frame),
+            Some(Reference::Globals) => user_globals(interpreter),
+            Some(Reference::Value(Value::Array(array))) => {
+                let elements = array.borrow().clone();
+                let vars = elements
+                    .into_iter()
+                    .enumerate()
+                    .map(|(i, value)| self.variable(i.to_string(), value))
+                    .collect::<Vec<_>>();
+                return Ok(json!({ "variables": vars }));
+            }
+            Some(Reference::Value(Value::Object(object))) => {
+                let mut entries = object
+                    .borrow()
+                    .map
+                    .iter()
+                    .map(|(key, value)| (key.to_string(), value.clone()))
+                    .collect::<Vec<_>>();
+                entries.sort_by(|a, b| a.0.cmp(&b.0));
+                let vars = entries
+                    .into_iter()
+                    .map(|(name, value)| self.variable(name, value))
+                    .collect::<Vec<_>>();
+                return Ok(json!({ "variables": vars }));
+            }
+            _ => return Err(format!("unknown variablesReference {reference}")),
+        };
+        let vars = vars
+            .into_iter()
+            .map(|(name, value)| self.variable(name.to_string(), value))
+            .collect::<Vec<_>>();
+        Ok(json!({ "variables": vars }))
+    }
+    fn stack_trace(&self, debugger: &Debugger, interpreter: &Interpreter) -> Json {
+        let frames = (0..interpreter.frame_count())
+            .rev()
+            .map(|frame| {
+                let name = match frame {
+                    0 => "<main>",
+                    _ => "<function>",
+                };
+                match interpreter.frame_span(frame) {
+                    Some(span) => json!({
+                        "id": frame,
+                        "name": name,
+                        "line": debugger.line_of(span).unwrap_or(0),
+                        "column": debugger.column_of(span),
+                        "source": { "path": self.path },
+                    }),
+                    None => json!({
+                        "id": frame,
+                        "name": "<builtin>",
+                        "line": 0,
+                        "column": 0,
+                        "presentationHint": "subtle",
+                    }),
+                }
+            })
+            .collect::<Vec<_>>();
+        json!({ "stackFrames": frames, "totalFrames": frames.len() })
+    }
+    fn frame_arg(interpreter: &Interpreter, args: &Json) -> Result<usize, String> {
+        match args["frameId"].as_u64() {
+            None => Ok(interpreter.frame_count() - 1),
+            Some(frame) if (frame as usize) < interpreter.frame_count() => Ok(frame as usize),
+            Some(frame) => Err(format!("unknown frameId {frame}")),
+        }
+    }
+}
+impl DebugFrontend for DapFrontend {
+    /// Reports the stop and serves requests until one resumes execution.
+    fn paused(
+        &mut self,
+        debugger: &mut Debugger,
+        interpreter: &mut Interpreter,
+        reason: StopReason,
+        _line: usize,
+    ) -> Result<Resume, ErrorKind> {
+        let connection = self.connection.clone();
+        if connection.borrow().disconnected {
+            return Err(ErrorKind::Interrupted);
+        }
+        let reason = match reason {
+            StopReason::Entry => "entry",
+            StopReason::Breakpoint => "breakpoint",
+            StopReason::Step => "step",
+            StopReason::Pause => "pause",
+        };
+        connection.borrow_mut().event(
+            "stopped",
+            json!({ "reason": reason, "threadId": THREAD_ID, "allThreadsStopped": true }),
+        )?;
+        self.references.clear();
+
+        loop {
+            let Some(request) = connection.borrow_mut().next_request() else {
+                return Err(ErrorKind::Interrupted);
+            };
+            let args = &request["arguments"];
+            let resume = match request["command"].as_str().unwrap_or_default() {
+                "continue" => Some(Resume::Continue),
+                "next" => Some(Resume::StepOver),
+                "stepIn" => Some(Resume::StepIn),
+                "stepOut" => Some(Resume::StepOut),
+                _ => None,
+            };
+            if let Some(resume) = resume {
+                let body = match resume {
+                    Resume::Continue => json!({ "allThreadsContinued": true }),
+                    _ => json!({}),
+                };
+                connection.borrow_mut().respond(&request, Ok(body))?;
+                return Ok(resume);
+            }
+            let body = match request["command"].as_str().unwrap_or_default() {
+                "threads" => Ok(threads()),
+                "stackTrace" => Ok(self.stack_trace(debugger, interpreter)),
+                "scopes" => Self::frame_arg(interpreter, args).map(|frame| {
+                    let locals = self.reference(Reference::Locals(frame));
+                    let upvalues = self.reference(Reference::Upvalues(frame));
+                    let globals = self.reference(Reference::Globals);
+                    json!({ "scopes": [
+                        { "name": "Locals", "presentationHint": "locals", "variablesReference": locals, "expensive": false },
+                        { "name": "Upvalues", "variablesReference": upvalues, "expensive": false },
+                        { "name": "Globals", "variablesReference": globals, "expensive": false },
+                    ]})
+                }),
+                "variables" => {
+                    let reference = args["variablesReference"].as_u64().unwrap_or(0) as usize;
+                    self.variables(interpreter, reference)
+                }
+                "evaluate" => Self::frame_arg(interpreter, args).and_then(|frame| {
+                    let expression = args["expression"].as_str().unwrap_or_default();
+                    let value = debugger.eval(interpreter, frame, expression)?;
+                    let variable = self.variable(String::new(), value);
+                    Ok(json!({
+                        "result": variable["value"],
+                        "type": variable["type"],
+                        "variablesReference": variable["variablesReference"],
+                    }))
+                }),
+                "setBreakpoints" => Ok(set_breakpoints(debugger, &self.path, args)),
+                "pause" => Ok(json!({})),
+                "disconnect" => {
+                    let mut connection = connection.borrow_mut();
+                    connection.disconnected = true;
+                    connection.respond(&request, Ok(json!({})))?;
+                    return Err(ErrorKind::Interrupted);
+                }
+                command => Err(format!("unsupported request `{command}`")),
+            };
+            connection.borrow_mut().respond(&request, body)?;
+        }
+    }
+    /// Serves the requests that can't wait for the program to pause.
+    fn pause_requested(&mut self, debugger: &mut Debugger) -> bool {
+        let mut connection = self.connection.borrow_mut();
+        let mut pause = false;
+        loop {
+            let request = match connection.requests.try_recv() {
+                Ok(request) => request,
+                // the client is gone, so stop to end the run
+                Err(TryRecvError::Disconnected) => {
+                    connection.disconnected = true;
+                    return true;
+                }
+                Err(TryRecvError::Empty) => return pause,
+            };
+            let args = &request["arguments"];
+            let body = match request["command"].as_str().unwrap_or_default() {
+                "pause" => {
+                    pause = true;
+                    json!({})
+                }
+                "setBreakpoints" => set_breakpoints(debugger, &self.path, args),
+                "threads" => threads(),
+                "disconnect" => {
+                    connection.disconnected = true;
+                    pause = true;
+                    json!({})
+                }
+                _ => {
+                    connection.deferred.push_back(request);
+                    continue;
+                }
+            };
+            // a client that can't be written to ends the run at the next pause
+            if connection.respond(&request, Ok(body)).is_err() {
+                connection.disconnected = true;
+                pause = true;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, io::PipeReader, io::PipeWriter};
+
+    const PROGRAM: &str = "\
+fn add(a, b) do
+    let sum = a + b
+    return sum
+end
+let items = [1, {name: \"x\"}]
+let total = add(1, 2)
+println(total)
+";
+
+    /// Scripted client talking to a server on another thread.
+    struct Client {
+        requests: PipeWriter,
+        messages: BufReader<PipeReader>,
+        seq: i64,
+        events: Vec<Json>,
+    }
+    impl Client {
+        fn start() -> Self {
+            let (server_input, requests) = io::pipe().unwrap();
+            let (messages, server_output) = io::pipe().unwrap();
+            thread::spawn(move || serve(BufReader::new(server_input), server_output).unwrap());
+            Self {
+                requests,
+                messages: BufReader::new(messages),
+                seq: 0,
+                events: vec![],
+            }
+        }
+        fn request(&mut self, command: &str, arguments: Json) -> Json {
+            self.seq += 1;
+            let request = json!({
+                "seq": self.seq,
+                "type": "request",
+                "command": command,
+                "arguments": arguments,
+            });
+            write_message(&mut self.requests, &request).unwrap();
+            loop {
+                let message = read_message(&mut self.messages).unwrap().unwrap();
+                if message["type"] == "event" {
+                    self.events.push(message);
+                } else if message["request_seq"] == self.seq {
+                    assert_eq!(message["success"], true, "{message}");
+                    return message["body"].clone();
+                }
+            }
+        }
+        fn event(&mut self, event: &str) -> Json {
+            if let Some(i) = self.events.iter().position(|e| e["event"] == event) {
+                return self.events.remove(i)["body"].clone();
+            }
+            loop {
+                let message = read_message(&mut self.messages).unwrap().unwrap();
+                if message["event"] == event {
+                    return message["body"].clone();
+                }
+                self.events.push(message);
+            }
+        }
+        fn stopped(&mut self, reason: &str) -> Json {
+            assert_eq!(self.event("stopped")["reason"], reason);
+            let trace = self.request("stackTrace", json!({ "threadId": THREAD_ID }));
+            trace["stackFrames"][0].clone()
+        }
+        /// Variables of the scope or value behind `reference`, as name and shown value.
+        fn variables(&mut self, reference: &Json) -> Vec<(String, String, Json)> {
+            let body = self.request("variables", json!({ "variablesReference": reference }));
+            body["variables"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|var| {
+                    (
+                        var["name"].as_str().unwrap().to_owned(),
+                        var["value"].as_str().unwrap().to_owned(),
+                        var["variablesReference"].clone(),
+                    )
+                })
+                .collect()
+        }
+        fn scope(&mut self, frame: &Json, name: &str) -> Json {
+            let scopes = self.request("scopes", json!({ "frameId": frame["id"] }));
+            let scopes = scopes["scopes"].as_array().unwrap();
+            let scope = scopes.iter().find(|scope| scope["name"] == name).unwrap();
+            scope["variablesReference"].clone()
+        }
+    }
+
+    #[test]
+    fn debug_session() {
+        let path = env::temp_dir().join(format!("rlox-dap-{}.rlox", std::process::id()));
+        fs::write(&path, PROGRAM).unwrap();
+        let path = path.to_str().unwrap();
+
+        let mut client = Client::start();
+        let capabilities = client.request("initialize", json!({ "adapterID": "rlox" }));
+        assert_eq!(capabilities["supportsConfigurationDoneRequest"], true);
+        client.request("launch", json!({ "program": path }));
+        client.event("initialized");
+        let breakpoints = client.request(
+            "setBreakpoints",
+            json!({ "source": { "path": path }, "breakpoints": [{ "line": 6 }, { "line": 4 }] }),
+        );
+        assert_eq!(
+            breakpoints["breakpoints"],
+            json!([{ "verified": true, "line": 6 }, { "verified": true, "line": 5 }])
+        );
+        client.request(
+            "setBreakpoints",
+            json!({ "source": { "path": path }, "breakpoints": [{ "line": 6 }] }),
+        );
+        client.request("configurationDone", json!({}));
+
+        let frame = client.stopped("breakpoint");
+        assert_eq!(
+            (&frame["line"], &frame["name"]),
+            (&json!(6), &json!("<main>"))
+        );
+        client.request("stepIn", json!({ "threadId": THREAD_ID }));
+        let frame = client.stopped("step");
+        assert_eq!(frame["line"], 2);
+        let trace = client.request("stackTrace", json!({ "threadId": THREAD_ID }));
+        assert_eq!(trace["totalFrames"], 2);
+        assert_eq!(trace["stackFrames"][1]["line"], 6);
+        let locals = client.scope(&frame, "Locals");
+        let locals = client.variables(&locals);
+        assert_eq!(
+            locals
+                .iter()
+                .map(|(name, value, _)| (name.as_str(), value.as_str()))
+                .collect::<Vec<_>>(),
+            [("a", "1"), ("b", "2"), ("sum", "nil")]
+        );
+
+        client.request("next", json!({ "threadId": THREAD_ID }));
+        let frame = client.stopped("step");
+        assert_eq!(frame["line"], 3);
+        let result = client.request(
+            "evaluate",
+            json!({ "expression": "sum * 10", "frameId": frame["id"] }),
+        );
+        assert_eq!(result["result"], "30");
+
+        client.request("stepOut", json!({ "threadId": THREAD_ID }));
+        let frame = client.stopped("step");
+        assert_eq!(frame["line"], 6);
+        let globals = client.scope(&frame, "Globals");
+        let globals = client.variables(&globals);
+        let (_, _, items) = globals.iter().find(|(name, ..)| name == "items").unwrap();
+        let items = client.variables(items);
+        assert_eq!((items[0].0.as_str(), items[0].1.as_str()), ("0", "1"));
+        let object = client.variables(&items[1].2);
+        assert_eq!(
+            (object[0].0.as_str(), object[0].1.as_str()),
+            ("name", "\"x\"")
+        );
+        let result = client.request("evaluate", json!({ "expression": "items" }));
+        assert_ne!(result["variablesReference"], 0);
+
+        client.request("continue", json!({ "threadId": THREAD_ID }));
+        assert_eq!(client.event("output")["output"], "3\n");
+        assert_eq!(client.event("exited")["exitCode"], 0);
+        client.event("terminated");
+        client.request("disconnect", json!({}));
+        fs::remove_file(path).unwrap();
+    }
+}
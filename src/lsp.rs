@@ -0,0 +1,506 @@
+//! Language server of `rlox lsp`, speaking the Language Server Protocol over stdio.
+//!
+//! Documents are parsed and compiled on every change. Navigation works off the symbols codegen records while
+//! resolving variables, so it sees the same scopes and upvalues as the compiled program.
+use crate::transport::{read_message, write_message};
+use compiler::{
+    ast::Parser,
+    codegen::{
+        symbol::{Symbol, SymbolKind},
+        Codegen,
+    },
+    error::Error,
+    interpreter::context::Context,
+    span::Span,
+};
+use serde_json::{json, Value as Json};
+use std::{
+    collections::{HashMap, HashSet},
+    io::{self, BufRead, Write},
+};
+
+const KEYWORDS: &[&str] = &[
+    "and", "break", "const", "continue", "do", "else", "end", "false", "fn", "for", "if", "in",
+    "let", "nil", "not", "or", "return", "then", "true", "while",
+];
+
+// LSP enumerations
+const SEVERITY_ERROR: i64 = 1;
+const COMPLETION_FUNCTION: i64 = 3;
+const COMPLETION_VARIABLE: i64 = 6;
+const COMPLETION_KEYWORD: i64 = 14;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+
+/// Parsed and compiled state of an open document.
+struct Document {
+    text: String,
+    line_starts: Vec<usize>,
+    errors: Vec<Error>,
+    symbols: Vec<Symbol>,
+}
+impl Document {
+    /// Compiles `text` to collect its errors and symbols. Parsing stops at the first syntax error and codegen at the
+    /// first compile error, the statements before them still count.
+    fn new(text: String) -> Self {
+        let mut parser = Parser::new(io::Cursor::new(text.clone()));
+        // the code is never run, so a context of its own will do
+        let mut codegen = Codegen::with_context(parser.sources(), Default::default());
+        codegen.record_symbols();
+        // every syntax error is reported, codegen stops at its first error
+        let (statements, mut errors) = parser.parse_program();
+        for statement in &statements {
+            if let Err(err) = codegen.gen_statement(statement) {
+                errors.push(err);
+                break;
+            }
+        }
+        let line_starts = std::iter::once(0)
+            .chain(text.match_indices('\n').map(|(i, _)| i + 1))
+            .collect();
+        Self {
+            symbols: codegen.symbols().to_vec(),
+            text,
+            line_starts,
+            errors,
+        }
+    }
+    /// LSP position of a byte offset, with the character counted in UTF-16 code units.
+    fn position(&self, offset: usize) -> Json {
+        let offset = offset.min(self.text.len());
+        let line = self.line_starts.partition_point(|&start| start <= offset) - 1;
+        let character = self.text[self.line_starts[line]..offset]
+            .encode_utf16()
+            .count();
+        json!({ "line": line, "character": character })
+    }
+    fn range(&self, span: Span) -> Json {
+        json!({ "start": self.position(span.start), "end": self.position(span.end) })
+    }
+    fn offset(&self, position: &Json) -> usize {
+        let line = position["line"].as_u64().unwrap_or(0) as usize;
+        let Some(&start) = self.line_starts.get(line) else {
+            return self.text.len();
+        };
+        let mut character = position["character"].as_u64().unwrap_or(0) as usize;
+        let mut offset = start;
+        for ch in self.text[start..].chars() {
+            if character == 0 || ch == '\n' {
+                break;
+            }
+            character = character.saturating_sub(ch.len_utf16());
+            offset += ch.len_utf8();
+        }
+        offset
+    }
+    fn symbol_at(&self, offset: usize) -> Option<&Symbol> {
+        self.symbols.iter().find(|symbol| symbol.is_at(offset))
+    }
+    fn diagnostics(&self) -> Json {
+        self.errors
+            .iter()
+            .map(|err| {
+                json!({
+                    "range": self.range(err.span),
+                    "severity": SEVERITY_ERROR,
+                    "source": "rlox",
+                    "message": err.kind.to_string(),
+                })
+            })
+            .collect()
+    }
+}
+
+/// Type of a function as values show it, like `fn(2)` or `fn(1...)` for variadic ones.
+fn function_type((arity, variadic): (usize, bool)) -> String {
+    format!("fn({}{})", arity, if variadic { "..." } else { "" })
+}
+
+struct Server {
+    output: Box<dyn Write>,
+    documents: HashMap<String, Document>,
+    // name, arity and variadic flag
+    builtins: Vec<(String, (usize, bool))>,
+}
+impl Server {
+    fn send(&mut self, message: Json) -> io::Result<()> {
+        write_message(&mut self.output, &message)
+    }
+    fn notify(&mut self, method: &str, params: Json) -> io::Result<()> {
+        self.send(json!({ "jsonrpc": "2.0", "method": method, "params": params }))
+    }
+    fn open(&mut self, uri: &str, text: String) -> io::Result<()> {
+        let document = Document::new(text);
+        let diagnostics = document.diagnostics();
+        self.documents.insert(uri.to_owned(), document);
+        self.notify(
+            "textDocument/publishDiagnostics",
+            json!({ "uri": uri, "diagnostics": diagnostics }),
+        )
+    }
+    fn builtin(&self, name: &str) -> Option<(usize, bool)> {
+        let (_, function) = self.builtins.iter().find(|(builtin, _)| builtin == name)?;
+        Some(*function)
+    }
+    /// Document and offset of a `TextDocumentPositionParams`.
+    fn position<'a>(&'a self, params: &Json) -> Result<(&'a Document, usize), String> {
+        let uri = params["textDocument"]["uri"].as_str().unwrap_or_default();
+        let document = self
+            .documents
+            .get(uri)
+            .ok_or_else(|| format!("unknown document `{uri}`"))?;
+        Ok((document, document.offset(&params["position"])))
+    }
+    fn definition(&self, params: &Json) -> Result<Json, String> {
+        let (document, offset) = self.position(params)?;
+        let uri = &params["textDocument"]["uri"];
+        Ok(document
+            .symbol_at(offset)
+            .and_then(|symbol| symbol.definition)
+            .map_or(
+                Json::Null,
+                |span| json!({ "uri": uri, "range": document.range(span) }),
+            ))
+    }
+    fn references(&self, params: &Json) -> Result<Json, String> {
+        let (document, offset) = self.position(params)?;
+        let uri = &params["textDocument"]["uri"];
+        let Some(symbol) = document.symbol_at(offset) else {
+            return Ok(Json::Null);
+        };
+        let declaration = params["context"]["includeDeclaration"]
+            .as_bool()
+            .unwrap_or(false);
+        let mut spans = symbol.references.clone();
+        spans.extend(symbol.definition.filter(|_| declaration));
+        spans.sort_by_key(|span| span.start);
+        let locations = spans
+            .into_iter()
+            .map(|span| json!({ "uri": uri, "range": document.range(span) }))
+            .collect::<Vec<_>>();
+        Ok(locations.into())
+    }
+    fn hover(&self, params: &Json) -> Result<Json, String> {
+        let (document, offset) = self.position(params)?;
+        let Some(symbol) = document.symbol_at(offset) else {
+            return Ok(Json::Null);
+        };
+        let builtin = symbol
+            .definition
+            .is_none()
+            .then(|| self.builtin(symbol.name.as_str()))
+            .flatten();
+        let kind = match (symbol.kind, builtin) {
+            (_, Some(_)) => "builtin",
+            (SymbolKind::Global, None) => "global",
+            (SymbolKind::Local, None) => "local",
+        };
+        let mut value = format!("({}) {}", kind, symbol.name);
+        if let Some(function) = builtin.or(symbol.function) {
+            let (arity, variadic) = function;
+            value = format!(
+                "{}: {}\n\nTakes {}{} argument{}",
+                value,
+                function_type(function),
+                arity,
+                if variadic { " or more" } else { "" },
+                if arity == 1 && !variadic { "" } else { "s" },
+            );
+        }
+        let span = symbol
+            .definition
+            .iter()
+            .chain(&symbol.references)
+            .find(|span| span.start <= offset && offset <= span.end);
+        Ok(json!({
+            "contents": { "kind": "markdown", "value": value },
+            "range": span.map(|span| document.range(*span)),
+        }))
+    }
+    fn completion(&self, params: &Json) -> Result<Json, String> {
+        let (document, offset) = self.position(params)?;
+        let mut seen = HashSet::new();
+        let mut items = vec![];
+        let mut item = |name: &str, function: Option<(usize, bool)>, detail: &str| {
+            if seen.insert(name.to_owned()) {
+                items.push(json!({
+                    "label": name,
+                    "kind": match function {
+                        Some(_) => COMPLETION_FUNCTION,
+                        None => COMPLETION_VARIABLE,
+                    },
+                    "detail": match function {
+                        Some(function) => format!("{detail} {}", function_type(function)),
+                        None => detail.to_owned(),
+                    },
+                }));
+            }
+        };
+        // innermost locals first, so they shadow outer ones
+        let mut locals = document
+            .symbols
+            .iter()
+            .filter(|symbol| {
+                symbol.scope.as_ref().is_some_and(|scope| {
+                    symbol.definition.is_some_and(|span| span.end < offset) && offset <= scope.end
+                })
+            })
+            .collect::<Vec<_>>();
+        locals.sort_by_key(|symbol| std::cmp::Reverse(symbol.scope.as_ref().unwrap().start));
+        for symbol in locals {
+            item(symbol.name.as_str(), symbol.function, "local");
+        }
+        for symbol in &document.symbols {
+            if symbol.kind == SymbolKind::Global && symbol.definition.is_some() {
+                item(symbol.name.as_str(), symbol.function, "global");
+            }
+        }
+        for (name, function) in &self.builtins {
+            item(name, Some(*function), "builtin");
+        }
+        items.extend(
+            KEYWORDS
+                .iter()
+                .map(|keyword| json!({ "label": keyword, "kind": COMPLETION_KEYWORD })),
+        );
+        Ok(items.into())
+    }
+}
+
+/// Serves a client reading messages from `input` and writing to `output`, until it sends `exit` or closes the input.
+pub fn serve(mut input: impl BufRead, output: impl Write + 'static) -> io::Result<()> {
+    let context = Context::default();
+    let mut builtins = context
+        .builtins()
+        .map(|(name, function)| {
+            let signature = &function.signature;
+            (name.to_string(), (signature.arity, signature.variadic))
+        })
+        .collect::<Vec<_>>();
+    builtins.sort();
+    let mut server = Server {
+        output: Box::new(output),
+        documents: HashMap::new(),
+        builtins,
+    };
+
+    while let Some(message) = read_message(&mut input)? {
+        let params = &message["params"];
+        let result = match message["method"].as_str().unwrap_or_default() {
+            "initialize" => Ok(json!({
+                "capabilities": {
+                    "textDocumentSync": 1, // full document on every change
+                    "definitionProvider": true,
+                    "referencesProvider": true,
+                    "hoverProvider": true,
+                    "completionProvider": {},
+                },
+                "serverInfo": { "name": "rlox" },
+            })),
+            "shutdown" => Ok(Json::Null),
+            "exit" => return Ok(()),
+            "textDocument/didOpen" => {
+                let document = &params["textDocument"];
+                let text = document["text"].as_str().unwrap_or_default().to_owned();
+                server.open(document["uri"].as_str().unwrap_or_default(), text)?;
+                continue;
+            }
+            "textDocument/didChange" => {
+                // full sync, so the last change has the whole text
+                let changes = params["contentChanges"].as_array();
+                if let Some(change) = changes.and_then(|changes| changes.last()) {
+                    let text = change["text"].as_str().unwrap_or_default().to_owned();
+                    server.open(
+                        params["textDocument"]["uri"].as_str().unwrap_or_default(),
+                        text,
+                    )?;
+                }
+                continue;
+            }
+            "textDocument/didClose" => {
+                let uri = params["textDocument"]["uri"].as_str().unwrap_or_default();
+                server.documents.remove(uri);
+                server.notify(
+                    "textDocument/publishDiagnostics",
+                    json!({ "uri": uri, "diagnostics": [] }),
+                )?;
+                continue;
+            }
+            "textDocument/definition" => server
+                .definition(params)
+                .map_err(|message| (INVALID_PARAMS, message)),
+            "textDocument/references" => server
+                .references(params)
+                .map_err(|message| (INVALID_PARAMS, message)),
+            "textDocument/hover" => server
+                .hover(params)
+                .map_err(|message| (INVALID_PARAMS, message)),
+            "textDocument/completion" => server
+                .completion(params)
+                .map_err(|message| (INVALID_PARAMS, message)),
+            method => {
+                let message = format!("unsupported method `{method}`");
+                Err((METHOD_NOT_FOUND, message))
+            }
+        };
+        // notifications have no id and get no response
+        let Some(id) = message.get("id") else {
+            continue;
+        };
+        let response = match result {
+            Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+            Err((code, message)) => json!({
+                "jsonrpc": "2.0",
+                "id": id,
+                "error": { "code": code, "message": message },
+            }),
+        };
+        server.send(response)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use compiler::interpreter::output::OutputBuffer;
+
+    const URI: &str = "file:///main.rlox";
+    const SOURCE: &str = "\
+fn add(a, b) a + b
+let total = add(1, 2)
+fn twice(f) do
+    return \\x -> f(f(x))
+end
+println(total)
+";
+
+    fn message(message: Json) -> Vec<u8> {
+        let mut framed = vec![];
+        write_message(&mut framed, &message).unwrap();
+        framed
+    }
+    fn open(uri: &str, text: &str) -> Vec<u8> {
+        message(json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/didOpen",
+            "params": { "textDocument": { "uri": uri, "languageId": "rlox", "version": 1, "text": text } },
+        }))
+    }
+    fn request(id: i64, method: &str, line: usize, character: usize) -> Vec<u8> {
+        message(json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "method": method,
+            "params": {
+                "textDocument": { "uri": URI },
+                "position": { "line": line, "character": character },
+                "context": { "includeDeclaration": true },
+            },
+        }))
+    }
+    /// Runs a session of the given messages, returning what the server sent.
+    fn session(messages: impl IntoIterator<Item = Vec<u8>>) -> Vec<Json> {
+        let input = messages.into_iter().flatten().collect::<Vec<_>>();
+        let output = OutputBuffer::default();
+        serve(input.as_slice(), output.clone()).unwrap();
+        let output = output.contents();
+        let mut output = output.as_bytes();
+        std::iter::from_fn(|| read_message(&mut output).unwrap()).collect()
+    }
+    fn result(messages: &[Json], id: i64) -> &Json {
+        let response = messages.iter().find(|message| message["id"] == id);
+        &response.unwrap()["result"]
+    }
+    fn range(line: usize, start: usize, end: usize) -> Json {
+        json!({
+            "start": { "line": line, "character": start },
+            "end": { "line": line, "character": end },
+        })
+    }
+
+    #[test]
+    fn diagnostics() {
+        let messages = session([
+            open(URI, SOURCE),
+            open("file:///break.rlox", "let a = 1\nbreak\n"),
+            open("file:///end.rlox", "while true do\n"),
+            open("file:///syntax.rlox", "let a = )\nprint(a @ 1)\n"),
+        ]);
+        let diagnostics = |n: usize| &messages[n]["params"]["diagnostics"];
+        assert_eq!(diagnostics(0), &json!([]));
+        assert_eq!(diagnostics(1)[0]["range"], range(1, 0, 5));
+        assert_eq!(
+            diagnostics(1)[0]["message"],
+            "Break statement outside of while/for loop"
+        );
+        assert_eq!(
+            diagnostics(2)[0]["message"],
+            "Expected `end` terminator at the end of block"
+        );
+        // every syntax error is reported
+        let messages = diagnostics(3)
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|diagnostic| &diagnostic["message"])
+            .collect::<Vec<_>>();
+        assert_eq!(messages, ["Expected expression", "Expected `)`"]);
+    }
+
+    #[test]
+    fn navigation() {
+        let messages = session([
+            open(URI, SOURCE),
+            // `add` in `let total = add(1, 2)`
+            request(1, "textDocument/definition", 1, 13),
+            // `f` captured by the closure
+            request(2, "textDocument/references", 3, 18),
+            request(3, "textDocument/hover", 1, 13),
+            request(4, "textDocument/hover", 5, 2),
+            message(json!({ "jsonrpc": "2.0", "id": 5, "method": "shutdown" })),
+            message(json!({ "jsonrpc": "2.0", "method": "exit" })),
+        ]);
+        assert_eq!(
+            result(&messages, 1),
+            &json!({ "uri": URI, "range": range(0, 3, 6) })
+        );
+        let references = result(&messages, 2).as_array().unwrap();
+        let ranges = references
+            .iter()
+            .map(|location| location["range"].clone())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            ranges,
+            [range(2, 9, 10), range(3, 17, 18), range(3, 19, 20)]
+        );
+        assert_eq!(
+            result(&messages, 3)["contents"]["value"],
+            "(global) add: fn(2)\n\nTakes 2 arguments"
+        );
+        let hover = result(&messages, 4)["contents"]["value"].as_str().unwrap();
+        assert!(hover.starts_with("(builtin) println: fn("), "{hover}");
+        assert_eq!(result(&messages, 5), &Json::Null);
+    }
+
+    #[test]
+    fn completion() {
+        let messages = session([
+            open(URI, SOURCE),
+            // inside the closure body
+            request(1, "textDocument/completion", 3, 18),
+            message(json!({ "jsonrpc": "2.0", "id": 2, "method": "textDocument/rename" })),
+        ]);
+        let items = result(&messages, 1).as_array().unwrap();
+        let label = |label: &str| items.iter().find(|item| item["label"] == label);
+        for name in ["x", "f", "add", "twice", "total", "println", "while"] {
+            assert!(label(name).is_some(), "missing {name}");
+        }
+        // parameter of `add`, out of scope
+        assert!(label("a").is_none());
+        assert_eq!(label("add").unwrap()["detail"], "global fn(2)");
+        assert_eq!(label("x").unwrap()["kind"], COMPLETION_VARIABLE);
+        let error = messages.iter().find(|message| message["id"] == 2).unwrap();
+        assert_eq!(error["error"]["code"], METHOD_NOT_FOUND);
+    }
+}
@@ -1,5 +1,28 @@
-use compiler::{ast::Parser, codegen::Codegen, interpreter::Interpreter};
-use std::{env, error::Error, fs, io::BufReader, process::exit, rc::Rc, sync::atomic::Ordering};
+use compiler::{
+    ast::Parser,
+    codegen::Codegen,
+    debugger::{Console, Debugger},
+    error::{self, ErrorKind},
+    formatter,
+    interpreter::{hook::Trace, replay::Replay, Interpreter},
+    source_map::SourceMap,
+    span::Span,
+};
+use std::{
+    env,
+    error::Error,
+    fs,
+    io::{self, BufReader, IsTerminal},
+    path::Path,
+    process::exit,
+    rc::Rc,
+};
+
+mod dap;
+mod lsp;
+mod replay;
+mod test_runner;
+mod transport;
 
 fn print_err_exit(err: impl Error) -> ! {
     eprintln!("{err}");
@@ -7,13 +30,137 @@ fn print_err_exit(err: impl Error) -> ! {
 }
 
 fn main() {
-    let args = env::args().skip(1);
+    let mut args = env::args().skip(1).peekable();
+    // `rlox dap` serves the Debug Adapter Protocol over stdio, the program comes with the launch request
+    if args.next_if(|arg| arg == "dap").is_some() {
+        dap::serve(BufReader::new(io::stdin()), io::stdout())
+            .unwrap_or_else(|err| print_err_exit(err));
+        return;
+    }
+    // `rlox lsp` is a language server over stdio
+    if args.next_if(|arg| arg == "lsp").is_some() {
+        lsp::serve(io::stdin().lock(), io::stdout()).unwrap_or_else(|err| print_err_exit(err));
+        return;
+    }
+    // `rlox fmt [--check] files...` formats the files in place, or with `--check` lists those that aren't formatted
+    if args.next_if(|arg| arg == "fmt").is_some() {
+        let check = args.next_if(|arg| arg == "--check").is_some();
+        let color = io::stderr().is_terminal();
+        let mut unformatted = false;
+        for file_path in args {
+            let source = fs::read_to_string(&file_path).unwrap_or_else(|err| {
+                eprintln!("Error loading file `{}`: {}", file_path, err);
+                exit(1)
+            });
+            let formatted = formatter::format_file(&file_path, &source).unwrap_or_else(|err| {
+                eprintln!("{}", err.render(color));
+                exit(1)
+            });
+            if formatted == source {
+                continue;
+            }
+            if check {
+                println!("{file_path}");
+                unformatted = true;
+            } else {
+                fs::write(&file_path, formatted).unwrap_or_else(|err| print_err_exit(err));
+            }
+        }
+        exit(unformatted as i32);
+    }
+    // `rlox test [--filter name] paths...` runs the `test_*` functions of the files, and of the `.rlox` files in
+    // directories, each in a fresh interpreter
+    if args.next_if(|arg| arg == "test").is_some() {
+        let mut filter = None;
+        let mut paths = vec![];
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--filter" => match args.next() {
+                    Some(name) => filter = Some(name),
+                    None => {
+                        eprintln!("Missing name after --filter");
+                        exit(1)
+                    }
+                },
+                _ => paths.push(arg),
+            }
+        }
+        if paths.is_empty() {
+            paths.push(".".to_string());
+        }
+        let color = io::stdout().is_terminal();
+        let passed = test_runner::run(&paths, filter.as_deref(), color, &mut io::stdout())
+            .unwrap_or_else(|err| print_err_exit(err));
+        exit(!passed as i32);
+    }
+    // `rlox record file.rlox [out.rrec]` runs the file and writes the inputs it read, `file.rrec` by default
+    // `rlox replay file.rlox [in.rrec]` steps back and forth through a recorded run
+    if let Some(command) = args.next_if(|arg| arg == "record" || arg == "replay") {
+        let Some(file_path) = args.next() else {
+            eprintln!("No file specified");
+            exit(1)
+        };
+        let recording_path = args.next().unwrap_or_else(|| {
+            let path = Path::new(&file_path).with_extension("rrec");
+            path.display().to_string()
+        });
+        let source = fs::read_to_string(&file_path).unwrap_or_else(|err| {
+            eprintln!("Error loading file `{}`: {}", file_path, err);
+            exit(1)
+        });
+        let color = io::stderr().is_terminal();
+        if command == "record" {
+            let (recording, error) = replay::record(&file_path, &source);
+            let mut out = vec![];
+            recording
+                .write(&mut out)
+                .unwrap_or_else(|err| print_err_exit(err));
+            fs::write(&recording_path, out).unwrap_or_else(|err| print_err_exit(err));
+            if let Some(err) = error {
+                eprintln!("{}", err.render(color));
+                exit(1)
+            }
+        } else {
+            let recording = replay::read_recording(&recording_path).unwrap_or_else(|err| {
+                eprintln!("Error loading recording `{}`: {}", recording_path, err);
+                exit(1)
+            });
+            let replay = Replay::new(file_path, source, recording);
+            replay::run(&replay, io::stdin().lock(), &mut io::stdout())
+                .unwrap_or_else(|err| print_err_exit(err));
+        }
+        return;
+    }
+    // `rlox debug file.rlox` runs the file under the interactive debugger
+    let debugger = args.next_if(|arg| arg == "debug").is_some();
+    // `rlox profile file.rlox [--folded out.folded]` prints time and instructions per function and source line,
+    // and writes the call stacks for flamegraph tools
+    let profile = !debugger && args.next_if(|arg| arg == "profile").is_some();
+    // `rlox coverage file.rlox [--lcov out.info]` prints the lines and branches covered per file,
+    // and writes them as an lcov tracefile, `lcov.info` by default
+    let coverage = !debugger && !profile && args.next_if(|arg| arg == "coverage").is_some();
+    let mut folded_path = None;
+    let mut lcov_path = "lcov.info".to_string();
     let mut file_path = None;
     let mut debug = false;
 
-    for arg in args {
+    while let Some(arg) = args.next() {
         match arg.as_str() {
             "-d" | "--debug" => debug = true,
+            "--folded" if profile => match args.next() {
+                Some(path) => folded_path = Some(path),
+                None => {
+                    eprintln!("Missing file after --folded");
+                    exit(1)
+                }
+            },
+            "--lcov" if coverage => match args.next() {
+                Some(path) => lcov_path = path,
+                None => {
+                    eprintln!("Missing file after --lcov");
+                    exit(1)
+                }
+            },
             _ => {
                 file_path = match file_path {
                     Some(_) => {
@@ -26,8 +173,6 @@ fn main() {
         }
     }
 
-    compiler::DEBUG_MODE.store(debug, Ordering::Relaxed);
-
     let file_path = match &file_path {
         Some(f) => f,
         None => {
@@ -42,31 +187,87 @@ fn main() {
             exit(1)
         }
     };
-    let mut parser = Parser::new(BufReader::new(file));
-    let mut codegen = Codegen::with_source(parser.source());
+    let sources = SourceMap::new();
+    let mut parser = Parser::with_file(BufReader::new(file), &sources, file_path);
     let mut interpreter = Interpreter::default();
+    let mut codegen = Codegen::with_context(parser.sources(), interpreter.context());
 
-    while let Some(statement) = parser
-        .next_statement()
-        .unwrap_or_else(|err| print_err_exit(err))
-    {
+    // diagnostics are colored when they're read in a terminal
+    let color = io::stderr().is_terminal();
+    let (statements, errors) = parser.parse_program();
+    if !errors.is_empty() {
+        for err in errors {
+            eprintln!("{}", err.render(color));
+        }
+        exit(1)
+    }
+    for statement in statements {
         if debug {
             println!("{}", statement);
         }
-        codegen
-            .gen_statement(&statement)
-            .unwrap_or_else(|err| print_err_exit(err));
+        if let Err(err) = codegen.gen_statement(&statement) {
+            eprintln!("{}", err.render(color));
+            exit(1)
+        }
     }
 
     if debug {
         for bc in codegen.bytecodes() {
-            println!("{:?}", bc.1);
+            let (line, column) = sources.line_col(bc.0.file, bc.0.start);
+            println!("{}:{}:{} {:?}", sources.name(bc.0.file), line, column, bc.1);
         }
     }
 
     let init_sig = Rc::new(codegen.pop_init_sig());
+    if debugger {
+        let console = Console::new(io::stdin().lock(), io::stdout());
+        Debugger::new(parser.sources(), parser.file(), &init_sig).attach(&mut interpreter, console);
+    } else if debug {
+        // every instruction is traced with its location and the stack it runs on
+        interpreter.set_hook(Some(Box::new(Trace::with_sources(sources.clone()))));
+    }
     let init_fn = Rc::new(interpreter.create_function(init_sig));
-    interpreter
-        .call_function_args(init_fn, std::iter::empty())
-        .unwrap_or_else(|err| print_err_exit(err));
+    if profile {
+        interpreter.start_profile();
+    }
+    if coverage {
+        interpreter.start_coverage();
+    }
+    let result = interpreter.call_function_args(init_fn, std::iter::empty());
+    if let Some(profile) = interpreter.take_profile() {
+        profile
+            .write_report(&sources, 20, &mut io::stderr())
+            .unwrap_or_else(|err| print_err_exit(err));
+        if let Some(path) = &folded_path {
+            let mut folded = vec![];
+            profile
+                .write_folded(&sources, &mut folded)
+                .unwrap_or_else(|err| print_err_exit(err));
+            fs::write(path, folded).unwrap_or_else(|err| print_err_exit(err));
+        }
+    }
+    if let Some(coverage) = interpreter.take_coverage() {
+        coverage
+            .write_summary(&sources, &mut io::stderr())
+            .unwrap_or_else(|err| print_err_exit(err));
+        let mut lcov = vec![];
+        coverage
+            .write_lcov(&sources, &mut lcov)
+            .unwrap_or_else(|err| print_err_exit(err));
+        fs::write(&lcov_path, lcov).unwrap_or_else(|err| print_err_exit(err));
+    }
+    match result {
+        Ok(_) => {}
+        // quitting the debugger
+        Err(ErrorKind::Interrupted) if debugger => {}
+        Err(kind) => {
+            let end = parser.source().borrow().len();
+            let span = interpreter
+                .error_span()
+                .unwrap_or(Span::from_len(end, 0).in_file(parser.file()));
+            let err = error::Error::new(kind, span, sources);
+            eprintln!("{}", err.render(color));
+            exit(1)
+        }
+    }
 }
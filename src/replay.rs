@@ -0,0 +1,178 @@
+//! `rlox record` and `rlox replay`, which record the inputs of a run and travel back and forth through it.
+//!
+//! Replays run the script with the recorded inputs up to the instruction asked for, resuming from the nearest
+//! checkpoint an earlier command saved, so going back is quick once the run was replayed past there. Files the run
+//! changed aren't changed again.
+use compiler::{
+    error::Error,
+    interpreter::replay::{Recording, Replay},
+    vm::Vm,
+};
+use std::{
+    cell::RefCell,
+    fs,
+    io::{self, BufRead, Write},
+    rc::Rc,
+};
+
+const HELP: &str = "\
+goto <n>         replay up to instruction n, or `g`
+step             go forward one instruction, or `s`
+back             go back one instruction, or `b`
+write <name>     go back to the last write of a variable, or `w`
+end              go to the last instruction
+help             print this help, or `h`
+quit             exit, or `q`";
+
+/// Runs `source` to completion while recording it. Returns the recording along with the script's error, if it
+/// failed.
+pub fn record(name: &str, source: &str) -> (Recording, Option<Error>) {
+    let mut vm = Vm::new();
+    vm.interpreter().start_recording();
+    let result = vm.eval_file(name, source);
+    let recording = vm.interpreter().take_recording().unwrap_or_default();
+    (recording, result.err())
+}
+
+/// Where a replay stopped: its location, line of source and the locals of the innermost frame.
+fn describe(replay: &Replay, instruction: u64) -> Result<Option<String>, Error> {
+    let description = Rc::new(RefCell::new(String::new()));
+    let written = description.clone();
+    let reached = replay.run_to(instruction, move |interpreter, sources| {
+        let frame = interpreter.frame_count() - 1;
+        let mut description = written.borrow_mut();
+        let location = interpreter
+            .frame_span(frame)
+            .filter(|span| *span != Default::default());
+        *description = match location {
+            Some(span) => {
+                let (line, column) = sources.line_col(span.file, span.start);
+                let text = sources.line_text(span.file, line);
+                format!(
+                    "{}:{line}:{column}: {}\n",
+                    sources.name(span.file),
+                    text.trim()
+                )
+            }
+            None => "<unknown location>\n".to_owned(),
+        };
+        for (name, value) in interpreter.frame_locals(frame) {
+            description.push_str(&format!("  {name} = {value}\n"));
+        }
+    })?;
+    Ok(reached.then(|| description.take()))
+}
+
+/// Reads commands from `input` until it ends or says to quit, starting at the first instruction.
+pub fn run(replay: &Replay, input: impl BufRead, out: &mut impl Write) -> io::Result<()> {
+    let last = replay.recording().instructions();
+    let mut position = 1;
+    writeln!(out, "Replaying {last} instructions, try `help`")?;
+    let mut show = true;
+    let mut lines = input.lines();
+    loop {
+        if show {
+            match describe(replay, position) {
+                Ok(Some(description)) => {
+                    write!(out, "Instruction {position}/{last} at {description}")?
+                }
+                Ok(None) => writeln!(out, "The run ends before instruction {position}")?,
+                Err(err) => write!(out, "{}", err.render(false))?,
+            }
+        }
+        show = true;
+        write!(out, "(replay) ")?;
+        out.flush()?;
+        let Some(command) = lines.next().transpose()? else {
+            writeln!(out)?;
+            return Ok(());
+        };
+        let (command, arg) = match command.trim().split_once(' ') {
+            Some((command, arg)) => (command.to_owned(), arg.trim().to_owned()),
+            None => (command.trim().to_owned(), String::new()),
+        };
+        match command.as_str() {
+            "goto" | "g" => match arg.parse::<u64>() {
+                Ok(n) if n >= 1 => position = n,
+                _ => {
+                    writeln!(out, "No instruction `{arg}`")?;
+                    show = false;
+                }
+            },
+            "step" | "s" => position += 1,
+            "back" | "b" => position = position.saturating_sub(1).max(1),
+            "write" | "w" => match replay.previous_write(&arg, position) {
+                Ok(Some(write)) => position = write,
+                Ok(None) => {
+                    writeln!(out, "No write of `{arg}` before instruction {position}")?;
+                    show = false;
+                }
+                Err(err) => {
+                    write!(out, "{}", err.render(false))?;
+                    show = false;
+                }
+            },
+            "end" => position = last.max(1),
+            "help" | "h" => {
+                writeln!(out, "{HELP}")?;
+                show = false;
+            }
+            "quit" | "q" => return Ok(()),
+            "" => show = false,
+            _ => {
+                writeln!(out, "Unknown command `{command}`, try `help`")?;
+                show = false;
+            }
+        }
+    }
+}
+
+/// Reads a recording written by `rlox record`.
+pub fn read_recording(path: &str) -> io::Result<Recording> {
+    Recording::read(&mut fs::File::open(path)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_replay() {
+        let source = "\
+let total = 0
+for i in range(0, 3, 1) do
+    total = total + i
+end
+";
+        let (recording, error) = record("sum.rlox", source);
+        assert!(error.is_none());
+        let mut written = vec![];
+        recording.write(&mut written).unwrap();
+        let recording = Recording::read(&mut written.as_slice()).unwrap();
+        let replay = Replay::new("sum.rlox", source, recording);
+
+        let input = "end\nwrite total\nback\nwrite nothing\ngoto 100000\nfly\nq\n";
+        let mut out = vec![];
+        run(&replay, input.as_bytes(), &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let shown = out
+            .lines()
+            .filter_map(|line| line.split_once("Instruction "))
+            .map(|(_, shown)| shown)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            shown,
+            [
+                "1/61 at sum.rlox:1:1: let total = 0",
+                "61/61 at <unknown location>",
+                // the last assignment, in the last iteration
+                "50/61 at sum.rlox:3:5: total = total + i",
+                "49/61 at sum.rlox:3:13: total = total + i",
+            ]
+        );
+        assert!(out.contains("49/61 at sum.rlox:3:13: total = total + i\n  i = 2\n"));
+        assert!(out.contains("No write of `nothing` before instruction"));
+        assert!(out.contains("The run ends before instruction 100000"));
+        assert!(out.contains("Unknown command `fly`, try `help`"));
+    }
+}
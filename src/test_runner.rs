@@ -0,0 +1,231 @@
+//! Test runner of `rlox test`, which runs every `test_*` function declared at the top of `.rlox` files.
+//!
+//! Each test gets a fresh VM that runs the whole file before calling the test, so tests can't leak globals into one
+//! another. Output of passing tests is dropped, failing tests show theirs along with the error.
+use compiler::{
+    ast::{declaration::Declaration, statement::Statement, Parser},
+    error::{Error, ErrorKind},
+    source_map::SourceMap,
+    vm::Vm,
+};
+use std::{
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+const RED: &str = "\x1b[31m";
+const GREEN: &str = "\x1b[32m";
+const RESET: &str = "\x1b[0m";
+
+struct Failure {
+    name: String,
+    output: String,
+    error: Error,
+}
+
+/// `.rlox` files in `path` and the directories below it, in order, or `path` itself if it's a file.
+fn discover(path: &Path, files: &mut Vec<PathBuf>) -> io::Result<()> {
+    if !path.is_dir() {
+        files.push(path.to_owned());
+        return Ok(());
+    }
+    let mut entries = fs::read_dir(path)?
+        .map(|entry| Ok(entry?.path()))
+        .collect::<io::Result<Vec<_>>>()?;
+    entries.sort();
+    for entry in entries {
+        if entry.is_dir() {
+            discover(&entry, files)?;
+        } else if entry.extension().is_some_and(|ext| ext == "rlox") {
+            files.push(entry);
+        }
+    }
+    Ok(())
+}
+
+/// Names of the `test_*` functions declared at the top level of `source`, or its syntax errors.
+fn test_names(path: &str, source: &str) -> Result<Vec<String>, Vec<Error>> {
+    let mut parser = Parser::with_file(source.as_bytes(), &SourceMap::new(), path);
+    let (statements, errors) = parser.parse_program();
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    let names = statements.iter().filter_map(|statement| match statement {
+        Statement::Declaration(Declaration::FuncDecl(decl)) => {
+            Some(decl.ident.get_str().to_string())
+        }
+        _ => None,
+    });
+    Ok(names.filter(|name| name.starts_with("test_")).collect())
+}
+
+/// Runs the tests in `paths`, files or directories searched for `.rlox` files, whose `file::name` contains `filter`.
+/// Writes the results to `out` and returns whether every test passed.
+pub fn run(
+    paths: &[String],
+    filter: Option<&str>,
+    color: bool,
+    out: &mut impl Write,
+) -> io::Result<bool> {
+    let paint = |style: &str, text: &str| match color {
+        true => format!("{style}{text}{RESET}"),
+        false => text.to_string(),
+    };
+    let mut files = vec![];
+    for path in paths {
+        discover(Path::new(path), &mut files)?;
+    }
+
+    let start = Instant::now();
+    let (mut passed, mut filtered) = (0, 0);
+    let mut failures = vec![];
+    for file in files {
+        let path = file.display().to_string();
+        let source = fs::read_to_string(&file)?;
+        let names = match test_names(&path, &source) {
+            Ok(names) => names,
+            Err(errors) => {
+                writeln!(out, "test {path} ... {}", paint(RED, "FAILED"))?;
+                for error in errors {
+                    failures.push(Failure {
+                        name: path.clone(),
+                        output: String::new(),
+                        error,
+                    });
+                }
+                continue;
+            }
+        };
+        for name in names {
+            let name = format!("{path}::{name}");
+            let test = &name[path.len() + 2..];
+            if filter.is_some_and(|filter| !name.contains(filter)) {
+                filtered += 1;
+                continue;
+            }
+            let mut vm = Vm::new();
+            let output = vm.interpreter().capture_stdout();
+            let test_start = Instant::now();
+            let result = vm
+                .eval_file(&path, &source)
+                .and_then(|_| vm.call_global(test, []));
+            let time = millis(test_start.elapsed());
+            match result {
+                Ok(_) => {
+                    writeln!(out, "test {name} ... {} ({time})", paint(GREEN, "ok"))?;
+                    passed += 1;
+                }
+                Err(error) => {
+                    writeln!(out, "test {name} ... {} ({time})", paint(RED, "FAILED"))?;
+                    failures.push(Failure {
+                        name,
+                        output: output.take(),
+                        error,
+                    });
+                }
+            }
+        }
+    }
+
+    if !failures.is_empty() {
+        writeln!(out, "\nfailures:")?;
+    }
+    for failure in &failures {
+        writeln!(out, "\n---- {} ----", failure.name)?;
+        if !failure.output.is_empty() {
+            writeln!(out, "{}", failure.output.trim_end())?;
+        }
+        write!(out, "{}", failure.error.render(color))?;
+        if let ErrorKind::AssertEqFailed(mismatch) = &failure.error.kind {
+            writeln!(
+                out,
+                "{}",
+                paint(RED, &format!("-  left: {}", mismatch.left))
+            )?;
+            writeln!(
+                out,
+                "{}",
+                paint(GREEN, &format!("+ right: {}", mismatch.right))
+            )?;
+        }
+    }
+    let result = match failures.is_empty() {
+        true => paint(GREEN, "ok"),
+        false => paint(RED, "FAILED"),
+    };
+    writeln!(
+        out,
+        "\ntest result: {result}. {passed} passed; {} failed; {filtered} filtered out; finished in {}",
+        failures.len(),
+        millis(start.elapsed())
+    )?;
+    Ok(failures.is_empty())
+}
+
+fn millis(time: Duration) -> String {
+    format!("{:.2}ms", time.as_secs_f64() * 1000.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn test_run() {
+        let dir = env::temp_dir().join(format!("rlox-test-{}", std::process::id()));
+        fs::create_dir_all(dir.join("nested")).unwrap();
+        let math = "\
+fn square(x) x * x
+fn test_square() do
+    assert_eq(square(3), 9)
+end
+fn test_list() do
+    println(\"checking\")
+    assert_eq([square(1), square(2)], [1, 5])
+end
+";
+        fs::write(dir.join("math.rlox"), math).unwrap();
+        fs::write(
+            dir.join("nested/error.rlox"),
+            "fn test_error() do\n    assert(assert_error(\\ -> nil + 1) != nil)\nend\n",
+        )
+        .unwrap();
+        fs::write(dir.join("notes.txt"), "fn test_skipped() nil").unwrap();
+
+        let paths = [dir.display().to_string()];
+        let mut out = vec![];
+        assert!(!run(&paths, None, false, &mut out).unwrap());
+        let out = String::from_utf8(out).unwrap();
+        let math = dir.join("math.rlox").display().to_string();
+        let lines = out.lines().map(|line| line.split(" (").next().unwrap());
+        let results = lines
+            .filter(|line| line.contains(" ... "))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            results,
+            [
+                format!("test {math}::test_square ... ok"),
+                format!("test {math}::test_list ... FAILED"),
+                format!(
+                    "test {}::test_error ... ok",
+                    dir.join("nested/error.rlox").display()
+                ),
+            ]
+        );
+        assert!(out.contains(&format!(
+            "---- {math}::test_list ----\nchecking\nerror: Assertion failed: `[1, 4]` != `[1, 5]`, \
+             first differing at `[1]`\n --> {math}:7:14\n"
+        )));
+        assert!(out.contains("-  left: [1, 4]\n+ right: [1, 5]\n"));
+        assert!(out.contains("test result: FAILED. 2 passed; 1 failed; 0 filtered out;"));
+
+        let mut out = vec![];
+        assert!(run(&paths, Some("square"), false, &mut out).unwrap());
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("test result: ok. 1 passed; 0 failed; 2 filtered out;"));
+        fs::remove_dir_all(dir).unwrap();
+    }
+}